@@ -0,0 +1,53 @@
+#!/bin/bash
+#
+# Rebuilds the FAT12 and FAT16 test images of lib/fat32, fat12.img and
+# fat16.img in ext/fat32-imgs. Each image holds an MBR with one partition
+# starting at sector 63 that contains:
+#
+#   /HELLO.TXT                   "hello from fat12\n" (or fat16)
+#   /DATA.BIN                    5000 bytes of `pattern(5000, 1)`
+#   /SUBDIR/a long file name.txt "nested\n"
+#
+# Requires sfdisk, mkfs.fat (dosfstools) and mtools.
+
+set -e
+
+TOP=$(git rev-parse --show-toplevel)
+FAT=$TOP/ext/fat32-imgs
+
+# first sector of the partition
+START=63
+
+# pattern LEN SEED: the bytes of `pattern()` in lib/fat32/src/tests.rs
+pattern() {
+    python3 -c "import sys; sys.stdout.buffer.write(bytes((i * 31 + $2) & 0xFF for i in range($1)))"
+}
+
+# mkimg NAME FAT_BITS PARTITION_TYPE SIZE_KB
+#
+# SIZE_KB is the partition size. It keeps fat12 below and fat16 above the
+# 4085 cluster boundary with one sector per cluster.
+mkimg() {
+    local img=$FAT/$1.img
+    local part="$img@@$(( START * 512 ))"
+    local tmp=$(mktemp -d)
+
+    rm -f $img
+    truncate -s $(( START * 512 + $4 * 1024 )) $img
+    echo "$START,$(( $4 * 2 )),$3,*" | sfdisk -q $img
+    mkfs.fat -F $2 -S 512 -s 1 -f 2 -r 512 -R 1 -h $START --offset $START $img $4 >/dev/null
+
+    echo "hello from $1" > $tmp/HELLO.TXT
+    pattern 5000 1 > $tmp/DATA.BIN
+    echo "nested" > "$tmp/a long file name.txt"
+
+    export MTOOLS_SKIP_CHECK=1
+    mcopy -i $part $tmp/HELLO.TXT $tmp/DATA.BIN ::/
+    mmd -i $part ::/SUBDIR
+    mcopy -i $part "$tmp/a long file name.txt" ::/SUBDIR/
+
+    rm -rf $tmp
+}
+
+mkimg fat12 12 1 1536
+mkimg fat16 16 6 2176
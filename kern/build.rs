@@ -1,3 +1,19 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
 pub fn main() {
     println!("cargo:rerun-if-changed=.cargo/layout.ld");
+
+    // the ustar or newc cpio archive linked into the kernel and mounted at
+    // /init. Without INITRAMFS the kernel gets an empty one.
+    println!("cargo:rerun-if-env-changed=INITRAMFS");
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("initramfs");
+    match env::var("INITRAMFS") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            fs::copy(&path, &out).expect("failed to copy INITRAMFS");
+        }
+        Err(_) => fs::write(&out, b"").unwrap(),
+    }
 }
@@ -0,0 +1,236 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use hashbrown::HashMap;
+
+use mountfs::fs::FileSystem;
+use mountfs::mount::*;
+use mountfs::mount::mfs::{Dir, FileId, FsId};
+use shim::{io, ioerr, newioerr};
+use shim::ffi::OsStr;
+use shim::path::{Component, Path};
+
+use crate::fs::handle::{FramebufferCursor, Sink, Source};
+use crate::iosync::{Global, SyncRead, SyncWrite};
+use crate::sync::Waitable;
+
+pub static DEV_FILES: Global<DevFiles> = Global::new(|| DevFiles::new());
+
+/// Creates the source and sink backing one open of a device.
+pub type DeviceFactory = Box<dyn Fn() -> (Source, Sink) + Send + Sync + 'static>;
+
+struct Device {
+    name: Arc<String>,
+    inode: usize,
+    factory: DeviceFactory,
+}
+
+pub struct DevFiles {
+    next_inode: usize,
+    devices: HashMap<String, Device>,
+}
+
+impl DevFiles {
+    pub fn new() -> Self {
+        let mut s = Self {
+            next_inode: 1,
+            devices: HashMap::new(),
+        };
+
+        s.add_device(String::from("console"), Box::new(|| (Source::KernSerial, Sink::KernSerial)));
+        s.add_device(String::from("null"), Box::new(|| (Source::Nil, Sink::Nil)));
+        s.add_device(String::from("zero"), Box::new(|| (Source::Zero, Sink::Nil)));
+        s.add_device(String::from("random"), Box::new(|| (Source::Random, Sink::Nil)));
+        s.add_device(String::from("fb0"), Box::new(|| {
+            let cursor = FramebufferCursor::new();
+            (Source::Framebuffer(cursor.clone()), Sink::Framebuffer(cursor))
+        }));
+
+        s
+    }
+
+    /// Adds `/dev/<name>`. Every open of the device calls `factory` for the
+    /// source and sink serving it.
+    pub fn add_device(&mut self, name: String, factory: DeviceFactory) {
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.devices.insert(name.clone(), Device {
+            name: Arc::new(name),
+            inode,
+            factory,
+        });
+    }
+}
+
+pub struct DevFileSystem {
+    id: FsId,
+}
+
+impl DevFileSystem {
+    pub fn new() -> Self {
+        Self {
+            id: 0,
+        }
+    }
+}
+
+impl mfs::FileSystem for DevFileSystem {
+    fn set_id(&mut self, id: FsId) {
+        self.id = id;
+    }
+
+    fn get_name(&self) -> Option<String> {
+        Some(String::from("dev"))
+    }
+
+    fn open(&self, _manager: &FileSystem, path: &Path) -> io::Result<mfs::Entry> {
+        for comp in path.components() {
+            if !matches!(comp, Component::RootDir) {
+                return ioerr!(NotFound, "unexpected path component in open()");
+            }
+        }
+
+        Ok(mfs::Entry::Dir(Arc::new(DevDir(self.id))))
+    }
+
+    fn entries(&self, _manager: &FileSystem, _dir: Arc<dyn Dir>) -> io::Result<Box<dyn Iterator<Item=mfs::DirEntry>>> {
+        let mut vec = Vec::new();
+
+        DEV_FILES.critical(|files| {
+            for (name, device) in files.devices.iter() {
+                vec.push(mfs::DirEntry::new(
+                    String::clone(name), Metadata::default(), 0,
+                    false, FileId(self.id, device.inode)));
+            }
+        });
+
+        Ok(Box::new(vec.into_iter()))
+    }
+
+    fn dir_entry(&self, _manager: &FileSystem, _dir: Arc<dyn Dir>, path: &OsStr) -> io::Result<mfs::Entry> {
+        let fs_id = self.id;
+        let name = path.to_string_lossy().into_owned();
+
+        DEV_FILES.critical(|files| {
+            let device = files.devices.get(&name).ok_or(newioerr!(NotFound, "no such device"))?;
+            let (source, sink) = (device.factory)();
+
+            Ok(mfs::Entry::File(Box::new(DevFile {
+                id: FileId(fs_id, device.inode),
+                name: device.name.clone(),
+                source: Arc::new(source),
+                sink,
+            })))
+        })
+    }
+}
+
+struct DevDir(FsId);
+
+impl mfs::FileInfo for DevDir {
+    fn name(&self) -> &str {
+        "/dev"
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata::default()
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn is_directory(&self) -> bool {
+        true
+    }
+
+    fn get_id(&self) -> FileId {
+        FileId(self.0, 0)
+    }
+}
+
+impl mfs::Dir for DevDir {}
+
+/// An open device. Reads from devices that may block fail with `WouldBlock`
+/// until data is available, instead of returning end of file.
+struct DevFile {
+    id: FileId,
+    name: Arc<String>,
+    source: Arc<Source>,
+    sink: Sink,
+}
+
+impl mfs::FileInfo for DevFile {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata::default()
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn is_directory(&self) -> bool {
+        false
+    }
+
+    fn get_id(&self) -> FileId {
+        self.id
+    }
+}
+
+impl io::Read for DevFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.source.may_block() && !self.source.done_waiting() {
+            return ioerr!(WouldBlock, "no data available");
+        }
+        self.source.read(buf)
+    }
+}
+
+impl io::Write for DevFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sink.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Seek for DevFile {
+    /// Only the framebuffer is seekable, other devices stay at offset 0.
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let cursor = match &self.sink {
+            Sink::Framebuffer(cursor) => cursor,
+            _ => return Ok(0),
+        };
+
+        let position = match pos {
+            io::SeekFrom::Start(offset) => offset as i64,
+            io::SeekFrom::Current(offset) => cursor.position() as i64 + offset,
+            io::SeekFrom::End(_) => return ioerr!(InvalidInput, "cannot seek from the end of a device"),
+        };
+        if position < 0 {
+            return ioerr!(InvalidInput, "cannot seek before start of file");
+        }
+
+        cursor.seek(position as usize);
+        Ok(position as u64)
+    }
+}
+
+impl mfs::File for DevFile {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+}
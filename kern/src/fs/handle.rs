@@ -1,21 +1,115 @@
 use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 use dsx::sync::mutex::LockableMutex;
 
 use shim::io;
+use shim::ioerr;
 
-use crate::{smp, sync};
+use crate::{smp, sync, timer};
 use crate::console::CONSOLE;
+use crate::display_manager::DISPLAY;
 use crate::iosync::{SyncRead, SyncWrite};
 use crate::kernel_call::syscall;
 use crate::net::buffer;
 use crate::sync::Waitable;
 
+/// Byte offset into the framebuffer, shared by the source and sink of one
+/// open `/dev/fb0`.
+#[derive(Clone)]
+pub struct FramebufferCursor(Arc<AtomicUsize>);
+
+impl FramebufferCursor {
+    pub fn new() -> Self {
+        Self(Arc::new(AtomicUsize::new(0)))
+    }
+
+    pub fn seek(&self, offset: usize) {
+        self.0.store(offset, Ordering::Relaxed);
+    }
+
+    pub fn position(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Runs `func` on the framebuffer bytes from the cursor onwards and
+    /// advances the cursor by the number of bytes `func` returns.
+    fn with_bytes<F: FnOnce(&mut [u8]) -> usize>(&self, func: F) -> io::Result<usize> {
+        let mut display = m_lock!(DISPLAY);
+        let display = match display.as_mut() {
+            Some(display) => display,
+            None => return ioerr!(NotConnected, "no framebuffer"),
+        };
+
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(display.lfb.as_mut_ptr() as *mut u8, display.lfb.len() * 4)
+        };
+
+        let position = self.position();
+        if position >= bytes.len() {
+            return Ok(0);
+        }
+
+        let amount = func(&mut bytes[position..]);
+        self.0.fetch_add(amount, Ordering::Relaxed);
+        Ok(amount)
+    }
+}
+
+static RANDOM_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Advances a xorshift64 generator state by one step.
+fn next_state(mut state: u64) -> u64 {
+    state ^= state >> 12;
+    state ^= state << 25;
+    state ^= state >> 27;
+    state
+}
+
+/// Fills `buf` from a xorshift64* generator seeded from the system timer.
+/// The output is not suitable for cryptographic use.
+fn fill_random(buf: &mut [u8]) {
+    if RANDOM_STATE.load(Ordering::Relaxed) == 0 {
+        let seed = (timer::current_time().as_nanos() as u64) | 1;
+        let _ = RANDOM_STATE.compare_exchange(0, seed, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    // claim every state `buf` needs at once so that concurrent readers never
+    // get the same bytes.
+    let steps = (buf.len() + 7) / 8;
+    let mut state = RANDOM_STATE.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |mut state| {
+        for _ in 0..steps {
+            state = next_state(state);
+        }
+        Some(state)
+    }).unwrap();
+
+    for chunk in buf.chunks_mut(8) {
+        state = next_state(state);
+        let value = state.wrapping_mul(0x2545_F491_4F6C_DD1D).to_le_bytes();
+        chunk.copy_from_slice(&value[..chunk.len()]);
+    }
+}
+
 #[derive(Clone)]
 pub enum Source {
     KernSerial,
     Buffer(buffer::BufferHandle),
     Nil,
+    Zero,
+    Random,
+    Framebuffer(FramebufferCursor),
+}
+
+impl Source {
+    /// Whether a read may have to wait for data to arrive. Reads from other
+    /// sources return immediately.
+    pub fn may_block(&self) -> bool {
+        match self {
+            Source::KernSerial | Source::Buffer(_) => true,
+            _ => false,
+        }
+    }
 }
 
 impl SyncRead for Source {
@@ -32,6 +126,21 @@ impl SyncRead for Source {
                 b.read(buf).map_err(|e| e.into_io_err())
             }
             Source::Nil => Ok(0),
+            Source::Zero => {
+                for byte in buf.iter_mut() {
+                    *byte = 0;
+                }
+                Ok(buf.len())
+            }
+            Source::Random => {
+                fill_random(buf);
+                Ok(buf.len())
+            }
+            Source::Framebuffer(cursor) => cursor.with_bytes(|bytes| {
+                let amount = core::cmp::min(bytes.len(), buf.len());
+                buf[..amount].copy_from_slice(&bytes[..amount]);
+                amount
+            }),
         }
     }
 }
@@ -50,6 +159,7 @@ impl sync::Waitable for Source {
                 buffer::ReadWaitable(b.clone()).done_waiting()
             }
             Source::Nil => false,
+            Source::Zero | Source::Random | Source::Framebuffer(_) => true,
         }
     }
 
@@ -58,6 +168,9 @@ impl sync::Waitable for Source {
             Source::KernSerial => "Source::KernSerial",
             Source::Buffer(_) => "Source::Buffer",
             Source::Nil => "Source::Nil",
+            Source::Zero => "Source::Zero",
+            Source::Random => "Source::Random",
+            Source::Framebuffer(_) => "Source::Framebuffer",
         }
     }
 }
@@ -68,6 +181,7 @@ pub enum Sink {
     KernSerial,
     Buffer(buffer::BufferHandle),
     Nil,
+    Framebuffer(FramebufferCursor),
 }
 
 impl Sink {
@@ -76,6 +190,7 @@ impl Sink {
             Sink::KernSerial => None,
             Sink::Buffer(b) => Some(b.free_capacity()),
             Sink::Nil => None,
+            Sink::Framebuffer(_) => None,
         }
     }
 }
@@ -94,6 +209,11 @@ impl SyncWrite for Sink {
                 b.write(buf).map_err(|e| e.into_io_err())
             }
             Sink::Nil => Ok(buf.len()),
+            Sink::Framebuffer(cursor) => cursor.with_bytes(|bytes| {
+                let amount = core::cmp::min(bytes.len(), buf.len());
+                bytes[..amount].copy_from_slice(&buf[..amount]);
+                amount
+            }),
         }
     }
 }
@@ -107,6 +227,7 @@ impl sync::Waitable for Sink {
                 buffer::WriteWaitable(b.clone()).done_waiting()
             }
             Sink::Nil => true,
+            Sink::Framebuffer(_) => true,
         }
     }
 
@@ -115,6 +236,7 @@ impl sync::Waitable for Sink {
             Sink::KernSerial => "Sink::KernSerial",
             Sink::Buffer(_) => "Sink::Buffer",
             Sink::Nil => "Sink::Nil",
+            Sink::Framebuffer(_) => "Sink::Framebuffer",
         }
     }
 }
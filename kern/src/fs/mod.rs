@@ -10,8 +10,11 @@ use shim::path::Path;
 
 use crate::mutex::Mutex;
 
+pub mod dev;
 pub mod handle;
+pub mod ninep;
 pub mod proc;
+pub mod ramdisk;
 pub mod sd;
 pub mod service;
 
@@ -0,0 +1,74 @@
+use alloc::boxed::Box;
+use core::time::Duration;
+
+use mountfs::NinePFileSystem;
+use pi::timer;
+use shim::{io, ioerr};
+
+use crate::fs::handle::{Sink, Source};
+use crate::iosync::{SyncRead, SyncWrite};
+use crate::net::ipv4;
+use crate::NET;
+
+/// The TCP port 9P servers listen on by default.
+pub const DEFAULT_PORT: u16 = 564;
+
+/// How long to wait for the server before a read or write fails.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait between polls of an idle network.
+const POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+/// Services the network while waiting for the server. 9P requests are made
+/// while handling system calls, where the calling process cannot sleep, so
+/// the connection is driven from here rather than by the network task.
+fn poll_network() {
+    if !NET.critical(|net| net.dispatch()) {
+        timer::spin_sleep(POLL_INTERVAL);
+    }
+}
+
+/// The receiving half of a TCP connection, polled until data arrives.
+struct TcpReader(Source);
+
+impl io::Read for TcpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = timer::current_time() + TIMEOUT;
+        loop {
+            match self.0.read(buf)? {
+                0 if timer::current_time() > deadline => return ioerr!(TimedOut, "9p server did not respond"),
+                0 => poll_network(),
+                n => return Ok(n),
+            }
+        }
+    }
+}
+
+/// The sending half of a TCP connection, waiting while the send buffer is
+/// full.
+struct TcpWriter(Sink);
+
+impl io::Write for TcpWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let deadline = timer::current_time() + TIMEOUT;
+        loop {
+            match self.0.write(buf)? {
+                0 if buf.len() > 0 && timer::current_time() > deadline => return ioerr!(TimedOut, "9p server is not receiving"),
+                0 if buf.len() > 0 => poll_network(),
+                n => return Ok(n),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Connects to the 9P2000.L server at `address` and attaches to its tree
+/// `aname`.
+pub fn connect(address: ipv4::Address, port: u16, aname: &str) -> io::Result<NinePFileSystem> {
+    let (sink, source) = NET.critical(|net| net.tcp.connect((address, port)))
+        .map_err(|e| e.into_io_err())?;
+
+    NinePFileSystem::connect(Box::new(TcpReader(source)), Box::new(TcpWriter(sink)), "pi", aname)
+}
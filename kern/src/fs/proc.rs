@@ -1,17 +1,29 @@
 use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
 
+use dsx::sync::mutex::LockableMutex;
 use hashbrown::HashMap;
 
 use mountfs::fs::FileSystem;
 use mountfs::mount::*;
 use mountfs::mount::mfs::{Dir, FsId, FileId};
-use shim::{io, path::Path, path::Component};
+use shim::{io, newioerr, path::Path, path::Component};
 use shim::ffi::OsStr;
+use common::fmt::ByteSize;
+use crate::{ALLOCATOR, BootVariant, debug, smp};
+use crate::allocator::AllocStats;
+use crate::hyper::HYPER_SCHEDULER;
+use crate::kernel::KERNEL_SCHEDULER;
 use crate::mutex::Mutex;
 use crate::iosync::Global;
+use crate::process::{Id, KernelProcess, Process, ProcessImpl, SnapProcess, SnapState, State};
+use crate::sync::Waitable;
+use crate::traps::Frame;
 
 pub static PROC_FILES: Global<ProcFiles> = Global::new(|| ProcFiles::new());
 
@@ -42,6 +54,8 @@ impl ProcFiles {
 
             Ok(())
         }));
+        s.add_file(String::from("meminfo"), Box::new(render_meminfo));
+        s.add_file(String::from("locks"), Box::new(render_locks));
 
         s
     }
@@ -57,18 +71,228 @@ impl ProcFiles {
     }
 }
 
+/// Inodes of `/proc/<pid>` and the files in it have this bit set. The pid is
+/// stored above the `PidFile` index.
+const PID_INODE_BIT: usize = 1 << 48;
+const MOUNTS_INODE: usize = 1;
+
+/// The files in every `/proc/<pid>` directory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum PidFile {
+    Dir = 0,
+    Status = 1,
+    Maps = 2,
+    Fds = 3,
+    Stack = 4,
+}
+
+impl PidFile {
+    const FILES: [PidFile; 4] = [PidFile::Status, PidFile::Maps, PidFile::Fds, PidFile::Stack];
+
+    fn name(&self) -> &'static str {
+        match self {
+            PidFile::Dir => "",
+            PidFile::Status => "status",
+            PidFile::Maps => "maps",
+            PidFile::Fds => "fds",
+            PidFile::Stack => "stack",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<PidFile> {
+        Self::FILES.iter().copied().find(|f| f.name() == name)
+    }
+
+    fn inode(&self, pid: Id) -> usize {
+        PID_INODE_BIT | ((pid as usize) << 4) | (*self as usize)
+    }
+}
+
+/// Returns the ids of all processes of the running scheduler.
+fn process_ids() -> Vec<Id> {
+    let mut snaps: Vec<SnapProcess> = Vec::new();
+    if BootVariant::kernel() {
+        KERNEL_SCHEDULER.get_all_process_snaps(&mut snaps);
+    } else {
+        HYPER_SCHEDULER.get_process_snaps(&mut snaps);
+    }
+
+    let mut ids: Vec<Id> = snaps.iter().map(|snap| snap.tpidr).collect();
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+/// Renders `file` of process `pid` into `w`. Returns `NotFound` if there is no
+/// such process.
+fn render_process(pid: Id, file: PidFile, w: &mut dyn io::Write) -> io::Result<()> {
+    let mut result = None;
+
+    if BootVariant::kernel() {
+        KERNEL_SCHEDULER.iter_all_processes(|_, proc| {
+            if result.is_none() && proc.context.get_id() == pid {
+                result = Some(match file {
+                    PidFile::Fds => render_fds(proc, w),
+                    PidFile::Stack => render_stack(proc, w),
+                    _ => render_common(proc, file, w),
+                });
+            }
+        });
+    } else {
+        HYPER_SCHEDULER.crit_process(pid, |proc| {
+            if let Some(proc) = proc {
+                result = Some(match file {
+                    PidFile::Fds | PidFile::Stack => {
+                        writeln!(w, "not available for guest processes")
+                    }
+                    _ => render_common(proc, file, w),
+                });
+            }
+        });
+    }
+
+    result.unwrap_or_else(|| ioerr!(NotFound, "no such process"))
+}
+
+fn render_common<T: ProcessImpl>(proc: &Process<T>, file: PidFile, w: &mut dyn io::Write) -> io::Result<()> {
+    match file {
+        PidFile::Status => {
+            writeln!(w, "name:          {}", proc.name)?;
+            writeln!(w, "state:         {:?}", SnapState::from(proc.get_state()))?;
+            writeln!(w, "priority:      {}", proc.priority)?;
+            writeln!(w, "affinity:      {:?}", proc.affinity)?;
+            writeln!(w, "cpu_time:      {:?}", proc.current_cpu_time())?;
+            writeln!(w, "task_switches: {}", proc.task_switches)?;
+        }
+        PidFile::Maps => {
+            for region in m_lock!(proc.vmap).regions.iter() {
+                let start = region.start().as_u64();
+                writeln!(w, "{:016x}-{:016x} {:?}", start, start.wrapping_add(region.length() as u64), region.kind)?;
+            }
+        }
+        PidFile::Dir | PidFile::Fds | PidFile::Stack => {}
+    }
+    Ok(())
+}
+
+fn render_fds(proc: &KernelProcess, w: &mut dyn io::Write) -> io::Result<()> {
+    for (i, fd) in m_lock!(proc.detail.file_descriptors).iter().enumerate() {
+        if fd.is_closed() {
+            continue;
+        }
+        if let Some(file) = &fd.file {
+            writeln!(w, "{}: file={}", i, file.path.display())?;
+            continue;
+        }
+        let read = fd.read.as_ref().map(|s| s.name()).unwrap_or("-");
+        let write = fd.write.as_ref().map(|s| s.name()).unwrap_or("-");
+        writeln!(w, "{}: read={} write={}", i, read, write)?;
+    }
+    Ok(())
+}
+
+fn symbol(addr: u64) -> Option<String> {
+    let info = debug::debug_ref()?;
+    let frame = info.context.find_frames(addr).ok()?.next().ok()??;
+    let function = frame.function?;
+    let name = function.demangle().ok()?;
+    Some(name.into_owned())
+}
+
+/// Writes the saved program counter of `proc` and, for kernel threads, the
+/// return addresses found by walking its frame pointers. Addresses are
+/// symbolized when kernel debug info has been loaded.
+fn render_stack(proc: &KernelProcess, w: &mut dyn io::Write) -> io::Result<()> {
+    const MAX_FRAMES: usize = 32;
+
+    if let State::Running(ctx) = proc.get_state() {
+        writeln!(w, "running on core {}", ctx.core_id)?;
+        return Ok(());
+    }
+
+    let mut addresses = vec![proc.context.get_elr()];
+    if proc.context.is_el1() {
+        let frames = unsafe { debug::stack_walker_bp(proc.context.regs[29]) };
+        addresses.extend(frames.take(MAX_FRAMES).map(|frame| frame.link_register));
+    }
+
+    for addr in addresses {
+        match symbol(addr) {
+            Some(name) => writeln!(w, "{:#018x} {}", addr, name)?,
+            None => writeln!(w, "{:#018x}", addr)?,
+        }
+    }
+    Ok(())
+}
+
+fn render_meminfo(w: &mut dyn io::Write) -> io::Result<()> {
+    let (used, total) = ALLOCATOR.with_internal(|a| a.total_allocation());
+    writeln!(w, "total: {}", ByteSize::from(total))?;
+    writeln!(w, "used:  {}", ByteSize::from(used))?;
+    writeln!(w, "free:  {}", ByteSize::from(total - used))?;
+    Ok(())
+}
+
+fn render_locks(w: &mut dyn io::Write) -> io::Result<()> {
+    use crate::mutex::{KERN_MUTEX_HOOKS, MUTEX_INFOS};
+    let _guard = smp::interrupt_guard();
+
+    writeln!(w, "held: {}", KERN_MUTEX_HOOKS.lock_count.load(Ordering::Relaxed))?;
+    writeln!(w, "lock operations: {}", KERN_MUTEX_HOOKS.lock_op_count.load(Ordering::Relaxed))?;
+
+    let infos = match unsafe { MUTEX_INFOS.as_ref() } {
+        Some(infos) => infos,
+        None => return Ok(()),
+    };
+
+    for info in infos.iter() {
+        if !info.assigned.load(Ordering::Acquire) {
+            continue;
+        }
+
+        let lock_name = unsafe { &*info.lock_name.get() };
+        let locker_name = unsafe { &*info.locker_name.get() };
+        writeln!(w, "{:?} locked by {:?} ({} times)", lock_name, locker_name, info.lock_op_count.load(Ordering::Relaxed))?;
+    }
+    Ok(())
+}
+
+fn render_mounts(manager: &FileSystem, w: &mut dyn io::Write) -> io::Result<()> {
+    for mount in manager.get_mounts() {
+        let mode = if mount.read_only { "ro" } else { "rw" };
+        writeln!(w, "{} {} {}", mount.path.display(), mount.fs_name.as_ref().map(|s| s.as_str()).unwrap_or("-"), mode)?;
+    }
+    Ok(())
+}
+
 pub struct ProcFileSystem {
     id: FsId,
 }
 
 impl ProcFileSystem {
-    const ROOT_INODE: usize = 1;
+    const ROOT_INODE: usize = 0;
 
     pub fn new() -> Self {
         Self {
             id: 0,
         }
     }
+
+    fn rendered(&self, inode: usize, name: String, render: impl FnOnce(&mut dyn io::Write) -> io::Result<()>) -> io::Result<mfs::Entry> {
+        let mut buffer: Vec<u8> = Vec::new();
+        render(&mut buffer)?;
+
+        Ok(mfs::Entry::File(Box::new(RenderedFile {
+            id: FileId(self.id, inode),
+            name: Arc::new(name),
+            buffer,
+            index: 0,
+        })))
+    }
+
+    fn pid_dir(&self, pid: Id) -> ProcDir {
+        ProcDir { id: FileId(self.id, PidFile::Dir.inode(pid)), name: format!("{}", pid), pid: Some(pid) }
+    }
 }
 
 impl mfs::FileSystem for ProcFileSystem {
@@ -80,7 +304,7 @@ impl mfs::FileSystem for ProcFileSystem {
         Some(String::from("proc"))
     }
 
-    fn open(&self, manager: &FileSystem, path: &Path) -> io::Result<mfs::Entry> {
+    fn open(&self, _manager: &FileSystem, path: &Path) -> io::Result<mfs::Entry> {
         for comp in path.components() {
             if !matches!(comp, Component::RootDir) {
                 info!("comp: {:?}", comp);
@@ -88,12 +312,26 @@ impl mfs::FileSystem for ProcFileSystem {
             }
         }
 
-        Ok(mfs::Entry::Dir(Arc::new(DummyDir(self.id))))
+        Ok(mfs::Entry::Dir(Arc::new(ProcDir {
+            id: FileId(self.id, Self::ROOT_INODE),
+            name: String::from("/proc"),
+            pid: None,
+        })))
     }
 
-    fn entries(&self, manager: &FileSystem, dir: Arc<dyn Dir>) -> io::Result<Box<dyn Iterator<Item=mfs::DirEntry>>> {
+    fn entries(&self, _manager: &FileSystem, dir: Arc<dyn Dir>) -> io::Result<Box<dyn Iterator<Item=mfs::DirEntry>>> {
+        let dir: &ProcDir = dir.downcast_ref().ok_or(newioerr!(InvalidInput, "[proc] bad directory handle"))?;
         let mut vec = Vec::new();
 
+        if let Some(pid) = dir.pid {
+            for file in PidFile::FILES.iter() {
+                vec.push(mfs::DirEntry::new(
+                    String::from(file.name()), Metadata::default(), 0,
+                    false, FileId(self.id, file.inode(pid))));
+            }
+            return Ok(Box::new(vec.into_iter()));
+        }
+
         PROC_FILES.critical(|files| {
             for (name, file) in files.files.iter() {
                 vec.push(mfs::DirEntry::new(
@@ -102,13 +340,39 @@ impl mfs::FileSystem for ProcFileSystem {
             }
         });
 
+        vec.push(mfs::DirEntry::new(
+            String::from("mounts"), Metadata::default(), 0,
+            false, FileId(self.id, MOUNTS_INODE)));
+
+        for pid in process_ids() {
+            let dir = self.pid_dir(pid);
+            vec.push(mfs::DirEntry::new(dir.name, Metadata::default(), 0, true, dir.id));
+        }
+
         Ok(Box::new(vec.into_iter()))
     }
 
     fn dir_entry(&self, manager: &FileSystem, dir: Arc<dyn Dir>, path: &OsStr) -> io::Result<mfs::Entry> {
-        let fs_id = self.id;
+        let dir: &ProcDir = dir.downcast_ref().ok_or(newioerr!(InvalidInput, "[proc] bad directory handle"))?;
         let name = path.to_string_lossy().into_owned();
 
+        if let Some(pid) = dir.pid {
+            let file = PidFile::from_name(&name).ok_or(newioerr!(NotFound, "file not found"))?;
+            return self.rendered(file.inode(pid), name, |w| render_process(pid, file, w));
+        }
+
+        if name == "mounts" {
+            return self.rendered(MOUNTS_INODE, name, |w| render_mounts(manager, w));
+        }
+
+        if let Ok(pid) = name.parse::<Id>() {
+            if process_ids().contains(&pid) {
+                return Ok(mfs::Entry::Dir(Arc::new(self.pid_dir(pid))));
+            }
+            return ioerr!(NotFound, "no such process");
+        }
+
+        let fs_id = self.id;
         PROC_FILES.critical(|files| {
             let file = match files.files.get(&name) {
                 Some(f) => f,
@@ -128,11 +392,16 @@ impl mfs::FileSystem for ProcFileSystem {
     }
 }
 
-struct DummyDir(FsId);
+/// `/proc` itself when `pid` is `None`, `/proc/<pid>` otherwise.
+struct ProcDir {
+    id: FileId,
+    name: String,
+    pid: Option<Id>,
+}
 
-impl mfs::FileInfo for DummyDir {
+impl mfs::FileInfo for ProcDir {
     fn name(&self) -> &str {
-        "/proc"
+        self.name.as_str()
     }
 
     fn metadata(&self) -> Metadata {
@@ -148,11 +417,11 @@ impl mfs::FileInfo for DummyDir {
     }
 
     fn get_id(&self) -> FileId {
-        FileId(self.0, 0)
+        self.id
     }
 }
 
-impl mfs::Dir for DummyDir {}
+impl mfs::Dir for ProcDir {}
 
 struct RenderedFile {
     id: FileId,
@@ -0,0 +1,159 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use dsx::sync::mutex::LockableMutex;
+use hashbrown::HashMap;
+
+use fat32::traits::BlockDevice;
+use fat32::vfat::{self, DynVFatHandle, FormatOptions, VFat};
+use shim::{io, ioerr, newioerr};
+
+use crate::iosync::Global;
+use crate::mutex::Mutex;
+
+pub static RAMDISKS: Global<RamDisks> = Global::new(|| RamDisks::new());
+
+const SECTOR_SIZE: usize = 512;
+
+/// A block device backed by kernel memory. Clones share the same memory, so
+/// a disk stays alive while any clone, e.g. a mounted file system, uses it.
+#[derive(Clone)]
+pub struct RamDisk(Arc<Mutex<Box<[u8]>>>);
+
+impl RamDisk {
+    /// Creates a zeroed disk of `size` bytes, rounded up to whole sectors.
+    pub fn new(size: usize) -> io::Result<Self> {
+        if size == 0 {
+            return ioerr!(InvalidInput, "ramdisk size must not be zero");
+        }
+
+        let sectors = (size + SECTOR_SIZE - 1) / SECTOR_SIZE;
+        Ok(RamDisk(Arc::new(mutex_new!(vec![0u8; sectors * SECTOR_SIZE].into_boxed_slice()))))
+    }
+
+    /// Creates a disk holding a copy of `image`, padded with zeros to whole
+    /// sectors.
+    pub fn from_image(image: &[u8]) -> io::Result<Self> {
+        let disk = Self::new(image.len())?;
+        m_lock!(disk.0)[..image.len()].copy_from_slice(image);
+        Ok(disk)
+    }
+
+    /// Size of the disk in bytes.
+    pub fn len(&self) -> usize {
+        m_lock!(self.0).len()
+    }
+
+    pub fn num_sectors(&self) -> u64 {
+        (self.len() / SECTOR_SIZE) as u64
+    }
+
+    /// Partitions the whole disk and formats it as FAT32.
+    pub fn format(&self, label: &str) -> io::Result<()> {
+        let options = FormatOptions::default().with_label(label);
+        vfat::format(self.clone(), self.num_sectors(), &options).map_err(vfat_error)?;
+        Ok(())
+    }
+
+    /// Opens the FAT file system on the disk.
+    pub fn vfat(&self) -> io::Result<DynVFatHandle> {
+        VFat::<DynVFatHandle>::from(self.clone()).map_err(vfat_error)
+    }
+}
+
+fn vfat_error(e: vfat::Error) -> io::Error {
+    match e {
+        vfat::Error::Io(e) => e,
+        vfat::Error::NotFound => newioerr!(NotFound, "no FAT partition on ramdisk"),
+        _ => newioerr!(InvalidData, "ramdisk does not hold a valid FAT file system"),
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_size(&self) -> u64 {
+        SECTOR_SIZE as u64
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let data = m_lock!(self.0);
+        let start = n as usize * SECTOR_SIZE;
+        if start >= data.len() {
+            return ioerr!(InvalidInput, "sector beyond end of ramdisk");
+        }
+
+        let amount = core::cmp::min(buf.len(), SECTOR_SIZE);
+        buf[..amount].copy_from_slice(&data[start..start + amount]);
+        Ok(amount)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() < SECTOR_SIZE {
+            return ioerr!(UnexpectedEof, "buffer smaller than a sector");
+        }
+
+        let mut data = m_lock!(self.0);
+        let start = n as usize * SECTOR_SIZE;
+        if start >= data.len() {
+            return ioerr!(InvalidInput, "sector beyond end of ramdisk");
+        }
+
+        data[start..start + SECTOR_SIZE].copy_from_slice(&buf[..SECTOR_SIZE]);
+        Ok(SECTOR_SIZE)
+    }
+}
+
+/// Ramdisks by name.
+pub struct RamDisks {
+    disks: HashMap<String, RamDisk>,
+}
+
+impl RamDisks {
+    pub fn new() -> Self {
+        Self {
+            disks: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: String, disk: RamDisk) -> io::Result<()> {
+        if self.disks.contains_key(&name) {
+            return ioerr!(AlreadyExists, "ramdisk already exists");
+        }
+        self.disks.insert(name, disk);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> io::Result<RamDisk> {
+        self.disks.get(name).cloned().ok_or(newioerr!(NotFound, "no such ramdisk"))
+    }
+
+    /// Formats a disk as FAT32. Fails while the disk is in use, since a
+    /// mounted file system would not notice.
+    pub fn format(&self, name: &str, label: &str) -> io::Result<()> {
+        let disk = self.disks.get(name).ok_or(newioerr!(NotFound, "no such ramdisk"))?;
+        if Arc::strong_count(&disk.0) > 1 {
+            return ioerr!(Other, "ramdisk is busy");
+        }
+        disk.format(label)
+    }
+
+    /// Removes a disk. Fails while the disk is in use, e.g. mounted.
+    pub fn remove(&mut self, name: &str) -> io::Result<()> {
+        let disk = self.disks.get(name).ok_or(newioerr!(NotFound, "no such ramdisk"))?;
+        if Arc::strong_count(&disk.0) > 1 {
+            return ioerr!(Other, "ramdisk is busy");
+        }
+        self.disks.remove(name);
+        Ok(())
+    }
+
+    /// Name, size and whether it is in use of every disk, sorted by name.
+    pub fn list(&self) -> Vec<(String, usize, bool)> {
+        let mut disks: Vec<_> = self.disks.iter()
+            .map(|(name, disk)| (name.clone(), disk.len(), Arc::strong_count(&disk.0) > 1))
+            .collect();
+        disks.sort();
+        disks
+    }
+}
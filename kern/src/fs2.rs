@@ -1,21 +1,37 @@
 use alloc::boxed::Box;
+use alloc::string::String;
 
 use dsx::sync::mutex::LockableMutex;
 
-use fat32::vfat::{DynVFatHandle, DynWrapper, VFat};
-use mountfs::{MetaFileSystem, NullFileSystem};
+use fat32::partition;
+use fat32::vfat::{DynVFatHandle, DynWrapper, VFat, VFatHandle};
+use mountfs::{ArchiveFileSystem, MetaFileSystem, NullFileSystem, OverlayFileSystem, TmpFileSystem};
 use mountfs::fs::FileSystem;
 use mountfs::mount::mfs;
 use shim::io;
 use shim::path::Path;
 use shim::path::PathBuf;
 
-use crate::fs::proc::ProcFileSystem;
+use crate::fs::dev::{DEV_FILES, DevFileSystem};
+use crate::fs::handle::{Sink, Source};
+use crate::fs::proc::{PROC_FILES, ProcFileSystem};
 use crate::fs::sd;
 use crate::hw;
 use crate::hw::ArchVariant;
 use crate::mutex::Mutex;
 
+/// Bytes of file data `/tmp` can hold.
+const TMPFS_CAPACITY: usize = 4 * 1024 * 1024;
+/// Number of files and directories `/tmp` can hold.
+const TMPFS_MAX_NODES: usize = 1024;
+/// Bytes of changed file data `/overlay` can hold.
+const OVERLAY_CAPACITY: usize = 4 * 1024 * 1024;
+/// Number of changed files and directories `/overlay` can hold.
+const OVERLAY_MAX_NODES: usize = 1024;
+
+/// The archive linked into the kernel by the build script, see `INITRAMFS`.
+static INITRAMFS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs"));
+
 pub struct FileSystem2(pub Mutex<Option<mountfs::fs::FileSystem>>);
 
 impl FileSystem2 {
@@ -33,11 +49,35 @@ impl FileSystem2 {
             fs.mount(Some(&PathBuf::from("/bar")), Box::new(NullFileSystem::new()));
 
             fs.mount(Some(&PathBuf::from("/proc")), Box::new(ProcFileSystem::new()));
+            fs.mount(Some(&PathBuf::from("/dev")), Box::new(DevFileSystem::new()));
+            fs.mount(Some(&PathBuf::from("/tmp")), Box::new(TmpFileSystem::new(TMPFS_CAPACITY, TMPFS_MAX_NODES)));
+
+            // mounted before the SD card so processes can be loaded from it
+            // early on.
+            if !INITRAMFS.is_empty() {
+                match ArchiveFileSystem::new(INITRAMFS) {
+                    Ok(archive) => {
+                        fs.mount(Some(&PathBuf::from("/init")), Box::new(archive));
+                    }
+                    Err(e) => error!("failed to read initramfs: {:?}", e),
+                }
+            }
 
             if matches!(hw::arch_variant(), ArchVariant::Pi(_)) {
-                let sd = sd::Sd::new().expect("failed to init sd card");
-                let vfat = VFat::<DynVFatHandle>::from(sd).expect("failed to init vfat");
-                fs.mount(Some(&PathBuf::from("/fat")), Box::new(DynWrapper(vfat)));
+                // the console is driven by the mini UART, which is UART1 on the Pi.
+                DEV_FILES.critical(|files| files.add_device(String::from("uart1"), Box::new(|| (Source::KernSerial, Sink::KernSerial))));
+
+                let mut sd = sd::Sd::new().expect("failed to init sd card");
+                let partition = partition::find_fat(&mut sd)
+                    .expect("failed to read sd partition table")
+                    .expect("no FAT partition on sd card");
+                let vfat = VFat::<DynVFatHandle>::from_partition(sd, &partition).expect("failed to init vfat");
+                register_cache_stats("fatcache", vfat.clone());
+                fs.mount(Some(&PathBuf::from("/fat")), Box::new(DynWrapper(vfat.clone())));
+
+                // the SD card again, with changes kept in memory.
+                let upper = TmpFileSystem::new(OVERLAY_CAPACITY, OVERLAY_MAX_NODES);
+                fs.mount(Some(&PathBuf::from("/overlay")), Box::new(OverlayFileSystem::new(Box::new(DynWrapper(vfat)), upper)));
             }
 
             fs
@@ -48,9 +88,30 @@ impl FileSystem2 {
         self.critical(|fs| fs.open(path))
     }
 
+    /// Writes the data buffered by every mounted file system back to its
+    /// backing store.
+    pub fn sync(&self) -> io::Result<()> {
+        self.critical(|fs| fs.sync())
+    }
+
     pub fn critical<R, F: FnOnce(&mut mountfs::fs::FileSystem) -> R>(&self, func: F) -> R {
         let mut lock = self.0.lock();
         let fs = lock.as_mut().expect("kernel::fs2 uninitialized");
         func(fs)
     }
-}
\ No newline at end of file
+}
+
+/// Exposes the sector cache counters of `vfat` as `/proc/<name>`.
+fn register_cache_stats(name: &str, vfat: DynVFatHandle) {
+    PROC_FILES.critical(|files| files.add_file(String::from(name), Box::new(move |w| {
+        let stats = vfat.lock(|fs| fs.cache_stats());
+        writeln!(w, "capacity:   {}", stats.capacity)?;
+        writeln!(w, "cached:     {}", stats.cached)?;
+        writeln!(w, "dirty:      {}", stats.dirty)?;
+        writeln!(w, "hits:       {}", stats.hits)?;
+        writeln!(w, "misses:     {}", stats.misses)?;
+        writeln!(w, "evictions:  {}", stats.evictions)?;
+        writeln!(w, "writebacks: {}", stats.writebacks)?;
+        Ok(())
+    })));
+}
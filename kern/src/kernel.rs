@@ -331,6 +331,11 @@ pub fn kernel_main() -> ! {
         KERNEL_SCHEDULER.add(proc);
     }
 
+    {
+        let proc = KernelProcess::kernel_process("fs sync".to_owned(), tasks::fs_sync_thread).unwrap();
+        KERNEL_SCHEDULER.add(proc);
+    }
+
     // if true || !hw::is_qemu() || matches!(hw::arch_variant(), ArchVariant::Khadas(_)) {
     //     let mut proc = KernelProcess::kernel_process("net thread".to_owned(), network_thread).unwrap();
     //     proc.affinity.set_only(0);
@@ -373,7 +373,30 @@ impl TcpConnection {
 
                 self.send_packet(manager, flags, empty_payload());
             }
-            State::C_SynSent => {}
+            State::C_SynSent => {
+                if frame.header.flags.get_rst() {
+                    debug!("connection refused");
+                    self.state = State::Killed;
+                    return;
+                }
+
+                if !frame.header.flags.get_syn() || !frame.header.flags.get_ack() {
+                    return;
+                }
+
+                if frame.header.ack_number.get() != self.seq_number.get() {
+                    trace!("Got SYN-ACK with ack mismatch: {} != {}", frame.header.ack_number.get(), self.seq_number);
+                    return;
+                }
+
+                self.acked_number = SeqRing::new(frame.header.sequence_number.get()).add(1);
+                self.remote_acked_number = frame.header.ack_number.get();
+                self.state = State::Established;
+
+                let mut flags = Flags::default();
+                flags.set_ack(true);
+                self.send_packet(manager, flags, empty_payload());
+            }
             State::S_SynReceived => {
                 if frame.header.sequence_number.get() != self.acked_number.get() {
                     trace!("Got packet with seq mismatch: {} != {}", frame.header.sequence_number.get(), self.acked_number);
@@ -562,10 +585,13 @@ impl TcpConnection {
 
 type ConnectionAcceptor = Box<dyn FnMut(Sink, Source) -> io::Result<()> + Send>;
 
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
 struct ConnectionManagerImpl {
     pub ip: Arc<ipv4::Interface>,
     connections: Option<HashMap<ConnectionKey, TcpConnection>>,
     pub listening_ports: HashMap<Socket, ConnectionAcceptor>,
+    next_port: u16,
 }
 
 pub struct ConnectionManager {
@@ -579,6 +605,7 @@ impl ConnectionManager {
                 ip,
                 connections: Some(HashMap::new()),
                 listening_ports: HashMap::new(),
+                next_port: *EPHEMERAL_PORTS.start(),
             })
         }
     }
@@ -601,6 +628,44 @@ impl ConnectionManager {
         m_lock!(self.inner).listening_ports.insert(socket, func);
     }
 
+    /// Opens a connection to `remote` from an ephemeral port. Like for accepted
+    /// connections, data written to the returned sink is sent and received
+    /// data can be read from the returned source once the connection is
+    /// established.
+    ///
+    /// Must not be called while events are being processed, i.e. call it from
+    /// within `NET.critical`.
+    pub fn connect(&self, remote: Socket) -> NetResult<(Sink, Source)> {
+        let local = {
+            let mut lock = m_lock!(self.inner);
+            let address = lock.ip.address();
+
+            loop {
+                let port = lock.next_port;
+                lock.next_port = if port == *EPHEMERAL_PORTS.end() { *EPHEMERAL_PORTS.start() } else { port + 1 };
+
+                let key = ConnectionKey { local: (address, port), remote };
+                if !lock.connections.as_ref().unwrap().contains_key(&key) && !lock.listening_ports.contains_key(&key.local) {
+                    break key.local;
+                }
+            }
+        };
+
+        let outgoing = BufferHandle::new();
+        let incoming = BufferHandle::new();
+        let mut conn = TcpConnection::new(
+            local, remote, State::C_SynSent,
+            Sink::Buffer(incoming.clone()), Source::Buffer(outgoing.clone()));
+
+        let mut flags = Flags::default();
+        flags.set_syn(true);
+        conn.send_packet(self, flags, empty_payload())?;
+
+        m_lock!(self.inner).connections.as_mut().unwrap().insert(conn.key(), conn);
+
+        Ok((Sink::Buffer(outgoing), Source::Buffer(incoming)))
+    }
+
     pub fn on_receive_packet(&self, ip_header: &ipv4::IPv4Header, frame: &TcpFrame) {
         let remote_sock: Socket = (ip_header.source, frame.header.source_port.get());
         let local_sock: Socket = (ip_header.destination, frame.header.destination_port.get());
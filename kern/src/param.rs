@@ -19,6 +19,9 @@ const_assert_eq!(
     ((1 << USER_MASK_BITS) - 1) << (64 - USER_MASK_BITS)
 );
 pub const USER_STACK_BASE: usize = core::usize::MAX & PAGE_MASK; 
+/// Largest size the user stack grows to below `USER_STACK_BASE`, including
+/// its first page.
+pub const USER_STACK_MAX_SIZE: usize = 8 * 1024 * 1024;
 pub const USER_MAX_VM_SIZE: usize = 0x4000_0000;
 const_assert_eq!(USER_IMG_BASE.wrapping_add(USER_MAX_VM_SIZE), 0);
 pub const KERN_STACK_BASE: usize = 0x80_000;
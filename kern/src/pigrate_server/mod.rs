@@ -3,6 +3,7 @@ use alloc::string::String;
 use alloc::sync::Arc;
 use core::time::Duration;
 
+use dsx::sync::mutex::LockableMutex;
 use shim::{io, ioerr};
 
 use crate::{NET};
@@ -17,7 +18,8 @@ pub fn pigrate_server() -> ! {
     let pid: Id = kernel_api::syscall::getpid();
     let (source, sink) = KERNEL_SCHEDULER.crit_process(pid, |f| {
         let f = f.unwrap();
-        (f.detail.file_descriptors[0].read.as_ref().unwrap().clone(), f.detail.file_descriptors[1].write.as_ref().unwrap().clone())
+        let fds = m_lock!(f.detail.file_descriptors);
+        (fds[0].read.as_ref().unwrap().clone(), fds[1].write.as_ref().unwrap().clone())
     });
 
     loop {
@@ -52,8 +54,10 @@ pub fn register_pigrate() {
             let mut proc = KernelProcess::kernel_process_old(String::from("pigrate server"), pigrate_server)
                 .or(ioerr!(Other, "foo"))?;
 
-            proc.detail.file_descriptors.push(FileDescriptor::read(Arc::new(source)));
-            proc.detail.file_descriptors.push(FileDescriptor::write(Arc::new(sink)));
+            let mut fds = m_lock!(proc.detail.file_descriptors);
+            fds.push(FileDescriptor::read(Arc::new(source)));
+            fds.push(FileDescriptor::write(Arc::new(sink)));
+            drop(fds);
 
             KERNEL_SCHEDULER.add(proc);
 
@@ -8,13 +8,54 @@ use crate::param::{PAGE_ALIGN, PAGE_MASK, PAGE_SIZE};
 use crate::virtualization::VirtDevice;
 use crate::vm::{GuestPageTable, PagePerm, PhysicalAddr, UserPageTable, VirtualAddr};
 use crate::process::ProcessImpl;
+use crate::process::fd::OpenFile;
 
-#[derive(Debug)]
+#[derive(Clone)]
 pub enum KernelRegionKind {
     Normal,
+    /// Zero-filled memory mapped with `mmap`.
+    Anonymous,
+    /// A read-only view of `file` starting at `offset`, mapped with `mmap`.
+    File { file: Arc<OpenFile>, offset: u64 },
+    /// Pages that are never mapped, below a thread stack.
+    Guard,
 }
 
-#[derive(Debug)]
+impl KernelRegionKind {
+    /// Whether the region was created by `mmap` and may be unmapped.
+    pub fn is_mapping(&self) -> bool {
+        match self {
+            KernelRegionKind::Normal | KernelRegionKind::Guard => false,
+            KernelRegionKind::Anonymous | KernelRegionKind::File { .. } => true,
+        }
+    }
+
+    /// The kind of the part of a region of this kind that starts `offset`
+    /// bytes into it.
+    pub fn split_at(&self, offset: usize) -> Self {
+        match self {
+            KernelRegionKind::Normal => KernelRegionKind::Normal,
+            KernelRegionKind::Anonymous => KernelRegionKind::Anonymous,
+            KernelRegionKind::File { file, offset: base } => {
+                KernelRegionKind::File { file: file.clone(), offset: base + offset as u64 }
+            }
+            KernelRegionKind::Guard => KernelRegionKind::Guard,
+        }
+    }
+}
+
+impl fmt::Debug for KernelRegionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KernelRegionKind::Normal => write!(f, "Normal"),
+            KernelRegionKind::Anonymous => write!(f, "Anonymous"),
+            KernelRegionKind::File { file, offset } => write!(f, "File({}@{:#x})", file.path.display(), offset),
+            KernelRegionKind::Guard => write!(f, "Guard"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum HyperRegionKind {
     Normal,
     Emulated(Arc<dyn VirtDevice>),
@@ -24,17 +65,44 @@ pub struct Region<T: ProcessImpl> {
     start: usize,
     length: usize,
     pub kind: T::RegionKind,
+    /// Permission of the pages of the region.
+    pub perm: PagePerm,
+    /// Whether pages are only allocated when first accessed, see
+    /// `AddressSpaceManager::fault_in`.
+    pub lazy: bool,
 }
 
 impl<T: ProcessImpl> Region<T> {
     pub fn new(start: VirtualAddr, length: usize, kind: T::RegionKind) -> Self {
-        Self { start: start.as_usize(), length, kind }
+        Self::with_perm(start, length, kind, PagePerm::RWX)
+    }
+
+    pub fn with_perm(start: VirtualAddr, length: usize, kind: T::RegionKind, perm: PagePerm) -> Self {
+        Self { start: start.as_usize(), length, kind, perm, lazy: false }
+    }
+
+    /// Makes the region allocate its pages on first access.
+    pub fn lazy(mut self) -> Self {
+        self.lazy = true;
+        self
+    }
+
+    pub fn start(&self) -> VirtualAddr {
+        VirtualAddr::from(self.start)
+    }
+
+    pub fn length(&self) -> usize {
+        self.length
     }
 
     pub fn repaint(&self, table: &mut T::PageTable) {
         assert_eq!(self.start % PAGE_SIZE, 0);
         assert_eq!(self.length % PAGE_SIZE, 0);
 
+        if self.lazy {
+            return;
+        }
+
         // debug!("Repainting region 0x{:x}", self.start);
 
         // careful to avoid wrapping on 0xFFFFFFF0000 (stack) + 0x1000 == 0
@@ -42,13 +110,39 @@ impl<T: ProcessImpl> Region<T> {
             let base = self.start + offset;
             if !table.is_valid(VirtualAddr::from(base)) {
                 // debug!("base not valid, allocating... 0x{:x}", base);
-                table.alloc(VirtualAddr::from(base), PagePerm::RWX);
+                table.alloc(VirtualAddr::from(base), self.perm);
             } else {
                 // debug!("base is valid, skipping 0x{:x}", base);
             }
         }
     }
 
+    /// End of the region, exclusive. Wraps to zero for the stack.
+    pub fn end(&self) -> usize {
+        self.start.wrapping_add(self.length)
+    }
+
+    /// Changes the permission of the region and its mapped pages.
+    pub fn protect(&mut self, table: &mut T::PageTable, perm: PagePerm) {
+        self.perm = perm;
+        for offset in (0..self.length).step_by(PAGE_SIZE) {
+            table.set_perm(VirtualAddr::from(self.start + offset), perm);
+        }
+    }
+
+    /// Splits the region at `at`, which must be a page boundary inside it,
+    /// and returns the upper part with the given kind.
+    pub fn split_off(&mut self, at: VirtualAddr, kind: T::RegionKind) -> Region<T> {
+        let at = at.as_usize();
+        assert_eq!(at % PAGE_SIZE, 0);
+        assert!(self.start < at && at - self.start < self.length);
+
+        let mut tail = Region::with_perm(VirtualAddr::from(at), self.length - (at - self.start), kind, self.perm);
+        tail.lazy = self.lazy;
+        self.length = at - self.start;
+        tail
+    }
+
     pub fn can_grow_up(&self, len: usize) -> bool {
         len % PAGE_SIZE == 0
     }
@@ -60,6 +154,20 @@ impl<T: ProcessImpl> Region<T> {
         self.length += len;
         self.repaint(table);
     }
+
+    /// Extends the region down by `len` bytes, a multiple of the page size.
+    pub fn grow_down(&mut self, table: &mut T::PageTable, len: usize) {
+        assert_eq!(len % PAGE_SIZE, 0);
+        self.start -= len;
+        self.length += len;
+        self.repaint(table);
+    }
+}
+
+impl<T: ProcessImpl> Clone for Region<T> where T::RegionKind: Clone {
+    fn clone(&self) -> Self {
+        Self { start: self.start, length: self.length, kind: self.kind.clone(), perm: self.perm, lazy: self.lazy }
+    }
 }
 
 impl<T: ProcessImpl> fmt::Debug for Region<T> {
@@ -68,6 +176,8 @@ impl<T: ProcessImpl> fmt::Debug for Region<T> {
             .field("start", &self.start)
             .field("length", &self.length)
             .field("kind", &self.kind)
+            .field("perm", &self.perm)
+            .field("lazy", &self.lazy)
             .finish()
     }
 }
@@ -120,6 +230,69 @@ impl<T: ProcessImpl> AddressSpaceManager<T> {
         Ok(())
     }
 
+    /// Removes the region at `index` and frees its pages.
+    pub fn remove_region(&mut self, index: usize) -> Region<T> {
+        let region = self.regions.remove(index);
+        for offset in (0..region.length).step_by(PAGE_SIZE) {
+            self.table.dealloc(VirtualAddr::from(region.start + offset));
+        }
+        region
+    }
+
+    /// Whether no region overlaps `[start, end)`.
+    pub fn is_free(&self, start: usize, end: usize) -> bool {
+        self.regions.iter().all(|r| r.start >= end || (r.end() != 0 && r.end() <= start))
+    }
+
+    /// Allocates and fills the page containing `va` of a lazy region, or
+    /// copies it if it is shared copy-on-write and the region is writable.
+    ///
+    /// Returns `OsError::BadAddress` if `va` is not in a region, and
+    /// `OsError::NoAccess` if the page is already mapped otherwise, i.e. the
+    /// access was not permitted.
+    pub fn fault_in(&mut self, va: VirtualAddr) -> OsResult<()> {
+        let base = va & VirtualAddr::from(PAGE_MASK);
+        let idx = self.get_region_idx(base).ok_or(OsError::BadAddress)?;
+        let region = &self.regions[idx];
+        if self.table.is_valid(base) {
+            // a write to a page shared by fork.
+            if region.perm.writable() && self.table.copy_on_write(base, region.perm) {
+                return Ok(());
+            }
+            return Err(OsError::NoAccess);
+        }
+
+        let page = self.table.alloc(base, region.perm);
+        let result = T::fill_page(&region.kind, base.as_usize() - region.start, page);
+        if result.is_err() {
+            self.table.dealloc(base);
+        }
+        result
+    }
+
+    /// Returns the lowest address in `[low, high)` where `length` bytes fit
+    /// without overlapping a region.
+    pub fn find_free(&self, length: usize, low: VirtualAddr, high: VirtualAddr) -> Option<VirtualAddr> {
+        let (mut base, high) = (low.as_usize(), high.as_usize());
+        for region in self.regions.iter() {
+            if region.end() != 0 && region.end() <= base {
+                continue;
+            }
+            if region.start >= base && region.start - base >= length {
+                break;
+            }
+            if region.end() == 0 {
+                return None;
+            }
+            base = core::cmp::max(base, region.end());
+        }
+
+        match base.checked_add(length) {
+            Some(end) if end <= high => Some(VirtualAddr::from(base)),
+            _ => None,
+        }
+    }
+
     pub fn get_region_idx(&self, va: VirtualAddr) -> Option<usize> {
         let va = va.as_usize();
         self.regions.iter()
@@ -161,6 +334,9 @@ impl<T: ProcessImpl> AddressSpaceManager<T> {
         let mut offset = (va - base).as_usize();
 
         while buf.len() > 0 {
+            if !self.table.is_valid(base) {
+                self.fault_in(base)?;
+            }
             let mut page = self.get_page_mut(base).ok_or(OsError::BadAddress)?;
             // offset is always less than page size.
             if offset > 0 {
@@ -177,11 +353,31 @@ impl<T: ProcessImpl> AddressSpaceManager<T> {
         Ok(())
     }
 
-    pub fn copy_in(&mut self, va: VirtualAddr, mut buf: &[u8]) -> OsResult<()> {
+    /// Copies `buf` to user memory at `va`, which has to lie in writable
+    /// regions.
+    pub fn copy_in(&mut self, va: VirtualAddr, buf: &[u8]) -> OsResult<()> {
+        self.write_pages(va, buf, true)
+    }
+
+    /// Copies `buf` to `va` whatever the permissions of the regions there,
+    /// for the loader to fill read only segments.
+    pub fn load(&mut self, va: VirtualAddr, buf: &[u8]) -> OsResult<()> {
+        self.write_pages(va, buf, false)
+    }
+
+    fn write_pages(&mut self, va: VirtualAddr, mut buf: &[u8], checked: bool) -> OsResult<()> {
         let mut base = va & VirtualAddr::from(PAGE_MASK);
         let mut offset = (va - base).as_usize();
 
         while buf.len() > 0 {
+            if checked && !self.get_region(base).map_or(false, |r| r.perm.writable()) {
+                return Err(OsError::BadAddress);
+            }
+            if !self.table.is_valid(base) {
+                self.fault_in(base)?;
+            } else if let Some(perm) = self.get_region(base).map(|r| r.perm) {
+                self.table.copy_on_write(base, perm);
+            }
             let mut page = self.get_page_mut(base).ok_or(OsError::BadAddress)?;
             // offset is always less than page size.
             if offset > 0 {
@@ -1,23 +1,246 @@
+use alloc::boxed::Box;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use dsx::sync::mutex::LockableMutex;
+use kernel_api::{DirEnt, O_ACCMODE, O_APPEND, O_CREAT, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, OsError, OsResult, SEEK_CUR, SEEK_END, SEEK_SET, Stat};
+use mountfs::mount::mfs;
+use shim::io;
+use shim::io::{Read, Seek, Write};
+use shim::path::{Path, PathBuf};
+
+use crate::FILESYSTEM2;
 use crate::fs::handle::{Source, Sink};
+use crate::mutex::Mutex;
 
 #[derive(Clone)]
 pub struct FileDescriptor {
     pub read: Option<Arc<Source>>,
     pub write: Option<Arc<Sink>>,
+    /// A file or directory of `FILESYSTEM2`, used instead of a source and sink.
+    pub file: Option<Arc<OpenFile>>,
 }
 
 impl FileDescriptor {
     pub fn read(source: Arc<Source>) -> Self {
-        Self { read: Some(source), write: None }
+        Self { read: Some(source), write: None, file: None }
     }
 
     pub fn write(sink: Arc<Sink>) -> Self {
-        Self { read: None, write: Some(sink) }
+        Self { read: None, write: Some(sink), file: None }
     }
 
     pub fn read_write(source: Arc<Source>, sink: Arc<Sink>) -> Self {
-        Self { read: Some(source), write: Some(sink) }
+        Self { read: Some(source), write: Some(sink), file: None }
+    }
+
+    pub fn file(file: Arc<OpenFile>) -> Self {
+        Self { read: None, write: None, file: Some(file) }
+    }
+
+    /// A slot of a closed descriptor, reused by the next open.
+    pub fn closed() -> Self {
+        Self { read: None, write: None, file: None }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.read.is_none() && self.write.is_none() && self.file.is_none()
+    }
+}
+
+/// Metadata of a file or directory as reported by `stat`.
+pub fn stat_of<T: mfs::FileInfo + ?Sized>(info: &T) -> Stat {
+    Stat {
+        size: info.size(),
+        is_dir: info.is_directory(),
+        read_only: info.metadata().read_only == Some(true),
+    }
+}
+
+enum OpenKind {
+    File(Box<dyn mfs::File>),
+    /// The entries of a directory as of when it was opened.
+    Dir { entries: Vec<DirEnt>, next: usize },
+}
+
+/// A file or directory opened by a process. Clones of a descriptor share the
+/// position.
+pub struct OpenFile {
+    pub path: PathBuf,
+    readable: bool,
+    writable: bool,
+    append: bool,
+    kind: Mutex<OpenKind>,
+    /// A handle of its own for `read_at`, opened along with the file, so that
+    /// positional reads leave the shared position alone.
+    positional: Mutex<Option<Box<dyn mfs::File>>>,
+}
+
+impl OpenFile {
+    /// Opens `path` with the `O_*` flags of the `open` syscall. Directories
+    /// can only be opened for reading.
+    pub fn open(path: &Path, flags: u64) -> OsResult<Self> {
+        let (readable, writable) = match flags & O_ACCMODE {
+            O_RDONLY => (true, false),
+            O_WRONLY => (false, true),
+            O_RDWR => (true, true),
+            _ => return Err(OsError::InvalidArgument),
+        };
+
+        let (entry, positional) = FILESYSTEM2.critical(|fs| -> OsResult<_> {
+            let mut entry = match fs.open(path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound && flags & O_CREAT != 0 => {
+                    fs.create_file(path).map(mfs::Entry::File)
+                }
+                result => result,
+            }?;
+
+            if let mfs::Entry::File(file) = &mut entry {
+                if writable && flags & O_TRUNC != 0 {
+                    file.set_len(0)?;
+                }
+            }
+
+            // the path may name another file by the time the file is mapped.
+            let positional = match &entry {
+                mfs::Entry::File(_) if readable => fs.open(path)?.into_file(),
+                _ => None,
+            };
+            Ok((entry, positional))
+        })?;
+
+        let kind = match entry {
+            mfs::Entry::File(file) => OpenKind::File(file),
+            mfs::Entry::Dir(_) if writable => return Err(OsError::InvalidArgument),
+            mfs::Entry::Dir(dir) => {
+                let entries = FILESYSTEM2.critical(|fs| fs.entries(dir))?
+                    .filter(|e| e.name != "." && e.name != "..")
+                    .map(|e| DirEnt::new(&e.name, Stat {
+                        size: e.size,
+                        is_dir: e.is_directory,
+                        read_only: e.metadata.read_only == Some(true),
+                    }))
+                    .collect();
+                OpenKind::Dir { entries, next: 0 }
+            }
+        };
+
+        Ok(OpenFile {
+            path: path.to_path_buf(),
+            readable,
+            writable,
+            append: flags & O_APPEND != 0,
+            kind: mutex_new!(kind),
+            positional: mutex_new!(positional),
+        })
+    }
+
+    /// Reads from the current position. Fails with `Waiting` while a device
+    /// has no data to read.
+    pub fn read(&self, buf: &mut [u8]) -> OsResult<usize> {
+        if !self.readable {
+            return Err(OsError::BadFileDescriptor);
+        }
+
+        match &mut *m_lock!(self.kind) {
+            OpenKind::File(file) => match file.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => Err(OsError::Waiting),
+                res => Ok(res?),
+            },
+            OpenKind::Dir { .. } => Err(OsError::InvalidArgument),
+        }
     }
 
+    pub fn write(&self, buf: &[u8]) -> OsResult<usize> {
+        if !self.writable {
+            return Err(OsError::BadFileDescriptor);
+        }
+
+        match &mut *m_lock!(self.kind) {
+            OpenKind::File(file) => {
+                if self.append {
+                    file.seek(io::SeekFrom::End(0))?;
+                }
+                Ok(file.write(buf)?)
+            }
+            OpenKind::Dir { .. } => Err(OsError::InvalidArgument),
+        }
+    }
+
+    /// Reads from `offset` into `buf` until it is full or the file ends,
+    /// without moving the position. Returns the number of bytes read, 0 if
+    /// `offset` is past the end of the file.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> OsResult<usize> {
+        if !self.readable {
+            return Err(OsError::BadFileDescriptor);
+        }
+
+        let mut positional = m_lock!(self.positional);
+        let file = positional.as_mut().ok_or(OsError::InvalidArgument)?;
+        if offset >= mfs::File::size(&**file) {
+            return Ok(0);
+        }
+
+        file.seek(io::SeekFrom::Start(offset))?;
+        let mut total = 0;
+        while total < buf.len() {
+            match file.read(&mut buf[total..])? {
+                0 => break,
+                n => total += n,
+            }
+        }
+        Ok(total)
+    }
+
+    pub fn readable(&self) -> bool {
+        self.readable
+    }
+
+    /// Moves the position to `offset` relative to `whence`, one of the
+    /// `SEEK_*` origins. Directories can only be rewound.
+    pub fn seek(&self, offset: i64, whence: u64) -> OsResult<u64> {
+        let pos = match whence {
+            SEEK_SET if offset >= 0 => io::SeekFrom::Start(offset as u64),
+            SEEK_CUR => io::SeekFrom::Current(offset),
+            SEEK_END => io::SeekFrom::End(offset),
+            _ => return Err(OsError::InvalidArgument),
+        };
+
+        match &mut *m_lock!(self.kind) {
+            OpenKind::File(file) => Ok(file.seek(pos)?),
+            OpenKind::Dir { next, .. } => match pos {
+                io::SeekFrom::Start(0) => {
+                    *next = 0;
+                    Ok(0)
+                }
+                _ => Err(OsError::InvalidArgument),
+            },
+        }
+    }
+
+    /// Returns the next entry of a directory, `None` after the last one.
+    pub fn readdir(&self) -> OsResult<Option<DirEnt>> {
+        match &mut *m_lock!(self.kind) {
+            OpenKind::File(_) => Err(OsError::InvalidArgument),
+            OpenKind::Dir { entries, next } => {
+                let entry = entries.get(*next).copied();
+                if entry.is_some() {
+                    *next += 1;
+                }
+                Ok(entry)
+            }
+        }
+    }
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        if let OpenKind::File(file) = &mut *m_lock!(self.kind) {
+            if self.writable {
+                if let Err(e) = file.sync() {
+                    error!("failed to sync {:?} on close: {:?}", self.path, e);
+                }
+            }
+        }
+    }
 }
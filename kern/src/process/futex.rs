@@ -0,0 +1,151 @@
+//! Futexes, which let user threads sleep until a word of memory changes.
+//!
+//! A thread waits on an aligned 32-bit word as long as it holds an expected
+//! value, until another thread wakes the word. Futexes are private to a
+//! process: waiters are keyed by the address space and the virtual address of
+//! the word, which stay the same when a fork or a write moves the word to
+//! another page.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+
+use dsx::sync::mutex::LockableMutex;
+use kernel_api::{OsError, OsResult};
+
+use crate::arm::VirtualCounter;
+use crate::mutex::Mutex;
+use crate::param::PAGE_MASK;
+use crate::process::{KernelImpl, Process};
+use crate::sync::Waitable;
+use crate::timing;
+use crate::vm::VirtualAddr;
+
+/// Identifies a futex word: the address of the address space the word is in
+/// and the virtual address of the word.
+type FutexKey = (usize, u64);
+
+/// Waiters of each futex word, oldest first.
+static FUTEXES: Mutex<Option<BTreeMap<FutexKey, Vec<Arc<FutexWaiter>>>>> = mutex_new!(None);
+
+/// A thread waiting on a futex word.
+pub struct FutexWaiter {
+    woken: AtomicBool,
+    /// When the wait times out, by the virtual counter.
+    deadline: Option<Duration>,
+}
+
+impl FutexWaiter {
+    /// Whether the word was woken, rather than the wait timing out.
+    pub fn woken(&self) -> bool {
+        self.woken.load(Ordering::Acquire)
+    }
+
+    fn timed_out(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => timing::clock_time::<VirtualCounter>() >= deadline,
+            None => false,
+        }
+    }
+
+    /// Whether a thread still waits on `waiter`. The thread holds a reference
+    /// in its state until it is scheduled again or killed.
+    fn is_pending(waiter: &Arc<FutexWaiter>) -> bool {
+        Arc::strong_count(waiter) > 1 && !waiter.done_waiting()
+    }
+}
+
+impl Waitable for FutexWaiter {
+    fn done_waiting(&self) -> bool {
+        self.woken() || self.timed_out()
+    }
+
+    fn name(&self) -> &'static str {
+        "[futex::FutexWaiter]"
+    }
+}
+
+impl Process<KernelImpl> {
+    /// The key of the futex word at `addr`.
+    fn futex_key(&self, addr: u64) -> OsResult<FutexKey> {
+        if addr % 4 != 0 {
+            return Err(OsError::InvalidArgument);
+        }
+        Ok((Arc::as_ptr(&self.vmap) as usize, addr))
+    }
+
+    /// The address the kernel reaches the futex word at `addr` at. The page is
+    /// allocated first.
+    fn futex_word(&mut self, addr: u64) -> OsResult<*const AtomicU32> {
+        let va = VirtualAddr::from(addr as usize);
+        let base = va & VirtualAddr::from(PAGE_MASK);
+        let offset = (va - base).as_usize();
+
+        let mut vmap = m_lock!(self.vmap);
+        if !vmap.table.is_valid(base) {
+            vmap.fault_in(base)?;
+        }
+
+        let page = vmap.get_page_mut(base).ok_or(OsError::BadAddress)?;
+        Ok(page[offset..].as_ptr() as *const AtomicU32)
+    }
+
+    /// Starts waiting on the futex word at `addr` if it holds `expected`, for
+    /// at most `timeout`. The returned waiter is done once the word is woken
+    /// or the wait timed out.
+    ///
+    /// Returns `OsError::WouldBlock` if the word does not hold `expected`.
+    pub fn futex_wait(&mut self, addr: u64, expected: u32, timeout: Option<Duration>) -> OsResult<Arc<FutexWaiter>> {
+        let key = self.futex_key(addr)?;
+        let word = self.futex_word(addr)?;
+
+        // the word is read again with the waiters locked, so that a wake
+        // after the word is changed cannot be missed.
+        let mut futexes = m_lock!(FUTEXES);
+        let value = unsafe { (*word).load(Ordering::SeqCst) };
+        if value != expected {
+            return Err(OsError::WouldBlock);
+        }
+
+        let waiter = Arc::new(FutexWaiter {
+            woken: AtomicBool::new(false),
+            deadline: timeout.map(|timeout| timing::clock_time::<VirtualCounter>() + timeout),
+        });
+
+        let waiters = futexes.get_or_insert_with(BTreeMap::new).entry(key).or_insert_with(Vec::new);
+        waiters.retain(FutexWaiter::is_pending);
+        waiters.push(waiter.clone());
+        Ok(waiter)
+    }
+
+    /// Wakes up to `count` threads waiting on the futex word at `addr`, oldest
+    /// first, and returns how many were woken.
+    pub fn futex_wake(&mut self, addr: u64, count: usize) -> OsResult<usize> {
+        let key = self.futex_key(addr)?;
+
+        let mut futexes = m_lock!(FUTEXES);
+        let futexes = match futexes.as_mut() {
+            Some(futexes) => futexes,
+            None => return Ok(0),
+        };
+
+        let woken = match futexes.get_mut(&key) {
+            Some(waiters) => {
+                waiters.retain(FutexWaiter::is_pending);
+                let woken = core::cmp::min(count, waiters.len());
+                for waiter in waiters.drain(..woken) {
+                    waiter.woken.store(true, Ordering::Release);
+                }
+                woken
+            }
+            None => 0,
+        };
+
+        if futexes.get(&key).map(|waiters| waiters.is_empty()).unwrap_or(false) {
+            futexes.remove(&key);
+        }
+        Ok(woken)
+    }
+}
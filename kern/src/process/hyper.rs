@@ -8,6 +8,7 @@ use alloc::vec::Vec;
 use core::sync::atomic::Ordering;
 use core::time::Duration;
 
+use dsx::sync::mutex::LockableMutex;
 use kernel_api::{OsError, OsResult};
 
 use aarch64::{HCR_EL2, SCTLR_EL1, SPSR_EL1, SPSR_EL2};
@@ -152,7 +153,7 @@ impl Process<HyperImpl> {
         p.context.SPSR_EL2 |= SPSR_EL2::M & 0b1000;
 
         // kernel thread still gets a vmap because it's easy
-        p.context.VTTBR_EL2 = p.vmap.get_baddr().as_u64();
+        p.context.VTTBR_EL2 = m_lock!(p.vmap).get_baddr().as_u64();
 
         p.context.HCR_EL2 = HCR_EL2::RW | HCR_EL2::IMO | HCR_EL2::CD | HCR_EL2::ID | HCR_EL2::RES1;
 
@@ -162,15 +163,17 @@ impl Process<HyperImpl> {
     fn init_guest(proc: &mut Self) {
         let total_size = 8000 * PAGE_SIZE;
 
+        let mut vmap = m_lock!(proc.vmap);
+
         // Allocate 512 megabytes
-        proc.vmap.add_region(Region::new(VirtualAddr::from(0), total_size, HyperRegionKind::Normal));
+        vmap.add_region(Region::new(VirtualAddr::from(0), total_size, HyperRegionKind::Normal));
 
-        assert!(proc.vmap.table.is_valid(VirtualAddr::from(0x80000)));
+        assert!(vmap.table.is_valid(VirtualAddr::from(0x80000)));
 
         {
             use pi::atags::raw;
             use fat32::util::*;
-            let buf = proc.vmap.get_page_mut(VirtualAddr::from(0)).expect("tried to deref bad page");
+            let buf = vmap.get_page_mut(VirtualAddr::from(0)).expect("tried to deref bad page");
             unsafe { VMM.mark_page_non_cached(buf.as_ptr() as usize) };
 
             // start of atags
@@ -192,9 +195,11 @@ impl Process<HyperImpl> {
         }
 
         // 257 = ceil( (0x4000_00FC - 0x3f00_0000) / PAGE_SIZE )
-        proc.vmap.add_region(Region::new(VirtualAddr::from(0x3f000000), 257 * PAGE_SIZE, HyperRegionKind::Emulated(proc.detail.virt_device.clone())));
+        vmap.add_region(Region::new(VirtualAddr::from(0x3f000000), 257 * PAGE_SIZE, HyperRegionKind::Emulated(proc.detail.virt_device.clone())));
 
-        assert!(proc.vmap.get_region(VirtualAddr::from(0x3f003004)).is_some());
+        assert!(vmap.get_region(VirtualAddr::from(0x3f003004)).is_some());
+        let baddr = vmap.get_baddr();
+        drop(vmap);
 
         // Networking
 
@@ -215,7 +220,7 @@ impl Process<HyperImpl> {
         proc.context.SP_EL1 = 0x420_000;
         proc.context.ELR_EL2 = 0x80000;
 
-        proc.context.VTTBR_EL2 = proc.vmap.get_baddr().as_u64();
+        proc.context.VTTBR_EL2 = baddr.as_u64();
         proc.context.HCR_EL2 = HCR_EL2::RW | HCR_EL2::VM | HCR_EL2::ID | HCR_EL2::IMO | HCR_EL2::RES1;
 
         proc.context.CNTVOFF_EL2 = 0;
@@ -248,9 +253,10 @@ impl Process<HyperImpl> {
                 EL2_KERNEL_INIT_LEN.load(Ordering::Relaxed) as usize)
         };
 
+        let mut vmap = m_lock!(proc.vmap);
         let mut base = VirtualAddr::from(0x80_000);
         loop {
-            let buf = proc.vmap.get_page_mut(base).expect("tried to deref bad page");
+            let buf = vmap.get_page_mut(base).expect("tried to deref bad page");
             unsafe { VMM.mark_page_non_cached(buf.as_ptr() as usize) };
 
             let amt = core::cmp::min(buf.len(), hyper_copy.len());
@@ -263,6 +269,7 @@ impl Process<HyperImpl> {
 
             base = base + VirtualAddr::from(PAGE_SIZE);
         }
+        drop(vmap);
 
         Ok(proc)
     }
@@ -295,9 +302,10 @@ impl Process<HyperImpl> {
 
         let mut file = FILESYSTEM2.open(pn)?.into_file().ok_or(OsError::InvalidArgument)?;
 
+        let mut vmap = m_lock!(proc.vmap);
         let mut base = VirtualAddr::from(0x80_000);
         'page_loop: loop {
-            let mut buf = proc.vmap.get_page_mut(base).expect("tried to deref bad page");
+            let mut buf = vmap.get_page_mut(base).expect("tried to deref bad page");
             unsafe { VMM.mark_page_non_cached(buf.as_ptr() as usize) };
 
             while buf.len() > 0 {
@@ -310,6 +318,7 @@ impl Process<HyperImpl> {
 
             base = base + VirtualAddr::from(PAGE_SIZE);
         }
+        drop(vmap);
 
         Ok(proc)
     }
@@ -335,14 +344,14 @@ impl Process<HyperImpl> {
     }
 
     pub fn on_access_fault(&mut self, esr: u32, addr: VirtualAddr, tf: &mut HyperTrapFrame) {
-        let region = match self.vmap.get_region(addr) {
-            Some(reg) => reg,
+        let kind = match m_lock!(self.vmap).get_region(addr) {
+            Some(reg) => reg.kind.clone(),
             None => {
                 error!("TPIDR: {}", unsafe { aarch64::TPIDR_EL2.get() });
                 panic!("on_access_fault() called on unmapped address: {:#x} by proc: {} <{}> AT elr={:#x}", addr.as_u64(), self.context.get_id(), self.name, self.context.ELR_EL2);
             },
         };
-        match &region.kind {
+        match kind {
             HyperRegionKind::Normal => {
                 use aarch64::regs::*;
                 use crate::traps::syndrome::Syndrome;
@@ -353,7 +362,7 @@ impl Process<HyperImpl> {
                 trace!("    EL1: {:?} (raw=0x{:x})", Syndrome::from(unsafe { ESR_EL1.get() } as u32), unsafe { ESR_EL1.get() });
                 trace!("    SP: {:#x}, ELR_EL1: {:#x}, SPSR: {:#x}", unsafe { SP_EL1.get() }, unsafe { ELR_EL1.get() }, tf.SPSR_EL2);
 
-                self.vmap.table.mark_accessed(VirtualAddr::from(addr.as_u64() & PAGE_MASK as u64));
+                m_lock!(self.vmap).table.mark_accessed(VirtualAddr::from(addr.as_u64() & PAGE_MASK as u64));
             }
             HyperRegionKind::Emulated(_) => {
                 use aarch64::regs::*;
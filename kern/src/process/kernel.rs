@@ -1,28 +1,34 @@
 use alloc::borrow::ToOwned;
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::string::String;
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
 
-use kernel_api::{OsError, OsResult};
+use dsx::sync::mutex::LockableMutex;
+use kernel_api::{AT_ENTRY, AT_NULL, AT_PAGESZ, AT_PID, ExitStatus, OsError, OsResult};
 
 use aarch64::SPSR_EL1;
 use pigrate_core::bundle::MemoryBundle;
 use pigrate_core::bundle::ProcessBundle;
+use pigrate_core::elf::{ElfImage, PF_W, PF_X};
 use shim::io;
 use shim::path::Path;
 
 use crate::{FILESYSTEM2, VMM};
 use crate::fs::handle::{Sink, Source};
 use crate::kernel::KERNEL_SCHEDULER;
-use crate::param::{PAGE_SIZE, USER_IMG_BASE};
+use crate::mutex::Mutex;
+use crate::param::{PAGE_MASK, PAGE_SIZE, USER_IMG_BASE};
 use crate::process::{Id, Process, ProcessImpl, State};
-use crate::process::address_space::{KernelRegionKind, Region};
+use crate::process::address_space::{AddressSpaceManager, KernelRegionKind, Region};
 use crate::process::fd::FileDescriptor;
+use crate::process::mmap;
+use crate::process::thread::ThreadGroup;
 use crate::sync::Completion;
 use crate::traps::{Frame, KernelTrapFrame};
 
-use crate::vm::{VirtualAddr, UserPageTable};
+use crate::vm::{PagePerm, VirtualAddr, UserPageTable};
 use alloc::format;
 
 pub struct KernProcessCtx {
@@ -34,22 +40,82 @@ impl KernProcessCtx {
     pub fn get_stdio_or_panic(&self) -> (Arc<Source>, Arc<Sink>) {
         KERNEL_SCHEDULER.crit_process(self.pid, |f| {
             let f = f.unwrap();
-            (f.detail.file_descriptors[0].read.as_ref().unwrap().clone(), f.detail.file_descriptors[1].write.as_ref().unwrap().clone())
+            let fds = m_lock!(f.detail.file_descriptors);
+            (fds[0].read.as_ref().unwrap().clone(), fds[1].write.as_ref().unwrap().clone())
         })
     }
 }
 
 type KernProcess = Box<dyn FnOnce(KernProcessCtx) + Send>;
 
+/// How the children of a process ended, by pid, kept until it waits for them.
+pub type ExitRecords = Mutex<BTreeMap<Id, ExitStatus>>;
 
 pub struct KernelImpl {
-    pub file_descriptors: Vec<FileDescriptor>,
+    /// Descriptor table, shared by the threads of the process.
+    pub file_descriptors: Arc<Mutex<Vec<FileDescriptor>>>,
 
-    pub dead_completions: Vec<Arc<Completion<Id>>>,
+    /// State shared by the threads of the process.
+    pub group: Arc<ThreadGroup>,
+    /// Children that ended and were not waited for, shared by the threads of
+    /// the process.
+    pub exited_children: Arc<ExitRecords>,
+    /// Where the process records how it ended for its parent. Dropped with
+    /// the parent, so nothing is recorded once the parent ended.
+    pub(crate) parent: Weak<ExitRecords>,
+    /// Start of the stack region of a thread other than the main thread.
+    pub(crate) thread_stack: Option<VirtualAddr>,
+
+    /// Arguments and environment the program was started with.
+    pub argv: Vec<String>,
+    pub envp: Vec<String>,
+
+    /// Where the pid goes in the auxiliary vector on the stack.
+    pub(crate) auxv_pid: Option<VirtualAddr>,
+
+    /// How the thread ended, set just before it is killed. `None` if it was
+    /// killed from outside.
+    pub exit_status: Option<ExitStatus>,
+
+    pub dead_completions: Vec<Arc<Completion<ExitStatus>>>,
 
     kernel_proc_entry: Option<KernProcess>,
 }
 
+impl KernelImpl {
+    /// Installs `fd` in the lowest free descriptor slot and returns its number.
+    pub fn add_fd(&mut self, fd: FileDescriptor) -> u64 {
+        let mut fds = m_lock!(self.file_descriptors);
+        match fds.iter().position(|slot| slot.is_closed()) {
+            Some(i) => {
+                fds[i] = fd;
+                i as u64
+            }
+            None => {
+                fds.push(fd);
+                (fds.len() - 1) as u64
+            }
+        }
+    }
+
+    pub fn get_fd(&self, n: u64) -> OsResult<FileDescriptor> {
+        match m_lock!(self.file_descriptors).get(n as usize) {
+            Some(fd) if !fd.is_closed() => Ok(fd.clone()),
+            _ => Err(OsError::BadFileDescriptor),
+        }
+    }
+
+    pub fn close_fd(&mut self, n: u64) -> OsResult<()> {
+        self.get_fd(n)?;
+        let mut fds = m_lock!(self.file_descriptors);
+        fds[n as usize] = FileDescriptor::closed();
+        while fds.last().map(|fd| fd.is_closed()).unwrap_or(false) {
+            fds.pop();
+        }
+        Ok(())
+    }
+}
+
 impl ProcessImpl for KernelImpl {
     type Frame = KernelTrapFrame;
     type RegionKind = KernelRegionKind;
@@ -57,7 +123,15 @@ impl ProcessImpl for KernelImpl {
 
     fn new() -> OsResult<Self> {
         Ok(Self {
-            file_descriptors: Vec::new(),
+            file_descriptors: Arc::new(mutex_new!(Vec::new())),
+            group: Arc::new(ThreadGroup::new()),
+            exited_children: Arc::new(mutex_new!(BTreeMap::new())),
+            parent: Weak::new(),
+            thread_stack: None,
+            argv: Vec::new(),
+            envp: Vec::new(),
+            auxv_pid: None,
+            exit_status: None,
             dead_completions: Vec::new(),
             kernel_proc_entry: None,
         })
@@ -81,11 +155,37 @@ impl ProcessImpl for KernelImpl {
         idle_tasks
     }
 
+    fn on_id_assigned(proc: &mut Process<Self>) {
+        if proc.is_main_thread() {
+            proc.detail.group.set_pid(proc.context.get_id());
+            proc.write_auxv_pid();
+        }
+    }
+
     fn on_process_killed(proc: &mut Process<Self>) {
+        let mut status = proc.detail.exit_status.unwrap_or(ExitStatus::Killed);
+        if proc.is_main_thread() {
+            // the process ends with its main thread, unless a thread ended it first.
+            status = proc.detail.group.exit(status);
+            if let Some(records) = proc.detail.parent.upgrade() {
+                m_lock!(records).insert(proc.detail.group.pid(), status);
+            }
+        } else {
+            proc.remove_thread_stack();
+        }
+
         for comp in proc.detail.dead_completions.drain(..) {
-            comp.complete(proc.context.get_id());
+            comp.complete(status);
         }
     }
+
+    fn should_kill(proc: &Process<Self>) -> bool {
+        proc.detail.group.has_exited()
+    }
+
+    fn fill_page(kind: &KernelRegionKind, offset: usize, page: &mut [u8]) -> OsResult<()> {
+        mmap::fill_page(kind, offset, page)
+    }
 }
 
 pub type KernelProcess = Process<KernelImpl>;
@@ -103,7 +203,7 @@ impl Process<KernelImpl> {
 
         p.context.TTBR0_EL1 = VMM.get_baddr().as_u64();
         // kernel thread still gets a vmap because it's easy
-        p.context.TTBR1_EL1 = p.vmap.get_baddr().as_u64();
+        p.context.TTBR1_EL1 = m_lock!(p.vmap).get_baddr().as_u64();
 
         Ok(p)
     }
@@ -141,65 +241,243 @@ impl Process<KernelImpl> {
         Self::kernel_process_boxed(name, Box::new(f))
     }
 
+    /// Load a program stored in the given path by calling `do_load()` method,
+    /// with the path as its only argument. See `load_with()`.
+    pub fn load<P: AsRef<Path>>(pn: P) -> OsResult<Self> {
+        let name = pn.as_ref().to_str().ok_or(OsError::InvalidArgument)?.to_owned();
+        Self::load_with(pn, vec![name], Vec::new())
+    }
+
     /// Load a program stored in the given path by calling `do_load()` method.
     /// Set trapframe `context` corresponding to the its page table.
-    /// `sp` - the address of stack top
-    /// `elr` - the address of image base.
+    /// `sp` - the address of the arguments on the stack, see `init_stack()`
     /// `ttbr0` - the base address of kernel page table
     /// `ttbr1` - the base address of user page table
     /// `spsr` - `F`, `A`, `D` bit should be set.
     ///
     /// Returns Os Error if do_load fails.
-    pub fn load<P: AsRef<Path>>(pn: P) -> OsResult<Self> {
+    pub fn load_with<P: AsRef<Path>>(pn: P, argv: Vec<String>, envp: Vec<String>) -> OsResult<Self> {
         use crate::VMM;
 
         let mut p = Self::do_load(pn)?;
 
-        p.context.SP_EL0 = Self::get_stack_top().as_u64();
-        p.context.ELR_EL1 = USER_IMG_BASE as u64;
-
         p.context.TTBR0_EL1 = VMM.get_baddr().as_u64();
-        p.context.TTBR1_EL1 = p.vmap.get_baddr().as_u64();
+        p.context.TTBR1_EL1 = m_lock!(p.vmap).get_baddr().as_u64();
+
+        p.init_stack(argv, envp)?;
+
+        p.set_stdio(Arc::new(Source::KernSerial), Arc::new(Sink::KernSerial));
 
         Ok(p)
     }
 
-    /// Creates a process and open a file with given path.
-    /// Allocates one page for stack with read/write permission, and N pages with read/write/execute
-    /// permission to load file's contents.
+    /// Lays out the arguments, environment and auxiliary vector at the top of
+    /// the stack as below, and points `sp` at them. `x0`-`x3` are set to
+    /// argc and the addresses of argv, envp and auxv.
+    ///
+    /// ```text
+    /// sp -> argc
+    ///       argv[0], ..., argv[argc - 1], 0
+    ///       envp[0], ..., envp[n - 1], 0
+    ///       (type, value) pairs of auxv, (AT_NULL, 0)
+    ///       strings, NUL terminated
+    /// ```
+    ///
+    /// The pid in auxv is filled in once the scheduler assigns one.
+    fn init_stack(&mut self, argv: Vec<String>, envp: Vec<String>) -> OsResult<()> {
+        let auxv = [
+            (AT_PAGESZ, PAGE_SIZE as u64),
+            (AT_ENTRY, self.context.ELR_EL1),
+            (AT_PID, 0),
+            (AT_NULL, 0),
+        ];
+
+        let top = Self::get_stack_top().as_u64();
+        let strings_len: usize = argv.iter().chain(envp.iter()).map(|s| s.len() + 1).sum();
+        let words = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * auxv.len();
+        let strings_base = (top - strings_len as u64) & !0xF;
+        let sp = (strings_base - 8 * words as u64) & !0xF;
+        if top - sp > PAGE_SIZE as u64 {
+            return Err(OsError::NoMemory);
+        }
+
+        let mut table: Vec<u8> = Vec::with_capacity(8 * words);
+        let mut strings: Vec<u8> = Vec::with_capacity(strings_len);
+
+        table.extend_from_slice(&(argv.len() as u64).to_le_bytes());
+        for list in [&argv, &envp].iter() {
+            for s in list.iter() {
+                table.extend_from_slice(&(strings_base + strings.len() as u64).to_le_bytes());
+                strings.extend_from_slice(s.as_bytes());
+                strings.push(0);
+            }
+            table.extend_from_slice(&0u64.to_le_bytes());
+        }
+
+        let auxv_addr = sp + table.len() as u64;
+        for &(key, value) in auxv.iter() {
+            if key == AT_PID {
+                self.detail.auxv_pid = Some(VirtualAddr::from(sp + table.len() as u64 + 8));
+            }
+            table.extend_from_slice(&key.to_le_bytes());
+            table.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let mut vmap = m_lock!(self.vmap);
+        vmap.copy_in(VirtualAddr::from(sp), &table)?;
+        vmap.copy_in(VirtualAddr::from(strings_base), &strings)?;
+        drop(vmap);
+
+        self.context.SP_EL0 = sp;
+        self.context.regs[0] = argv.len() as u64;
+        self.context.regs[1] = sp + 8;
+        self.context.regs[2] = sp + 8 * (argv.len() as u64 + 2);
+        self.context.regs[3] = auxv_addr;
+
+        self.detail.argv = argv;
+        self.detail.envp = envp;
+        Ok(())
+    }
+
+    /// Creates a copy of the process that continues from the trap frame `tf`
+    /// of the calling thread with a zero result. The address space is shared
+    /// copy-on-write, and the copy gets a copy of the descriptors, and the
+    /// affinity and name of the process. Only the calling thread is copied.
+    pub fn fork(&mut self, tf: &KernelTrapFrame) -> OsResult<Self> {
+        let mut child = Self::new(self.name.clone())?;
+
+        *child.context = tf.clone();
+        child.context.regs[0] = 0;
+        child.context.regs[7] = OsError::Ok as u64;
+        child.context.TPIDR_EL0 = 0; // will get a new process id when scheduled.
+
+        let vmap = {
+            let mut vmap = m_lock!(self.vmap);
+            AddressSpaceManager { table: vmap.table.fork(), regions: vmap.regions.clone() }
+        };
+        child.context.TTBR1_EL1 = vmap.get_baddr().as_u64();
+        child.vmap = Arc::new(mutex_new!(vmap));
+
+        child.affinity = self.affinity;
+        child.priority = self.priority;
+        child.detail.file_descriptors = Arc::new(mutex_new!(m_lock!(self.detail.file_descriptors).clone()));
+        child.detail.argv = self.detail.argv.clone();
+        child.detail.envp = self.detail.envp.clone();
+        child.detail.auxv_pid = self.detail.auxv_pid;
+        child.detail.parent = Arc::downgrade(&self.detail.exited_children);
+
+        Ok(child)
+    }
+
+    /// Handles a translation or permission fault of the process at `va` by
+    /// allocating the page, growing the stack down to it if it is below the
+    /// stack and above the stack limit, or by copying it if it is shared
+    /// copy-on-write.
+    ///
+    /// Pages of file mappings are read by `mmap::fault_in_file_pages` instead.
+    ///
+    /// Returns `OsError::BadAddress` if `va` is not part of the process.
+    pub fn handle_page_fault(&mut self, va: VirtualAddr) -> OsResult<()> {
+        let base = va.as_usize() & PAGE_MASK;
+        let mut vmap = m_lock!(self.vmap);
+        if vmap.get_region(VirtualAddr::from(base)).is_none() {
+            Self::grow_stack(&mut vmap, base)?;
+        }
+        vmap.fault_in(VirtualAddr::from(base))
+    }
+
+    /// Extends the stack region of the main thread in `vmap` down to the page
+    /// at `base`.
+    fn grow_stack(vmap: &mut AddressSpaceManager<KernelImpl>, base: usize) -> OsResult<()> {
+        let idx = vmap.get_region_idx(Self::get_stack_base()).ok_or(OsError::BadAddress)?;
+        let stack_start = vmap.regions[idx].start().as_usize();
+
+        if base < Self::get_stack_limit().as_usize() || base >= stack_start || !vmap.is_free(base, stack_start) {
+            return Err(OsError::BadAddress);
+        }
+
+        vmap.regions[idx].grow_down(&mut vmap.table, stack_start - base);
+        Ok(())
+    }
+
+    /// Writes the pid into the auxiliary vector on the stack.
+    pub fn write_auxv_pid(&mut self) {
+        if let Some(va) = self.detail.auxv_pid {
+            let pid = self.context.get_id();
+            if let Err(e) = m_lock!(self.vmap).copy_in(va, &pid.to_le_bytes()) {
+                warn!("failed to write the pid of {} to its stack: {:?}", pid, e);
+            }
+        }
+    }
+
+    /// Creates a process and loads the ELF executable at the given path.
+    /// Reserves one page for stack with read/write permission, and maps the
+    /// loadable segments of the executable with their own permissions.
+    ///
+    /// Returns `OsError::IoErrorInvalidData` if the file is not a valid
+    /// executable.
     fn do_load<P: AsRef<Path>>(pn: P) -> OsResult<Self> {
-        use fat32::traits::*;
-        use shim::io::Read;
         let mut proc = Self::new(pn.as_ref().to_str().ok_or(OsError::InvalidArgument)?.to_owned())?;
 
-        proc.vmap.add_region(Region::new(Self::get_stack_base(), PAGE_SIZE, KernelRegionKind::Normal));
-
-        let image_base = Self::get_image_base();
+        m_lock!(proc.vmap).add_region(Region::with_perm(Self::get_stack_base(), PAGE_SIZE, KernelRegionKind::Normal, PagePerm::RW).lazy())?;
 
         let mut file = FILESYSTEM2.open(pn)?.into_file().ok_or(OsError::InvalidArgument)?;
+        let mut data = Vec::new();
+        io::copy(file.as_mut(), &mut data)?;
 
-        let mut base = image_base;
-        'page_loop: loop {
-            if image_base == base {
-                proc.vmap.add_region(Region::new(image_base, PAGE_SIZE, KernelRegionKind::Normal));
-            } else {
-                proc.vmap.expand_region(image_base, PAGE_SIZE);
+        proc.load_elf(&data)?;
+        Ok(proc)
+    }
+
+    /// Maps the loadable segments of the ELF executable `data` into the
+    /// address space, zeroes their bss and sets the entry point. Segments that
+    /// share a page are mapped as one region with the permissions of both.
+    pub fn load_elf(&mut self, data: &[u8]) -> OsResult<()> {
+        let elf = ElfImage::parse(data).map_err(|_| OsError::IoErrorInvalidData)?;
+
+        let mut regions: Vec<(usize, usize, u32)> = Vec::new();
+        for segment in elf.segments.iter() {
+            if (segment.vaddr as usize) < USER_IMG_BASE || segment.end() > Self::get_stack_base().as_u64() {
+                return Err(OsError::IoErrorInvalidData);
             }
 
-            let mut buf = proc.vmap.get_page_mut(base).expect("tried to deref bad page");
+            let start = segment.vaddr as usize & PAGE_MASK;
+            let end = (segment.end() as usize + PAGE_SIZE - 1) & PAGE_MASK;
+            match regions.last_mut() {
+                Some(last) if start < last.1 => {
+                    last.1 = core::cmp::max(last.1, end);
+                    last.2 |= segment.flags;
+                }
+                _ => regions.push((start, end, segment.flags)),
+            }
+        }
 
-            while buf.len() > 0 {
-                let read_amount = file.read(buf)?;
-                if read_amount == 0 {
-                    break 'page_loop;
+        let mut vmap = m_lock!(self.vmap);
+        for &(start, end, flags) in regions.iter() {
+            let perm = match (flags & PF_W != 0, flags & PF_X != 0) {
+                (true, true) => PagePerm::RWX,
+                (true, false) => PagePerm::RW,
+                (false, true) => PagePerm::RX,
+                (false, false) => PagePerm::RO,
+            };
+            vmap.add_region(Region::with_perm(VirtualAddr::from(start), end - start, KernelRegionKind::Normal, perm))?;
+
+            // pages are not zeroed when allocated, this also zeroes the bss.
+            for base in (start..end).step_by(PAGE_SIZE) {
+                let page = vmap.get_page_mut(VirtualAddr::from(base)).ok_or(OsError::BadAddress)?;
+                for byte in page.iter_mut() {
+                    *byte = 0;
                 }
-                buf = &mut buf[read_amount..];
             }
+        }
 
-            base = base + VirtualAddr::from(PAGE_SIZE);
+        for segment in elf.segments.iter() {
+            vmap.load(VirtualAddr::from(segment.vaddr), elf.segment_data(segment))?;
         }
+        drop(vmap);
 
-        Ok(proc)
+        self.context.ELR_EL1 = elf.entry;
+        Ok(())
     }
 
     pub fn from_bundle(bundle: &ProcessBundle) -> OsResult<Self> {
@@ -210,13 +488,14 @@ impl Process<KernelImpl> {
         // set kernel specific values that don't make sense to use from the bundle.
         proc.context.TPIDR_EL0 = 0; // will get a new process id when scheduled.
         proc.context.TTBR0_EL1 = VMM.get_baddr().as_u64();
-        proc.context.TTBR1_EL1 = proc.vmap.get_baddr().as_u64();
+        let mut vmap = m_lock!(proc.vmap);
+        proc.context.TTBR1_EL1 = vmap.get_baddr().as_u64();
 
         for (raw_va, data) in bundle.memory.generic_pages.iter() {
             let va = VirtualAddr::from(*raw_va);
 
-            proc.vmap.add_region(Region::new(va, PAGE_SIZE, KernelRegionKind::Normal));
-            let page = proc.vmap.get_page_mut(va).expect("could not deref bad va");
+            vmap.add_region(Region::new(va, PAGE_SIZE, KernelRegionKind::Normal));
+            let page = vmap.get_page_mut(va).expect("could not deref bad va");
 
             if page.len() != data.len() {
                 return Err(OsError::BadAddress);
@@ -224,21 +503,23 @@ impl Process<KernelImpl> {
 
             page.copy_from_slice(data.as_slice());
         }
+        drop(vmap);
 
         Ok(proc)
     }
 
     pub fn set_stdio(&mut self, source: Arc<Source>, sink: Arc<Sink>) {
-        if self.detail.file_descriptors.len() >= 1 {
-            self.detail.file_descriptors[0] = FileDescriptor::read(source);
+        let mut fds = m_lock!(self.detail.file_descriptors);
+        if fds.len() >= 1 {
+            fds[0] = FileDescriptor::read(source);
         } else {
-            self.detail.file_descriptors.push(FileDescriptor::read(source));
+            fds.push(FileDescriptor::read(source));
         }
 
-        if self.detail.file_descriptors.len() >= 2 {
-            self.detail.file_descriptors[1] = FileDescriptor::write(sink);
+        if fds.len() >= 2 {
+            fds[1] = FileDescriptor::write(sink);
         } else {
-            self.detail.file_descriptors.push(FileDescriptor::write(sink));
+            fds.push(FileDescriptor::write(sink));
         }
     }
 
@@ -248,7 +529,7 @@ impl Process<KernelImpl> {
 
         let mut bundle = MemoryBundle::default();
 
-        for (va, pa) in self.vmap.table.iter_mapped_pages() {
+        for (va, pa) in m_lock!(self.vmap).table.iter_mapped_pages() {
             let mut page_copy: Vec<u8> = Vec::with_capacity(PAGE_SIZE);
             page_copy.extend_from_slice(unsafe { core::slice::from_raw_parts(pa.as_ptr(), PAGE_SIZE) });
             bundle.generic_pages.insert(va.as_u64(), page_copy);
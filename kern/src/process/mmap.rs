@@ -0,0 +1,224 @@
+//! Memory mappings created by the `mmap` family of syscalls.
+//!
+//! Each mapping is its own lazy `Region` of kind `Anonymous` or `File`
+//! somewhere between the image base and the stack, whose pages are filled
+//! when first accessed. Anonymous pages are zeroed by `fill_page`. File pages
+//! are read by `fault_in_file_pages` before the address space is locked, so
+//! that no process waits on the lock while a file system reads. Unmapping or
+//! reprotecting part of a mapping splits its region.
+
+use alloc::sync::Arc;
+use alloc::vec;
+
+use dsx::sync::mutex::LockableMutex;
+use kernel_api::{OsError, OsResult, PROT_EXEC, PROT_READ, PROT_WRITE};
+
+use crate::mutex::Mutex;
+use crate::param::{PAGE_MASK, PAGE_SIZE, USER_IMG_BASE};
+use crate::process::{KernelImpl, Process};
+use crate::process::address_space::{AddressSpaceManager, KernelRegionKind, Region};
+use crate::process::fd::OpenFile;
+use crate::vm::{GuestPageTable, PagePerm, VirtualAddr};
+
+/// The page permission for the `PROT_*` flags. Mapped pages are always
+/// readable, so `PROT_NONE` is not supported.
+pub fn prot_to_perm(prot: u64) -> OsResult<PagePerm> {
+    if prot == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(OsError::InvalidArgument);
+    }
+
+    Ok(match (prot & PROT_WRITE != 0, prot & PROT_EXEC != 0) {
+        (true, true) => PagePerm::RWX,
+        (true, false) => PagePerm::RW,
+        (false, true) => PagePerm::RX,
+        (false, false) => PagePerm::RO,
+    })
+}
+
+/// Fills the page `offset` bytes into a region of kind `kind` with zeroes.
+///
+/// Pages of file mappings are installed by `fault_in_file_pages` instead. One
+/// that is missing here was unmapped and mapped again since, and is reported
+/// as a bad address. Guard pages are never filled.
+pub fn fill_page(kind: &KernelRegionKind, _offset: usize, page: &mut [u8]) -> OsResult<()> {
+    match kind {
+        KernelRegionKind::File { .. } => return Err(OsError::BadAddress),
+        KernelRegionKind::Guard => return Err(OsError::NoAccess),
+        _ => {}
+    }
+
+    for byte in page.iter_mut() {
+        *byte = 0;
+    }
+    Ok(())
+}
+
+/// The file, offset in the file and permission of the page at `base` of
+/// `vmap`, if it belongs to a file mapping and is not present yet.
+fn missing_file_page(vmap: &AddressSpaceManager<KernelImpl>, base: VirtualAddr) -> Option<(Arc<OpenFile>, u64, PagePerm)> {
+    if vmap.table.is_valid(base) {
+        return None;
+    }
+
+    let region = vmap.get_region(base)?;
+    match &region.kind {
+        KernelRegionKind::File { file, offset } => {
+            let offset = offset + (base.as_usize() - region.start().as_usize()) as u64;
+            Some((file.clone(), offset, region.perm))
+        }
+        _ => None,
+    }
+}
+
+/// Reads the pages of file mappings among the `length` bytes at `va` of
+/// `vmap` that are not present yet and installs them, zero past the end of
+/// the file. The address space is only locked to look the pages up and to
+/// install them, so neither it nor the process may be locked by the caller.
+///
+/// Returns whether a page was installed.
+pub fn fault_in_file_pages(vmap: &Mutex<AddressSpaceManager<KernelImpl>>, va: VirtualAddr, length: usize) -> OsResult<bool> {
+    let start = va.as_usize() & PAGE_MASK;
+    let end = va.as_usize().checked_add(length).ok_or(OsError::BadAddress)?;
+
+    let mut installed = false;
+    for base in (start..end).step_by(PAGE_SIZE) {
+        let base = VirtualAddr::from(base);
+        let (file, offset, _) = match missing_file_page(&m_lock!(vmap), base) {
+            Some(page) => page,
+            None => continue,
+        };
+
+        let mut data = vec![0u8; PAGE_SIZE];
+        file.read_at(offset, &mut data)?;
+
+        // the page is dropped if another thread installed it or the mapping
+        // changed while it was read.
+        let mut vmap = m_lock!(vmap);
+        if let Some((now, now_offset, perm)) = missing_file_page(&vmap, base) {
+            if Arc::ptr_eq(&now, &file) && now_offset == offset {
+                vmap.table.alloc(base, perm).copy_from_slice(&data);
+                installed = true;
+            }
+        }
+    }
+    Ok(installed)
+}
+
+/// `length` rounded up to whole pages.
+fn page_round(length: u64) -> OsResult<usize> {
+    if length == 0 {
+        return Err(OsError::InvalidArgument);
+    }
+    (length as usize).checked_add(PAGE_SIZE - 1)
+        .map(|len| len & PAGE_MASK)
+        .ok_or(OsError::InvalidArgument)
+}
+
+impl Process<KernelImpl> {
+    /// The pages `[start, end)` of `length` bytes at `addr`, if `addr` is
+    /// page aligned and they lie between the image base and the stack.
+    fn mapping_range(addr: u64, length: usize) -> OsResult<(usize, usize)> {
+        let start = addr as usize;
+        if start % PAGE_SIZE != 0 || start < USER_IMG_BASE {
+            return Err(OsError::InvalidArgument);
+        }
+
+        match start.checked_add(length) {
+            Some(end) if end <= Self::get_stack_base().as_usize() => Ok((start, end)),
+            _ => Err(OsError::InvalidArgument),
+        }
+    }
+
+    /// Splits the region of `vmap` containing `at` so that a region starts at
+    /// `at`.
+    fn split_region_at(vmap: &mut AddressSpaceManager<KernelImpl>, at: usize) {
+        if let Some(idx) = vmap.get_region_idx(VirtualAddr::from(at)) {
+            let region = &mut vmap.regions[idx];
+            let offset = at - region.start().as_usize();
+            if offset != 0 {
+                let kind = region.kind.split_at(offset);
+                let tail = region.split_off(VirtualAddr::from(at), kind);
+                vmap.regions.insert(idx + 1, tail);
+            }
+        }
+    }
+
+    /// Maps `length` bytes with permission `perm` and returns their address.
+    ///
+    /// With `file`, the mapping is a read-only view of the file starting at
+    /// the given page aligned offset, zero past the end of the file; otherwise
+    /// it is zero-filled. `addr` is used if it is free, or else a free range
+    /// is picked unless `fixed` is set.
+    pub fn mmap(&mut self, addr: u64, length: u64, perm: PagePerm, fixed: bool,
+                file: Option<(Arc<OpenFile>, u64)>) -> OsResult<VirtualAddr> {
+        let length = page_round(length)?;
+
+        let kind = match file {
+            Some(_) if perm.writable() => return Err(OsError::NoAccess),
+            Some((_, offset)) if offset as usize % PAGE_SIZE != 0 => return Err(OsError::InvalidArgument),
+            Some((file, offset)) => KernelRegionKind::File { file, offset },
+            None => KernelRegionKind::Anonymous,
+        };
+
+        let mut vmap = m_lock!(self.vmap);
+        let start = match Self::mapping_range(addr, length) {
+            Ok((start, end)) if vmap.is_free(start, end) => start,
+            _ if fixed => return Err(OsError::InvalidArgument),
+            _ => vmap.find_free(length, Self::get_image_base(), Self::get_stack_limit())
+                .ok_or(OsError::NoVmSpace)?
+                .as_usize(),
+        };
+
+        vmap.add_region(Region::with_perm(VirtualAddr::from(start), length, kind, perm).lazy())?;
+        Ok(VirtualAddr::from(start))
+    }
+
+    /// Unmaps the mappings in the `length` bytes at `addr` and frees their
+    /// pages. Parts of the range that are not mapped are ignored, but the range
+    /// must not overlap the image, heap or stack.
+    pub fn munmap(&mut self, addr: u64, length: u64) -> OsResult<()> {
+        let (start, end) = Self::mapping_range(addr, page_round(length)?)?;
+
+        let overlapping = |r: &Region<KernelImpl>| r.start().as_usize() < end && (r.end() == 0 || r.end() > start);
+        let mut vmap = m_lock!(self.vmap);
+        if vmap.regions.iter().any(|r| overlapping(r) && !r.kind.is_mapping()) {
+            return Err(OsError::InvalidArgument);
+        }
+
+        Self::split_region_at(&mut vmap, start);
+        Self::split_region_at(&mut vmap, end);
+        while let Some(idx) = vmap.regions.iter().position(|r| overlapping(r)) {
+            vmap.remove_region(idx);
+        }
+        Ok(())
+    }
+
+    /// Changes the permission of the `length` bytes at `addr`, which must all
+    /// be mapped. File mappings cannot be made writable.
+    pub fn mprotect(&mut self, addr: u64, length: u64, perm: PagePerm) -> OsResult<()> {
+        let (start, end) = Self::mapping_range(addr, page_round(length)?)?;
+
+        let mut vmap = m_lock!(self.vmap);
+        for base in (start..end).step_by(PAGE_SIZE) {
+            match vmap.get_region(VirtualAddr::from(base)) {
+                None => return Err(OsError::BadAddress),
+                Some(r) => if let KernelRegionKind::File { .. } = r.kind {
+                    if perm.writable() {
+                        return Err(OsError::NoAccess);
+                    }
+                },
+            }
+        }
+
+        Self::split_region_at(&mut vmap, start);
+        Self::split_region_at(&mut vmap, end);
+        let vmap = &mut *vmap;
+        for region in vmap.regions.iter_mut() {
+            let region_start = region.start().as_usize();
+            if start <= region_start && region_start < end {
+                region.protect(&mut vmap.table, perm);
+            }
+        }
+        Ok(())
+    }
+}
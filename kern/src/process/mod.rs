@@ -3,14 +3,17 @@ use core::time::Duration;
 
 mod address_space;
 pub mod fd;
+pub mod futex;
 mod hyper;
 mod kernel;
 mod mailbox;
+pub mod mmap;
 mod process;
 mod scheduler;
 mod snap;
 mod stack;
 mod state;
+pub mod thread;
 
 pub use crate::param::TICK;
 
@@ -10,6 +10,7 @@ use core::ops::Add;
 use core::ops::Deref;
 use core::time::Duration;
 
+use dsx::sync::mutex::LockableMutex;
 use kernel_api::{OsError, OsResult};
 
 use aarch64;
@@ -20,6 +21,7 @@ use shim::path::Path;
 use crate::{smp, VMM};
 use crate::fs::handle::{Sink, Source};
 use crate::kernel::KERNEL_SCHEDULER;
+use crate::mutex::Mutex;
 use crate::param::*;
 use crate::pigrate::bundle::{MemoryBundle, ProcessBundle};
 use crate::process::{Stack, State, TimeRatio, TimeRing};
@@ -86,8 +88,26 @@ pub trait ProcessImpl: Sized + Send {
 
     fn create_idle_processes(count: usize) -> Vec<Process<Self>>;
 
+    /// Called when the scheduler assigns `proc` its id.
+    fn on_id_assigned(proc: &mut Process<Self>) {}
+
     fn on_process_killed(proc: &mut Process<Self>) {}
 
+    /// Whether `proc` should be killed the next time it is scheduled, in
+    /// addition to when it was requested.
+    fn should_kill(_proc: &Process<Self>) -> bool {
+        false
+    }
+
+    /// Fills `page`, which is `offset` bytes into a lazy region of kind
+    /// `kind`, when it is first accessed.
+    fn fill_page(_kind: &Self::RegionKind, _offset: usize, page: &mut [u8]) -> OsResult<()> {
+        for byte in page.iter_mut() {
+            *byte = 0;
+        }
+        Ok(())
+    }
+
     fn dump<W: io::Write>(w: &mut W, proc: &Process<Self>) {}
 }
 
@@ -97,8 +117,9 @@ pub struct Process<T: ProcessImpl> {
     pub context: Box<T::Frame>,
     /// The memory allocation used for the process's stack.
     pub stack: Stack,
-    /// The page table describing the Virtual Memory of the process
-    pub vmap: Box<AddressSpaceManager<T>>,
+    /// The page table describing the Virtual Memory of the process, shared
+    /// with its other threads.
+    pub vmap: Arc<Mutex<AddressSpaceManager<T>>>,
     /// The scheduling state of the process.
     pub(crate) state: State<T>,
 
@@ -133,7 +154,7 @@ impl<T: ProcessImpl> Process<T> {
     /// If enough memory could not be allocated to start the process, returns
     /// `None`. Otherwise returns `Some` of the new `Process`.
     pub fn new(name: String) -> OsResult<Self> {
-        let vmap = Box::new(AddressSpaceManager::new());
+        let vmap = Arc::new(mutex_new!(AddressSpaceManager::new()));
         let stack = Stack::new().ok_or(OsError::NoMemory)?;
         let context = Box::new(T::Frame::default());
 
@@ -165,7 +186,7 @@ impl<T: ProcessImpl> Process<T> {
         writeln!(w, "{:?}", self.context);
 
         writeln!(w, "Memory Regions:");
-        for region in self.vmap.regions.iter() {
+        for region in m_lock!(self.vmap).regions.iter() {
             writeln!(w, "  {:x?}", region);
         }
 
@@ -196,6 +217,12 @@ impl<T: ProcessImpl> Process<T> {
         VirtualAddr::from(u64::max_value() & PAGE_MASK as u64)
     }
 
+    /// Returns the lowest `VirtualAddr` the user process's stack can grow
+    /// down to.
+    pub fn get_stack_limit() -> VirtualAddr {
+        VirtualAddr::from((USER_STACK_BASE - (USER_STACK_MAX_SIZE - PAGE_SIZE)) as u64)
+    }
+
     /// Returns the `VirtualAddr` represents the top of the user process's
     /// stack.
     pub fn get_stack_top() -> VirtualAddr {
@@ -321,6 +348,7 @@ impl<T: ProcessImpl> kscheduler::Process<T::Frame, State<T>> for Process<T> {
 
     fn set_id(&mut self, id: usize) {
         self.context.set_id(id as u64);
+        T::on_id_assigned(self);
     }
 
     fn get_id(&self) -> usize {
@@ -336,7 +364,7 @@ impl<T: ProcessImpl> kscheduler::Process<T::Frame, State<T>> for Process<T> {
     }
 
     fn should_kill(&self) -> bool {
-        self.has_request_kill()
+        self.has_request_kill() || T::should_kill(self)
     }
 
     fn get_priority(&self) -> usize {
@@ -344,7 +372,8 @@ impl<T: ProcessImpl> kscheduler::Process<T::Frame, State<T>> for Process<T> {
     }
 
     fn check_ready(&mut self) -> bool {
-        if self.is_ready() {
+        // a process that should be killed is not left waiting.
+        if kscheduler::Process::should_kill(self) || self.is_ready() {
             true
         } else {
             self.update_timing();
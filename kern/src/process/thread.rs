@@ -0,0 +1,153 @@
+//! Threads of user processes.
+//!
+//! A thread is a `Process` of its own, scheduled independently, that shares
+//! the address space and descriptor table of the process that created it, as
+//! well as its `ThreadGroup`. The thread that was loaded or forked is the main
+//! thread; its id is the pid of the process. Every other thread runs on a lazy
+//! stack region of its own, below the stack of the main thread, with a guard
+//! region of one page below it. Both are removed when the thread ends.
+//!
+//! A thread can be joined once, by any thread of the process, through the
+//! completion the group keeps for it until then.
+//!
+//! The process ends when its main thread ends or any thread calls `exit`. The
+//! remaining threads are then killed the next time they are scheduled.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use dsx::sync::mutex::LockableMutex;
+use kernel_api::{ExitStatus, OsError, OsResult};
+
+use crate::mutex::Mutex;
+use crate::param::{PAGE_MASK, PAGE_SIZE};
+use crate::process::{Id, KernelImpl, Process};
+use crate::process::address_space::{KernelRegionKind, Region};
+use crate::sync::Completion;
+use crate::traps::KernelTrapFrame;
+use crate::vm::{PagePerm, VirtualAddr};
+
+/// State shared by all threads of a process.
+pub struct ThreadGroup {
+    /// Id of the main thread, 0 until it is scheduled.
+    pid: AtomicU64,
+    /// How the process ended, `None` while it is running.
+    exit_status: Mutex<Option<ExitStatus>>,
+    /// Completions of the threads besides the main thread that were not
+    /// joined yet, by thread id.
+    joinable: Mutex<BTreeMap<Id, Arc<Completion<ExitStatus>>>>,
+}
+
+impl ThreadGroup {
+    pub fn new() -> Self {
+        ThreadGroup {
+            pid: AtomicU64::new(0),
+            exit_status: mutex_new!(None),
+            joinable: mutex_new!(BTreeMap::new()),
+        }
+    }
+
+    pub fn pid(&self) -> Id {
+        self.pid.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_pid(&self, pid: Id) {
+        self.pid.store(pid, Ordering::Relaxed);
+    }
+
+    /// Ends the process with `status` unless it already ended. Returns how
+    /// the process ended.
+    ///
+    /// Threads that were not joined cannot be joined anymore, so their
+    /// completions are dropped.
+    pub fn exit(&self, status: ExitStatus) -> ExitStatus {
+        m_lock!(self.joinable).clear();
+        *m_lock!(self.exit_status).get_or_insert(status)
+    }
+
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        *m_lock!(self.exit_status)
+    }
+
+    pub fn has_exited(&self) -> bool {
+        self.exit_status().is_some()
+    }
+
+    /// Makes thread `tid` joinable, with `done` completed when it ends.
+    pub(crate) fn add_joinable(&self, tid: Id, done: Arc<Completion<ExitStatus>>) {
+        m_lock!(self.joinable).insert(tid, done);
+    }
+
+    /// Takes the completion of thread `tid`, unless it was already joined or
+    /// is not part of the group.
+    pub fn take_joinable(&self, tid: Id) -> Option<Arc<Completion<ExitStatus>>> {
+        m_lock!(self.joinable).remove(&tid)
+    }
+}
+
+impl Process<KernelImpl> {
+    /// Whether this is the main thread of its process.
+    pub fn is_main_thread(&self) -> bool {
+        self.detail.thread_stack.is_none()
+    }
+
+    /// Creates a thread of the process that starts at `entry` with `arg` in
+    /// `x0`, on a new stack of `stack_size` bytes rounded up to whole pages.
+    /// The thread inherits the affinity and priority of the calling thread,
+    /// whose trap frame is `tf`.
+    ///
+    /// `entry` must not return; the thread ends by calling `thread_exit`.
+    pub fn new_thread(&mut self, tf: &KernelTrapFrame, entry: u64, arg: u64, stack_size: usize) -> OsResult<Self> {
+        let stack_size = stack_size.checked_add(PAGE_SIZE - 1)
+            .map(|len| len & PAGE_MASK)
+            .filter(|&len| len != 0)
+            .ok_or(OsError::InvalidArgument)?;
+
+        let mut thread = Self::new(self.name.clone())?;
+
+        // a page below the stack that is never mapped, so that overflowing
+        // it faults.
+        let stack_start = {
+            let mut vmap = m_lock!(self.vmap);
+            let guard = vmap.find_free(stack_size + PAGE_SIZE, Self::get_image_base(), Self::get_stack_limit())
+                .ok_or(OsError::NoVmSpace)?;
+            let start = VirtualAddr::from(guard.as_usize() + PAGE_SIZE);
+            vmap.add_region(Region::with_perm(guard, PAGE_SIZE, KernelRegionKind::Guard, PagePerm::RO).lazy())?;
+            vmap.add_region(Region::with_perm(start, stack_size, KernelRegionKind::Normal, PagePerm::RW).lazy())?;
+            start
+        };
+
+        *thread.context = tf.clone();
+        for reg in thread.context.regs.iter_mut() {
+            *reg = 0;
+        }
+        thread.context.regs[0] = arg;
+        thread.context.ELR_EL1 = entry;
+        thread.context.SP_EL0 = (stack_start.as_usize() + stack_size) as u64;
+        thread.context.TPIDR_EL0 = 0; // will get a new thread id when scheduled.
+
+        thread.vmap = self.vmap.clone();
+        thread.affinity = self.affinity;
+        thread.priority = self.priority;
+        thread.detail.file_descriptors = self.detail.file_descriptors.clone();
+        thread.detail.group = self.detail.group.clone();
+        thread.detail.exited_children = self.detail.exited_children.clone();
+        thread.detail.thread_stack = Some(stack_start);
+
+        Ok(thread)
+    }
+
+    /// Removes the stack and guard regions of a thread that is not the main
+    /// thread.
+    pub(crate) fn remove_thread_stack(&mut self) {
+        if let Some(start) = self.detail.thread_stack {
+            let mut vmap = m_lock!(self.vmap);
+            for va in [start, VirtualAddr::from(start.as_usize() - PAGE_SIZE)].iter() {
+                if let Some(idx) = vmap.get_region_idx(*va) {
+                    vmap.remove_region(idx);
+                }
+            }
+        }
+    }
+}
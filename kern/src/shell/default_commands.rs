@@ -8,6 +8,7 @@ use core::sync::atomic::Ordering;
 use core::time::Duration;
 
 use hashbrown::HashMap;
+use kernel_api::OsError;
 use log::Level;
 use xmas_elf::sections::ShType;
 
@@ -17,6 +18,7 @@ use fat32::traits::{Dir, Entry, File, Metadata};
 use fat32::traits::FileSystem;
 use fat32::vfat::{DynVFatHandle, DynWrapper, VFat};
 use mountfs::MetaFileSystem;
+use mountfs::fs::MountOptions;
 use mountfs::mount::mfs;
 use pi::interrupt::{CoreInterrupt, Interrupt};
 use shim::io;
@@ -28,6 +30,7 @@ use crate::{ALLOCATOR, BootVariant, FILESYSTEM2, hw, MP_ALLOC, NET, perf, timer,
 use crate::allocator::AllocStats;
 use crate::arm::PhysicalCounter;
 use crate::fs::handle::{Sink, Source};
+use crate::fs::ninep;
 use crate::fs::sd;
 use crate::fs::service::PipeService;
 use crate::hyper::HYPER_SCHEDULER;
@@ -36,8 +39,10 @@ use crate::kernel::{KERNEL_IRQ, KERNEL_CORES};
 use crate::kernel::KERNEL_SCHEDULER;
 use crate::mutex::Mutex;
 use crate::net::arp::ArpResolver;
+use crate::net::ipv4;
 use crate::perf::PERF_EVENTS_ENABLED;
 use crate::pigrate::bundle::ProcessBundle;
+use crate::pigrate::elf::{ElfImage, PF_R, PF_W, PF_X};
 use crate::pigrate_server::{pigrate_server, register_pigrate};
 use crate::process::{Process, SnapProcess};
 use crate::shell::command::{Command, CommandBuilder};
@@ -53,6 +58,7 @@ use crate::kernel_call::syscall::exec_in_exc;
 
 mod mem;
 mod net;
+mod ramdisk;
 
 fn describe_ls_entry<W: io::Write, T: mfs::FileInfo>(writer: &mut W, entry: T, show_all: bool) {
     if !show_all && (entry.metadata().hidden == Some(true) || entry.name() == "." || entry.name() == "..") {
@@ -358,13 +364,186 @@ pub fn register_commands<R: io::Read, W: io::Write>(sh: &mut Shell<R, W>) {
             let mount_info = FILESYSTEM2.critical(|fs| fs.get_mounts());
             writeln!(sh.writer, "Mounts:")?;
             for mount in mount_info.iter() {
-                writeln!(sh.writer, "  {:?} - {:?}", mount.path, mount.fs_name)?;
+                let mode = if mount.read_only { "ro" } else { "rw" };
+                writeln!(sh.writer, "  {:?} - {:?} ({})", mount.path, mount.fs_name, mode)?;
             }
 
             Ok(())
         })
         .build();
 
+    sh.command()
+        .name("umount")
+        .help("Unmount a filesystem: umount [-l] <path>, -l detaches it even while files are open")
+        .func_result(|sh, cmd| {
+            let mut lazy = false;
+            let mut target = None;
+            for arg in cmd.args[1..].iter() {
+                match *arg {
+                    "-l" => lazy = true,
+                    other => target = Some(other),
+                }
+            }
+
+            let path = sh.handle_path(target.ok_or("usage: umount [-l] <path>")?);
+            FILESYSTEM2.critical(|fs| fs.unmount(&path, lazy))?;
+            Ok(())
+        })
+        .build();
+
+    sh.command()
+        .name("remount")
+        .help("Change mount options: remount <path> ro|rw")
+        .func_result(|sh, cmd| {
+            if cmd.args.len() != 3 {
+                Err("usage: remount <path> ro|rw")?;
+            }
+
+            let read_only = match cmd.args[2] {
+                "ro" => true,
+                "rw" => false,
+                _ => Err("usage: remount <path> ro|rw")?,
+            };
+
+            let path = sh.handle_path(cmd.args[1]);
+            FILESYSTEM2.critical(|fs| fs.remount(&path, MountOptions { read_only }))?;
+            Ok(())
+        })
+        .build();
+
+    sh.command()
+        .name("mount9p")
+        .help("Mount a 9P2000.L export at /host: mount9p <ip> [port] [aname]")
+        .func_result(|_sh, cmd| {
+            if cmd.args.len() < 2 || cmd.args.len() > 4 {
+                Err("usage: mount9p <ip> [port] [aname]")?;
+            }
+
+            let address: ipv4::Address = cmd.args[1].parse()?;
+            let port = match cmd.args.get(2) {
+                Some(port) => port.parse()?,
+                None => ninep::DEFAULT_PORT,
+            };
+            let aname = cmd.args.get(3).copied().unwrap_or("");
+
+            let ninep = ninep::connect(address, port, aname)?;
+            FILESYSTEM2.critical(|fs| fs.mount(Some(&PathBuf::from("/host")), Box::new(ninep)))?;
+            Ok(())
+        })
+        .build();
+
+    sh.command()
+        .name("mkdir")
+        .help("Create directories")
+        .func_result(|sh, cmd| {
+            for arg in cmd.args[1..].iter() {
+                let path = sh.handle_path(arg);
+                FILESYSTEM2.critical(|fs| fs.create_dir(&path))?;
+            }
+            Ok(())
+        })
+        .build();
+
+    sh.command()
+        .name("touch")
+        .help("Create empty files if they do not exist")
+        .func_result(|sh, cmd| {
+            for arg in cmd.args[1..].iter() {
+                let path = sh.handle_path(arg);
+                FILESYSTEM2.critical(|fs| match fs.open(&path) {
+                    Ok(_) => Ok(()),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => fs.create_file(&path).map(|_| ()),
+                    Err(e) => Err(e),
+                })?;
+            }
+            Ok(())
+        })
+        .build();
+
+    sh.command()
+        .name("rm")
+        .help("Remove files or empty directories")
+        .func_result(|sh, cmd| {
+            for arg in cmd.args[1..].iter() {
+                let path = sh.handle_path(arg);
+                FILESYSTEM2.critical(|fs| fs.remove(&path))?;
+            }
+            Ok(())
+        })
+        .build();
+
+    sh.command()
+        .name("write")
+        .help("Replace the contents of a file: write <path> [text...]")
+        .func_result(|sh, cmd| {
+            if cmd.args.len() < 2 {
+                Err("usage: write <path> [text...]")?;
+            }
+
+            let path = sh.handle_path(cmd.args[1]);
+            let mut file = FILESYSTEM2.critical(|fs| match fs.open(&path) {
+                Ok(mfs::Entry::File(file)) => Ok(file),
+                Ok(mfs::Entry::Dir(_)) => ioerr!(InvalidInput, "is a directory"),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => fs.create_file(&path),
+                Err(e) => Err(e),
+            })?;
+
+            file.set_len(0)?;
+            let text = cmd.args[2..].join(" ");
+            io::Write::write_all(file.as_mut(), text.as_bytes())?;
+            io::Write::write_all(file.as_mut(), b"\n")?;
+            file.sync()?;
+            Ok(())
+        })
+        .build();
+
+    sh.command()
+        .name("fsck")
+        .help("Check a mounted FAT volume: fsck [-r] [path], -r repairs problems")
+        .func_result(|sh, cmd| {
+            let mut path = "/fat";
+            let mut repair = false;
+            for arg in cmd.args[1..].iter() {
+                match *arg {
+                    "-r" => repair = true,
+                    other => path = other,
+                }
+            }
+
+            let root = match FILESYSTEM2.open(path)? {
+                mfs::Entry::Dir(dir) => dir,
+                mfs::Entry::File(_) => Err("not a directory")?,
+            };
+            let dir = match root.downcast_ref::<fat32::vfat::Dir<DynVFatHandle>>() {
+                Some(dir) => dir,
+                None => Err("not a FAT volume")?,
+            };
+
+            let report = dir.vfat.lock(|fs| fs.check(repair))?;
+            for problem in report.problems.iter() {
+                writeln!(sh.writer, "{}", problem)?;
+            }
+            writeln!(sh.writer, "{} files, {} directories, {} clusters in use",
+                     report.files, report.directories, report.used_clusters)?;
+            if report.is_clean() {
+                writeln!(sh.writer, "volume is clean")?;
+            } else {
+                writeln!(sh.writer, "{} problem(s), {} repaired", report.problems.len(), report.repaired)?;
+            }
+
+            Ok(())
+        })
+        .build();
+
+    sh.command()
+        .name("sync")
+        .help("Write buffered file system data to disk")
+        .func_result(|sh, cmd| {
+            FILESYSTEM2.sync()?;
+            Ok(())
+        })
+        .build();
+
     sh.command()
         .name("mem")
         .help("network stack utilities")
@@ -377,6 +556,12 @@ pub fn register_commands<R: io::Read, W: io::Write>(sh: &mut Shell<R, W>) {
         .func_result(|sh, cmd| net::NetCmd::process(sh, cmd))
         .build();
 
+    sh.command()
+        .name("ramdisk")
+        .help("create, format and mount RAM disks")
+        .func_result(|sh, cmd| ramdisk::RamDiskCmd::process(sh, cmd))
+        .build();
+
     sh.command()
         .name("help")
         .func(|sh, _cmd| {
@@ -440,7 +625,26 @@ pub fn register_commands<R: io::Read, W: io::Write>(sh: &mut Shell<R, W>) {
 
     sh.command()
         .name("elf")
-        .func_result(|sh, _cmd| {
+        .func_result(|sh, cmd| {
+            if cmd.args.len() == 2 {
+                // show how the executable would be loaded
+                let path = sh.handle_path(cmd.args[1]);
+                let mut file: Box<dyn mfs::File> = FILESYSTEM2.open(&path)?.into_file().ok_or("not a file")?;
+                let mut data = Vec::new();
+                io::copy(file.as_mut(), &mut data)?;
+
+                let elf = ElfImage::parse(&data).map_err(|_| OsError::IoErrorInvalidData)?;
+                writeln!(sh.writer, "entry: {:#x}", elf.entry)?;
+                for segment in elf.segments.iter() {
+                    writeln!(sh.writer, "  {:#x}-{:#x} {}{}{} file={} mem={}", segment.vaddr, segment.end(),
+                             if segment.flags & PF_R != 0 { "r" } else { "-" },
+                             if segment.flags & PF_W != 0 { "w" } else { "-" },
+                             if segment.flags & PF_X != 0 { "x" } else { "-" },
+                             ByteSize::from(segment.file_size as usize), ByteSize::from(segment.mem_size as usize))?;
+                }
+                return Ok(());
+            }
+
             let debug_info = crate::debug::debug_ref().ok_or("Debug info not loaded")?;
 
             let mut lr = crate::debug::base_pointer() as u64;
@@ -0,0 +1,130 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use common::fmt::ByteSize;
+use fat32::vfat::DynWrapper;
+use mountfs::mount::mfs;
+use shim::io;
+
+use crate::FILESYSTEM2;
+use crate::fs::ramdisk::{RamDisk, RAMDISKS};
+use crate::shell::{CommandArgs, Shell};
+use crate::shell::command::CommandError;
+
+pub struct RamDiskCmd<R: io::Read, W: io::Write> {
+    _r: PhantomData<R>,
+    _w: PhantomData<W>,
+}
+
+impl<R: io::Read, W: io::Write> RamDiskCmd<R, W> {
+    /// Parses a size in bytes with an optional K, M or G suffix.
+    fn parse_size(s: &str) -> Result<usize, CommandError> {
+        let (digits, unit) = match s.chars().last() {
+            Some('K') | Some('k') => (&s[..s.len() - 1], 1024),
+            Some('M') | Some('m') => (&s[..s.len() - 1], 1024 * 1024),
+            Some('G') | Some('g') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+            _ => (s, 1),
+        };
+        let size: usize = digits.parse()?;
+        Ok(size.checked_mul(unit).ok_or("size too large")?)
+    }
+
+    fn list(sh: &mut Shell<R, W>, _cmd: &CommandArgs) -> Result<(), CommandError> {
+        for (name, size, busy) in RAMDISKS.critical(|disks| disks.list()) {
+            writeln!(sh.writer, "{:<12} {:>10} {}", name, ByteSize::from(size), if busy { "busy" } else { "" })?;
+        }
+        Ok(())
+    }
+
+    fn create(sh: &mut Shell<R, W>, cmd: &CommandArgs) -> Result<(), CommandError> {
+        if cmd.args.len() != 4 {
+            writeln!(sh.writer, "usage: ramdisk create <name> <size>")?;
+            return Ok(())
+        }
+
+        let disk = RamDisk::new(Self::parse_size(cmd.args[3])?)?;
+        RAMDISKS.critical(|disks| disks.add(String::from(cmd.args[2]), disk))?;
+        Ok(())
+    }
+
+    fn load(sh: &mut Shell<R, W>, cmd: &CommandArgs) -> Result<(), CommandError> {
+        if cmd.args.len() != 4 {
+            writeln!(sh.writer, "usage: ramdisk load <name> <image>")?;
+            return Ok(())
+        }
+
+        let path = sh.handle_path(cmd.args[3]);
+        let mut file: Box<dyn mfs::File> = FILESYSTEM2.open(&path)?.into_file().ok_or("not a file")?;
+        let mut image = Vec::new();
+        io::copy(file.as_mut(), &mut image)?;
+
+        let disk = RamDisk::from_image(&image)?;
+        RAMDISKS.critical(|disks| disks.add(String::from(cmd.args[2]), disk))?;
+        writeln!(sh.writer, "loaded {}", ByteSize::from(image.len()))?;
+        Ok(())
+    }
+
+    fn format(sh: &mut Shell<R, W>, cmd: &CommandArgs) -> Result<(), CommandError> {
+        if cmd.args.len() < 3 || cmd.args.len() > 4 {
+            writeln!(sh.writer, "usage: ramdisk format <name> [label]")?;
+            return Ok(())
+        }
+
+        let label = cmd.args.get(3).copied().unwrap_or(cmd.args[2]);
+        RAMDISKS.critical(|disks| disks.format(cmd.args[2], label))?;
+        Ok(())
+    }
+
+    fn mount(sh: &mut Shell<R, W>, cmd: &CommandArgs) -> Result<(), CommandError> {
+        if cmd.args.len() != 4 {
+            writeln!(sh.writer, "usage: ramdisk mount <name> <path>")?;
+            return Ok(())
+        }
+
+        let disk = RAMDISKS.critical(|disks| disks.get(cmd.args[2]))?;
+        let vfat = disk.vfat()?;
+        let path = sh.handle_path(cmd.args[3]);
+        FILESYSTEM2.critical(|fs| fs.mount(Some(&path), Box::new(DynWrapper(vfat))))?;
+        Ok(())
+    }
+
+    fn remove(sh: &mut Shell<R, W>, cmd: &CommandArgs) -> Result<(), CommandError> {
+        if cmd.args.len() != 3 {
+            writeln!(sh.writer, "usage: ramdisk rm <name>")?;
+            return Ok(())
+        }
+
+        RAMDISKS.critical(|disks| disks.remove(cmd.args[2]))?;
+        Ok(())
+    }
+
+    pub fn process(sh: &mut Shell<R, W>, cmd: &CommandArgs) -> Result<(), CommandError> {
+        // cmd.args == ["ramdisk", "sub-command", ...]
+
+        if cmd.args.len() < 2 {
+            writeln!(sh.writer, "usage: ramdisk <subcommand>")?;
+            writeln!(sh.writer, "    ramdisk list                  -  list ramdisks")?;
+            writeln!(sh.writer, "    ramdisk create <name> <size>  -  create an empty ramdisk, e.g. 64M")?;
+            writeln!(sh.writer, "    ramdisk load <name> <image>   -  create a ramdisk from a disk image")?;
+            writeln!(sh.writer, "    ramdisk format <name> [label] -  partition and format as FAT32")?;
+            writeln!(sh.writer, "    ramdisk mount <name> <path>   -  mount the FAT file system")?;
+            writeln!(sh.writer, "    ramdisk rm <name>             -  free an unmounted ramdisk")?;
+            return Ok(())
+        }
+
+        match cmd.args[1] {
+            "list" => Self::list(sh, cmd),
+            "create" => Self::create(sh, cmd),
+            "load" => Self::load(sh, cmd),
+            "format" => Self::format(sh, cmd),
+            "mount" => Self::mount(sh, cmd),
+            "rm" => Self::remove(sh, cmd),
+            c => {
+                writeln!(sh.writer, "unknown subcommand: {}", c)?;
+                Ok(())
+            }
+        }
+    }
+}
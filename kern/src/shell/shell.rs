@@ -6,6 +6,7 @@ use core::ops::DerefMut;
 use core::time::Duration;
 
 use hashbrown::HashMap;
+use kernel_api::ExitStatus;
 
 use aarch64::MPIDR_EL1;
 use fat32::traits::{Dir, Entry, File, Metadata};
@@ -39,6 +40,8 @@ pub struct Shell<'a, R: io::Read, W: io::Write> {
     pub reader: R,
     pub writer: W,
     pub commands: HashMap<&'a str, Option<Command<'a, R, W>>>,
+    /// How the last program started with `run` ended.
+    pub last_status: Option<ExitStatus>,
     buffered_byte: Option<u8>,
 }
 
@@ -51,6 +54,7 @@ impl<'a, R: io::Read, W: io::Write> Shell<'a, R, W> {
             reader,
             writer,
             commands: HashMap::new(),
+            last_status: None,
             buffered_byte: None,
         };
 
@@ -93,12 +97,14 @@ impl<'a, R: io::Read, W: io::Write> Shell<'a, R, W> {
         }
     }
 
-    fn load_process(&self, piece: &str) -> kernel_api::OsResult<KernelProcess> {
-        let path = Path::new(piece);
+    /// Loads the program `args[0]` with `args` as its arguments.
+    fn load_process(&self, args: &[&str]) -> kernel_api::OsResult<KernelProcess> {
+        let argv = args.iter().map(|arg| String::from(*arg)).collect();
+        let path = Path::new(args[0]);
         if path.has_root() {
-            KernelProcess::load(path)
+            KernelProcess::load_with(path, argv, Vec::new())
         } else {
-            KernelProcess::load(self.cwd.join(path))
+            KernelProcess::load_with(self.cwd.join(path), argv, Vec::new())
         }
     }
 
@@ -281,13 +287,28 @@ impl<'a, R: io::Read, W: io::Write> Shell<'a, R, W> {
                 }
             }
             "run" => {
-                if command.args.len() == 2 {
-                    match self.load_process(command.args[1]) {
-                        Ok(proc) => {
+                if command.args.len() >= 2 {
+                    match self.load_process(&command.args[1..]) {
+                        Ok(mut proc) => {
+                            // make the shell the parent so that waitpid() finds the
+                            // exit status even if the program ends right away.
+                            let shell = kernel_api::syscall::getpid();
+                            proc.detail.parent = KERNEL_SCHEDULER.crit_process(shell, |shell| {
+                                shell.map(|shell| Arc::downgrade(&shell.detail.exited_children)).unwrap_or_default()
+                            });
+
                             let id = KERNEL_SCHEDULER.add(proc);
 
                             if let Some(id) = id {
-                                kernel_api::syscall::waitpid(id);
+                                match kernel_api::syscall::waitpid(id) {
+                                    Ok((_, status)) => {
+                                        if !status.success() {
+                                            writeln!(self.writer, "{}: {}", command.args[1], status)?;
+                                        }
+                                        self.last_status = Some(status);
+                                    }
+                                    Err(e) => writeln!(self.writer, "waitpid: {:?}", e)?,
+                                }
                             } else {
                                 writeln!(self.writer, "scheduler: failed to start process")?;
                             }
@@ -299,12 +320,12 @@ impl<'a, R: io::Read, W: io::Write> Shell<'a, R, W> {
                         }
                     }
                 } else {
-                    writeln!(self.writer, "usage: run <program>")?;
+                    writeln!(self.writer, "usage: run <program> [args...]")?;
                 }
             }
             "runb" => {
-                if command.args.len() == 2 {
-                    match self.load_process(command.args[1]) {
+                if command.args.len() >= 2 {
+                    match self.load_process(&command.args[1..]) {
                         Ok(proc) => {
                             KERNEL_SCHEDULER.add(proc);
                         }
@@ -314,7 +335,13 @@ impl<'a, R: io::Read, W: io::Write> Shell<'a, R, W> {
                         }
                     }
                 } else {
-                    writeln!(self.writer, "usage: run <program>")?;
+                    writeln!(self.writer, "usage: runb <program> [args...]")?;
+                }
+            }
+            "status" => {
+                match self.last_status {
+                    Some(status) => writeln!(self.writer, "{}", status)?,
+                    None => writeln!(self.writer, "no program has run")?,
                 }
             }
             "current-el" => {
@@ -0,0 +1,17 @@
+use core::time::Duration;
+
+use crate::FILESYSTEM2;
+use crate::process::KernProcessCtx;
+
+/// How often buffered file system data is written back.
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn fs_sync_thread(_ctx: KernProcessCtx) {
+    loop {
+        kernel_api::syscall::sleep(SYNC_INTERVAL);
+
+        if let Err(e) = FILESYSTEM2.sync() {
+            error!("periodic file system sync failed: {:?}", e);
+        }
+    }
+}
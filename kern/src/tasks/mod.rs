@@ -1,6 +1,8 @@
 
 mod core_balancing;
+mod fs_sync;
 mod net;
 
 pub use core_balancing::*;
+pub use fs_sync::*;
 pub use net::*;
@@ -2,6 +2,7 @@ use alloc::boxed::Box;
 use alloc::sync::Arc;
 use core::time::Duration;
 
+use dsx::sync::mutex::LockableMutex;
 use kernel_api::*;
 use kernel_api::OsError;
 
@@ -95,7 +96,7 @@ fn hyper_vnic_send(tf: &mut HyperTrapFrame) {
         }
 
         let mut frame = physical::Frame::default();
-        proc.vmap.copy_out(addr, &mut frame.0[..len])?;
+        m_lock!(proc.vmap).copy_out(addr, &mut frame.0[..len])?;
         frame.1 = len;
 
         if let Some(nic) = &proc.detail.nic {
@@ -119,7 +120,7 @@ fn hyper_vnic_receive(tf: &mut HyperTrapFrame) {
             return Err(OsError::InvalidSocket);
         }
 
-        proc.vmap.copy_in(addr, frame.as_slice())?;
+        m_lock!(proc.vmap).copy_in(addr, frame.as_slice())?;
         set_result(tf, &[frame.1 as u64]);
         Ok(())
     });
@@ -1,16 +1,18 @@
 use alloc::vec::Vec;
 use core::time::Duration;
 
+use kernel_api::ExitStatus;
 use pi::interrupt::{Controller, CoreInterrupt, Interrupt};
 
 use crate::{debug, shell, smp, hw, timing};
 use crate::kernel::{KERNEL_IRQ, KERNEL_SCHEDULER, KERNEL_TIMER};
 use crate::param::{PAGE_MASK, PAGE_SIZE, USER_IMG_BASE};
 use crate::process::State;
+use crate::process::mmap;
 use crate::traps::{Info, IRQ_EL, IRQ_ESR, IRQ_INFO, IRQ_RECURSION_DEPTH, KernelTrapFrame, Kind, IRQ_FP, Source};
 use crate::traps::Kind::Synchronous;
-use crate::traps::syndrome::Syndrome;
-use crate::traps::syscall::handle_syscall;
+use crate::traps::syndrome::{Fault, Syndrome};
+use crate::traps::syscall::{exit_process, handle_syscall};
 use crate::vm::VirtualAddr;
 use crate::arm::{VirtualCounter, PhysicalCounter, GenericCounterImpl};
 use crate::traps::coreinfo::{exc_enter, exc_record_time, exc_exit, ExceptionType};
@@ -95,6 +97,25 @@ fn handle_irqs(tf: &mut KernelTrapFrame) {
     debug_shell(tf);
 }
 
+/// Reads, allocates or copies the page at `far` for the current user
+/// process. Returns whether the faulting instruction can be retried.
+fn handle_user_page_fault(far: u64, tf: &KernelTrapFrame) -> bool {
+    let va = VirtualAddr::from(far);
+
+    // pages of file mappings are read without holding the process.
+    let vmap = KERNEL_SCHEDULER.crit_process(tf.TPIDR_EL0, |proc| proc.map(|proc| proc.vmap.clone()));
+    match vmap.map(|vmap| mmap::fault_in_file_pages(&vmap, va, 1)) {
+        None | Some(Err(_)) => return false,
+        Some(Ok(true)) => return true,
+        Some(Ok(false)) => {}
+    }
+
+    KERNEL_SCHEDULER.crit_process(tf.TPIDR_EL0, |proc| match proc {
+        Some(proc) => proc.handle_page_fault(va).is_ok(),
+        None => false,
+    })
+}
+
 fn do_kernel_handle_exception(info: Info, esr: u32, tf: &mut KernelTrapFrame) {
     match info.kind {
         Kind::Irq | Kind::Fiq => {
@@ -120,9 +141,24 @@ fn do_kernel_handle_exception(info: Info, esr: u32, tf: &mut KernelTrapFrame) {
                     use core::fmt::Write;
                     writeln!(hw::arch().early_writer(), "bad atomic instruction :( {:?} {:?} (raw={:#x}) @ {:#x}", info, s, esr, tf.ELR_EL1);
                 }
-                Syndrome::DataAbort(_) | Syndrome::InstructionAbort(_) => {
+                Syndrome::DataAbort(abort) | Syndrome::InstructionAbort(abort) => {
                     let s = Syndrome::from(esr);
-                    error!("MemAbort {:?} {:?} (FAR_EL1={:#x}) @ {:#x}", info, s, unsafe { aarch64::FAR_EL1.get() }, tf.ELR_EL1);
+                    let far = unsafe { aarch64::FAR_EL1.get() };
+
+                    // pages of lazy regions are allocated on the first access, and
+                    // pages shared by fork are copied on the first write.
+                    let user = info.source == Source::LowerAArch64;
+                    let handled = user && !abort.far_not_valid
+                        && (abort.kind == Fault::Translation || abort.kind == Fault::Permission)
+                        && handle_user_page_fault(far, tf);
+
+                    if !handled {
+                        error!("MemAbort {:?} {:?} (FAR_EL1={:#x}) @ {:#x}", info, s, far, tf.ELR_EL1);
+                    }
+                    if user && !handled {
+                        // the faulting instruction would only fault again.
+                        exit_process(ExitStatus::Faulted, tf);
+                    }
                 }
                 s => {
                     error!("F {:?} {:?} (raw={:#x}) @ {:#x}", info, s, esr, tf.ELR_EL1);
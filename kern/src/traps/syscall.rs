@@ -8,13 +8,22 @@ use crate::kernel_call::*;
 
 use crate::console::CONSOLE;
 use crate::kernel::KERNEL_SCHEDULER;
-use crate::process::{EventPollFn, State, KernelImpl};
-use crate::{process, timing};
+use crate::iosync::{SyncRead, SyncWrite};
+use crate::process::{EventPollFn, State, KernelImpl, KernelProcess};
+use crate::process::fd::{stat_of, FileDescriptor, OpenFile};
+use crate::process::mmap::{self, prot_to_perm};
+use crate::timing;
 use crate::traps::KernelTrapFrame;
 use crate::sync::{Completion, Waitable};
 use crate::param::PAGE_SIZE;
 use crate::arm::VirtualCounter;
 use crate::kernel_call::syscall::{ExecInExcPayload, ExcContext};
+use crate::vm::VirtualAddr;
+use crate::FILESYSTEM2;
+use mountfs::mount::mfs;
+use shim::path::PathBuf;
+use alloc::string::String;
+use alloc::vec::Vec;
 
 
 fn set_result(tf: &mut KernelTrapFrame, regs: &[u64]) {
@@ -27,6 +36,26 @@ fn set_err(tf: &mut KernelTrapFrame, res: OsError) {
     tf.regs[7] = res as u64;
 }
 
+fn set_os_result(tf: &mut KernelTrapFrame, res: OsResult<u64>) {
+    match res {
+        Ok(v) => {
+            set_result(tf, &[v]);
+            set_err(tf, OsError::Ok);
+        }
+        Err(e) => set_err(tf, e),
+    }
+}
+
+/// Runs `f` on the calling process.
+fn with_process<R, F>(tf: &KernelTrapFrame, f: F) -> OsResult<R>
+    where F: FnOnce(&mut KernelProcess) -> OsResult<R>
+{
+    KERNEL_SCHEDULER.crit_process(tf.TPIDR_EL0, |proc| match proc {
+        Some(proc) => f(proc),
+        None => Err(OsError::Unknown),
+    })
+}
+
 /// Sleep for `ms` milliseconds.
 ///
 /// This system call takes one parameter: the number of milliseconds to sleep.
@@ -72,10 +101,33 @@ pub fn sys_time(tf: &mut KernelTrapFrame) {
 
 }
 
-/// Kills current process.
+/// Kills current process, including all of its threads.
 ///
-/// This system call does not take paramer and does not return any value.
-pub fn sys_exit(tf: &mut KernelTrapFrame) {
+/// This system call takes one parameter: the exit code reported to `waitpid`.
+/// It does not return.
+pub fn sys_exit(code: i32, tf: &mut KernelTrapFrame) {
+    exit_process(ExitStatus::Exited(code), tf);
+}
+
+/// Kills the current process, recording `status` for `waitpid`, and
+/// schedules another one. The other threads of the process are killed the
+/// next time they are scheduled.
+pub fn exit_process(status: ExitStatus, tf: &mut KernelTrapFrame) {
+    exit_thread(status, true, tf);
+}
+
+/// Kills the calling thread, recording `status` for `thread_join`, and
+/// schedules another one. With `whole_process`, the process ends with
+/// `status` as well.
+fn exit_thread(status: ExitStatus, whole_process: bool, tf: &mut KernelTrapFrame) {
+    let _ = with_process(tf, |proc| {
+        proc.detail.exit_status = Some(status);
+        if whole_process {
+            proc.detail.group.exit(status);
+        }
+        Ok(())
+    });
+
     KERNEL_SCHEDULER.kill(tf).expect("killed");
     // we need to schedule a new process otherwise things will be very bad
     KERNEL_SCHEDULER.switch_to(tf);
@@ -95,7 +147,7 @@ pub fn sys_write(b: u8, _tf: &mut KernelTrapFrame) {
 
 }
 
-/// Returns current process's ID.
+/// Returns current process's ID, which is the ID of its main thread.
 ///
 /// This system call does not take parameter.
 ///
@@ -103,14 +155,22 @@ pub fn sys_write(b: u8, _tf: &mut KernelTrapFrame) {
 /// parameter: the current process's ID.
 pub fn sys_getpid(tf: &mut KernelTrapFrame) {
 
-    tf.regs[0] = tf.TPIDR_EL0;
+    tf.regs[0] = with_process(tf, |proc| Ok(proc.detail.group.pid())).unwrap_or(tf.TPIDR_EL0);
 
 }
 
+/// Waits for a process to end.
+///
+/// This system call takes one parameter: the pid of the process. A child of
+/// the calling process that already ended can still be waited for, once.
+///
+/// In addition to the usual status value, this system call returns three
+/// parameters: the time waited in milliseconds, and how the process ended as
+/// encoded by `ExitStatus::to_raw()`.
 pub fn sys_waitpid(pid: u64, tf: &mut KernelTrapFrame) {
     let start = timing::clock_time::<VirtualCounter>();
 
-    let comp = Arc::new(Completion::<process::Id>::new());
+    let comp = Arc::new(Completion::<ExitStatus>::new());
 
     let comp_clone = comp.clone();
     let did_register = KERNEL_SCHEDULER.crit_process(pid, move |proc| {
@@ -118,17 +178,33 @@ pub fn sys_waitpid(pid: u64, tf: &mut KernelTrapFrame) {
             proc.detail.dead_completions.push(comp_clone);
             true
         } else {
-            comp_clone.complete(pid);
             false
         }
     });
 
+    if !did_register {
+        let res = with_process(tf, |proc| m_lock!(proc.detail.exited_children).remove(&pid).ok_or(OsError::InvalidArgument));
+        match res {
+            Ok(status) => {
+                let (kind, code) = status.to_raw();
+                set_result(tf, &[0, kind, code]);
+                set_err(tf, OsError::Ok);
+            }
+            Err(e) => set_err(tf, e),
+        }
+        return;
+    }
+
     let time_fn: EventPollFn<KernelImpl> = Box::new(move |tf| {
         let now = timing::clock_time::<VirtualCounter>();
-        if comp.get().is_some() {
+        if let Some(status) = comp.get() {
+            // the status was also recorded if this is the parent.
+            m_lock!(tf.detail.exited_children).remove(&pid);
+
             let d = (now - start).as_millis() as u64;
-            set_result(&mut tf.context, &[d]);
-            set_err(&mut tf.context, if did_register { OsError::Ok } else { OsError::InvalidArgument });
+            let (kind, code) = status.to_raw();
+            set_result(&mut tf.context, &[d, kind, code]);
+            set_err(&mut tf.context, OsError::Ok);
             true
         } else {
             false
@@ -156,6 +232,574 @@ pub fn sys_sbrk(tf: &mut KernelTrapFrame) {
     set_err(tf, OsError::Ok);
 }
 
+/// Largest amount of data a single `read` or `write` transfers.
+const MAX_IO_SIZE: usize = 64 * 1024;
+
+/// Reads in the pages of file mappings among the `len` bytes of user memory
+/// at `va`, without holding the calling process.
+fn fault_in_user(tf: &KernelTrapFrame, va: VirtualAddr, len: usize) -> OsResult<()> {
+    let vmap = with_process(tf, |proc| Ok(proc.vmap.clone()))?;
+    mmap::fault_in_file_pages(&vmap, va, len).map(|_| ())
+}
+
+/// Copies the `buf.len()` bytes at `va` out of the calling process. Pages of
+/// file mappings among them are read in first, without holding the process.
+fn copy_from_user(tf: &KernelTrapFrame, va: VirtualAddr, buf: &mut [u8]) -> OsResult<()> {
+    fault_in_user(tf, va, buf.len())?;
+    with_process(tf, |proc| m_lock!(proc.vmap).copy_out(va, buf))
+}
+
+/// Copies the string of `len` bytes at `ptr` out of the calling process.
+fn user_str(tf: &KernelTrapFrame, ptr: u64, len: u64, max: usize) -> OsResult<String> {
+    if len as usize > max {
+        return Err(OsError::InvalidArgument);
+    }
+
+    let mut buf = vec![0u8; len as usize];
+    copy_from_user(tf, VirtualAddr::from(ptr), &mut buf)?;
+    String::from_utf8(buf).map_err(|_| OsError::InvalidArgument)
+}
+
+/// Copies the absolute path of `len` bytes at `ptr` out of the calling
+/// process.
+fn user_path(tf: &KernelTrapFrame, ptr: u64, len: u64) -> OsResult<PathBuf> {
+    let path = user_str(tf, ptr, len, PATH_MAX)?;
+    if !path.starts_with('/') {
+        return Err(OsError::InvalidArgument);
+    }
+    Ok(PathBuf::from(path))
+}
+
+/// Copies the `count` strings described by the `RawStr`s at `ptr` out of the
+/// calling process. At most `*budget` bytes are copied, and `*budget` is
+/// reduced by the bytes copied.
+fn user_strs(tf: &KernelTrapFrame, ptr: u64, count: u64, budget: &mut usize) -> OsResult<Vec<String>> {
+    if count as usize > MAX_ARGS {
+        return Err(OsError::InvalidArgument);
+    }
+
+    let mut raw = vec![RawStr::default(); count as usize];
+    copy_from_user(tf, VirtualAddr::from(ptr), as_bytes_mut(raw.as_mut_slice()))?;
+
+    let mut strs = Vec::with_capacity(raw.len());
+    for s in raw.iter() {
+        let s = user_str(tf, s.ptr, s.len, *budget)?;
+        *budget -= s.len();
+        strs.push(s);
+    }
+    Ok(strs)
+}
+
+/// Reads the path, arguments and environment of `spawn` and `exec` from the
+/// first six parameters.
+fn program_args(tf: &KernelTrapFrame) -> OsResult<(PathBuf, Vec<String>, Vec<String>)> {
+    let path = user_path(tf, tf.regs[0], tf.regs[1])?;
+    let mut budget = ARG_MAX;
+    let argv = user_strs(tf, tf.regs[2], tf.regs[3], &mut budget)?;
+    let envp = user_strs(tf, tf.regs[4], tf.regs[5], &mut budget)?;
+    if argv.len() + envp.len() > MAX_ARGS {
+        return Err(OsError::InvalidArgument);
+    }
+    Ok((path, argv, envp))
+}
+
+/// Opens a file or directory.
+///
+/// This system call takes three parameters: the address and length of an
+/// absolute path, and the `O_*` flags.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the new file descriptor.
+pub fn sys_open(tf: &mut KernelTrapFrame) {
+    let res = user_path(tf, tf.regs[0], tf.regs[1])
+        .and_then(|path| OpenFile::open(&path, tf.regs[2]))
+        .and_then(|file| {
+            let fd = FileDescriptor::file(Arc::new(file));
+            with_process(tf, |proc| Ok(proc.detail.add_fd(fd)))
+        });
+    set_os_result(tf, res);
+}
+
+/// Reads from a file descriptor.
+///
+/// This system call takes three parameters: the file descriptor, and the
+/// address and length of the buffer to read into. Reads from the console or a
+/// pipe block until data is available.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the number of bytes read.
+pub fn sys_read(tf: &mut KernelTrapFrame) {
+    let (fd, va) = (tf.regs[0], VirtualAddr::from(tf.regs[1]));
+    let len = core::cmp::min(tf.regs[2] as usize, MAX_IO_SIZE);
+
+    let fd = match with_process(tf, |proc| proc.detail.get_fd(fd)) {
+        Ok(fd) => fd,
+        Err(e) => return set_err(tf, e),
+    };
+
+    if let Some(file) = fd.file {
+        let mut buf = vec![0u8; len];
+        match file.read(&mut buf) {
+            Err(OsError::Waiting) => {}
+            res => {
+                let res = res.and_then(|n| {
+                    with_process(tf, |proc| m_lock!(proc.vmap).copy_in(va, &buf[..n]))?;
+                    Ok(n as u64)
+                });
+                return set_os_result(tf, res);
+            }
+        }
+
+        // a device without data, retried until it has some.
+        let poll_fn: EventPollFn<KernelImpl> = Box::new(move |proc| {
+            let res = match file.read(&mut buf) {
+                Err(OsError::Waiting) => return false,
+                res => res.and_then(|n| {
+                    m_lock!(proc.vmap).copy_in(va, &buf[..n])?;
+                    Ok(n as u64)
+                }),
+            };
+            set_os_result(&mut proc.context, res);
+            true
+        });
+        KERNEL_SCHEDULER.switch(State::Waiting(poll_fn), tf);
+        return;
+    }
+
+    let source = match fd.read {
+        Some(source) => source,
+        None => return set_err(tf, OsError::BadFileDescriptor),
+    };
+
+    let read_fn = move |proc: &mut KernelProcess| -> OsResult<u64> {
+        let mut buf = vec![0u8; len];
+        let n = source.read(&mut buf)?;
+        m_lock!(proc.vmap).copy_in(va, &buf[..n])?;
+        Ok(n as u64)
+    };
+
+    if len == 0 || !source.may_block() || source.done_waiting() {
+        let res = with_process(tf, read_fn);
+        return set_os_result(tf, res);
+    }
+
+    let waiting = source.clone();
+    let mut read_fn = Some(read_fn);
+    let poll_fn: EventPollFn<KernelImpl> = Box::new(move |proc| {
+        if !waiting.done_waiting() {
+            return false;
+        }
+        let res = (read_fn.take().unwrap())(proc);
+        set_os_result(&mut proc.context, res);
+        true
+    });
+    KERNEL_SCHEDULER.switch(State::Waiting(poll_fn), tf);
+}
+
+/// Writes to a file descriptor.
+///
+/// This system call takes three parameters: the file descriptor, and the
+/// address and length of the data to write.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the number of bytes written.
+pub fn sys_write_fd(tf: &mut KernelTrapFrame) {
+    let (fd, va) = (tf.regs[0], VirtualAddr::from(tf.regs[1]));
+    let len = core::cmp::min(tf.regs[2] as usize, MAX_IO_SIZE);
+
+    let mut buf = vec![0u8; len];
+    let res = with_process(tf, |proc| proc.detail.get_fd(fd)).and_then(|fd| {
+        copy_from_user(tf, va, &mut buf)?;
+        let n = match (fd.file, fd.write) {
+            (Some(file), _) => file.write(&buf)?,
+            (None, Some(sink)) => sink.write(&buf)?,
+            (None, None) => return Err(OsError::BadFileDescriptor),
+        };
+        Ok(n as u64)
+    });
+    set_os_result(tf, res);
+}
+
+/// Closes a file descriptor.
+///
+/// This system call takes one parameter: the file descriptor.
+///
+/// It only returns the usual status value.
+pub fn sys_close(tf: &mut KernelTrapFrame) {
+    let fd = tf.regs[0];
+    let res = with_process(tf, |proc| proc.detail.close_fd(fd));
+    set_os_result(tf, res.map(|_| 0));
+}
+
+/// Moves the position of a file descriptor.
+///
+/// This system call takes three parameters: the file descriptor, the signed
+/// offset and one of the `SEEK_*` origins.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the new position.
+pub fn sys_lseek(tf: &mut KernelTrapFrame) {
+    let (fd, offset, whence) = (tf.regs[0], tf.regs[1] as i64, tf.regs[2]);
+    let res = with_process(tf, |proc| proc.detail.get_fd(fd))
+        .and_then(|fd| fd.file.ok_or(OsError::InvalidArgument))
+        .and_then(|file| file.seek(offset, whence));
+    set_os_result(tf, res);
+}
+
+/// Returns the metadata of a file or directory.
+///
+/// This system call takes three parameters: the address and length of an
+/// absolute path, and the address of a `Stat` to fill in.
+///
+/// It only returns the usual status value.
+pub fn sys_stat(tf: &mut KernelTrapFrame) {
+    let va = VirtualAddr::from(tf.regs[2]);
+    let res = user_path(tf, tf.regs[0], tf.regs[1]).and_then(|path| {
+        let stat = match FILESYSTEM2.open(&path)? {
+            mfs::Entry::File(file) => stat_of(file.as_ref()),
+            mfs::Entry::Dir(dir) => stat_of(dir.as_ref()),
+        };
+        with_process(tf, |proc| m_lock!(proc.vmap).copy_in(va, as_bytes(&stat)))?;
+        Ok(0)
+    });
+    set_os_result(tf, res);
+}
+
+/// Returns the next entry of a directory.
+///
+/// This system call takes two parameters: the file descriptor of an open
+/// directory and the address of a `DirEnt` to fill in.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: 1 if an entry was filled in, 0 after the last entry.
+pub fn sys_readdir(tf: &mut KernelTrapFrame) {
+    let (fd, va) = (tf.regs[0], VirtualAddr::from(tf.regs[1]));
+    let res = with_process(tf, |proc| proc.detail.get_fd(fd))
+        .and_then(|fd| fd.file.ok_or(OsError::InvalidArgument))
+        .and_then(|file| match file.readdir()? {
+            Some(entry) => {
+                with_process(tf, |proc| m_lock!(proc.vmap).copy_in(va, as_bytes(&entry)))?;
+                Ok(1)
+            }
+            None => Ok(0),
+        });
+    set_os_result(tf, res);
+}
+
+/// Creates a directory.
+///
+/// This system call takes two parameters: the address and length of an
+/// absolute path.
+///
+/// It only returns the usual status value.
+pub fn sys_mkdir(tf: &mut KernelTrapFrame) {
+    let res = user_path(tf, tf.regs[0], tf.regs[1]).and_then(|path| {
+        FILESYSTEM2.critical(|fs| fs.create_dir(&path))?;
+        Ok(0)
+    });
+    set_os_result(tf, res);
+}
+
+/// Removes a file or an empty directory.
+///
+/// This system call takes two parameters: the address and length of an
+/// absolute path.
+///
+/// It only returns the usual status value.
+pub fn sys_unlink(tf: &mut KernelTrapFrame) {
+    let res = user_path(tf, tf.regs[0], tf.regs[1]).and_then(|path| {
+        FILESYSTEM2.critical(|fs| fs.remove(&path))?;
+        Ok(0)
+    });
+    set_os_result(tf, res);
+}
+
+/// Creates a copy of the calling process sharing its memory copy-on-write.
+///
+/// This system call does not take parameter.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the pid of the new process in the calling process, and zero in
+/// the new process.
+pub fn sys_fork(tf: &mut KernelTrapFrame) {
+    let res = with_process(tf, |proc| proc.fork(tf))
+        .and_then(|child| KERNEL_SCHEDULER.add(child).ok_or(OsError::NoMemory));
+    set_os_result(tf, res);
+}
+
+/// Starts a thread in the calling process.
+///
+/// This system call takes three parameters: the address the thread starts at,
+/// the argument passed to it in `x0`, and the size of its stack or zero for
+/// `THREAD_STACK_SIZE`.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the id of the new thread.
+pub fn sys_thread_create(tf: &mut KernelTrapFrame) {
+    let (entry, arg) = (tf.regs[0], tf.regs[1]);
+    let stack_size = match tf.regs[2] as usize {
+        0 => THREAD_STACK_SIZE,
+        size => size,
+    };
+
+    let res = with_process(tf, |proc| Ok((proc.new_thread(tf, entry, arg, stack_size)?, proc.detail.group.clone())))
+        .and_then(|(mut thread, group)| {
+            let done = Arc::new(Completion::new());
+            thread.detail.dead_completions.push(done.clone());
+            let tid = KERNEL_SCHEDULER.add(thread).ok_or(OsError::NoMemory)?;
+            group.add_joinable(tid, done);
+            Ok(tid)
+        });
+    set_os_result(tf, res);
+}
+
+/// Ends the calling thread. Ending the main thread ends the process.
+///
+/// This system call takes one parameter: the exit code reported to
+/// `thread_join`. It does not return.
+pub fn sys_thread_exit(code: i32, tf: &mut KernelTrapFrame) {
+    exit_thread(ExitStatus::Exited(code), false, tf);
+}
+
+/// Waits for a thread of the calling process to end. Each thread can be
+/// joined once.
+///
+/// This system call takes one parameter: the id of the thread.
+///
+/// In addition to the usual status value, this system call returns two
+/// parameters: how the thread ended as encoded by `ExitStatus::to_raw()`.
+pub fn sys_thread_join(tid: u64, tf: &mut KernelTrapFrame) {
+    let done = with_process(tf, |proc| {
+        // a thread never sees itself end.
+        if tid == tf.TPIDR_EL0 {
+            return Err(OsError::InvalidArgument);
+        }
+        proc.detail.group.take_joinable(tid).ok_or(OsError::InvalidArgument)
+    });
+
+    let done = match done {
+        Ok(done) => done,
+        Err(e) => {
+            set_err(tf, e);
+            return;
+        }
+    };
+
+    let join_fn: EventPollFn<KernelImpl> = Box::new(move |tf| {
+        if let Some(status) = done.get() {
+            let (kind, code) = status.to_raw();
+            set_result(&mut tf.context, &[kind, code]);
+            set_err(&mut tf.context, OsError::Ok);
+            true
+        } else {
+            false
+        }
+    });
+    KERNEL_SCHEDULER.switch(State::Waiting(join_fn), tf);
+}
+
+/// Waits on a futex word of the calling process.
+///
+/// This system call takes three parameters: the 4-byte aligned address of the
+/// word, the value it is expected to hold, and a timeout in milliseconds or
+/// zero to wait without one.
+///
+/// Returns only the usual status value: `OsError::WouldBlock` right away if
+/// the word does not hold the expected value, success once the word is woken
+/// by `futex_wake`, or `OsError::IoErrorTimedOut` once the timeout passed.
+pub fn sys_futex_wait(tf: &mut KernelTrapFrame) {
+    let (addr, expected, ms) = (tf.regs[0], tf.regs[1] as u32, tf.regs[2]);
+    let timeout = match ms {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    };
+
+    let res = fault_in_user(tf, VirtualAddr::from(addr), 4)
+        .and_then(|_| with_process(tf, |proc| proc.futex_wait(addr, expected, timeout)));
+    let waiter = match res {
+        Ok(waiter) => waiter,
+        Err(e) => return set_err(tf, e),
+    };
+
+    let wait_fn: EventPollFn<KernelImpl> = Box::new(move |proc| {
+        if !waiter.done_waiting() {
+            return false;
+        }
+        set_err(&mut proc.context, if waiter.woken() { OsError::Ok } else { OsError::IoErrorTimedOut });
+        true
+    });
+    KERNEL_SCHEDULER.switch(State::Waiting(wait_fn), tf);
+}
+
+/// Wakes threads waiting on a futex word of the calling process.
+///
+/// This system call takes two parameters: the address of the word and the
+/// most threads to wake.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the number of threads woken.
+pub fn sys_futex_wake(tf: &mut KernelTrapFrame) {
+    let (addr, count) = (tf.regs[0], tf.regs[1] as usize);
+    let res = fault_in_user(tf, VirtualAddr::from(addr), 4)
+        .and_then(|_| with_process(tf, |proc| proc.futex_wake(addr, count)));
+    set_os_result(tf, res.map(|woken| woken as u64));
+}
+
+/// Maps memory into the calling process.
+///
+/// This system call takes six parameters: the address hint, the length, the
+/// `PROT_*` protection, the `MAP_*` flags, the file descriptor of a file to map
+/// unless `MAP_ANONYMOUS` is given, and the page aligned offset in that file.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the address of the mapping.
+pub fn sys_mmap(tf: &mut KernelTrapFrame) {
+    let (addr, len, prot, flags, fd, offset) = (tf.regs[0], tf.regs[1], tf.regs[2], tf.regs[3], tf.regs[4], tf.regs[5]);
+    let res = with_process(tf, |proc| {
+        if flags & !(MAP_FIXED | MAP_ANONYMOUS) != 0 {
+            return Err(OsError::InvalidArgument);
+        }
+        let perm = prot_to_perm(prot)?;
+
+        let file = if flags & MAP_ANONYMOUS != 0 {
+            None
+        } else {
+            let file = proc.detail.get_fd(fd)?.file.ok_or(OsError::InvalidArgument)?;
+            if !file.readable() {
+                return Err(OsError::NoAccess);
+            }
+            Some((file, offset))
+        };
+
+        proc.mmap(addr, len, perm, flags & MAP_FIXED != 0, file)
+    });
+    set_os_result(tf, res.map(|va| va.as_u64()));
+}
+
+/// Unmaps memory mapped with `mmap`.
+///
+/// This system call takes two parameters: the page aligned address and the
+/// length of the range to unmap.
+///
+/// It only returns the usual status value.
+pub fn sys_munmap(tf: &mut KernelTrapFrame) {
+    let (addr, len) = (tf.regs[0], tf.regs[1]);
+    let res = with_process(tf, |proc| proc.munmap(addr, len));
+    set_os_result(tf, res.map(|_| 0));
+}
+
+/// Changes the protection of mapped memory.
+///
+/// This system call takes three parameters: the page aligned address, the
+/// length and the new `PROT_*` protection.
+///
+/// It only returns the usual status value.
+pub fn sys_mprotect(tf: &mut KernelTrapFrame) {
+    let (addr, len, prot) = (tf.regs[0], tf.regs[1], tf.regs[2]);
+    let res = with_process(tf, |proc| proc.mprotect(addr, len, prot_to_perm(prot)?));
+    set_os_result(tf, res.map(|_| 0));
+}
+
+/// Starts a program as a new process.
+///
+/// This system call takes seven parameters: the address and length of the
+/// absolute path of the program, the address and count of the `RawStr`
+/// arguments, the address and count of the `RawStr` environment variables,
+/// and the address of three descriptors to use as the new process's
+/// descriptors 0, 1 and 2 or zero. The new process gets a copy of all
+/// descriptors of the calling process; the three given ones only replace its
+/// descriptors 0, 1 and 2, every other descriptor is still inherited.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the pid of the new process.
+pub fn sys_spawn(tf: &mut KernelTrapFrame) {
+    let stdio_ptr = tf.regs[6];
+    let res = program_args(tf).and_then(|(path, argv, envp)| {
+        let mut child = KernelProcess::load_with(&path, argv, envp)?;
+
+        let mut stdio = [0u64; 3];
+        if stdio_ptr != 0 {
+            copy_from_user(tf, VirtualAddr::from(stdio_ptr), as_bytes_mut(&mut stdio[..]))?;
+        }
+
+        let (fds, parent) = with_process(tf, |proc| {
+            let mut fds = m_lock!(proc.detail.file_descriptors).clone();
+            if stdio_ptr != 0 {
+                fds.resize(core::cmp::max(fds.len(), stdio.len()), FileDescriptor::closed());
+                for (i, &fd) in stdio.iter().enumerate() {
+                    fds[i] = match fd {
+                        FD_INHERIT => continue,
+                        FD_NONE => FileDescriptor::closed(),
+                        fd => proc.detail.get_fd(fd)?,
+                    };
+                }
+            }
+            Ok((fds, Arc::downgrade(&proc.detail.exited_children)))
+        })?;
+        child.detail.file_descriptors = Arc::new(mutex_new!(fds));
+        child.detail.parent = parent;
+
+        KERNEL_SCHEDULER.add(child).ok_or(OsError::NoMemory)
+    });
+    set_os_result(tf, res);
+}
+
+/// Replaces the program of the calling process.
+///
+/// This system call takes the first six parameters of `spawn`. The process
+/// keeps its pid and descriptors. It must be called from the main thread; the
+/// other threads are killed.
+///
+/// Returns only the usual status value, and only if it fails. Otherwise the
+/// new program starts.
+pub fn sys_exec(tf: &mut KernelTrapFrame) {
+    let res = program_args(tf).and_then(|(path, argv, envp)| {
+        let mut new = KernelProcess::load_with(&path, argv, envp)?;
+        with_process(tf, |proc| {
+            if !proc.is_main_thread() {
+                return Err(OsError::InvalidArgument);
+            }
+
+            // the other threads keep the old address space until they are
+            // killed, and the process continues in the group of `new`.
+            new.detail.group.set_pid(proc.detail.group.pid());
+            proc.detail.group.exit(ExitStatus::Killed);
+            core::mem::swap(&mut proc.detail.group, &mut new.detail.group);
+
+            core::mem::swap(&mut proc.vmap, &mut new.vmap);
+            core::mem::swap(&mut proc.detail.argv, &mut new.detail.argv);
+            core::mem::swap(&mut proc.detail.envp, &mut new.detail.envp);
+            proc.detail.auxv_pid = new.detail.auxv_pid;
+            proc.name = core::mem::replace(&mut new.name, String::new());
+            proc.write_auxv_pid();
+            Ok(())
+        })?;
+
+        // the old address space is freed with `new`, unless other threads
+        // still use it, when returning through the new page table.
+        let pid = tf.TPIDR_EL0;
+        *tf = *new.context;
+        tf.TPIDR_EL0 = pid;
+        Ok(())
+    });
+
+    if let Err(e) = res {
+        set_err(tf, e);
+    }
+}
+
+/// The in-memory representation of a `#[repr(C)]` value shared with user
+/// space.
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>()) }
+}
+
+/// The in-memory representation of `#[repr(C)]` values filled in from user
+/// space. Only for types valid for any bit pattern.
+fn as_bytes_mut<T: Copy>(values: &mut [T]) -> &mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(values.as_mut_ptr() as *mut u8, core::mem::size_of_val(values)) }
+}
+
 pub fn handle_syscall(num: u16, tf: &mut KernelTrapFrame) {
     match num as usize {
         NR_SLEEP => {
@@ -166,7 +810,8 @@ pub fn handle_syscall(num: u16, tf: &mut KernelTrapFrame) {
             sys_time(tf);
         }
         NR_EXIT => {
-            sys_exit(tf);
+            let code = tf.regs[0] as i32;
+            sys_exit(code, tf);
         }
         NR_WRITE => {
             let b = tf.regs[0] as u8;
@@ -185,6 +830,32 @@ pub fn handle_syscall(num: u16, tf: &mut KernelTrapFrame) {
         NR_SBRK => {
             sys_sbrk(tf);
         }
+        NR_OPEN => sys_open(tf),
+        NR_READ => sys_read(tf),
+        NR_WRITE_FD => sys_write_fd(tf),
+        NR_CLOSE => sys_close(tf),
+        NR_LSEEK => sys_lseek(tf),
+        NR_STAT => sys_stat(tf),
+        NR_READDIR => sys_readdir(tf),
+        NR_MKDIR => sys_mkdir(tf),
+        NR_UNLINK => sys_unlink(tf),
+        NR_SPAWN => sys_spawn(tf),
+        NR_EXEC => sys_exec(tf),
+        NR_MMAP => sys_mmap(tf),
+        NR_MUNMAP => sys_munmap(tf),
+        NR_MPROTECT => sys_mprotect(tf),
+        NR_FORK => sys_fork(tf),
+        NR_THREAD_CREATE => sys_thread_create(tf),
+        NR_THREAD_EXIT => {
+            let code = tf.regs[0] as i32;
+            sys_thread_exit(code, tf);
+        }
+        NR_THREAD_JOIN => {
+            let tid = tf.regs[0];
+            sys_thread_join(tid, tf);
+        }
+        NR_FUTEX_WAIT => sys_futex_wait(tf),
+        NR_FUTEX_WAKE => sys_futex_wake(tf),
         NR_YIELD_FOR_TIMERS => {
             // do nothing here, this syscall is handled specially.
         }
@@ -1,4 +1,5 @@
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::fmt;
 use alloc::vec::Vec;
 use core::alloc::{GlobalAlloc, Layout};
@@ -10,10 +11,12 @@ use core::slice::Iter;
 
 use aarch64::vmsa::*;
 use aarch64::vmsa::EntryPerm::{KERN_RW, USER_RW};
+use dsx::sync::mutex::LockableMutex;
 use shim::const_assert_size;
 
 use crate::{allocator, hw};
 use crate::ALLOCATOR;
+use crate::mutex::Mutex;
 use crate::param::*;
 use crate::process::HyperProcess;
 use crate::traps::HyperTrapFrame;
@@ -33,6 +36,43 @@ impl Page {
     }
 }
 
+/// Number of user page tables mapping each page shared by
+/// `UserPageTable::fork`, by physical address. Pages mapped by a single table
+/// are not in the map.
+static SHARED_PAGES: Mutex<Option<BTreeMap<u64, usize>>> = mutex_new!(None);
+
+/// Adds a reference to the user page at `addr`.
+fn share_page(addr: u64) {
+    let mut shared = m_lock!(SHARED_PAGES);
+    *shared.get_or_insert_with(BTreeMap::new).entry(addr).or_insert(1) += 1;
+}
+
+fn is_page_shared(addr: u64) -> bool {
+    m_lock!(SHARED_PAGES).as_ref().map(|shared| shared.contains_key(&addr)).unwrap_or(false)
+}
+
+/// Drops a reference to the user page at `addr` and frees it if it was the
+/// last one.
+fn release_page(addr: u64) {
+    let last = match m_lock!(SHARED_PAGES).as_mut() {
+        None => true,
+        Some(shared) => match shared.get_mut(&addr) {
+            None => true,
+            Some(count) => {
+                *count -= 1;
+                if *count == 1 {
+                    shared.remove(&addr);
+                }
+                false
+            }
+        },
+    };
+
+    if last {
+        unsafe { ALLOCATOR.dealloc(addr as *mut u8, Page::layout()) }
+    }
+}
+
 const L2_PAGES: usize = 12;
 
 #[repr(C)]
@@ -321,12 +361,20 @@ impl KernPageTable {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PagePerm {
     RW,
     RO,
+    RX,
     RWX,
 }
 
+impl PagePerm {
+    pub fn writable(&self) -> bool {
+        *self == PagePerm::RW || *self == PagePerm::RWX
+    }
+}
+
 pub trait GuestPageTable: Sized + Send {
     fn new() -> Self;
 
@@ -337,6 +385,18 @@ pub trait GuestPageTable: Sized + Send {
     fn is_valid(&self, va: VirtualAddr) -> bool;
 
     fn alloc(&mut self, va: VirtualAddr, _perm: PagePerm) -> &mut [u8];
+
+    /// Unmaps the page at `va` and frees it. Returns `false` if it was not
+    /// mapped.
+    fn dealloc(&mut self, va: VirtualAddr) -> bool;
+
+    /// Changes the permission of the page at `va`. Returns `false` if it is
+    /// not mapped.
+    fn set_perm(&mut self, va: VirtualAddr, perm: PagePerm) -> bool;
+
+    /// Gives this table its own copy of the page at `va` with permission
+    /// `perm` if it is shared copy-on-write. Returns `false` if it is not.
+    fn copy_on_write(&mut self, va: VirtualAddr, perm: PagePerm) -> bool;
 }
 
 pub struct UserPageTable(Box<PageTable>);
@@ -363,6 +423,22 @@ impl UserPageTable {
         va.sub(VirtualAddr::from(USER_IMG_BASE))
     }
 
+    /// Sets the access permission and execute-never bits of `entry`. Pages
+    /// shared copy-on-write stay read-only.
+    fn apply_perm(entry: &mut RawL3Entry, perm: PagePerm) {
+        let (mut ap, uxn) = match perm {
+            PagePerm::RW => (EntryPerm::USER_RW, 1),
+            PagePerm::RO => (EntryPerm::USER_RO, 1),
+            PagePerm::RX => (EntryPerm::USER_RO, 0),
+            PagePerm::RWX => (EntryPerm::USER_RW, 0),
+        };
+        if entry.get_value(RawL3Entry::COW) != 0 {
+            ap = EntryPerm::USER_RO;
+        }
+        entry.set_value(ap, RawL3Entry::AP);
+        entry.set_value(uxn, RawL3Entry::UXN);
+    }
+
     pub fn dealloc(&mut self, va: VirtualAddr) -> bool {
         if va.as_usize() < USER_IMG_BASE {
             panic!("[dealloc] Tried to create user page below USER_IMG_BASE: {:x}", va.as_usize());
@@ -371,8 +447,7 @@ impl UserPageTable {
         let entry = self.0.get_entry_mut(Self::as_va_sub(va));
 
         if entry.is_valid() {
-            let addr = entry.0.get_value(RawL3Entry::ADDR) << 16;
-            unsafe { ALLOCATOR.dealloc(addr as *mut u8, Page::layout()) }
+            release_page(entry.0.get_value(RawL3Entry::ADDR) << 16);
             entry.reset();
             true
         } else {
@@ -380,6 +455,26 @@ impl UserPageTable {
         }
     }
 
+    /// Returns a table mapping the same pages as this one. The pages are
+    /// shared copy-on-write: they are read-only in both tables until a write
+    /// fault makes `copy_on_write` give the writer its own copy.
+    pub fn fork(&mut self) -> UserPageTable {
+        let mut child = UserPageTable::new();
+        for (i, entry) in self.0.l3.iter_mut().flat_map(|l3| l3.entries.iter_mut()).enumerate() {
+            if !entry.is_valid() {
+                continue;
+            }
+
+            entry.0.set_value(1, RawL3Entry::COW);
+            entry.0.set_value(EntryPerm::USER_RO, RawL3Entry::AP);
+            aarch64::clean_data_cache_obj(&entry.0);
+
+            share_page(entry.0.get_value(RawL3Entry::ADDR) << 16);
+            child.0.set_entry(VirtualAddr::from(i * PAGE_SIZE), entry.0);
+        }
+        child
+    }
+
     pub unsafe fn get_page_ref(&self, va: VirtualAddr) -> Option<&mut [u8]> {
         if va.as_usize() < USER_IMG_BASE {
             panic!("[get_page_ref] Tried to create user page below USER_IMG_BASE: {:x}", va.as_usize());
@@ -442,8 +537,7 @@ impl GuestPageTable for UserPageTable {
     /// Panics if allocator fails to allocate a page.
     ///
     /// TODO. use Result<T> and make it failurable
-    /// TODO. use perm properly
-    fn alloc(&mut self, va: VirtualAddr, _perm: PagePerm) -> &mut [u8] {
+    fn alloc(&mut self, va: VirtualAddr, perm: PagePerm) -> &mut [u8] {
         if va.as_usize() < USER_IMG_BASE {
             panic!("[GPT:alloc] Tried to create user page below USER_IMG_BASE: {:x}", va.as_usize());
         }
@@ -457,7 +551,7 @@ impl GuestPageTable for UserPageTable {
         let mut entry = RawL3Entry::new(0);
         entry.set_value(EntryValid::Valid, RawL3Entry::VALID);
         entry.set_value(EntryType::Table, RawL3Entry::TYPE);
-        entry.set_value(EntryPerm::USER_RW, RawL3Entry::AP);
+        Self::apply_perm(&mut entry, perm);
 
         entry.set_value(EntrySh::ISh, RawL3Entry::SH);
         entry.set_value(EntryAttr::Mem, RawL3Entry::ATTR);
@@ -473,6 +567,44 @@ impl GuestPageTable for UserPageTable {
 
         unsafe { core::slice::from_raw_parts_mut(alloc, PAGE_SIZE) }
     }
+
+    fn dealloc(&mut self, va: VirtualAddr) -> bool {
+        UserPageTable::dealloc(self, va)
+    }
+
+    fn set_perm(&mut self, va: VirtualAddr, perm: PagePerm) -> bool {
+        let entry = self.0.get_entry_mut(Self::as_va_sub(va));
+        if !entry.is_valid() {
+            return false;
+        }
+        Self::apply_perm(&mut entry.0, perm);
+        true
+    }
+
+    fn copy_on_write(&mut self, va: VirtualAddr, perm: PagePerm) -> bool {
+        let va_sub = Self::as_va_sub(va);
+        let entry = self.0.get_entry_mut(va_sub);
+        if !entry.is_valid() || entry.0.get_value(RawL3Entry::COW) == 0 {
+            return false;
+        }
+
+        let mut raw = entry.0;
+        let addr = raw.get_value(RawL3Entry::ADDR) << 16;
+
+        // the other tables may drop the page meanwhile, so it is released only
+        // once it is copied.
+        if is_page_shared(addr) {
+            let copy = unsafe { ALLOCATOR.alloc(Page::layout()) };
+            unsafe { core::ptr::copy_nonoverlapping(addr as *const u8, copy, PAGE_SIZE) };
+            raw.set_value((copy as u64) >> 16, RawL3Entry::ADDR);
+            release_page(addr);
+        }
+
+        raw.set_value(0, RawL3Entry::COW);
+        Self::apply_perm(&mut raw, perm);
+        self.0.set_entry(va_sub, raw);
+        true
+    }
 }
 
 impl Deref for KernPageTable {
@@ -494,8 +626,7 @@ impl Drop for UserPageTable {
         for l3 in self.0.l3.iter_mut() {
             for entry in l3.entries.iter_mut() {
                 if entry.is_valid() {
-                    let addr = entry.0.get_value(RawL3Entry::ADDR) << 16;
-                    unsafe { ALLOCATOR.dealloc(addr as *mut u8, Page::layout()) }
+                    release_page(entry.0.get_value(RawL3Entry::ADDR) << 16);
                 }
             }
         }
@@ -653,6 +784,21 @@ impl GuestPageTable for VirtualizationPageTable {
 
         unsafe { core::slice::from_raw_parts_mut(alloc, PAGE_SIZE) }
     }
+
+    fn dealloc(&mut self, va: VirtualAddr) -> bool {
+        VirtualizationPageTable::dealloc(self, va)
+    }
+
+    /// Guest pages are always mapped read-write-execute, so this only checks
+    /// that the page is mapped.
+    fn set_perm(&mut self, va: VirtualAddr, _perm: PagePerm) -> bool {
+        self.is_valid(va)
+    }
+
+    /// Guest pages are never shared.
+    fn copy_on_write(&mut self, _va: VirtualAddr, _perm: PagePerm) -> bool {
+        false
+    }
 }
 
 
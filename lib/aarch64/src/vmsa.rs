@@ -58,6 +58,9 @@ defbit!(RawL2Entry, [
 ]);
 
 defbit!(RawL3Entry, [
+    COW   [55-55], // Software use: shared copy-on-write page
+    UXN   [54-54], // Unprivileged execute-never
+    PXN   [53-53], // Privileged execute-never
     ADDR  [47-16],
 
     AF    [10-10],
@@ -0,0 +1,282 @@
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::size_of;
+
+use shim::const_assert_size;
+use shim::io;
+
+use crate::mbr::MasterBootRecord;
+use crate::traits::BlockDevice;
+
+/// MBR partition type of the protective partition covering a GPT disk.
+pub const PROTECTIVE_PARTITION_TYPE: u8 = 0xEE;
+
+const GPT_SIGNATURE: [u8; 8] = *b"EFI PART";
+
+/// Largest partition entry array accepted, in bytes. Tables written by common
+/// tools hold 128 entries of 128 bytes.
+const MAX_ENTRY_ARRAY_SIZE: u64 = 1 << 20;
+
+/// A GUID as it is stored on disk: the first three fields are little endian.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// Type GUID of unused partition entries.
+    pub const UNUSED: Guid = Guid([0; 16]);
+    /// Microsoft basic data partition (FAT, NTFS, exFAT).
+    pub const BASIC_DATA: Guid = Guid::new(0xEBD0A0A2, 0xB9E5, 0x4433, [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7]);
+    /// EFI system partition, always formatted as FAT.
+    pub const EFI_SYSTEM: Guid = Guid::new(0xC12A7328, 0xF81F, 0x11D2, [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]);
+
+    /// Builds a GUID from its textual representation
+    /// `d1-d2-d3-d4[0..2]-d4[2..8]`.
+    pub const fn new(d1: u32, d2: u16, d3: u16, d4: [u8; 8]) -> Guid {
+        let a = d1.to_le_bytes();
+        let b = d2.to_le_bytes();
+        let c = d3.to_le_bytes();
+        Guid([a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1],
+            d4[0], d4[1], d4[2], d4[3], d4[4], d4[5], d4[6], d4[7]])
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(f, "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-",
+               b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9])?;
+        for byte in &b[10..] {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+const_assert_size!(Guid, 16);
+
+/// The GPT header found at LBA 1 and, as a backup, at the last LBA of a disk.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct GptHeader {
+    signature: [u8; 8],
+    pub revision: u32,
+    pub header_size: u32,
+    header_crc32: u32,
+    __r0: u32,
+    pub current_lba: u64,
+    pub backup_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: Guid,
+    pub partition_entry_lba: u64,
+    pub num_partition_entries: u32,
+    pub partition_entry_size: u32,
+    partition_entries_crc32: u32,
+}
+
+const_assert_size!(GptHeader, 92);
+
+impl fmt::Debug for GptHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GptHeader")
+            .field("current_lba", &{ self.current_lba })
+            .field("backup_lba", &{ self.backup_lba })
+            .field("first_usable_lba", &{ self.first_usable_lba })
+            .field("last_usable_lba", &{ self.last_usable_lba })
+            .field("disk_guid", &{ self.disk_guid })
+            .field("num_partition_entries", &{ self.num_partition_entries })
+            .finish()
+    }
+}
+
+/// An entry of the GPT partition entry array.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct GptPartitionEntry {
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+    name: [u16; 36],
+}
+
+const_assert_size!(GptPartitionEntry, 128);
+
+impl GptPartitionEntry {
+    /// The UTF-16 partition name, up to the first NUL.
+    pub fn name(&self) -> impl Iterator<Item=char> {
+        let name = self.name;
+        core::char::decode_utf16(
+            (0..name.len()).map(move |i| name[i]).take_while(|&c| c != 0)
+        ).map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+    }
+
+    /// Number of LBAs covered by the partition.
+    pub fn num_sectors(&self) -> u64 {
+        (self.last_lba + 1).saturating_sub(self.first_lba)
+    }
+}
+
+impl fmt::Debug for GptPartitionEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name: alloc::string::String = self.name().collect();
+        f.debug_struct("GptPartitionEntry")
+            .field("type_guid", &{ self.type_guid })
+            .field("unique_guid", &{ self.unique_guid })
+            .field("first_lba", &{ self.first_lba })
+            .field("last_lba", &{ self.last_lba })
+            .field("name", &name)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// There was an I/O error while reading the partition table.
+    Io(io::Error),
+    /// The disk does not carry a protective MBR.
+    NoProtectiveMbr,
+    /// The header magic signature was invalid.
+    BadSignature,
+    /// The header or the partition entry array failed its CRC32 check.
+    BadChecksum,
+    /// The header describes an impossible partition entry array or is not
+    /// stored where it says it is.
+    InvalidHeader,
+}
+
+/// A GUID partition table (GPT).
+#[derive(Debug)]
+pub struct GuidPartitionTable {
+    pub header: GptHeader,
+    /// The partition entry array, including unused entries.
+    pub partitions: Vec<GptPartitionEntry>,
+}
+
+impl GuidPartitionTable {
+    /// Reads and returns the GUID partition table of `device`.
+    ///
+    /// The primary header at LBA 1 is used when it and its partition entry
+    /// array pass their CRC32 checks. Otherwise the backup header at the last
+    /// LBA of the disk, as recorded by the protective MBR, is tried.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NoProtectiveMbr` if sector 0 holds no protective MBR. Returns
+    /// the error of the primary table if neither table is valid, and `Io(err)`
+    /// if the I/O error `err` occured while reading either table.
+    pub fn from<T: BlockDevice>(mut device: T) -> Result<GuidPartitionTable, Error> {
+        let mbr = MasterBootRecord::from(&mut device).map_err(|e| match e {
+            crate::mbr::Error::Io(e) => Error::Io(e),
+            _ => Error::NoProtectiveMbr,
+        })?;
+
+        let protective = mbr.partitions.iter()
+            .find(|p| p.partition_type == PROTECTIVE_PARTITION_TYPE)
+            .ok_or(Error::NoProtectiveMbr)?;
+
+        let primary = match GuidPartitionTable::read(&mut device, 1) {
+            Ok(table) => return Ok(table),
+            Err(Error::Io(e)) => return Err(Error::Io(e)),
+            Err(e) => e,
+        };
+
+        let (start, total) = (protective.relative_sector as u64, protective.total_sectors as u64);
+        if total == 0 || total == u32::MAX as u64 {
+            // the size of the disk is unknown, so is the backup location.
+            return Err(primary);
+        }
+
+        match GuidPartitionTable::read(&mut device, start + total - 1) {
+            Ok(table) => Ok(table),
+            Err(Error::Io(e)) => Err(Error::Io(e)),
+            Err(_) => Err(primary),
+        }
+    }
+
+    /// Returns the used entries of the partition entry array along with their
+    /// index in the array.
+    pub fn used(&self) -> impl Iterator<Item=(usize, &GptPartitionEntry)> {
+        self.partitions.iter().enumerate().filter(|(_, entry)| entry.type_guid != Guid::UNUSED)
+    }
+
+    /// Reads the GPT whose header is stored at `lba`.
+    fn read<T: BlockDevice>(device: &mut T, lba: u64) -> Result<GuidPartitionTable, Error> {
+        let mut sector = vec![0u8; device.sector_size() as usize];
+        device.read_sector(lba, &mut sector).map_err(Error::Io)?;
+
+        let mut raw = [0u8; size_of::<GptHeader>()];
+        raw.copy_from_slice(&sector[..size_of::<GptHeader>()]);
+        let header: GptHeader = unsafe { core::mem::transmute(raw) };
+
+        if header.signature != GPT_SIGNATURE {
+            return Err(Error::BadSignature);
+        }
+
+        let header_size = header.header_size as usize;
+        if header_size < size_of::<GptHeader>() || header_size > sector.len() {
+            return Err(Error::InvalidHeader);
+        }
+
+        // the header CRC is computed with the CRC field itself zeroed.
+        sector[16..20].copy_from_slice(&[0; 4]);
+        if crc32(&sector[..header_size]) != header.header_crc32 {
+            return Err(Error::BadChecksum);
+        }
+
+        // a backup header copied to LBA 1, or the other way round.
+        let current_lba = header.current_lba;
+        if current_lba != lba {
+            return Err(Error::InvalidHeader);
+        }
+
+        let entry_size = header.partition_entry_size as usize;
+        if entry_size < size_of::<GptPartitionEntry>() || entry_size % 8 != 0 {
+            return Err(Error::InvalidHeader);
+        }
+
+        let array_len = entry_size as u64 * header.num_partition_entries as u64;
+        if array_len > MAX_ENTRY_ARRAY_SIZE {
+            return Err(Error::InvalidHeader);
+        }
+
+        let array_len = array_len as usize;
+        let mut array = Vec::with_capacity(array_len);
+        let mut lba = header.partition_entry_lba;
+        while array.len() < array_len {
+            if device.read_all_sector(lba, &mut array).map_err(Error::Io)? == 0 {
+                return Err(Error::InvalidHeader);
+            }
+            lba += 1;
+        }
+        array.truncate(array_len);
+
+        if crc32(&array) != header.partition_entries_crc32 {
+            return Err(Error::BadChecksum);
+        }
+
+        let partitions = array.chunks_exact(entry_size).map(|raw| {
+            let mut entry = [0u8; size_of::<GptPartitionEntry>()];
+            entry.copy_from_slice(&raw[..size_of::<GptPartitionEntry>()]);
+            let entry: GptPartitionEntry = unsafe { core::mem::transmute(entry) };
+            entry
+        }).collect();
+
+        Ok(GuidPartitionTable { header, partitions })
+    }
+}
+
+/// CRC32 (IEEE 802.3, reflected) as used by the GPT header and entry array.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -12,7 +12,9 @@ extern crate alloc;
 #[cfg(not(target_endian = "little"))]
 compile_error!("only little endian platforms supported");
 
+pub mod gpt;
 mod mbr;
+pub mod partition;
 #[cfg(test)]
 mod tests;
 pub mod util;
@@ -0,0 +1,99 @@
+use alloc::vec::Vec;
+
+use crate::gpt::{self, Guid, GuidPartitionTable, PROTECTIVE_PARTITION_TYPE};
+use crate::mbr::{self, MasterBootRecord};
+use crate::traits::BlockDevice;
+
+/// MBR partition types of FAT12, FAT16 and FAT32 partitions.
+const FAT_PARTITION_TYPES: [u8; 6] = [0x01, 0x04, 0x06, 0x0B, 0x0C, 0x0E];
+
+/// The partitioning scheme specific type of a partition.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// An MBR partition with the given partition type byte.
+    Mbr(u8),
+    /// A GPT partition with the given partition type GUID.
+    Gpt(Guid),
+}
+
+impl PartitionKind {
+    /// Whether the partition type denotes a FAT file system.
+    pub fn is_fat(&self) -> bool {
+        match self {
+            PartitionKind::Mbr(kind) => FAT_PARTITION_TYPES.contains(kind),
+            PartitionKind::Gpt(guid) => *guid == Guid::BASIC_DATA || *guid == Guid::EFI_SYSTEM,
+        }
+    }
+}
+
+/// A partition of a block device, independent of the partitioning scheme.
+#[derive(Copy, Clone, Debug)]
+pub struct PartitionInfo {
+    /// 0-indexed number of the partition in its partition table.
+    pub index: usize,
+    /// First device sector of the partition.
+    pub start: u64,
+    /// Number of device sectors in the partition.
+    pub num_sectors: u64,
+    pub kind: PartitionKind,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Mbr(mbr::Error),
+    Gpt(gpt::Error),
+}
+
+impl From<mbr::Error> for Error {
+    fn from(error: mbr::Error) -> Error {
+        Error::Mbr(error)
+    }
+}
+
+impl From<gpt::Error> for Error {
+    fn from(error: gpt::Error) -> Error {
+        Error::Gpt(error)
+    }
+}
+
+/// Returns the partitions of `device`.
+///
+/// A disk whose MBR holds a protective partition is read as a GPT disk, any
+/// other disk as an MBR disk. Empty slots are skipped.
+///
+/// # Errors
+///
+/// Returns `Mbr(err)` if the MBR could not be read and `Gpt(err)` if the disk
+/// is a GPT disk but neither the primary nor the backup GPT is valid.
+pub fn partitions<T: BlockDevice>(mut device: T) -> Result<Vec<PartitionInfo>, Error> {
+    let mbr = MasterBootRecord::from(&mut device)?;
+
+    if mbr.partitions.iter().any(|p| p.partition_type == PROTECTIVE_PARTITION_TYPE) {
+        let gpt = GuidPartitionTable::from(&mut device)?;
+        return Ok(gpt.used().map(|(index, p)| PartitionInfo {
+            index,
+            start: p.first_lba,
+            num_sectors: p.num_sectors(),
+            kind: PartitionKind::Gpt(p.type_guid),
+        }).collect());
+    }
+
+    Ok(mbr.partitions.iter().enumerate()
+        .filter(|(_, p)| p.partition_type != 0 && p.total_sectors != 0)
+        .map(|(index, p)| PartitionInfo {
+            index,
+            start: p.relative_sector as u64,
+            num_sectors: p.total_sectors as u64,
+            kind: PartitionKind::Mbr(p.partition_type),
+        }).collect())
+}
+
+/// Returns the first FAT partition of `device`.
+///
+/// # Errors
+///
+/// Returns `Ok(None)` if the partition table could be read but holds no FAT
+/// partition, and the errors of `partitions()` otherwise.
+pub fn find_fat<T: BlockDevice>(device: T) -> Result<Option<PartitionInfo>, Error> {
+    Ok(partitions(device)?.into_iter().find(|p| p.kind.is_fat()))
+}
@@ -7,7 +7,9 @@ use std::io::Cursor;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 
+use crate::gpt;
 use crate::mbr;
+use crate::partition;
 use crate::traits::*;
 use crate::vfat;
 
@@ -474,3 +476,872 @@ fn shuffle_test() {
     let hash = hash_files_recursive_from(vfat, "/");
     assert_hash_eq!("mock 1 file hashes", hash, hash_for!("files-1"));
 }
+
+/// An in-memory copy of an image that can be mounted several times so that
+/// writes made through one mount can be observed through another.
+#[derive(Clone)]
+struct SharedImage(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+impl SharedImage {
+    fn new(mut file: ::std::fs::File) -> SharedImage {
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).expect("read image");
+        SharedImage(Arc::new(Mutex::new(Cursor::new(data))))
+    }
+
+    fn mount(&self) -> StdVFatHandle {
+        VFat::<StdVFatHandle>::from(self.clone()).expect("failed to initialize VFAT from image")
+    }
+}
+
+impl BlockDevice for SharedImage {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write_sector(n, buf)
+    }
+}
+
+macro shared_from_resource($name:expr) {
+    SharedImage::new(resource!($name))
+}
+
+fn read_all<P: AsRef<Path>>(vfat: &StdVFatHandle, path: P) -> Vec<u8> {
+    let mut file = vfat.open_file(path).expect("file exists");
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).expect("read file");
+    assert_eq!(data.len() as u64, file.size());
+    data
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+}
+
+#[test]
+fn test_write_append() {
+    let image = shared_from_resource!("mock1.fat32.img");
+    let original = read_all(&image.mount(), "/CS140E");
+
+    let vfat = image.mount();
+    let mut file = vfat.open_file("/CS140E").expect("file exists");
+    let extra = pattern(20000, 7);
+    file.seek(io::SeekFrom::End(0)).expect("seek to end");
+    file.write_all(&extra).expect("append");
+    assert_eq!(file.size(), (original.len() + extra.len()) as u64);
+    file.sync().expect("sync");
+
+    let data = read_all(&image.mount(), "/CS140E");
+    assert_eq!(&data[..original.len()], &original[..]);
+    assert_eq!(&data[original.len()..], &extra[..]);
+}
+
+#[test]
+fn test_write_overwrite() {
+    let image = shared_from_resource!("mock1.fat32.img");
+    let original = read_all(&image.mount(), "/CS140E");
+    assert!(original.len() > 1000);
+
+    let vfat = image.mount();
+    let mut file = vfat.open_file("/CS140E").expect("file exists");
+    let patch = pattern(600, 3);
+    file.seek(io::SeekFrom::Start(300)).expect("seek");
+    file.write_all(&patch).expect("overwrite");
+    assert_eq!(file.size(), original.len() as u64);
+    file.flush().expect("flush");
+
+    let data = read_all(&image.mount(), "/CS140E");
+    assert_eq!(&data[..300], &original[..300]);
+    assert_eq!(&data[300..900], &patch[..]);
+    assert_eq!(&data[900..], &original[900..]);
+}
+
+#[test]
+fn test_write_updates_modified_time() {
+    let image = shared_from_resource!("mock1.fat32.img");
+
+    let vfat = image.mount();
+    vfat.lock(|fs| fs.set_clock(|| vfat::Timestamp {
+        date: vfat::Date::new(2020, 4, 12),
+        time: vfat::Time::new(13, 37, 42),
+    }));
+
+    let mut file = vfat.open_file("/CS140E").expect("file exists");
+    file.write_all(b"hello").expect("write");
+    file.sync().expect("sync");
+
+    let entry = image.mount().open("/CS140E").expect("entry exists");
+    let modified = entry.metadata().modified();
+    assert_eq!((modified.year(), modified.month(), modified.day()), (2020, 4, 12));
+    assert_eq!((modified.hour(), modified.minute(), modified.second()), (13, 37, 42));
+}
+
+#[test]
+fn test_set_len() {
+    let image = shared_from_resource!("mock1.fat32.img");
+    let original = read_all(&image.mount(), "/CS140E");
+
+    let vfat = image.mount();
+    let mut file = vfat.open_file("/CS140E").expect("file exists");
+    file.set_len(100).expect("truncate");
+    file.sync().expect("sync");
+    assert_eq!(read_all(&image.mount(), "/CS140E"), &original[..100]);
+
+    file.set_len(10000).expect("extend");
+    file.sync().expect("sync");
+    let data = read_all(&image.mount(), "/CS140E");
+    assert_eq!(&data[..100], &original[..100]);
+    assert!(data[100..].iter().all(|b| *b == 0));
+
+    file.set_len(0).expect("truncate to zero");
+    file.write_all(b"fresh start").expect("write");
+    file.sync().expect("sync");
+    assert_eq!(read_all(&image.mount(), "/CS140E"), b"fresh start");
+}
+
+#[test]
+fn test_write_reuses_freed_clusters() {
+    let image = shared_from_resource!("mock1.fat32.img");
+    let vfat = image.mount();
+
+    let mut file = vfat.open_file("/CS140E").expect("file exists");
+    let data = pattern(64 * 1024, 11);
+    for _ in 0..8 {
+        file.set_len(0).expect("truncate");
+        file.write_all(&data).expect("write");
+    }
+    file.sync().expect("sync");
+
+    assert_eq!(read_all(&image.mount(), "/CS140E"), data);
+    let hash = hash_files_recursive_from(image.mount(), "/NOTES");
+    let expected = hash_files_recursive_from(vfat_from_resource!("mock1.fat32.img"), "/NOTES");
+    assert_hash_eq!("mock 1 untouched files", hash, expected);
+}
+
+fn names_in<P: AsRef<Path>>(vfat: &StdVFatHandle, path: P) -> Vec<String> {
+    let mut names: Vec<String> = vfat.open_dir(path).expect("directory exists")
+        .entries().expect("entries interator")
+        .map(|e| e.name().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_create_file() {
+    let image = shared_from_resource!("mock1.fat32.img");
+
+    let vfat = image.mount();
+    let root = vfat.open_dir("/").expect("root");
+    let mut file = root.create_file("hello.txt").expect("create file");
+    file.write_all(b"hello, world").expect("write");
+    file.sync().expect("sync");
+
+    let err = root.create_file("HELLO.TXT").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+    let vfat = image.mount();
+    assert_eq!(read_all(&vfat, "/hello.txt"), b"hello, world");
+    assert!(names_in(&vfat, "/").contains(&"hello.txt".to_string()));
+    assert!(names_in(&vfat, "/").contains(&"CS140E".to_string()));
+}
+
+#[test]
+fn test_create_long_names() {
+    let image = shared_from_resource!("mock1.fat32.img");
+
+    let vfat = image.mount();
+    let dir = vfat.open_dir("/NOTES").expect("directory");
+    let names: Vec<String> = (0..40)
+        .map(|i| format!("A long file name that needs several LFN entries {}.data", i))
+        .collect();
+    for (i, name) in names.iter().enumerate() {
+        let mut file = dir.create_file(name).expect("create file");
+        file.write_all(format!("file {}", i).as_bytes()).expect("write");
+    }
+    dir.create_file("SHORT.TXT").expect("create short name");
+    vfat.lock(|fs| fs.flush()).expect("flush");
+
+    let vfat = image.mount();
+    let listed = names_in(&vfat, "/NOTES");
+    for (i, name) in names.iter().enumerate() {
+        assert!(listed.contains(name), "missing {}", name);
+        let path = Path::new("/NOTES").join(name);
+        assert_eq!(read_all(&vfat, path), format!("file {}", i).as_bytes());
+    }
+    assert!(listed.contains(&"SHORT.TXT".to_string()));
+    assert!(listed.contains(&"LEC1".to_string()));
+}
+
+#[test]
+fn test_create_dir() {
+    let image = shared_from_resource!("mock1.fat32.img");
+
+    let vfat = image.mount();
+    let root = vfat.open_dir("/").expect("root");
+    let dir = root.create_dir("new dir").expect("create dir");
+    let nested = dir.create_dir("NESTED").expect("create nested dir");
+    nested.create_file("leaf").expect("create file")
+        .write_all(b"leaf data").expect("write");
+    vfat.lock(|fs| fs.flush()).expect("flush");
+
+    let vfat = image.mount();
+    assert_eq!(names_in(&vfat, "/new dir"), vec![".", "..", "NESTED"]);
+    assert_eq!(names_in(&vfat, "/new dir/NESTED"), vec![".", "..", "leaf"]);
+    assert_eq!(read_all(&vfat, "/new dir/NESTED/leaf"), b"leaf data");
+
+    let nested_dir = vfat.open_dir("/new dir/NESTED").expect("nested");
+    let dot = nested_dir.find(".").expect("dot").into_dir().expect("dir");
+    assert_eq!(dot.cluster, nested.cluster);
+    let dotdot = nested_dir.find("..").expect("dotdot").into_dir().expect("dir");
+    assert_eq!(dotdot.cluster, dir.cluster);
+    let root_link = vfat.open_dir("/new dir").expect("dir").find("..").expect("dotdot");
+    assert_eq!(root_link.into_dir().expect("dir").cluster.raw(), 0);
+}
+
+#[test]
+fn test_remove() {
+    let image = shared_from_resource!("mock1.fat32.img");
+
+    let vfat = image.mount();
+    let root = vfat.open_dir("/").expect("root");
+    let cluster = vfat.open_file("/CS140E").expect("file exists").cluster;
+    root.remove("CS140E").expect("remove file");
+    assert_eq!(vfat.lock(|fs| fs.fat_entry(cluster).unwrap().status()), vfat::Status::Free);
+
+    let err = root.remove("NOTES").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+
+    let dir = root.create_dir("scratch").expect("create dir");
+    root.remove("scratch").expect("remove empty dir");
+    assert_eq!(vfat.lock(|fs| fs.fat_entry(dir.cluster).unwrap().status()), vfat::Status::Free);
+    vfat.lock(|fs| fs.flush()).expect("flush");
+
+    let vfat = image.mount();
+    let names = names_in(&vfat, "/");
+    assert!(!names.contains(&"CS140E".to_string()));
+    assert!(!names.contains(&"scratch".to_string()));
+    assert_eq!(vfat.open("/CS140E").unwrap_err().kind(), io::ErrorKind::NotFound);
+    assert!(names.contains(&"NOTES".to_string()));
+}
+
+#[test]
+fn test_rename() {
+    let image = shared_from_resource!("mock1.fat32.img");
+    let original = read_all(&image.mount(), "/CS140E");
+
+    let vfat = image.mount();
+    let root = vfat.open_dir("/").expect("root");
+    root.rename("CS140E", &root, "cs140e notes.bin").expect("rename");
+
+    let notes = vfat.open_dir("/NOTES").expect("directory");
+    root.rename("cs140e notes.bin", &notes, "moved").expect("move");
+
+    let lec1 = vfat.open_dir("/NOTES/LEC1").expect("directory");
+    root.rename("NOTES", &root, "Notes").expect("case only rename");
+    let err = root.rename("Notes", &lec1, "inside").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    let docs = vfat.open_dir("/rpi3-docs").expect("directory");
+    docs.rename("..", &root, "x").unwrap_err();
+    root.rename("rpi3-docs", &lec1, "docs").expect("move dir");
+    vfat.lock(|fs| fs.flush()).expect("flush");
+
+    let vfat = image.mount();
+    assert_eq!(read_all(&vfat, "/Notes/moved"), original);
+    let names = names_in(&vfat, "/");
+    assert!(names.contains(&"Notes".to_string()));
+    assert!(!names.contains(&"NOTES".to_string()));
+    assert!(!names.contains(&"CS140E".to_string()));
+    assert!(!names.contains(&"rpi3-docs".to_string()));
+
+    let moved = vfat.open_dir("/Notes/LEC1/docs").expect("moved dir");
+    assert_eq!(moved.cluster, docs.cluster);
+    let parent = moved.find("..").expect("dotdot").into_dir().expect("dir");
+    assert_eq!(parent.cluster, lec1.cluster);
+}
+
+#[test]
+fn test_remove_and_rename_open_file() {
+    let image = shared_from_resource!("mock1.fat32.img");
+    let original = read_all(&image.mount(), "/CS140E");
+
+    let vfat = image.mount();
+    let root = vfat.open_dir("/").expect("root");
+    let mut file = vfat.open_file("/CS140E").expect("file exists");
+    assert_eq!(root.remove("CS140E").unwrap_err().kind(), io::ErrorKind::Other);
+    assert_eq!(root.rename("CS140E", &root, "moved").unwrap_err().kind(), io::ErrorKind::Other);
+
+    // the handle still owns its entry and clusters.
+    file.seek(io::SeekFrom::End(0)).expect("seek to end");
+    file.write_all(&pattern(3000, 4)).expect("append");
+    drop(file);
+
+    let mut data = original.clone();
+    data.extend_from_slice(&pattern(3000, 4));
+    assert_eq!(read_all(&vfat, "/CS140E"), data);
+
+    root.rename("CS140E", &root, "moved").expect("rename closed file");
+    let mut file = vfat.open_file("/moved").expect("file exists");
+    assert_eq!(root.remove("moved").unwrap_err().kind(), io::ErrorKind::Other);
+    file.write_all(b"head").expect("overwrite");
+    drop(file);
+    root.remove("moved").expect("remove closed file");
+    vfat.lock(|fs| fs.flush()).expect("flush");
+
+    let vfat = image.mount();
+    assert_eq!(vfat.open("/CS140E").unwrap_err().kind(), io::ErrorKind::NotFound);
+    assert_eq!(vfat.open("/moved").unwrap_err().kind(), io::ErrorKind::NotFound);
+    assert!(names_in(&vfat, "/").contains(&"NOTES".to_string()));
+}
+
+// fat12.img and fat16.img are built by bin/gen-fat-imgs.sh.
+fn check_small_image(image: &SharedImage, fat_type: vfat::FatType, name: &str) {
+    let vfat = image.mount();
+    assert_eq!(vfat.lock(|fs| fs.fat_type()), fat_type);
+
+    assert_eq!(read_all(&vfat, "/HELLO.TXT"), format!("hello from {}\n", name).as_bytes());
+    assert_eq!(read_all(&vfat, "/DATA.BIN"), pattern(5000, 1));
+    assert_eq!(read_all(&vfat, "/SUBDIR/a long file name.txt"), b"nested\n");
+    assert_eq!(names_in(&vfat, "/"), vec!["DATA.BIN", "HELLO.TXT", "SUBDIR"]);
+
+    let subdir = vfat.open_dir("/SUBDIR").expect("directory");
+    let parent = subdir.find("..").expect("dotdot").into_dir().expect("dir");
+    assert_eq!(names_in(&vfat, "/"), {
+        let mut names: Vec<String> = parent.entries().expect("entries").map(|e| e.name().to_string()).collect();
+        names.sort();
+        names
+    });
+}
+
+fn check_small_image_writes(image: &SharedImage) {
+    let vfat = image.mount();
+    let mut file = vfat.open_file("/DATA.BIN").expect("file exists");
+    file.seek(io::SeekFrom::End(0)).expect("seek to end");
+    file.write_all(&pattern(7000, 9)).expect("append");
+
+    let root = vfat.open_dir("/").expect("root");
+    root.create_file("a new file.txt").expect("create file")
+        .write_all(b"new").expect("write");
+    let dir = root.create_dir("NEWDIR").expect("create dir");
+    dir.create_file("INNER").expect("create file");
+    root.remove("HELLO.TXT").expect("remove");
+    vfat.lock(|fs| fs.flush()).expect("flush");
+
+    let vfat = image.mount();
+    let data = read_all(&vfat, "/DATA.BIN");
+    assert_eq!(&data[..5000], &pattern(5000, 1)[..]);
+    assert_eq!(&data[5000..], &pattern(7000, 9)[..]);
+    assert_eq!(read_all(&vfat, "/a new file.txt"), b"new");
+    assert_eq!(names_in(&vfat, "/NEWDIR"), vec![".", "..", "INNER"]);
+    assert_eq!(names_in(&vfat, "/"), vec!["DATA.BIN", "NEWDIR", "SUBDIR", "a new file.txt"]);
+}
+
+#[test]
+fn test_fat16() {
+    let image = shared_from_resource!("fat16.img");
+    check_small_image(&image, vfat::FatType::Fat16, "fat16");
+    check_small_image_writes(&image);
+}
+
+#[test]
+fn test_fat12() {
+    let image = shared_from_resource!("fat12.img");
+    check_small_image(&image, vfat::FatType::Fat12, "fat12");
+    check_small_image_writes(&image);
+}
+
+#[test]
+fn test_fat16_root_dir_full() {
+    let image = shared_from_resource!("fat16.img");
+    let vfat = image.mount();
+    let root = vfat.open_dir("/").expect("root");
+
+    let mut created = 0;
+    let err = loop {
+        match root.create_file(format!("F{}", created)) {
+            Ok(_) => created += 1,
+            Err(e) => break e,
+        }
+    };
+
+    // the root directory of the image holds 512 entries, 3 are in use.
+    assert_eq!(created, 509);
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    assert_eq!(read_all(&vfat, "/DATA.BIN"), pattern(5000, 1));
+}
+
+#[test]
+fn test_file_ids() {
+    use mountfs::mount::mfs::FileInfo;
+
+    // Empty files have no cluster, and the root directory of a FAT16 volume
+    // reports cluster 0 as well.
+    let image = shared_from_resource!("fat16.img");
+    let vfat = VFat::<vfat::DynVFatHandle>::from(image).expect("failed to initialize VFAT from image");
+    let root = vfat.open_dir("/").expect("root");
+    let first = root.create_file("EMPTY1").expect("create file");
+    let second = root.create_file("EMPTY2").expect("create file");
+
+    assert_ne!(first.get_id(), second.get_id());
+    assert_ne!(first.get_id(), root.get_id());
+    assert_ne!(second.get_id(), root.get_id());
+    assert_eq!(vfat.open_file("/EMPTY1").expect("file exists").get_id(), first.get_id());
+}
+
+#[test]
+fn test_gpt_crc32() {
+    assert_eq!(crate::gpt::crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(crate::gpt::crc32(b""), 0);
+}
+
+#[test]
+fn check_gpt_sizes() {
+    check_size!(gpt::GptHeader, 92);
+    check_size!(gpt::GptPartitionEntry, 128);
+}
+
+const GPT_ENTRIES: usize = 16;
+const GPT_FIRST_LBA: usize = 64;
+
+/// Writes a GPT header at `lba` whose entry array starts at `entries_lba`.
+fn write_gpt_header(disk: &mut [u8], lba: usize, backup: usize, entries_lba: usize, last_usable: usize, entries_crc: u32) {
+    let header = &mut disk[lba * 512..lba * 512 + 92];
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[24..32].copy_from_slice(&(lba as u64).to_le_bytes());
+    header[32..40].copy_from_slice(&(backup as u64).to_le_bytes());
+    header[40..48].copy_from_slice(&(GPT_FIRST_LBA as u64).to_le_bytes());
+    header[48..56].copy_from_slice(&(last_usable as u64).to_le_bytes());
+    header[56..72].copy_from_slice(&[0x42; 16]);
+    header[72..80].copy_from_slice(&(entries_lba as u64).to_le_bytes());
+    header[80..84].copy_from_slice(&(GPT_ENTRIES as u32).to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+    let crc = crate::gpt::crc32(header);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Recomputes the CRC32 of the GPT header at `lba` after it was modified.
+fn fix_gpt_header_crc(disk: &mut [u8], lba: usize) {
+    let header = &mut disk[lba * 512..lba * 512 + 92];
+    header[16..20].copy_from_slice(&[0; 4]);
+    let crc = crate::gpt::crc32(header);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Moves the first partition of the MBR image `image` into the second slot of
+/// a freshly built GPT disk.
+fn gpt_from_mbr_image(image: &[u8]) -> Vec<u8> {
+    let entry = &image[446..462];
+    let start = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as usize;
+    let sectors = u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]) as usize;
+    let entry_sectors = GPT_ENTRIES * 128 / 512;
+    let disk_sectors = GPT_FIRST_LBA + sectors + entry_sectors + 1;
+    let last_lba = disk_sectors - 1;
+
+    let mut disk = vec![0u8; disk_sectors * 512];
+    disk[GPT_FIRST_LBA * 512..(GPT_FIRST_LBA + sectors) * 512]
+        .copy_from_slice(&image[start * 512..(start + sectors) * 512]);
+
+    // protective MBR covering the whole disk.
+    disk[446 + 4] = gpt::PROTECTIVE_PARTITION_TYPE;
+    disk[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+    disk[446 + 12..446 + 16].copy_from_slice(&((disk_sectors - 1) as u32).to_le_bytes());
+    disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    let mut entries = vec![0u8; GPT_ENTRIES * 128];
+    let second = &mut entries[128..256];
+    second[0..16].copy_from_slice(&gpt::Guid::BASIC_DATA.0);
+    second[16..32].copy_from_slice(&[0x17; 16]);
+    second[32..40].copy_from_slice(&(GPT_FIRST_LBA as u64).to_le_bytes());
+    second[40..48].copy_from_slice(&((GPT_FIRST_LBA + sectors - 1) as u64).to_le_bytes());
+    for (i, c) in "data".encode_utf16().enumerate() {
+        second[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+    }
+    let entries_crc = crate::gpt::crc32(&entries);
+
+    let backup_entries = last_lba - entry_sectors;
+    let last_usable = backup_entries - 1;
+    disk[2 * 512..(2 + entry_sectors) * 512].copy_from_slice(&entries);
+    disk[backup_entries * 512..last_lba * 512].copy_from_slice(&entries);
+    write_gpt_header(&mut disk, 1, last_lba, 2, last_usable, entries_crc);
+    write_gpt_header(&mut disk, last_lba, 1, backup_entries, last_usable, entries_crc);
+    disk
+}
+
+macro gpt_from_resource($name:expr) {{
+    let mut image = Vec::new();
+    resource!($name).read_to_end(&mut image).expect("read image");
+    gpt_from_mbr_image(&image)
+}}
+
+#[test]
+fn test_mbr_partitions() {
+    let partitions = partition::partitions(resource!("fat16.img")).expect("partition table");
+    assert_eq!(partitions.len(), 1);
+    assert_eq!(partitions[0].index, 0);
+    assert_eq!(partitions[0].start, 63);
+    assert_eq!(partitions[0].kind, partition::PartitionKind::Mbr(0x06));
+    assert!(partitions[0].kind.is_fat());
+}
+
+#[test]
+fn test_gpt_partitions() {
+    let mut disk = gpt_from_resource!("fat16.img");
+
+    let table = gpt::GuidPartitionTable::from(Cursor::new(&mut disk[..])).expect("valid GPT");
+    assert_eq!({ table.header.current_lba }, 1);
+    assert_eq!(table.partitions.len(), GPT_ENTRIES);
+    let used: Vec<_> = table.used().collect();
+    assert_eq!(used.len(), 1);
+    assert_eq!(used[0].0, 1);
+    assert_eq!(used[0].1.name().collect::<String>(), "data");
+
+    let partitions = partition::partitions(Cursor::new(&mut disk[..])).expect("partition table");
+    assert_eq!(partitions.len(), 1);
+    assert_eq!(partitions[0].index, 1);
+    assert_eq!(partitions[0].start, GPT_FIRST_LBA as u64);
+    assert_eq!(partitions[0].kind, partition::PartitionKind::Gpt(gpt::Guid::BASIC_DATA));
+    assert!(partitions[0].kind.is_fat());
+
+    let image = SharedImage(Arc::new(Mutex::new(Cursor::new(disk))));
+    check_small_image(&image, vfat::FatType::Fat16, "fat16");
+}
+
+#[test]
+fn test_gpt_backup_header() {
+    let mut disk = gpt_from_resource!("fat12.img");
+    // corrupt the primary partition entry array.
+    disk[2 * 512 + 200] ^= 0xFF;
+
+    let table = gpt::GuidPartitionTable::from(Cursor::new(&mut disk[..])).expect("backup GPT");
+    assert_eq!({ table.header.current_lba }, (disk.len() / 512 - 1) as u64);
+
+    let image = SharedImage(Arc::new(Mutex::new(Cursor::new(disk))));
+    check_small_image(&image, vfat::FatType::Fat12, "fat12");
+}
+
+#[test]
+fn test_gpt_bad_tables() {
+    let mut disk = gpt_from_resource!("fat12.img");
+    let last = disk.len() - 512;
+    disk[512 + 40] ^= 0xFF;
+    disk[last + 40] ^= 0xFF;
+
+    let e = gpt::GuidPartitionTable::from(Cursor::new(&mut disk[..])).unwrap_err();
+    expect_variant!(e, gpt::Error::BadChecksum);
+
+    let e = VFat::<StdVFatHandle>::from(Cursor::new(disk)).unwrap_err();
+    expect_variant!(e, vfat::Error::Gpt(gpt::Error::BadChecksum));
+
+    let e = gpt::GuidPartitionTable::from(resource!("fat12.img")).unwrap_err();
+    expect_variant!(e, gpt::Error::NoProtectiveMbr);
+
+    // a primary header that claims to be the backup is passed over.
+    let mut disk = gpt_from_resource!("fat12.img");
+    let last = disk.len() / 512 - 1;
+    disk[512 + 24..512 + 32].copy_from_slice(&(last as u64).to_le_bytes());
+    fix_gpt_header_crc(&mut disk, 1);
+    let table = gpt::GuidPartitionTable::from(Cursor::new(&mut disk[..])).expect("backup GPT");
+    assert_eq!({ table.header.current_lba }, last as u64);
+
+    // a header with an oversized entry array is rejected.
+    disk[512 + 24..512 + 32].copy_from_slice(&1u64.to_le_bytes());
+    disk[512 + 80..512 + 84].copy_from_slice(&u32::MAX.to_le_bytes());
+    fix_gpt_header_crc(&mut disk, 1);
+    disk[last * 512 + 40] ^= 0xFF;
+    let e = gpt::GuidPartitionTable::from(Cursor::new(&mut disk[..])).unwrap_err();
+    expect_variant!(e, gpt::Error::InvalidHeader);
+}
+
+/// A block device that records the sectors written to it.
+struct RecordingDevice {
+    data: Cursor<Vec<u8>>,
+    writes: Arc<Mutex<Vec<u64>>>,
+}
+
+impl BlockDevice for RecordingDevice {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.writes.lock().unwrap().push(n);
+        self.data.write_sector(n, buf)
+    }
+}
+
+#[test]
+fn test_cache_lru_eviction() {
+    use crate::vfat::{CachedPartition, Partition};
+
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    let device = RecordingDevice {
+        data: Cursor::new((0..8 * 512).map(|i| (i / 512) as u8).collect()),
+        writes: writes.clone(),
+    };
+    let mut cache = CachedPartition::with_capacity(device, Partition {
+        start: 0,
+        num_sectors: 8,
+        sector_size: 512,
+    }, 2);
+
+    assert_eq!(cache.get(0).unwrap()[0], 0);
+    assert_eq!(cache.get(1).unwrap()[0], 1);
+    assert_eq!(cache.get(0).unwrap()[0], 0);
+    // sector 1 is the least recently used one.
+    assert_eq!(cache.get(2).unwrap()[0], 2);
+    cache.get(0).unwrap();
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 3, 1));
+    assert_eq!((stats.cached, stats.capacity), (2, 2));
+
+    cache.get_mut(3).unwrap()[0] = 0xAA;
+    assert_eq!(cache.stats().dirty, 1);
+    assert!(writes.lock().unwrap().is_empty());
+
+    // evicting the dirty sector writes it back.
+    cache.get(4).unwrap();
+    cache.get(5).unwrap();
+    assert_eq!(*writes.lock().unwrap(), vec![3]);
+    assert_eq!(cache.get(3).unwrap()[0], 0xAA);
+
+    cache.get_mut(6).unwrap()[1] = 0xBB;
+    cache.sync().unwrap();
+    assert_eq!(*writes.lock().unwrap(), vec![3, 6]);
+    let stats = cache.stats();
+    assert_eq!((stats.dirty, stats.writebacks), (0, 2));
+
+    cache.set_capacity(1).unwrap();
+    assert_eq!(cache.stats().cached, 1);
+    assert!(cache.set_capacity(0).is_err());
+}
+
+#[test]
+fn test_small_cache_writes() {
+    let image = shared_from_resource!("fat16.img");
+    let vfat = image.mount();
+    vfat.lock(|fs| fs.set_cache_capacity(3)).expect("set capacity");
+
+    let data = pattern(40000, 5);
+    let root = vfat.open_dir("/").expect("root");
+    root.create_file("BIG.BIN").expect("create file").write_all(&data).expect("write");
+    assert_eq!(read_all(&vfat, "/BIG.BIN"), data);
+    assert_eq!(read_all(&vfat, "/DATA.BIN"), pattern(5000, 1));
+
+    let stats = vfat.lock(|fs| fs.cache_stats());
+    assert!(stats.cached <= 3);
+    assert!(stats.evictions > 0 && stats.writebacks > 0);
+
+    vfat.lock(|fs| fs.flush()).expect("flush");
+    assert_eq!(read_all(&image.mount(), "/BIG.BIN"), data);
+}
+
+/// Returns the location of the regular entry with the 8.3 name `short` in the
+/// directory `dir`.
+fn entry_location(vfat: &StdVFatHandle, dir: &str, short: &[u8; 11]) -> vfat::vfat::EntryLocation {
+    let cluster = vfat.open_dir(dir).expect("directory exists").cluster;
+    let mut data = Vec::new();
+    vfat.lock(|fs| fs.read_chain(cluster, &mut data)).expect("read directory");
+
+    let slot = data.chunks(32)
+        .position(|raw| &raw[..11] == &short[..] && raw[11] != 0x0F)
+        .expect("entry exists");
+    vfat::vfat::EntryLocation { dir_cluster: cluster, offset: slot * 32 }
+}
+
+fn check(vfat: &StdVFatHandle, repair: bool) -> vfat::CheckReport {
+    vfat.lock(|fs| fs.check(repair)).expect("check volume")
+}
+
+#[test]
+fn test_check_clean() {
+    for name in ["fat12", "fat16"].iter() {
+        let image = match *name {
+            "fat12" => shared_from_resource!("fat12.img"),
+            _ => shared_from_resource!("fat16.img"),
+        };
+        let report = check(&image.mount(), false);
+        assert!(report.is_clean(), "{}: {:?}", name, report.problems);
+        assert_eq!((report.files, report.directories), (3, 2));
+
+        check_small_image_writes(&image);
+        let report = check(&image.mount(), false);
+        assert!(report.is_clean(), "{}: {:?}", name, report.problems);
+        assert_eq!(report.repaired, 0);
+    }
+}
+
+#[test]
+fn test_check_lost_clusters_and_sizes() {
+    use vfat::Problem;
+
+    let image = shared_from_resource!("fat16.img");
+    let vfat = image.mount();
+    let lost = vfat.lock(|fs| -> io::Result<u32> {
+        let first = fs.alloc_cluster(None)?;
+        fs.alloc_cluster(Some(first))?;
+        Ok(first.raw())
+    }).expect("allocate clusters");
+
+    let location = entry_location(&vfat, "/", b"DATA    BIN");
+    vfat.lock(|fs| fs.update_entry(location, |entry| entry.set_file_size(100_000))).expect("update entry");
+    let location = entry_location(&vfat, "/", b"HELLO   TXT");
+    vfat.lock(|fs| fs.update_entry(location, |entry| entry.set_file_size(0))).expect("update entry");
+
+    let report = check(&vfat, false);
+    assert_eq!(report.repaired, 0);
+    assert_eq!(report.problems.len(), 3);
+    assert!(report.problems.contains(&Problem::LostClusters { start: lost, length: 2 }));
+    assert!(report.problems.iter().any(|p| match p {
+        Problem::SizeMismatch { path, size: 100_000, .. } => path == "/DATA.BIN",
+        _ => false,
+    }));
+    assert!(report.problems.iter().any(|p| match p {
+        Problem::SizeMismatch { path, size: 0, clusters: 1 } => path == "/HELLO.TXT",
+        _ => false,
+    }));
+
+    let report = check(&vfat, true);
+    assert_eq!(report.repaired, 3);
+
+    let vfat = image.mount();
+    assert!(check(&vfat, false).is_clean());
+    let data = read_all(&vfat, "/DATA.BIN");
+    assert!(data.len() >= 5000);
+    assert_eq!(&data[..5000], &pattern(5000, 1)[..]);
+    assert_eq!(read_all(&vfat, "/HELLO.TXT"), b"");
+}
+
+#[test]
+fn test_check_chains() {
+    use vfat::Problem;
+
+    let image = shared_from_resource!("fat12.img");
+    let vfat = image.mount();
+    let root = vfat.open_dir("/").expect("root");
+    root.create_file("BIG").expect("create").write_all(&pattern(20000, 2)).expect("write");
+    root.create_file("OTHER").expect("create").write_all(&pattern(700, 3)).expect("write");
+    vfat.lock(|fs| fs.flush()).expect("flush");
+
+    // BIG loses everything after its first cluster, OTHER shares DATA.BIN's clusters.
+    let big = vfat.open_file("/BIG").expect("file").cluster;
+    let data = vfat.open_file("/DATA.BIN").expect("file").cluster;
+    vfat.lock(|fs| fs.set_fat_entry(big, 0xFF7)).expect("set fat entry");
+    let location = entry_location(&vfat, "/", b"OTHER      ");
+    vfat.lock(|fs| fs.update_entry(location, |entry| entry.set_cluster(data))).expect("update entry");
+
+    let report = check(&vfat, false);
+    assert!(report.problems.iter().any(|p| match p {
+        Problem::BadChain { path, cluster } => path == "/BIG" && *cluster == big.raw(),
+        _ => false,
+    }), "{:?}", report.problems);
+    assert!(report.problems.iter().any(|p| match p {
+        Problem::CrossLinked { path, cluster } => (path == "/OTHER" || path == "/DATA.BIN") && *cluster == data.raw(),
+        _ => false,
+    }), "{:?}", report.problems);
+    assert!(report.problems.iter().any(|p| match p {
+        Problem::LostClusters { .. } => true,
+        _ => false,
+    }), "{:?}", report.problems);
+
+    check(&vfat, true);
+    let vfat = image.mount();
+    let report = check(&vfat, false);
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn test_check_directory_entries() {
+    use vfat::Problem;
+
+    let image = shared_from_resource!("fat16.img");
+    let vfat = image.mount();
+    let subdir = vfat.open_dir("/SUBDIR").expect("directory").cluster;
+
+    // point `..` somewhere else and break the checksum of the long name.
+    vfat.lock(|fs| fs.update_entry(vfat::vfat::EntryLocation { dir_cluster: subdir, offset: 32 },
+                                   |entry| entry.set_cluster(subdir))).expect("update entry");
+    let mut data = Vec::new();
+    vfat.lock(|fs| fs.read_chain(subdir, &mut data)).expect("read directory");
+    let lfn = data.chunks(32).position(|raw| raw[11] == 0x0F).expect("lfn entry");
+    vfat.lock(|fs| fs.write_cluster(subdir, lfn * 32 + 13, &[data[lfn * 32 + 13] ^ 0xFF])).expect("write");
+
+    let report = check(&vfat, false);
+    assert_eq!(report.problems.len(), 2, "{:?}", report.problems);
+    assert!(report.problems.contains(&Problem::BadDotEntry { path: String::from("/SUBDIR"), name: ".." }));
+    assert!(report.problems.iter().any(|p| match p {
+        Problem::BrokenLfn { dir, slot, .. } => dir == "/SUBDIR" && *slot == lfn,
+        _ => false,
+    }));
+
+    let report = check(&vfat, true);
+    assert_eq!(report.repaired, 2);
+
+    let vfat = image.mount();
+    assert!(check(&vfat, false).is_clean());
+    // the file survives under its short name.
+    let names: Vec<String> = names_in(&vfat, "/SUBDIR").into_iter().filter(|n| n != "." && n != "..").collect();
+    assert_eq!(names.len(), 1);
+    assert_eq!(read_all(&vfat, &format!("/SUBDIR/{}", names[0])), b"nested\n");
+}
+
+#[test]
+fn test_format() {
+    let image = SharedImage(Arc::new(Mutex::new(Cursor::new(vec![0u8; 40 * 1024 * 1024]))));
+    let options = vfat::FormatOptions::default().with_label("ramdisk");
+    let partition = vfat::format(image.clone(), 80 * 1024, &options).expect("format");
+    assert_eq!(partition.start, 2048);
+
+    let found = partition::find_fat(image.clone()).expect("partition table").expect("fat partition");
+    assert_eq!((found.start, found.num_sectors), (partition.start, partition.num_sectors));
+
+    let vfat = image.mount();
+    assert_eq!(vfat.lock(|fs| fs.fat_type()), vfat::FatType::Fat32);
+    assert_eq!(names_in(&vfat, "/"), Vec::<String>::new());
+    let report = check(&vfat, false);
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    let root = vfat.open_dir("/").expect("root");
+    root.create_file("hello.txt").expect("create file")
+        .write_all(&pattern(10000, 5)).expect("write");
+    root.create_dir("dir").expect("create dir").create_file("INNER").expect("create file");
+    vfat.lock(|fs| fs.flush()).expect("flush");
+
+    let vfat = image.mount();
+    assert_eq!(read_all(&vfat, "/hello.txt"), pattern(10000, 5));
+    assert_eq!(names_in(&vfat, "/dir"), vec![".", "..", "INNER"]);
+    let report = check(&vfat, false);
+    assert!(report.is_clean(), "{:?}", report.problems);
+    assert_eq!((report.files, report.directories), (2, 2));
+
+    let small = Cursor::new(vec![0u8; 1024 * 1024]);
+    expect_variant!(vfat::format(small, 2048, &options), Err(vfat::Error::Io(_)));
+
+    for &size in &[256, 1000, 8192] {
+        let device = SectorSize(Cursor::new(vec![0u8; 40 * 1024 * 1024]), size);
+        expect_variant!(vfat::format(device, 40 * 1024 * 1024 / size, &options), Err(vfat::Error::Io(_)));
+    }
+}
+
+/// A device with sectors of the given size.
+struct SectorSize<T: BlockDevice>(T, u64);
+
+impl<T: BlockDevice> BlockDevice for SectorSize<T> {
+    fn sector_size(&self) -> u64 {
+        self.1
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_sector(n, buf)
+    }
+}
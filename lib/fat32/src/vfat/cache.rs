@@ -1,16 +1,43 @@
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use core::fmt;
 use hashbrown::HashMap;
 use shim::io;
+use shim::ioerr;
 
 use crate::traits::BlockDevice;
 use crate::util::SliceExt;
 
+/// Number of sectors a `CachedPartition` keeps in memory unless configured
+/// otherwise.
+pub const DEFAULT_CACHE_CAPACITY: usize = 256;
+
 #[derive(Debug)]
 struct CacheEntry {
     data: Vec<u8>,
     dirty: bool,
+    /// Value of the partition's use counter when the entry was last accessed.
+    last_use: u64,
+}
+
+/// Counters describing the behaviour of a `CachedPartition`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CacheStats {
+    /// Accesses served from memory.
+    pub hits: u64,
+    /// Accesses that had to read the sector from the device.
+    pub misses: u64,
+    /// Sectors dropped from memory to make room for another sector.
+    pub evictions: u64,
+    /// Dirty sectors written back to the device, on eviction or sync.
+    pub writebacks: u64,
+    /// Sectors currently held in memory.
+    pub cached: usize,
+    /// Sectors currently held in memory that differ from the device.
+    pub dirty: usize,
+    /// Maximum number of sectors held in memory.
+    pub capacity: usize,
 }
 
 pub struct Partition {
@@ -25,8 +52,14 @@ pub struct Partition {
 pub struct CachedPartition {
     device: Box<dyn BlockDevice>,
     cache: HashMap<u64, CacheEntry>,
+    /// Cached sectors by the `last_use` of their entry, least recently used
+    /// first.
+    lru: BTreeMap<u64, u64>,
     partition: Partition,
     cache_line_buffer: Vec<u32>,
+    capacity: usize,
+    use_counter: u64,
+    stats: CacheStats,
 }
 
 impl CachedPartition {
@@ -43,20 +76,79 @@ impl CachedPartition {
     /// `partition.sector_size` must be an integer multiple of
     /// `device.sector_size()`.
     ///
+    /// At most `DEFAULT_CACHE_CAPACITY` sectors are kept in memory; see
+    /// `with_capacity()`.
+    ///
     /// # Panics
     ///
     /// Panics if the partition's sector size is < the device's sector size.
     pub fn new<T>(device: T, partition: Partition) -> CachedPartition
         where
             T: BlockDevice + 'static,
+    {
+        CachedPartition::with_capacity(device, partition, DEFAULT_CACHE_CAPACITY)
+    }
+
+    /// Like `new()`, but keeps at most `capacity` sectors in memory. When the
+    /// cache is full the least recently used sector is evicted, and written
+    /// back to the device first if it is dirty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the partition's sector size is < the device's sector size or
+    /// if `capacity` is 0.
+    pub fn with_capacity<T>(device: T, partition: Partition, capacity: usize) -> CachedPartition
+        where
+            T: BlockDevice + 'static,
     {
         assert!(partition.sector_size >= device.sector_size());
+        assert!(capacity > 0, "cache capacity must be at least one sector");
 
         CachedPartition {
             device: Box::new(device),
             cache: HashMap::new(),
+            lru: BTreeMap::new(),
             partition,
             cache_line_buffer: Vec::new(),
+            capacity,
+            use_counter: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Returns the maximum number of sectors kept in memory.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes the maximum number of sectors kept in memory, evicting least
+    /// recently used sectors until the cache fits.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidInput` if `capacity` is 0 and an error if writing back
+    /// an evicted dirty sector fails. In that case the capacity is updated but
+    /// the cache may still hold more than `capacity` sectors.
+    pub fn set_capacity(&mut self, capacity: usize) -> io::Result<()> {
+        if capacity == 0 {
+            return ioerr!(InvalidInput, "cache capacity must be at least one sector");
+        }
+
+        self.capacity = capacity;
+        while self.cache.len() > self.capacity {
+            self.evict()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the cache counters.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            cached: self.cache.len(),
+            dirty: self.cache.values().filter(|entry| entry.dirty).count(),
+            capacity: self.capacity,
+            ..self.stats
         }
     }
 
@@ -105,18 +197,79 @@ impl CachedPartition {
         Ok(())
     }
 
+    fn store_sector(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        let physical_sector = self.virtual_to_physical(sector).ok_or(io::ErrorKind::InvalidInput)?;
+        let device_sector_size = self.device.sector_size() as usize;
+
+        for (i, chunk) in data.chunks(device_sector_size).enumerate() {
+            let raw = Self::line_buffer(&mut self.cache_line_buffer, device_sector_size as u64);
+            raw[..chunk.len()].copy_from_slice(chunk);
+            self.device.write_sector(physical_sector + i as u64, raw)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the dirty entry of `sector` back to the device and marks it
+    /// clean. The entry stays dirty if the write fails.
+    fn write_back(&mut self, sector: u64) -> io::Result<()> {
+        // temporarily take the data so the entry can be written while `self` is borrowed.
+        let data = core::mem::replace(&mut self.cache.get_mut(&sector).unwrap().data, Vec::new());
+        let result = self.store_sector(sector, data.as_slice());
+
+        let entry = self.cache.get_mut(&sector).unwrap();
+        entry.data = data;
+        result?;
+        entry.dirty = false;
+        self.stats.writebacks += 1;
+
+        Ok(())
+    }
+
+    /// Drops the least recently used sector from the cache, writing it back
+    /// first if it is dirty.
+    fn evict(&mut self) -> io::Result<()> {
+        let victim = self.lru.iter().next().map(|(last_use, sector)| (*last_use, *sector));
+
+        if let Some((last_use, sector)) = victim {
+            if self.cache[&sector].dirty {
+                self.write_back(sector)?;
+            }
+            self.cache.remove(&sector);
+            self.lru.remove(&last_use);
+            self.stats.evictions += 1;
+        }
+
+        Ok(())
+    }
+
     fn get_entry(&mut self, sector: u64) -> io::Result<&mut CacheEntry> {
-        if let None = self.cache.get_mut(&sector) {
+        self.use_counter += 1;
+
+        if self.cache.contains_key(&sector) {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+
+            while self.cache.len() >= self.capacity {
+                self.evict()?;
+            }
+
             let mut buf: Vec<u8> = Vec::new();
             self.load_sector(&mut buf, sector)?;
 
             self.cache.insert(sector, CacheEntry {
                 dirty: false,
                 data: buf,
+                last_use: 0,
             });
         }
 
-        Ok(self.cache.get_mut(&sector).unwrap())
+        let entry = self.cache.get_mut(&sector).unwrap();
+        self.lru.remove(&entry.last_use);
+        self.lru.insert(self.use_counter, sector);
+        entry.last_use = self.use_counter;
+        Ok(entry)
     }
 
     /// Returns a mutable reference to the cached sector `sector`. If the sector
@@ -145,6 +298,27 @@ impl CachedPartition {
     pub fn get(&mut self, sector: u64) -> io::Result<&[u8]> {
         self.get_entry(sector).map(|entry| entry.data.as_slice())
     }
+
+    /// Writes every dirty sector back to the underlying device and marks it
+    /// clean.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing a sector to the disk fails. Sectors that
+    /// were not written remain dirty.
+    pub fn sync(&mut self) -> io::Result<()> {
+        let mut dirty: Vec<u64> = self.cache.iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(sector, _)| *sector)
+            .collect();
+        dirty.sort();
+
+        for sector in dirty {
+            self.write_back(sector)?;
+        }
+
+        Ok(())
+    }
 }
 
 // FIXME: Implement `BlockDevice` for `CacheDevice`. The `read_sector` and
@@ -181,7 +355,8 @@ impl fmt::Debug for CachedPartition {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("CachedPartition")
             .field("device", &"<block device>")
-            .field("cache", &self.cache)
+            .field("capacity", &self.capacity)
+            .field("stats", &self.stats)
             .finish()
     }
 }
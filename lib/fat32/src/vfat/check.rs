@@ -0,0 +1,575 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use shim::io;
+use shim::ioerr;
+
+use crate::vfat::{Cluster, Status, VFat, VFatHandle};
+use crate::vfat::dir::{lfn_checksum, parse_lfns, VFatLfnDirEntry, VFatRegularDirEntry, DELETED_ENTRY};
+use crate::vfat::vfat::EOC_MARKER;
+
+const DIR_ENTRY_SIZE: usize = 32;
+const LFN_ATTRIBUTES: u8 = 0x0F;
+const DOT: [u8; 11] = *b".          ";
+const DOT_DOT: [u8; 11] = *b"..         ";
+
+/// An inconsistency found by `VFat::check()`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// A chain of `length` clusters starting at `start` is allocated in the
+    /// FAT but not referenced by any directory entry.
+    LostClusters { start: u32, length: u32 },
+    /// The chain of `path` runs into `cluster`, which belongs to another entry.
+    CrossLinked { path: String, cluster: u32 },
+    /// The chain of `path` runs into `cluster`, which is free, bad, out of
+    /// range or already part of the same chain.
+    BadChain { path: String, cluster: u32 },
+    /// The size of the file `path` does not fit its chain of `clusters`
+    /// clusters.
+    SizeMismatch { path: String, size: u32, clusters: u32 },
+    /// `count` LFN entries starting at slot `slot` of the directory `dir` do
+    /// not form a valid name for the entry that follows them.
+    BrokenLfn { dir: String, slot: usize, count: usize },
+    /// The `name` (`.` or `..`) entry of the directory `path` is missing or
+    /// refers to the wrong cluster.
+    BadDotEntry { path: String, name: &'static str },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::LostClusters { start, length } =>
+                write!(f, "{} lost cluster(s) in chain starting at {}", length, start),
+            Problem::CrossLinked { path, cluster } =>
+                write!(f, "{}: cross-linked at cluster {}", path, cluster),
+            Problem::BadChain { path, cluster } =>
+                write!(f, "{}: chain contains invalid cluster {}", path, cluster),
+            Problem::SizeMismatch { path, size, clusters } =>
+                write!(f, "{}: size {} does not match chain of {} cluster(s)", path, size, clusters),
+            Problem::BrokenLfn { dir, slot, count } =>
+                write!(f, "{}: {} orphaned long file name entries at slot {}", dir, count, slot),
+            Problem::BadDotEntry { path, name } =>
+                write!(f, "{}: bad '{}' entry", path, name),
+        }
+    }
+}
+
+/// The result of `VFat::check()`.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub problems: Vec<Problem>,
+    /// Number of problems that were repaired. Always 0 unless repairing.
+    pub repaired: usize,
+    pub files: usize,
+    pub directories: usize,
+    /// Number of clusters referenced by the directory tree.
+    pub used_clusters: u32,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+struct Bitmap(Vec<u64>);
+
+impl Bitmap {
+    fn new(bits: u32) -> Bitmap {
+        Bitmap(vec![0; (bits as usize + 63) / 64])
+    }
+
+    fn get(&self, bit: u32) -> bool {
+        self.0[bit as usize / 64] & (1 << (bit % 64)) != 0
+    }
+
+    fn set(&mut self, bit: u32, value: bool) {
+        if value {
+            self.0[bit as usize / 64] |= 1 << (bit % 64);
+        } else {
+            self.0[bit as usize / 64] &= !(1 << (bit % 64));
+        }
+    }
+
+    fn count(&self) -> u32 {
+        self.0.iter().map(|word| word.count_ones()).sum()
+    }
+}
+
+/// Why a cluster chain ended early.
+enum Fault {
+    /// The chain refers to a cluster that is not a valid part of a chain.
+    Invalid(u32),
+    /// The chain runs into a cluster that already belongs to another chain.
+    CrossLink(u32),
+}
+
+/// The clusters of a chain up to the first fault, if any.
+struct Chain {
+    clusters: Vec<u32>,
+    fault: Option<Fault>,
+}
+
+/// A directory waiting to be checked.
+struct PendingDir {
+    path: String,
+    /// The clusters holding the directory. `[0]` for the FAT12/16 root
+    /// directory region.
+    clusters: Vec<u32>,
+    /// The clusters its `.` and `..` entries must refer to, `None` for the
+    /// root directory.
+    links: Option<(u32, u32)>,
+}
+
+struct Checker<'a, HANDLE: VFatHandle> {
+    fs: &'a mut VFat<HANDLE>,
+    repair: bool,
+    /// Clusters referenced by the directory tree.
+    used: Bitmap,
+    /// Clusters of the chain currently being walked.
+    in_chain: Bitmap,
+    report: CheckReport,
+}
+
+fn join(dir: &str, name: &str) -> String {
+    let mut path = String::from(dir);
+    if !path.ends_with('/') {
+        path.push('/');
+    }
+    path.push_str(name);
+    path
+}
+
+impl<'a, HANDLE: VFatHandle> Checker<'a, HANDLE> {
+    fn end(&self) -> u32 {
+        self.fs.cluster_count() + 2
+    }
+
+    /// Records a problem that is repaired when repairing.
+    fn problem(&mut self, problem: Problem) {
+        self.record(problem, self.repair);
+    }
+
+    /// Records a problem, counting it as repaired if `repaired` is set.
+    fn record(&mut self, problem: Problem, repaired: bool) {
+        self.report.problems.push(problem);
+        if repaired {
+            self.report.repaired += 1;
+        }
+    }
+
+    /// Follows the chain starting at `start`, marking its clusters as used,
+    /// until its end or the first fault.
+    fn walk(&mut self, start: u32) -> io::Result<Chain> {
+        let end = self.end();
+        let mut clusters = Vec::new();
+        let mut current = start;
+
+        let fault = loop {
+            if current < 2 || current >= end || self.in_chain.get(current) {
+                break Some(Fault::Invalid(current));
+            }
+            if self.used.get(current) {
+                break Some(Fault::CrossLink(current));
+            }
+
+            let next = match self.fs.fat_entry(Cluster::from(current))?.status() {
+                Status::Data(next) => Some(next.raw()),
+                Status::Eoc(_) => None,
+                _ => break Some(Fault::Invalid(current)),
+            };
+
+            self.used.set(current, true);
+            self.in_chain.set(current, true);
+            clusters.push(current);
+
+            match next {
+                Some(next) => current = next,
+                None => break None,
+            }
+        };
+
+        for cluster in clusters.iter() {
+            self.in_chain.set(*cluster, false);
+        }
+
+        Ok(Chain { clusters, fault })
+    }
+
+    /// Records the fault of the chain of `path`. When repairing, the chain is
+    /// cut off before the fault.
+    fn chain_fault(&mut self, path: &str, chain: &Chain) -> io::Result<()> {
+        let problem = match chain.fault {
+            Some(Fault::Invalid(cluster)) => Problem::BadChain { path: String::from(path), cluster },
+            Some(Fault::CrossLink(cluster)) => Problem::CrossLinked { path: String::from(path), cluster },
+            None => return Ok(()),
+        };
+        self.problem(problem);
+
+        if self.repair {
+            if let Some(last) = chain.clusters.last() {
+                self.fs.set_fat_entry(Cluster::from(*last), EOC_MARKER)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns every cluster in `clusters` to the FAT.
+    fn free(&mut self, clusters: &[u32]) -> io::Result<()> {
+        for cluster in clusters {
+            self.fs.set_fat_entry(Cluster::from(*cluster), 0)?;
+            self.used.set(*cluster, false);
+        }
+        Ok(())
+    }
+
+    /// The cluster and byte offset of slot `slot` of the directory stored in
+    /// `clusters`.
+    fn slot_position(&self, clusters: &[u32], slot: usize) -> (Cluster, usize) {
+        let per_cluster = self.fs.cluster_bytes(Cluster::from(clusters[0])) / DIR_ENTRY_SIZE;
+        (Cluster::from(clusters[slot / per_cluster]), (slot % per_cluster) * DIR_ENTRY_SIZE)
+    }
+
+    fn release_slots(&mut self, clusters: &[u32], first: usize, count: usize) -> io::Result<()> {
+        for slot in first..first + count {
+            let (cluster, offset) = self.slot_position(clusters, slot);
+            self.fs.write_cluster(cluster, offset, &[DELETED_ENTRY])?;
+        }
+        Ok(())
+    }
+
+    fn update_slot(&mut self, clusters: &[u32], slot: usize, f: impl FnOnce(&mut VFatRegularDirEntry)) -> io::Result<()> {
+        let (cluster, offset) = self.slot_position(clusters, slot);
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        self.fs.read_cluster(cluster, offset, &mut raw)?;
+
+        let mut entry = VFatRegularDirEntry::from_bytes(raw);
+        f(&mut entry);
+        self.fs.write_cluster(cluster, offset, &entry.to_bytes())?;
+        Ok(())
+    }
+
+    fn broken_lfn(&mut self, dir: &PendingDir, slot: usize, count: usize) -> io::Result<()> {
+        self.problem(Problem::BrokenLfn { dir: dir.path.clone(), slot, count });
+        if self.repair {
+            self.release_slots(&dir.clusters, slot, count)?;
+        }
+        Ok(())
+    }
+
+    fn read_dir(&mut self, clusters: &[u32]) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for cluster in clusters {
+            let cluster = Cluster::from(*cluster);
+            let start = data.len();
+            data.resize(start + self.fs.cluster_bytes(cluster), 0);
+            let read = self.fs.read_cluster(cluster, 0, &mut data[start..])?;
+            data.truncate(start + read);
+        }
+        Ok(data)
+    }
+
+    /// Checks the `.` and `..` entries in the first two slots of `dir`.
+    fn check_dot_entries(&mut self, dir: &PendingDir, data: &[u8], links: (u32, u32)) -> io::Result<()> {
+        let root = self.fs.root_cluster().raw();
+
+        for (slot, (short, name, expected)) in [(DOT, ".", links.0), (DOT_DOT, "..", links.1)].iter().enumerate() {
+            let entry = match data.get(slot * DIR_ENTRY_SIZE..(slot + 1) * DIR_ENTRY_SIZE) {
+                Some(raw) => {
+                    let mut bytes = [0u8; DIR_ENTRY_SIZE];
+                    bytes.copy_from_slice(raw);
+                    VFatRegularDirEntry::from_bytes(bytes)
+                }
+                None => {
+                    self.record(Problem::BadDotEntry { path: dir.path.clone(), name: *name }, false);
+                    continue;
+                }
+            };
+
+            let named = entry.short_name() == *short && entry.attributes().directory();
+            let cluster = entry.cluster().raw();
+            // some implementations store the root cluster instead of 0 in `..`.
+            let linked = cluster == *expected || (*expected == 0 && cluster == root);
+            if named && linked {
+                continue;
+            }
+
+            // only a wrong cluster can be fixed in place.
+            let repair = self.repair && named;
+            self.record(Problem::BadDotEntry { path: dir.path.clone(), name: *name }, repair);
+            if repair {
+                let expected = *expected;
+                self.update_slot(&dir.clusters, slot, |entry| entry.set_cluster(Cluster::from(expected)))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_file(&mut self, dir: &PendingDir, slot: usize, path: &str, entry: &VFatRegularDirEntry) -> io::Result<()> {
+        self.report.files += 1;
+
+        let chain = match entry.cluster().raw() {
+            0 => Chain { clusters: Vec::new(), fault: None },
+            start => self.walk(start)?,
+        };
+        self.chain_fault(path, &chain)?;
+        if self.repair && chain.fault.is_some() && chain.clusters.is_empty() {
+            self.update_slot(&dir.clusters, slot, |entry| entry.set_cluster(Cluster::from(0)))?;
+        }
+
+        let cluster_size = self.fs.cluster_size_bytes() as u64;
+        let size = entry.file_size();
+        let needed = ((size as u64 + cluster_size - 1) / cluster_size) as usize;
+        let length = chain.clusters.len();
+        if length == needed {
+            return Ok(());
+        }
+
+        self.problem(Problem::SizeMismatch { path: String::from(path), size, clusters: length as u32 });
+        if !self.repair {
+            return Ok(());
+        }
+
+        if length < needed {
+            let size = (length as u64 * cluster_size) as u32;
+            self.update_slot(&dir.clusters, slot, |entry| entry.set_file_size(size))?;
+        } else if needed == 0 {
+            self.free(&chain.clusters)?;
+            self.update_slot(&dir.clusters, slot, |entry| entry.set_cluster(Cluster::from(0)))?;
+        } else {
+            self.fs.set_fat_entry(Cluster::from(chain.clusters[needed - 1]), EOC_MARKER)?;
+            self.free(&chain.clusters[needed..])?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks a subdirectory entry and returns the directory if its contents
+    /// can be checked.
+    fn check_subdir(&mut self, dir: &PendingDir, slots: (usize, usize), path: String, entry: &VFatRegularDirEntry) -> io::Result<Option<PendingDir>> {
+        let start = entry.cluster().raw();
+        let chain = match start {
+            0 => Chain { clusters: Vec::new(), fault: Some(Fault::Invalid(0)) },
+            start => self.walk(start)?,
+        };
+        self.chain_fault(&path, &chain)?;
+
+        if chain.clusters.is_empty() {
+            // there is nothing left of the directory: drop its entry.
+            if self.repair {
+                self.release_slots(&dir.clusters, slots.0, slots.1 - slots.0 + 1)?;
+            }
+            return Ok(None);
+        }
+
+        let parent = match dir.links {
+            Some((this, _)) => this,
+            None => 0,
+        };
+        Ok(Some(PendingDir { path, clusters: chain.clusters, links: Some((start, parent)) }))
+    }
+
+    /// Checks the entries of `dir` and returns its subdirectories.
+    fn check_dir(&mut self, dir: PendingDir) -> io::Result<Vec<PendingDir>> {
+        self.report.directories += 1;
+
+        let data = self.read_dir(&dir.clusters)?;
+        if let Some(links) = dir.links {
+            self.check_dot_entries(&dir, &data, links)?;
+        }
+
+        let mut subdirs = Vec::new();
+        let mut lfns: Vec<VFatLfnDirEntry> = Vec::new();
+        let mut lfn_start = 0;
+
+        for (slot, raw) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+            let mut bytes = [0u8; DIR_ENTRY_SIZE];
+            bytes.copy_from_slice(raw);
+
+            if bytes[0] == 0 {
+                break;
+            }
+
+            if bytes[0] == DELETED_ENTRY {
+                if !lfns.is_empty() {
+                    self.broken_lfn(&dir, lfn_start, lfns.len())?;
+                    lfns.clear();
+                }
+                continue;
+            }
+
+            if bytes[11] == LFN_ATTRIBUTES {
+                let lfn = VFatLfnDirEntry::from_bytes(bytes);
+                // checksums are verified against the regular entry once the sequence is complete.
+                let continues = lfns.last().map_or(false, |prev| {
+                    !lfn.is_last() && prev.sequence_number() == lfn.sequence_number() + 1
+                });
+
+                if !continues {
+                    if !lfns.is_empty() {
+                        self.broken_lfn(&dir, lfn_start, lfns.len())?;
+                        lfns.clear();
+                    }
+                    if !lfn.is_last() {
+                        self.broken_lfn(&dir, slot, 1)?;
+                        continue;
+                    }
+                    lfn_start = slot;
+                }
+                lfns.push(lfn);
+                continue;
+            }
+
+            let entry = VFatRegularDirEntry::from_bytes(bytes);
+            let short = entry.short_name();
+            let first_slot = if lfns.is_empty() { slot } else { lfn_start };
+
+            let mut name = None;
+            if !lfns.is_empty() {
+                let checksum = lfn_checksum(&short);
+                let complete = lfns.last().map_or(false, |lfn| lfn.sequence_number() == 1);
+                if complete && lfns.iter().all(|lfn| lfn.checksum() == checksum) {
+                    name = Some(parse_lfns(&mut lfns));
+                } else {
+                    self.broken_lfn(&dir, lfn_start, lfns.len())?;
+                }
+                lfns.clear();
+            }
+
+            let attributes = entry.attributes();
+            if short == DOT || short == DOT_DOT || (attributes.volume_id() && !attributes.directory()) {
+                continue;
+            }
+
+            let name = name.unwrap_or_else(|| entry.basic_name());
+            let path = join(&dir.path, &name);
+            if attributes.directory() {
+                if let Some(subdir) = self.check_subdir(&dir, (first_slot, slot), path, &entry)? {
+                    subdirs.push(subdir);
+                }
+            } else {
+                self.check_file(&dir, slot, &path, &entry)?;
+            }
+        }
+
+        if !lfns.is_empty() {
+            self.broken_lfn(&dir, lfn_start, lfns.len())?;
+        }
+
+        Ok(subdirs)
+    }
+
+    /// Finds clusters that are allocated in the FAT but were not reached from
+    /// the directory tree.
+    fn check_lost_clusters(&mut self) -> io::Result<()> {
+        let end = self.end();
+
+        let mut lost = Bitmap::new(end);
+        let mut has_predecessor = Bitmap::new(end);
+        for cluster in 2..end {
+            if self.used.get(cluster) {
+                continue;
+            }
+            match self.fs.fat_entry(Cluster::from(cluster))?.status() {
+                Status::Data(_) | Status::Eoc(_) => lost.set(cluster, true),
+                _ => {}
+            }
+        }
+
+        for cluster in 2..end {
+            if !lost.get(cluster) {
+                continue;
+            }
+            if let Status::Data(next) = self.fs.fat_entry(Cluster::from(cluster))?.status() {
+                if next.raw() >= 2 && next.raw() < end {
+                    has_predecessor.set(next.raw(), true);
+                }
+            }
+        }
+
+        // chain heads first, then whatever is left over in cycles.
+        for heads_only in [true, false].iter() {
+            for start in 2..end {
+                if !lost.get(start) || (*heads_only && has_predecessor.get(start)) {
+                    continue;
+                }
+
+                let mut chain = Vec::new();
+                let mut current = start;
+                while current >= 2 && current < end && lost.get(current) {
+                    lost.set(current, false);
+                    chain.push(current);
+                    match self.fs.fat_entry(Cluster::from(current))?.status() {
+                        Status::Data(next) => current = next.raw(),
+                        _ => break,
+                    }
+                }
+
+                self.problem(Problem::LostClusters { start, length: chain.len() as u32 });
+                if self.repair {
+                    self.free(&chain)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn run(mut self) -> io::Result<CheckReport> {
+        let root = self.fs.root_cluster();
+        let root_dir = if self.fs.is_root_region(root) {
+            PendingDir { path: String::from("/"), clusters: vec![0], links: None }
+        } else {
+            let chain = self.walk(root.raw())?;
+            self.chain_fault("/", &chain)?;
+            if chain.clusters.is_empty() {
+                return ioerr!(InvalidData, "root directory chain is invalid");
+            }
+            PendingDir { path: String::from("/"), clusters: chain.clusters, links: None }
+        };
+
+        let mut pending = vec![root_dir];
+        while let Some(dir) = pending.pop() {
+            let subdirs = self.check_dir(dir)?;
+            pending.extend(subdirs);
+        }
+
+        self.check_lost_clusters()?;
+
+        self.report.used_clusters = self.used.count();
+        if self.report.repaired > 0 {
+            self.fs.flush()?;
+        }
+
+        Ok(self.report)
+    }
+}
+
+impl<HANDLE: VFatHandle> VFat<HANDLE> {
+    /// Walks the directory tree and the FAT looking for lost clusters,
+    /// cross-linked or broken chains, files whose size does not match their
+    /// chain, broken long file names and bad `.`/`..` entries.
+    ///
+    /// If `repair` is set, problems are fixed as they are found: lost
+    /// clusters are freed, broken chains are cut off before the fault, sizes
+    /// are adjusted to the chain or the chain to the size, orphaned LFN
+    /// entries are deleted and wrong `.`/`..` clusters are rewritten.
+    /// Directories with no valid clusters are removed from their parent.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidData` if the root directory cannot be read
+    /// and any I/O error encountered while reading or repairing the volume.
+    pub fn check(&mut self, repair: bool) -> io::Result<CheckReport> {
+        let end = self.cluster_count() + 2;
+        Checker {
+            fs: self,
+            repair,
+            used: Bitmap::new(end),
+            in_chain: Bitmap::new(end),
+            report: CheckReport::default(),
+        }.run()
+    }
+}
@@ -13,6 +13,7 @@ use crate::traits;
 use crate::util::VecExt;
 use crate::vfat::{Attributes, Date, Metadata, Time, Timestamp};
 use crate::vfat::{Cluster, Entry, File, VFatHandle};
+use crate::vfat::vfat::{EntryLocation, SeekHandle};
 use crate::vfat::mnt::DynVFatHandle;
 
 #[derive(Debug)]
@@ -130,12 +131,272 @@ impl VFatRegularDirEntry {
 
         s
     }
+
+    pub fn new(short_name: [u8; 11], attributes: Attributes, cluster: Cluster, file_size: u32, ts: Timestamp) -> VFatRegularDirEntry {
+        let mut entry = VFatRegularDirEntry {
+            name: [b' '; 8],
+            ext: [b' '; 3],
+            attributes,
+            __r0: 0,
+            creation_time_tenths: 0,
+            creation_time: ts.time,
+            creation_date: ts.date,
+            accessed_date: ts.date,
+            cluster_high: 0,
+            modified_time: ts.time,
+            modified_date: ts.date,
+            cluster_low: 0,
+            file_size,
+        };
+        entry.set_short_name(short_name);
+        entry.set_cluster(cluster);
+        entry
+    }
+
+    /// The raw 8.3 name: 8 name bytes followed by 3 extension bytes.
+    pub fn short_name(&self) -> [u8; 11] {
+        let mut short = [0u8; 11];
+        short[..8].copy_from_slice(&self.name);
+        short[8..].copy_from_slice(&self.ext);
+        short
+    }
+
+    pub fn set_short_name(&mut self, short: [u8; 11]) {
+        self.name.copy_from_slice(&short[..8]);
+        self.ext.copy_from_slice(&short[8..]);
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        unsafe { core::mem::transmute(*self) }
+    }
+
+    pub fn from_bytes(raw: [u8; 32]) -> VFatRegularDirEntry {
+        unsafe { core::mem::transmute(raw) }
+    }
+
+    pub fn attributes(&self) -> Attributes {
+        self.attributes
+    }
+
+    pub fn file_size(&self) -> u32 {
+        self.file_size
+    }
+
+    pub fn set_cluster(&mut self, cluster: Cluster) {
+        self.cluster_high = (cluster.raw() >> 16) as u16;
+        self.cluster_low = cluster.raw() as u16;
+    }
+
+    pub fn set_file_size(&mut self, size: u32) {
+        self.file_size = size;
+    }
+
+    pub fn set_modified(&mut self, ts: Timestamp) {
+        self.modified_date = ts.date;
+        self.modified_time = ts.time;
+        self.accessed_date = ts.date;
+    }
 }
 
 impl VFatLfnDirEntry {
     pub fn sequence_number(&self) -> u8 {
         self.sequence_number & 0b1_1111
     }
+
+    /// Whether this entry holds the last part of the name, i.e. is the first
+    /// LFN entry of its sequence on disk.
+    pub fn is_last(&self) -> bool {
+        self.sequence_number & LFN_LAST_ENTRY != 0
+    }
+
+    pub fn checksum(&self) -> u8 {
+        self.name_checksum
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        unsafe { core::mem::transmute(*self) }
+    }
+
+    pub fn from_bytes(raw: [u8; 32]) -> VFatLfnDirEntry {
+        unsafe { core::mem::transmute(raw) }
+    }
+}
+
+/// Characters that may not appear in a long file name.
+const INVALID_NAME_CHARS: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Characters besides ASCII letters and digits that may appear in a short name.
+const SHORT_NAME_SPECIAL_CHARS: &[u8] = b"$%'-_@~`!(){}^#&";
+
+/// Maximum length of a long file name in UTF-16 code units.
+const MAX_LFN_LENGTH: usize = 255;
+
+/// Number of UTF-16 code units stored in a single LFN entry.
+const LFN_CHARS_PER_ENTRY: usize = 13;
+
+const LFN_LAST_ENTRY: u8 = 0x40;
+pub(crate) const DELETED_ENTRY: u8 = 0xE5;
+
+fn validate_name(name: &str) -> io::Result<()> {
+    if name.is_empty() || name == "." || name == ".." {
+        return ioerr!(InvalidInput, "invalid file name");
+    }
+    if name.encode_utf16().count() > MAX_LFN_LENGTH {
+        return ioerr!(InvalidInput, "file name too long");
+    }
+    if name.chars().any(|c| (c as u32) < 0x20 || INVALID_NAME_CHARS.contains(&c)) {
+        return ioerr!(InvalidInput, "invalid character in file name");
+    }
+    Ok(())
+}
+
+fn short_name_char(c: char) -> Option<u8> {
+    if c.is_ascii_alphanumeric() || (c.is_ascii() && SHORT_NAME_SPECIAL_CHARS.contains(&(c as u8))) {
+        Some(c.to_ascii_uppercase() as u8)
+    } else {
+        None
+    }
+}
+
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    }
+}
+
+/// Returns the short name for `name` if `name` is already a valid upper case
+/// 8.3 name and can be stored without LFN entries.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = split_extension(name);
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || (name.ends_with('.')) {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    for (i, c) in base.chars().enumerate() {
+        match short_name_char(c) {
+            Some(b) if b == c as u8 => short[i] = b,
+            _ => return None,
+        }
+    }
+    for (i, c) in ext.chars().enumerate() {
+        match short_name_char(c) {
+            Some(b) if b == c as u8 => short[8 + i] = b,
+            _ => return None,
+        }
+    }
+
+    Some(short)
+}
+
+/// Generates a `BASE~N.EXT` alias for `name` that does not collide with any
+/// of the short names in `taken`.
+fn generate_short_name(name: &str, taken: &[[u8; 11]]) -> io::Result<[u8; 11]> {
+    let (base, ext) = split_extension(name.trim_start_matches('.'));
+
+    let convert = |c: char| short_name_char(c).unwrap_or(b'_');
+    let mut base: Vec<u8> = base.chars().filter(|c| *c != ' ' && *c != '.').map(convert).collect();
+    let ext: Vec<u8> = ext.chars().filter(|c| *c != ' ').map(convert).take(3).collect();
+    if base.is_empty() {
+        base.push(b'_');
+    }
+
+    for n in 1..1_000_000usize {
+        let tail = format!("~{}", n);
+        let keep = core::cmp::min(base.len(), 8 - tail.len());
+
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        short[8..8 + ext.len()].copy_from_slice(&ext);
+
+        if !taken.contains(&short) {
+            return Ok(short);
+        }
+    }
+
+    ioerr!(AlreadyExists, "no unique short name available")
+}
+
+/// The checksum of a short name stored in each of its LFN entries.
+pub(crate) fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*c))
+}
+
+/// Builds the LFN entries for `name` in on-disk order, i.e. starting with the
+/// entry holding the last part of the name.
+fn build_lfns(name: &str, checksum: u8) -> Vec<VFatLfnDirEntry> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    if chars.len() % LFN_CHARS_PER_ENTRY != 0 {
+        // names that do not fill the last entry are NUL terminated and padded with 0xFFFF.
+        chars.push(0);
+        while chars.len() % LFN_CHARS_PER_ENTRY != 0 {
+            chars.push(0xFFFF);
+        }
+    }
+
+    let count = chars.len() / LFN_CHARS_PER_ENTRY;
+    let mut lfns = Vec::new();
+    for (i, chunk) in chars.chunks(LFN_CHARS_PER_ENTRY).enumerate().rev() {
+        let mut bytes = [0u8; LFN_CHARS_PER_ENTRY * 2];
+        for (j, c) in chunk.iter().enumerate() {
+            bytes[j * 2..j * 2 + 2].copy_from_slice(&c.to_le_bytes());
+        }
+
+        let mut lfn = VFatLfnDirEntry {
+            sequence_number: (i + 1) as u8 | if i + 1 == count { LFN_LAST_ENTRY } else { 0 },
+            name_set_1: [0; 10],
+            attributes: 0xF,
+            lfn_type: 0,
+            name_checksum: checksum,
+            name_set_2: [0; 12],
+            __r0: [0; 2],
+            name_set_3: [0; 4],
+        };
+        lfn.name_set_1.copy_from_slice(&bytes[..10]);
+        lfn.name_set_2.copy_from_slice(&bytes[10..22]);
+        lfn.name_set_3.copy_from_slice(&bytes[22..]);
+        lfns.push(lfn);
+    }
+
+    lfns
+}
+
+/// Returns the index of the first run of `count` unused slots in `buf`. The
+/// returned run may extend past the end of `buf`.
+fn find_free_slots(buf: &[VFatDirEntry], count: usize) -> usize {
+    let mut run_start = 0;
+    let mut run_length = 0;
+
+    for (i, entry) in buf.iter().enumerate() {
+        if entry.was_prev_last() {
+            // every slot after the end marker is unused.
+            return if run_length > 0 { run_start } else { i };
+        }
+
+        if entry.is_deleted() {
+            if run_length == 0 {
+                run_start = i;
+            }
+            run_length += 1;
+            if run_length == count {
+                return run_start;
+            }
+        } else {
+            run_length = 0;
+        }
+    }
+
+    if run_length > 0 { run_start } else { buf.len() }
+}
+
+/// A located directory entry along with the slots it occupies.
+struct Slot<HANDLE: VFatHandle> {
+    entry: Entry<HANDLE>,
+    regular: VFatRegularDirEntry,
+    first: usize,
+    raw: Vec<u8>,
 }
 
 impl<HANDLE: VFatHandle> Dir<HANDLE> {
@@ -173,15 +434,309 @@ impl<HANDLE: VFatHandle> Dir<HANDLE> {
 
         ioerr!(NotFound, "file not found")
     }
+
+    fn raw_entries(&self) -> io::Result<Vec<VFatDirEntry>> {
+        let mut buf: Vec<u8> = Vec::new();
+
+        self.vfat.lock(|fs| fs.read_chain(self.cluster, &mut buf))?;
+
+        Ok(unsafe { buf.cast() })
+    }
+
+    /// Like `find()` but also returns the slots that make up the entry.
+    fn locate(&self, name: &str) -> io::Result<Slot<HANDLE>> {
+        use traits::{Dir, Entry};
+
+        let mut entries = self.entries()?;
+        while let Some(entry) = entries.next() {
+            if str::eq_ignore_ascii_case(entry.name(), name) {
+                let (first, last) = entries.span;
+
+                let mut raw = Vec::new();
+                for slot in entries.buf[first..=last].iter() {
+                    raw.extend_from_slice(&unsafe { slot.regular }.to_bytes());
+                }
+
+                return Ok(Slot {
+                    entry,
+                    regular: unsafe { entries.buf[last].regular },
+                    first,
+                    raw,
+                });
+            }
+        }
+
+        ioerr!(NotFound, "file not found")
+    }
+
+    /// Fails if the file in `slot` has `File` handles besides the one `slot`
+    /// holds. Such a handle would keep using the entry and the clusters after
+    /// they are released or moved.
+    fn check_unused(&self, slot: &Slot<HANDLE>) -> io::Result<()> {
+        if let Entry::File(_) = slot.entry {
+            let size = core::mem::size_of::<VFatDirEntry>();
+            let location = EntryLocation {
+                dir_cluster: self.cluster,
+                offset: slot.first * size + slot.raw.len() - size,
+            };
+            if self.vfat.lock(|fs| fs.open_count(location)) > 1 {
+                return ioerr!(Other, "file is open");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes `raw` over the directory's slots starting at slot `first`,
+    /// growing the directory if needed.
+    fn write_slots(&self, first: usize, raw: &[u8]) -> io::Result<()> {
+        let cluster = self.cluster;
+        self.vfat.lock(|fs| {
+            let start = SeekHandle { cluster, offset: 0, total_offset: 0 };
+            let handle = fs.seek_handle(cluster, start, first * core::mem::size_of::<VFatDirEntry>())?;
+            fs.write_cluster_unaligned(handle, raw)
+        })?;
+        Ok(())
+    }
+
+    /// Marks `count` slots starting at slot `first` as deleted.
+    fn release_slots(&self, first: usize, count: usize) -> io::Result<()> {
+        let cluster = self.cluster;
+        self.vfat.lock(|fs| {
+            let start = SeekHandle { cluster, offset: 0, total_offset: 0 };
+            for i in first..first + count {
+                let handle = fs.seek_handle(cluster, start, i * core::mem::size_of::<VFatDirEntry>())?;
+                fs.write_cluster(handle.cluster, handle.offset, &[DELETED_ENTRY])?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Adds `regular` to the directory under `name`, generating a short name
+    /// and LFN entries as required. Returns the location of the new regular
+    /// entry.
+    fn insert(&self, name: &str, mut regular: VFatRegularDirEntry) -> io::Result<EntryLocation> {
+        validate_name(name)?;
+        if self.find(name).is_ok() {
+            return ioerr!(AlreadyExists, "file already exists");
+        }
+
+        let buf = self.raw_entries()?;
+        let taken: Vec<[u8; 11]> = buf.iter()
+            .take_while(|e| !e.was_prev_last())
+            .filter(|e| !e.is_deleted() && !e.is_lfn())
+            .map(|e| unsafe { e.regular }.short_name())
+            .collect();
+
+        let mut raw: Vec<u8> = Vec::new();
+        match exact_short_name(name) {
+            Some(short) if !taken.contains(&short) => regular.set_short_name(short),
+            _ => {
+                let short = generate_short_name(name, taken.as_slice())?;
+                for lfn in build_lfns(name, lfn_checksum(&short)) {
+                    raw.extend_from_slice(&lfn.to_bytes());
+                }
+                regular.set_short_name(short);
+            }
+        }
+        raw.extend_from_slice(&regular.to_bytes());
+
+        let count = raw.len() / core::mem::size_of::<VFatDirEntry>();
+        let first = find_free_slots(buf.as_slice(), count);
+        self.write_slots(first, raw.as_slice())?;
+
+        Ok(EntryLocation {
+            dir_cluster: self.cluster,
+            offset: (first + count - 1) * core::mem::size_of::<VFatDirEntry>(),
+        })
+    }
+
+    /// The cluster `..` entries of subdirectories of `self` refer to.
+    fn parent_link(&self) -> Cluster {
+        // the root directory is always referred to by cluster 0.
+        if self.cluster == self.vfat.lock(|fs| fs.root_cluster()) {
+            Cluster::from(0)
+        } else {
+            self.cluster
+        }
+    }
+
+    /// Returns `true` if `self` is the directory starting at `cluster` or one
+    /// of its descendants.
+    fn is_within(&self, cluster: Cluster) -> io::Result<bool> {
+        let root = self.vfat.lock(|fs| fs.root_cluster());
+
+        let mut current = self.cluster;
+        loop {
+            if current == cluster {
+                return Ok(true);
+            }
+            if current == root || current.raw() == 0 {
+                return Ok(false);
+            }
+
+            let dir = Dir { vfat: self.vfat.clone(), cluster: current, name: String::new(), metadata: Default::default() };
+            match dir.find("..")? {
+                Entry::Dir(parent) => current = parent.cluster,
+                Entry::File(_) => return ioerr!(InvalidData, "'..' is not a directory"),
+            }
+        }
+    }
+
+    /// Creates an empty file named `name` in `self`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `AlreadyExists` if an entry named `name` exists and
+    /// `InvalidInput` if `name` is not a valid file name.
+    pub fn create_file<P: AsRef<OsStr>>(&self, name: P) -> io::Result<File<HANDLE>> {
+        let name = name.as_ref().to_str().ok_or(io::ErrorKind::InvalidInput)?;
+        let now = self.vfat.lock(|fs| fs.now());
+
+        let regular = VFatRegularDirEntry::new([b' '; 11], Attributes::ARCHIVE, Cluster::from(0), 0, now);
+        let location = self.insert(name, regular)?;
+
+        Ok(File::new(self.vfat.clone(), Cluster::from(0), String::from(name), regular.metadata(), 0, Some(location)))
+    }
+
+    /// Creates an empty directory named `name` in `self` containing only the
+    /// `.` and `..` entries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `AlreadyExists` if an entry named `name` exists and
+    /// `InvalidInput` if `name` is not a valid file name.
+    pub fn create_dir<P: AsRef<OsStr>>(&self, name: P) -> io::Result<Dir<HANDLE>> {
+        let name = name.as_ref().to_str().ok_or(io::ErrorKind::InvalidInput)?;
+        validate_name(name)?;
+        if self.find(name).is_ok() {
+            return ioerr!(AlreadyExists, "file already exists");
+        }
+
+        let parent = self.parent_link();
+        let (cluster, now) = self.vfat.lock(|fs| -> io::Result<(Cluster, Timestamp)> {
+            let now = fs.now();
+            let cluster = fs.alloc_cluster(None)?;
+
+            let mut raw: Vec<u8> = Vec::new();
+            raw.extend_from_slice(&VFatRegularDirEntry::new(*b".          ", Attributes::DIRECTORY, cluster, 0, now).to_bytes());
+            raw.extend_from_slice(&VFatRegularDirEntry::new(*b"..         ", Attributes::DIRECTORY, parent, 0, now).to_bytes());
+            fs.write_cluster(cluster, 0, raw.as_slice())?;
+
+            Ok((cluster, now))
+        })?;
+
+        let regular = VFatRegularDirEntry::new([b' '; 11], Attributes::DIRECTORY, cluster, 0, now);
+        if let Err(e) = self.insert(name, regular) {
+            self.vfat.lock(|fs| fs.free_chain(cluster))?;
+            return Err(e);
+        }
+
+        Ok(Dir {
+            vfat: self.vfat.clone(),
+            cluster,
+            name: String::from(name),
+            metadata: regular.metadata(),
+        })
+    }
+
+    /// Removes the file or empty directory named `name` from `self` and
+    /// returns its clusters to the FAT.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `NotFound` if there is no entry named `name` and
+    /// `Other` if it is a directory that is not empty or a file that is open.
+    pub fn remove<P: AsRef<OsStr>>(&self, name: P) -> io::Result<()> {
+        use traits::{Dir, Entry};
+
+        let name = name.as_ref().to_str().ok_or(io::ErrorKind::InvalidInput)?;
+        validate_name(name)?;
+
+        let slot = self.locate(name)?;
+        self.check_unused(&slot)?;
+        if let Some(dir) = slot.entry.as_dir() {
+            if dir.entries()?.any(|e| e.name() != "." && e.name() != "..") {
+                return ioerr!(Other, "directory not empty");
+            }
+        }
+
+        self.release_slots(slot.first, slot.raw.len() / core::mem::size_of::<VFatDirEntry>())?;
+
+        let cluster = slot.regular.cluster();
+        if cluster.raw() != 0 {
+            self.vfat.lock(|fs| fs.free_chain(cluster))?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the entry named `name` in `self` to `new_name` in `target`.
+    /// `target` may refer to the same directory as `self`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `NotFound` if there is no entry named `name`,
+    /// `AlreadyExists` if `target` already has an entry named `new_name`,
+    /// `InvalidInput` if a directory would be moved into itself and `Other` if
+    /// it is a file that is open.
+    pub fn rename<P: AsRef<OsStr>, Q: AsRef<OsStr>>(&self, name: P, target: &Dir<HANDLE>, new_name: Q) -> io::Result<()> {
+        let name = name.as_ref().to_str().ok_or(io::ErrorKind::InvalidInput)?;
+        let new_name = new_name.as_ref().to_str().ok_or(io::ErrorKind::InvalidInput)?;
+        validate_name(name)?;
+        validate_name(new_name)?;
+
+        let slot = self.locate(name)?;
+        self.check_unused(&slot)?;
+
+        let same_dir = target.cluster == self.cluster;
+        if target.find(new_name).is_ok() && !(same_dir && name.eq_ignore_ascii_case(new_name)) {
+            return ioerr!(AlreadyExists, "file already exists");
+        }
+
+        let moved_dir = match &slot.entry {
+            Entry::Dir(dir) => {
+                if target.is_within(dir.cluster)? {
+                    return ioerr!(InvalidInput, "cannot move a directory into itself");
+                }
+                Some(dir.cluster)
+            }
+            Entry::File(_) => None,
+        };
+
+        // release the old slots first so that a rename that only changes case
+        // does not collide with itself.
+        let count = slot.raw.len() / core::mem::size_of::<VFatDirEntry>();
+        self.release_slots(slot.first, count)?;
+
+        if let Err(e) = target.insert(new_name, slot.regular) {
+            self.write_slots(slot.first, slot.raw.as_slice())?;
+            return Err(e);
+        }
+
+        if let (Some(cluster), false) = (moved_dir, same_dir) {
+            let parent = target.parent_link();
+            self.vfat.lock(|fs| fs.update_entry(EntryLocation {
+                dir_cluster: cluster,
+                offset: core::mem::size_of::<VFatDirEntry>(),
+            }, |entry| entry.set_cluster(parent)))?;
+        }
+
+        Ok(())
+    }
 }
 
 pub struct EntriesIterator<HANDLE: VFatHandle> {
     vfat: HANDLE,
+    cluster: Cluster,
     buf: Vec<VFatDirEntry>,
     index: usize,
+    /// Slot indices of the first LFN and of the regular entry that make up
+    /// the most recently returned entry.
+    span: (usize, usize),
 }
 
-fn parse_lfns(lfns: &mut Vec<VFatLfnDirEntry>) -> String {
+pub(crate) fn parse_lfns(lfns: &mut Vec<VFatLfnDirEntry>) -> String {
     lfns.sort_by(|a, b| a.sequence_number().cmp(&b.sequence_number()));
 
     let mut buf: Vec<u8> = Vec::new();
@@ -210,6 +765,7 @@ impl<HANDLE: VFatHandle> Iterator for EntriesIterator<HANDLE> {
 
     fn next(&mut self) -> Option<Self::Item> {
         let mut lfns: Vec<VFatLfnDirEntry> = Vec::new();
+        let mut first = self.index;
 
         while self.index < self.buf.len() {
             let entry = &self.buf[self.index];
@@ -229,11 +785,15 @@ impl<HANDLE: VFatHandle> Iterator for EntriesIterator<HANDLE> {
 
             // defer LFNs until we see the corresponding normal entry.
             if entry.is_lfn() {
+                if lfns.is_empty() {
+                    first = self.index - 1;
+                }
                 lfns.push(unsafe { entry.long_filename });
                 continue;
             }
 
             let entry = unsafe { entry.regular };
+            self.span = (if lfns.is_empty() { self.index - 1 } else { first }, self.index - 1);
 
             let name: String;
             if lfns.len() > 0 {
@@ -257,6 +817,10 @@ impl<HANDLE: VFatHandle> Iterator for EntriesIterator<HANDLE> {
                     name,
                     entry.metadata(),
                     entry.file_size,
+                    Some(EntryLocation {
+                        dir_cluster: self.cluster,
+                        offset: (self.index - 1) * core::mem::size_of::<VFatDirEntry>(),
+                    }),
                 ))
             });
         }
@@ -270,14 +834,12 @@ impl<HANDLE: VFatHandle> traits::Dir for Dir<HANDLE> {
     type Iter = EntriesIterator<HANDLE>;
 
     fn entries(&self) -> io::Result<Self::Iter> {
-        let mut buf: Vec<u8> = Vec::new();
-
-        self.vfat.lock(|fs| fs.read_chain(self.cluster, &mut buf))?;
-
         Ok(EntriesIterator {
             vfat: self.vfat.clone(),
-            buf: unsafe { buf.cast() },
+            cluster: self.cluster,
+            buf: self.raw_entries()?,
             index: 0,
+            span: (0, 0),
         })
     }
 }
@@ -2,7 +2,7 @@ use core::fmt;
 use shim::const_assert_size;
 
 use crate::traits::BlockDevice;
-use crate::vfat::Error;
+use crate::vfat::{Error, FatType};
 
 #[repr(C, packed)]
 pub struct BiosParameterBlock {
@@ -78,6 +78,31 @@ impl BiosParameterBlock {
         }
     }
 
+    /// The number of sectors in the fixed size FAT12/16 root directory
+    /// region. Always zero for FAT32.
+    pub fn root_dir_sectors(&self) -> u32 {
+        let bytes_per_sector = self.bytes_per_sector as u32;
+        ((self.max_directory_entries as u32 * 32) + bytes_per_sector - 1) / bytes_per_sector
+    }
+
+    /// The number of data clusters in the file system.
+    pub fn cluster_count(&self) -> u32 {
+        let meta_sectors = self.reserved_sectors as u32
+            + (self.fat_count as u32 * self.sectors_per_fat())
+            + self.root_dir_sectors();
+
+        self.total_logical_sectors().saturating_sub(meta_sectors) / self.sectors_per_cluster as u32
+    }
+
+    /// Determines the FAT variant from the cluster count, which is the only
+    /// reliable indicator according to the specification.
+    pub fn fat_type(&self) -> FatType {
+        match self.cluster_count() {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        }
+    }
 }
 
 impl fmt::Debug for BiosParameterBlock {
@@ -2,6 +2,24 @@ use core::fmt;
 
 use crate::vfat::*;
 
+/// The FAT variant of a file system, determined by its cluster count.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FatType::Fat12 => "fat12",
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32",
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Status {
     /// The FAT entry corresponds to an unused (free) cluster.
@@ -4,8 +4,8 @@ use shim::ioerr;
 use shim::io::{self, SeekFrom};
 
 use crate::traits;
-use crate::vfat::{Cluster, Metadata, VFatHandle};
-use crate::vfat::vfat::SeekHandle;
+use crate::vfat::{Cluster, Metadata, VFat, VFatHandle};
+use crate::vfat::vfat::{EntryLocation, SeekHandle};
 use mountfs::mount::mfs;
 use mountfs::mount;
 use crate::vfat::mnt::DynVFatHandle;
@@ -20,10 +20,15 @@ pub struct File<HANDLE: VFatHandle> {
     pub metadata: Metadata,
     pub size: u32,
     pointer: SeekHandle,
+    location: Option<EntryLocation>,
 }
 
 impl<HANDLE: VFatHandle> File<HANDLE> {
-    pub fn new(vfat: HANDLE, cluster: Cluster, name: String, metadata: Metadata, size: u32) -> File<HANDLE> {
+    pub fn new(vfat: HANDLE, cluster: Cluster, name: String, metadata: Metadata, size: u32, location: Option<EntryLocation>) -> File<HANDLE> {
+        if let Some(location) = location {
+            vfat.lock(|fs| fs.open_entry(location));
+        }
+
         File {
             vfat,
             cluster,
@@ -35,13 +40,92 @@ impl<HANDLE: VFatHandle> File<HANDLE> {
                 offset: 0,
                 total_offset: 0,
             },
+            location,
+        }
+    }
+
+    /// Writes the file's first cluster, size and modification time back to
+    /// its directory entry.
+    fn update_entry(&mut self, fs: &mut VFat<HANDLE>) -> io::Result<()> {
+        let now = fs.now();
+        self.metadata.last_modified = now;
+        self.metadata.last_accessed.date = now.date;
+
+        if let Some(location) = self.location {
+            let (cluster, size) = (self.cluster, self.size);
+            fs.update_entry(location, |entry| {
+                entry.set_cluster(cluster);
+                entry.set_file_size(size);
+                entry.set_modified(now);
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Truncates or extends the file to `size` bytes. Extended regions are
+    /// filled with zeroes. The file position is kept unless it lies beyond
+    /// the new end of the file, in which case it is moved to the end.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `PermissionDenied` if the file is read only and
+    /// `InvalidInput` if `size` does not fit in a FAT32 file.
+    pub fn set_len(&mut self, size: u64) -> io::Result<()> {
+        use traits::Metadata;
+
+        if self.metadata.read_only() {
+            return ioerr!(PermissionDenied, "file is read only");
+        }
+        if size > u32::max_value() as u64 {
+            return ioerr!(InvalidInput, "file size too large");
+        }
+
+        let position = core::cmp::min(self.pointer.total_offset as u64, size);
+
+        if size > self.size as u64 {
+            let zeroes = [0u8; 512];
+            io::Seek::seek(self, SeekFrom::End(0))?;
+            while (self.size as u64) < size {
+                let amt = core::cmp::min(zeroes.len() as u64, size - self.size as u64) as usize;
+                io::Write::write_all(self, &zeroes[..amt])?;
+            }
+        } else if size < self.size as u64 {
+            let vfat = self.vfat.clone();
+            vfat.lock(|fs| -> io::Result<()> {
+                if size == 0 {
+                    if self.cluster.raw() != 0 {
+                        fs.free_chain(self.cluster)?;
+                    }
+                    self.cluster = Cluster::from(0);
+                } else {
+                    let start = SeekHandle { cluster: self.cluster, offset: 0, total_offset: 0 };
+                    let last = fs.seek_handle(self.cluster, start, (size - 1) as usize)?;
+                    fs.truncate_chain(last.cluster)?;
+                }
+
+                self.size = size as u32;
+                self.update_entry(fs)
+            })?;
+        }
+
+        self.pointer = SeekHandle { cluster: self.cluster, offset: 0, total_offset: 0 };
+        io::Seek::seek(self, SeekFrom::Start(position))?;
+        Ok(())
+    }
+}
+
+impl<HANDLE: VFatHandle> Drop for File<HANDLE> {
+    fn drop(&mut self) {
+        if let Some(location) = self.location {
+            self.vfat.lock(|fs| fs.close_entry(location));
         }
     }
 }
 
 impl<HANDLE: VFatHandle> traits::File for File<HANDLE> {
     fn sync(&mut self) -> io::Result<()> {
-        unimplemented!("read only file system")
+        self.vfat.lock(|fs| fs.flush())
     }
 
     fn size(&self) -> u64 {
@@ -50,12 +134,40 @@ impl<HANDLE: VFatHandle> traits::File for File<HANDLE> {
 }
 
 impl<HANDLE: VFatHandle> io::Write for File<HANDLE> {
-    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
-        unimplemented!("read only file system")
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        use traits::Metadata;
+
+        if self.metadata.read_only() {
+            return ioerr!(PermissionDenied, "file is read only");
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let max_file_write = core::cmp::min(u32::max_value() as usize - self.pointer.total_offset, buf.len());
+        if max_file_write == 0 {
+            return ioerr!(WriteZero, "maximum file size reached");
+        }
+
+        let vfat = self.vfat.clone();
+        vfat.lock(|fs| {
+            if self.cluster.raw() == 0 {
+                // empty files do not own a cluster until they are first written.
+                self.cluster = fs.alloc_cluster(None)?;
+                self.pointer.cluster = self.cluster;
+            }
+
+            let (written, cloff) = fs.write_cluster_unaligned(self.pointer, &buf[..max_file_write])?;
+            self.pointer = cloff;
+            self.size = core::cmp::max(self.size, cloff.total_offset as u32);
+
+            self.update_entry(fs)?;
+            Ok(written)
+        })
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        unimplemented!("read only file system")
+        traits::File::sync(self)
     }
 }
 
@@ -95,6 +207,9 @@ impl<HANDLE: VFatHandle> io::Seek for File<HANDLE> {
             if offset < 0 {
                 return ioerr!(InvalidInput, "cannot seek before start of file");
             }
+            if offset > self.size as isize {
+                return ioerr!(InvalidInput, "cannot seek beyond end of file");
+            }
 
             fs.seek_handle(self.cluster, self.pointer, offset as usize)
         });
@@ -134,17 +249,26 @@ impl mfs::FileInfo for File<DynVFatHandle> {
         false
     }
 
+    /// Files are identified by their directory entry, since empty files have
+    /// no cluster.
     fn get_id(&self) -> FileId {
-        FileId(self.vfat.get_id(), self.cluster.raw() as usize)
+        match self.location {
+            Some(location) => FileId(self.vfat.get_id(), location.inode()),
+            None => FileId(self.vfat.get_id(), self.cluster.raw() as usize),
+        }
     }
 }
 
 impl mfs::File for File<DynVFatHandle> {
     fn sync(&mut self) -> io::Result<()> {
-        unimplemented!()
+        traits::File::sync(self)
     }
 
     fn size(&self) -> u64 {
         self.size as u64
     }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
 }
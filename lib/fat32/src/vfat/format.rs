@@ -0,0 +1,206 @@
+use alloc::vec::Vec;
+use core::cmp::min;
+
+use shim::{io, newioerr};
+
+use crate::partition::{PartitionInfo, PartitionKind};
+use crate::traits::BlockDevice;
+use crate::vfat::Error;
+
+/// First sector of the partition created by `format()`, aligned to 1 MiB.
+const PARTITION_START: u64 = 2048;
+/// MBR partition type of a FAT32 partition addressed by LBA.
+const PARTITION_TYPE_FAT32_LBA: u8 = 0x0C;
+
+const RESERVED_SECTORS: u16 = 32;
+const FAT_COUNT: u8 = 2;
+const FSINFO_SECTOR: u16 = 1;
+const BACKUP_BOOT_SECTOR: u16 = 6;
+const ROOT_CLUSTER: u32 = 2;
+
+/// The smallest and largest cluster counts of a FAT32 file system.
+const MIN_CLUSTERS: u64 = 65525;
+const MAX_CLUSTERS: u64 = 0x0FFF_FFF5;
+
+/// Options for `format()`.
+#[derive(Copy, Clone, Debug)]
+pub struct FormatOptions {
+    /// Volume label, padded with spaces.
+    pub label: [u8; 11],
+    /// Volume serial number.
+    pub volume_id: u32,
+    /// Sectors per cluster, or `None` to choose by the size of the volume.
+    pub sectors_per_cluster: Option<u8>,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            label: *b"NO NAME    ",
+            volume_id: 0,
+            sectors_per_cluster: None,
+        }
+    }
+}
+
+impl FormatOptions {
+    /// Sets the volume label to the first 11 bytes of `label`, upper cased.
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = [b' '; 11];
+        for (dst, src) in self.label.iter_mut().zip(label.bytes()) {
+            *dst = src.to_ascii_uppercase();
+        }
+        self
+    }
+}
+
+/// The cluster size recommended by the FAT specification for a volume of
+/// `bytes` bytes.
+fn default_cluster_bytes(bytes: u64) -> u64 {
+    const MIB: u64 = 1024 * 1024;
+    match bytes {
+        b if b <= 260 * MIB => 512,
+        b if b <= 8 * 1024 * MIB => 4096,
+        b if b <= 16 * 1024 * MIB => 8192,
+        b if b <= 32 * 1024 * MIB => 16384,
+        _ => 32768,
+    }
+}
+
+fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Writes `buf` to `count` sectors of `device` starting at `start`.
+fn fill<T: BlockDevice>(device: &mut T, start: u64, count: u64, buf: &[u8]) -> io::Result<()> {
+    for sector in start..start + count {
+        device.write_sector(sector, buf)?;
+    }
+    Ok(())
+}
+
+/// Creates an MBR with a single partition spanning the first `num_sectors`
+/// sectors of `device` and formats the partition as FAT32. Returns the new
+/// partition, which `VFat::from()` will find.
+///
+/// Only the metadata of the new file system is written: the reserved
+/// sectors, the FATs and the root directory.
+///
+/// # Errors
+///
+/// If the device is too small or too large for FAT32 with the requested
+/// cluster size, or its sector size is not a power of two between 512 and
+/// 4096 bytes, an `Io` error of kind `InvalidInput` is returned.
+pub fn format<T: BlockDevice>(mut device: T, num_sectors: u64, options: &FormatOptions) -> Result<PartitionInfo, Error> {
+    let sector_size = device.sector_size();
+    if sector_size < 512 || sector_size > 4096 || !sector_size.is_power_of_two() {
+        return Err(newioerr!(InvalidInput, "unsupported sector size").into());
+    }
+
+    let total = min(num_sectors.saturating_sub(PARTITION_START), u32::MAX as u64);
+    let sectors_per_cluster = match options.sectors_per_cluster {
+        Some(n) if n.is_power_of_two() => n as u64,
+        Some(_) => return Err(newioerr!(InvalidInput, "sectors per cluster must be a power of two").into()),
+        None => (default_cluster_bytes(total * sector_size) / sector_size).max(1),
+    };
+
+    // grow the FATs until they can map every cluster that remains.
+    let mut sectors_per_fat = 1;
+    let clusters = loop {
+        let meta = RESERVED_SECTORS as u64 + FAT_COUNT as u64 * sectors_per_fat;
+        let clusters = total.saturating_sub(meta) / sectors_per_cluster;
+        let needed = ((clusters + 2) * 4 + sector_size - 1) / sector_size;
+        if needed <= sectors_per_fat {
+            break clusters;
+        }
+        sectors_per_fat = needed;
+    };
+
+    if clusters < MIN_CLUSTERS {
+        return Err(newioerr!(InvalidInput, "device is too small for FAT32").into());
+    }
+    if clusters > MAX_CLUSTERS {
+        return Err(newioerr!(InvalidInput, "device is too large for this cluster size").into());
+    }
+
+    let start = PARTITION_START;
+    let mut sector = vec![0u8; sector_size as usize];
+
+    // master boot record
+    {
+        let entry = &mut sector[446..462];
+        entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+        entry[4] = PARTITION_TYPE_FAT32_LBA;
+        entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+        put_u32(entry, 8, start as u32);
+        put_u32(entry, 12, total as u32);
+    }
+    sector[510..512].copy_from_slice(&[0x55, 0xAA]);
+    device.write_sector(0, &sector)?;
+
+    // zero the reserved sectors, then write the boot sectors and FSInfo.
+    let zero = vec![0u8; sector_size as usize];
+    fill(&mut device, start, RESERVED_SECTORS as u64, &zero)?;
+
+    let mut boot = vec![0u8; sector_size as usize];
+    boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"MSWIN4.1");
+    put_u16(&mut boot, 11, sector_size as u16);
+    boot[13] = sectors_per_cluster as u8;
+    put_u16(&mut boot, 14, RESERVED_SECTORS);
+    boot[16] = FAT_COUNT;
+    boot[21] = 0xF8;
+    put_u16(&mut boot, 24, 63);
+    put_u16(&mut boot, 26, 255);
+    put_u32(&mut boot, 28, start as u32);
+    put_u32(&mut boot, 32, total as u32);
+    put_u32(&mut boot, 36, sectors_per_fat as u32);
+    put_u32(&mut boot, 44, ROOT_CLUSTER);
+    put_u16(&mut boot, 48, FSINFO_SECTOR);
+    put_u16(&mut boot, 50, BACKUP_BOOT_SECTOR);
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    put_u32(&mut boot, 67, options.volume_id);
+    boot[71..82].copy_from_slice(&options.label);
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    let mut fsinfo = vec![0u8; sector_size as usize];
+    put_u32(&mut fsinfo, 0, 0x4161_5252);
+    put_u32(&mut fsinfo, 484, 0x6141_7272);
+    // the root directory occupies the first cluster.
+    put_u32(&mut fsinfo, 488, (clusters - 1) as u32);
+    put_u32(&mut fsinfo, 492, ROOT_CLUSTER + 1);
+    put_u32(&mut fsinfo, 508, 0xAA55_0000);
+
+    for base in [0, BACKUP_BOOT_SECTOR as u64].iter() {
+        device.write_sector(start + base, &boot)?;
+        device.write_sector(start + base + FSINFO_SECTOR as u64, &fsinfo)?;
+    }
+
+    // FATs: media descriptor, reserved entry and the root directory's chain.
+    let mut first = vec![0u8; sector_size as usize];
+    put_u32(&mut first, 0, 0x0FFF_FFF8);
+    put_u32(&mut first, 4, 0x0FFF_FFFF);
+    put_u32(&mut first, 8, 0x0FFF_FFFF);
+    for fat in 0..FAT_COUNT as u64 {
+        let fat_start = start + RESERVED_SECTORS as u64 + fat * sectors_per_fat;
+        device.write_sector(fat_start, &first)?;
+        fill(&mut device, fat_start + 1, sectors_per_fat - 1, &zero)?;
+    }
+
+    // empty root directory
+    let data_start = start + RESERVED_SECTORS as u64 + FAT_COUNT as u64 * sectors_per_fat;
+    fill(&mut device, data_start, sectors_per_cluster, &zero)?;
+
+    Ok(PartitionInfo {
+        index: 0,
+        start,
+        num_sectors: total,
+        kind: PartitionKind::Mbr(PARTITION_TYPE_FAT32_LBA),
+    })
+}
@@ -33,7 +33,25 @@ pub struct Metadata {
     pub last_modified: Timestamp,
 }
 
+impl Date {
+    /// Creates a date from a calendar year (>= 1980), month and day.
+    pub fn new(year: usize, month: u8, day: u8) -> Date {
+        Date(((year.saturating_sub(1980) as u16) << 9) | ((month as u16 & 0b1111) << 5) | (day as u16 & 0b1_1111))
+    }
+}
+
+impl Time {
+    /// Creates a time from a 24-hour hour, minute and second. Seconds are
+    /// stored with a two second granularity.
+    pub fn new(hour: u8, minute: u8, second: u8) -> Time {
+        Time(((hour as u16) << 11) | ((minute as u16 & 0b11_1111) << 5) | ((second / 2) as u16 & 0b1_1111))
+    }
+}
+
 impl Attributes {
+    pub const DIRECTORY: Attributes = Attributes(0x10);
+    pub const ARCHIVE: Attributes = Attributes(0x20);
+
     pub fn read_only(&self) -> bool {
         (self.0 & 0x1) != 0
     }
@@ -68,7 +68,7 @@ impl mfs::FileSystem for DynWrapper {
     }
 
     fn get_name(&self) -> Option<String> {
-        Some(String::from("fat32"))
+        Some(String::from(self.0.lock(|fs| fs.fat_type()).name()))
     }
 
     fn open(&self, _manager: &fs::FileSystem, path: &Path) -> io::Result<mfs::Entry> {
@@ -113,4 +113,26 @@ impl mfs::FileSystem for DynWrapper {
             Err(e) => Err(e),
         }
     }
+
+    fn create_file(&self, _manager: &FileSystem, dir: Arc<dyn mfs::Dir>, name: &OsStr) -> io::Result<Box<dyn mfs::File>> {
+        let my_dir: &Dir<DynVFatHandle> = dir.downcast_ref().ok_or(newioerr!(InvalidInput, "[vfat] bad directory handle"))?;
+
+        Ok(Box::new(my_dir.create_file(name)?))
+    }
+
+    fn create_dir(&self, _manager: &FileSystem, dir: Arc<dyn mfs::Dir>, name: &OsStr) -> io::Result<Arc<dyn mfs::Dir>> {
+        let my_dir: &Dir<DynVFatHandle> = dir.downcast_ref().ok_or(newioerr!(InvalidInput, "[vfat] bad directory handle"))?;
+
+        Ok(Arc::new(my_dir.create_dir(name)?))
+    }
+
+    fn remove(&self, _manager: &FileSystem, dir: Arc<dyn mfs::Dir>, name: &OsStr) -> io::Result<()> {
+        let my_dir: &Dir<DynVFatHandle> = dir.downcast_ref().ok_or(newioerr!(InvalidInput, "[vfat] bad directory handle"))?;
+
+        my_dir.remove(name)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.0.lock(|fs| fs.flush())
+    }
 }
\ No newline at end of file
@@ -1,3 +1,4 @@
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use core::fmt::Debug;
 use core::marker::PhantomData;
@@ -6,10 +7,11 @@ use shim::io;
 use shim::ioerr;
 use shim::path::{Component, Path};
 
-use crate::mbr::MasterBootRecord;
+use crate::partition::{self, PartitionInfo};
 use crate::traits::{BlockDevice, FileSystem};
-use crate::vfat::{BiosParameterBlock, CachedPartition, Partition};
-use crate::vfat::{Cluster, Dir, Entry, Error, FatEntry, File, Status};
+use crate::vfat::{BiosParameterBlock, CacheStats, CachedPartition, Partition};
+use crate::vfat::{Cluster, Date, Dir, Entry, Error, FatEntry, FatType, File, Status, Time, Timestamp};
+use crate::vfat::dir::VFatRegularDirEntry;
 
 /// A generic trait that handles a critical section as a closure
 pub trait VFatHandle: Clone + Debug + Send + Sync {
@@ -28,9 +30,18 @@ pub struct VFat<HANDLE: VFatHandle> {
     bytes_per_sector: u16,
     sectors_per_cluster: u8,
     sectors_per_fat: u32,
+    fat_count: u8,
+    fat_type: FatType,
     fat_start_sector: u64,
+    root_dir_start_sector: u64,
+    root_dir_sectors: u64,
     data_start_sector: u64,
     rootdir_cluster: Cluster,
+    cluster_count: u32,
+    next_free: u32,
+    clock: fn() -> Timestamp,
+    /// Number of `File` handles for each directory entry, by inode.
+    open_files: BTreeMap<usize, usize>,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -40,48 +51,164 @@ pub struct SeekHandle {
     pub total_offset: usize,
 }
 
+/// Location of a regular directory entry: the first cluster of the directory
+/// that holds it and the byte offset of the entry within that directory.
+#[derive(Copy, Clone, Debug)]
+pub struct EntryLocation {
+    pub dir_cluster: Cluster,
+    pub offset: usize,
+}
+
+impl EntryLocation {
+    /// An inode number for the entry. It differs from those of other entries
+    /// and from the cluster numbers that identify directories.
+    pub fn inode(&self) -> usize {
+        (1 << 63 | (self.dir_cluster.raw() as u64) << 32 | self.offset as u64) as usize
+    }
+}
+
+/// Value written to the FAT to terminate a cluster chain. It is truncated to
+/// the entry width on FAT12/16.
+pub(crate) const EOC_MARKER: u32 = 0x0FFF_FFFF;
+
+/// Clock used until `VFat::set_clock()` is called: 1980-01-01 00:00:00, the
+/// FAT epoch.
+fn epoch_clock() -> Timestamp {
+    Timestamp {
+        date: Date::new(1980, 1, 1),
+        time: Time::new(0, 0, 0),
+    }
+}
+
 impl<HANDLE: VFatHandle> VFat<HANDLE> {
+    /// Mounts the first FAT partition of `device`, which may be partitioned
+    /// with either an MBR or a GPT.
     pub fn from<T>(mut device: T) -> Result<HANDLE, Error>
         where
             T: BlockDevice + 'static,
     {
-        let mbr = MasterBootRecord::from(&mut device).map_err(|e| Error::Mbr(e))?;
-
-        let partition = mbr.partitions.iter().find(|p| {
-            [0xBu8, 0xCu8].contains(&p.partition_type)
-        }).ok_or(Error::NotFound)?;
+        let partition = partition::find_fat(&mut device)?.ok_or(Error::NotFound)?;
+        VFat::from_partition(device, &partition)
+    }
 
-        let bpb = BiosParameterBlock::from(&mut device, partition.relative_sector as u64)?;
+    /// Mounts the FAT file system in `partition` of `device`.
+    pub fn from_partition<T>(mut device: T, partition: &PartitionInfo) -> Result<HANDLE, Error>
+        where
+            T: BlockDevice + 'static,
+    {
+        let bpb = BiosParameterBlock::from(&mut device, partition.start)?;
 
         let device_sector_size = device.sector_size();
 
+        let fat_type = bpb.fat_type();
+        let root_dir_start_sector = (bpb.reserved_sectors as u64) + (bpb.fat_count as u64 * bpb.sectors_per_fat() as u64);
+        let data_start_sector = root_dir_start_sector + bpb.root_dir_sectors() as u64;
+
         Ok(HANDLE::new(VFat {
             phantom: PhantomData {},
             device: CachedPartition::new(device, Partition {
-                start: partition.relative_sector as u64,
-                num_sectors: (partition.total_sectors as u64) / ((bpb.bytes_per_sector as u64) / device_sector_size),
+                start: partition.start,
+                num_sectors: partition.num_sectors / ((bpb.bytes_per_sector as u64) / device_sector_size),
                 sector_size: bpb.bytes_per_sector as u64,
             }),
             bytes_per_sector: bpb.bytes_per_sector,
             sectors_per_cluster: bpb.sectors_per_cluster,
             sectors_per_fat: bpb.sectors_per_fat(),
+            fat_count: bpb.fat_count,
+            fat_type,
             fat_start_sector: bpb.reserved_sectors as u64,
-            data_start_sector: (bpb.reserved_sectors as u64) + (bpb.fat_count as u64 * bpb.sectors_per_fat() as u64),
-            rootdir_cluster: Cluster::from(bpb.root_cluster),
+            root_dir_start_sector,
+            root_dir_sectors: bpb.root_dir_sectors() as u64,
+            data_start_sector,
+            rootdir_cluster: match fat_type {
+                FatType::Fat32 => Cluster::from(bpb.root_cluster),
+                // FAT12/16 keep the root directory in a fixed region before the data area.
+                FatType::Fat12 | FatType::Fat16 => Cluster::from(0),
+            },
+            cluster_count: bpb.cluster_count(),
+            next_free: 2,
+            clock: epoch_clock,
+            open_files: BTreeMap::new(),
         }))
     }
 
+    /// Sets the function used to timestamp entries that are modified through
+    /// this file system.
+    pub fn set_clock(&mut self, clock: fn() -> Timestamp) {
+        self.clock = clock;
+    }
+
+    pub fn now(&self) -> Timestamp {
+        (self.clock)()
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// Number of data clusters. Valid cluster numbers are `2..cluster_count + 2`.
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    /// Whether `cluster` refers to the fixed size root directory region of a
+    /// FAT12/16 file system.
+    pub(crate) fn is_root_region(&self, cluster: Cluster) -> bool {
+        self.fat_type != FatType::Fat32 && cluster.raw() == 0
+    }
+
+    /// Resolves cluster 0, which directory entries use to refer to the root
+    /// directory, to the root directory's first cluster.
+    fn resolve(&self, cluster: Cluster) -> Cluster {
+        if cluster.raw() == 0 {
+            self.rootdir_cluster
+        } else {
+            cluster
+        }
+    }
+
     fn cluster_start(&self, cluster: Cluster) -> u64 {
-        self.data_start_sector + (cluster.raw() as u64 - 2) * self.sectors_per_cluster as u64
+        if self.is_root_region(cluster) {
+            return self.root_dir_start_sector;
+        }
+        self.data_start_sector + (self.resolve(cluster).raw() as u64 - 2) * self.sectors_per_cluster as u64
+    }
+
+    fn cluster_sectors(&self, cluster: Cluster) -> u64 {
+        if self.is_root_region(cluster) {
+            self.root_dir_sectors
+        } else {
+            self.sectors_per_cluster as u64
+        }
+    }
+
+    /// The number of bytes stored in `cluster`. This is the cluster size for
+    /// every cluster but the FAT12/16 root directory region.
+    pub(crate) fn cluster_bytes(&self, cluster: Cluster) -> usize {
+        self.cluster_sectors(cluster) as usize * self.bytes_per_sector as usize
+    }
+
+    /// Returns the cluster following `cluster` in its chain or `None` if
+    /// `cluster` is the last one.
+    fn next_cluster(&mut self, cluster: Cluster) -> io::Result<Option<Cluster>> {
+        if self.is_root_region(cluster) {
+            return Ok(None);
+        }
+
+        match self.fat_entry(self.resolve(cluster))?.status() {
+            Status::Data(next) => Ok(Some(next)),
+            Status::Eoc(_) => Ok(None),
+            _ => ioerr!(Other, "unexpected fat entry"),
+        }
     }
 
     pub fn read_cluster(&mut self, cluster: Cluster, mut offset: usize, buf: &mut [u8]) -> io::Result<usize> {
-        if offset >= self.cluster_size_bytes() {
+        if offset >= self.cluster_bytes(cluster) {
             return Ok(0);
         }
 
         let cluster_start = self.cluster_start(cluster);
-        let cluster_end = cluster_start + self.sectors_per_cluster as u64;
+        let cluster_end = cluster_start + self.cluster_sectors(cluster);
 
         let mut current_sector = cluster_start + (offset / self.bytes_per_sector as usize) as u64;
         offset = offset % self.bytes_per_sector as usize;
@@ -105,7 +232,43 @@ impl<HANDLE: VFatHandle> VFat<HANDLE> {
         Ok(ptr)
     }
 
-    fn cluster_size_bytes(&self) -> usize {
+    pub fn write_cluster(&mut self, cluster: Cluster, mut offset: usize, buf: &[u8]) -> io::Result<usize> {
+        if offset >= self.cluster_bytes(cluster) {
+            return Ok(0);
+        }
+
+        let cluster_start = self.cluster_start(cluster);
+        let cluster_end = cluster_start + self.cluster_sectors(cluster);
+
+        let mut current_sector = cluster_start + (offset / self.bytes_per_sector as usize) as u64;
+        offset = offset % self.bytes_per_sector as usize;
+
+        let mut ptr: usize = 0;
+        while ptr < buf.len() && current_sector < cluster_end {
+            let sector_data = &mut self.device.get_mut(current_sector)?[offset..];
+            offset = 0;
+
+            let amt = core::cmp::min(sector_data.len(), buf.len() - ptr);
+            sector_data[..amt].copy_from_slice(&buf[ptr..ptr + amt]);
+            ptr += amt;
+
+            current_sector += 1;
+        }
+
+        Ok(ptr)
+    }
+
+    fn zero_cluster(&mut self, cluster: Cluster) -> io::Result<()> {
+        let cluster_start = self.cluster_start(cluster);
+        for sector in cluster_start..cluster_start + self.sectors_per_cluster as u64 {
+            for b in self.device.get_mut(sector)?.iter_mut() {
+                *b = 0;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn cluster_size_bytes(&self) -> usize {
         self.bytes_per_sector as usize * self.sectors_per_cluster as usize
     }
 
@@ -118,13 +281,41 @@ impl<HANDLE: VFatHandle> VFat<HANDLE> {
             cloff.offset += amt;
             cloff.total_offset += amt;
 
-            if cloff.offset == self.cluster_size_bytes() {
-                match self.fat_entry(cloff.cluster)?.status() {
-                    Status::Data(next) => cloff = SeekHandle { cluster: next, offset: 0, total_offset: cloff.total_offset },
-                    Status::Eoc(_) => break 'cluster_loop,
-                    _ => return ioerr!(Other, "unexpected fat entry"),
+            if cloff.offset == self.cluster_bytes(cloff.cluster) {
+                match self.next_cluster(cloff.cluster)? {
+                    Some(next) => cloff = SeekHandle { cluster: next, offset: 0, total_offset: cloff.total_offset },
+                    None => break 'cluster_loop,
+                }
+            }
+        }
+
+        Ok((written, cloff))
+    }
+
+    /// Writes `buf` starting at `cloff`, following the cluster chain and
+    /// appending newly allocated clusters when the end of the chain is
+    /// reached. Returns the number of bytes written and the position just
+    /// after the last byte written.
+    pub fn write_cluster_unaligned(&mut self, mut cloff: SeekHandle, buf: &[u8]) -> io::Result<(usize, SeekHandle)> {
+        let mut written = 0usize;
+
+        while written < buf.len() {
+            if cloff.offset == self.cluster_bytes(cloff.cluster) {
+                if self.is_root_region(cloff.cluster) {
+                    return ioerr!(Other, "root directory is full");
                 }
+
+                let next = match self.next_cluster(cloff.cluster)? {
+                    Some(next) => next,
+                    None => self.alloc_cluster(Some(self.resolve(cloff.cluster)))?,
+                };
+                cloff = SeekHandle { cluster: next, offset: 0, total_offset: cloff.total_offset };
             }
+
+            let amt = self.write_cluster(cloff.cluster, cloff.offset, &buf[written..])?;
+            written += amt;
+            cloff.offset += amt;
+            cloff.total_offset += amt;
         }
 
         Ok((written, cloff))
@@ -136,28 +327,226 @@ impl<HANDLE: VFatHandle> VFat<HANDLE> {
 
         'cluster_loop: loop {
             let start = buf.len();
-            buf.resize(start + self.cluster_size_bytes(), 0);
+            buf.resize(start + self.cluster_bytes(cluster), 0);
             let wrote = self.read_cluster(cluster, 0, &mut buf.as_mut_slice()[start..])?;
             buf.truncate(start + wrote);
 
-            match self.fat_entry(cluster)?.status() {
+            match self.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None => break 'cluster_loop,
+            }
+        }
+
+        Ok(buf.len() - initial_size)
+    }
+
+    /// Byte offset of `cluster`'s entry within a FAT and the number of bytes
+    /// that have to be accessed to read it.
+    fn fat_entry_position(&self, cluster: Cluster) -> (u64, usize) {
+        let cluster = cluster.raw() as u64;
+        match self.fat_type {
+            FatType::Fat12 => (cluster + cluster / 2, 2),
+            FatType::Fat16 => (cluster * 2, 2),
+            FatType::Fat32 => (cluster * 4, 4),
+        }
+    }
+
+    /// Reads `buf.len()` bytes starting at byte `offset` of FAT number `fat`.
+    /// The bytes may span a sector boundary.
+    fn read_fat_bytes(&mut self, fat: u64, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let fat_start = (self.fat_start_sector + fat * self.sectors_per_fat as u64) * self.bytes_per_sector as u64;
+        for (i, b) in buf.iter_mut().enumerate() {
+            let location = fat_start + offset + i as u64;
+            let sector = self.device.get(location / self.bytes_per_sector as u64)?;
+            *b = sector[(location % self.bytes_per_sector as u64) as usize];
+        }
+        Ok(())
+    }
+
+    fn write_fat_bytes(&mut self, fat: u64, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let fat_start = (self.fat_start_sector + fat * self.sectors_per_fat as u64) * self.bytes_per_sector as u64;
+        for (i, b) in buf.iter().enumerate() {
+            let location = fat_start + offset + i as u64;
+            let sector = self.device.get_mut(location / self.bytes_per_sector as u64)?;
+            sector[(location % self.bytes_per_sector as u64) as usize] = *b;
+        }
+        Ok(())
+    }
+
+    /// Returns the FAT entry for `cluster`. FAT12/16 entries are widened so
+    /// that their status decodes the same way as FAT32 entries.
+    pub fn fat_entry(&mut self, cluster: Cluster) -> io::Result<FatEntry> {
+        let (offset, width) = self.fat_entry_position(cluster);
+
+        let mut raw = [0u8; 4];
+        self.read_fat_bytes(0, offset, &mut raw[..width])?;
+        let raw = u32::from_le_bytes(raw);
+
+        Ok(FatEntry(match self.fat_type {
+            FatType::Fat12 => {
+                let value = if cluster.raw() % 2 == 0 { raw & 0xFFF } else { raw >> 4 };
+                if value >= 0xFF0 { value | 0x0FFF_F000 } else { value }
+            }
+            FatType::Fat16 => if raw >= 0xFFF0 { raw | 0x0FFF_0000 } else { raw },
+            FatType::Fat32 => raw,
+        }))
+    }
+
+    /// Returns the FAT entry of cluster number `cluster`, widened like
+    /// `fat_entry()`, and the number of the cluster that follows it in its
+    /// chain, if any.
+    pub fn fat_link(&mut self, cluster: u32) -> io::Result<(u32, Option<u32>)> {
+        let entry = self.fat_entry(Cluster::from(cluster))?;
+        let next = match entry.status() {
+            Status::Data(next) => Some(next.raw()),
+            _ => None,
+        };
+        Ok((entry.0, next))
+    }
+
+    pub(crate) fn set_fat_entry(&mut self, cluster: Cluster, value: u32) -> io::Result<()> {
+        let (offset, width) = self.fat_entry_position(cluster);
+
+        // keep every copy of the FAT in sync.
+        for fat in 0..self.fat_count as u64 {
+            let mut raw = [0u8; 4];
+            self.read_fat_bytes(fat, offset, &mut raw[..width])?;
+            let old = u32::from_le_bytes(raw);
+
+            let new = match self.fat_type {
+                // FAT12 entries share a byte with their neighbour.
+                FatType::Fat12 if cluster.raw() % 2 == 0 => (old & 0xF000) | (value & 0xFFF),
+                FatType::Fat12 => (old & 0x000F) | ((value & 0xFFF) << 4),
+                FatType::Fat16 => value & 0xFFFF,
+                // the upper 4 bits of a FAT32 entry are reserved and must be preserved.
+                FatType::Fat32 => (old & 0xF000_0000) | (value & 0x0FFF_FFFF),
+            };
+            self.write_fat_bytes(fat, offset, &new.to_le_bytes()[..width])?;
+        }
+
+        Ok(())
+    }
+
+    /// Allocates a free cluster, marks it as the end of a chain and zeroes its
+    /// contents. If `prev` is given, the new cluster is linked after `prev`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `Other` if there are no free clusters left.
+    pub fn alloc_cluster(&mut self, prev: Option<Cluster>) -> io::Result<Cluster> {
+        let first = 2;
+        let end = self.cluster_count + 2;
+
+        let mut candidate = core::cmp::max(self.next_free, first);
+        for _ in first..end {
+            if candidate >= end {
+                candidate = first;
+            }
+
+            let cluster = Cluster::from(candidate);
+            if self.fat_entry(cluster)?.status() == Status::Free {
+                self.set_fat_entry(cluster, EOC_MARKER)?;
+                if let Some(prev) = prev {
+                    self.set_fat_entry(prev, cluster.raw())?;
+                }
+                self.zero_cluster(cluster)?;
+
+                self.next_free = candidate + 1;
+                return Ok(cluster);
+            }
+
+            candidate += 1;
+        }
+
+        ioerr!(Other, "no free clusters")
+    }
+
+    /// Returns every cluster in the chain starting at `start` to the FAT.
+    pub fn free_chain(&mut self, start: Cluster) -> io::Result<()> {
+        let mut cluster = start;
+
+        loop {
+            let status = self.fat_entry(cluster)?.status();
+            self.set_fat_entry(cluster, 0)?;
+
+            if cluster.raw() < self.next_free {
+                self.next_free = cluster.raw();
+            }
+
+            match status {
                 Status::Data(next) => cluster = next,
-                Status::Eoc(_) => break 'cluster_loop,
+                Status::Eoc(_) => return Ok(()),
                 _ => return ioerr!(Other, "unexpected fat entry"),
             }
         }
+    }
 
-        Ok(buf.len() - initial_size)
+    /// Makes `cluster` the last cluster of its chain, freeing any clusters
+    /// that followed it.
+    pub fn truncate_chain(&mut self, cluster: Cluster) -> io::Result<()> {
+        let status = self.fat_entry(cluster)?.status();
+        self.set_fat_entry(cluster, EOC_MARKER)?;
+
+        match status {
+            Status::Data(next) => self.free_chain(next),
+            Status::Eoc(_) => Ok(()),
+            _ => ioerr!(Other, "unexpected fat entry"),
+        }
     }
 
-    pub fn fat_entry(&mut self, cluster: Cluster) -> io::Result<&FatEntry> {
-        let entry_location = (self.fat_start_sector * self.bytes_per_sector as u64) + (cluster.raw() * 4) as u64;
+    /// Reads the regular directory entry at `location`, passes it to `f` and
+    /// writes it back.
+    pub(crate) fn update_entry(&mut self, location: EntryLocation, f: impl FnOnce(&mut VFatRegularDirEntry)) -> io::Result<()> {
+        let start = SeekHandle { cluster: location.dir_cluster, offset: 0, total_offset: 0 };
+        let handle = self.seek_handle(location.dir_cluster, start, location.offset)?;
+
+        let mut raw = [0u8; 32];
+        self.read_cluster(handle.cluster, handle.offset, &mut raw)?;
 
-        let sector = self.device.get(entry_location / self.bytes_per_sector as u64)?;
+        let mut entry: VFatRegularDirEntry = unsafe { core::mem::transmute(raw) };
+        f(&mut entry);
 
-        let raw_entry = &sector[(entry_location % self.bytes_per_sector as u64) as usize];
+        let raw: [u8; 32] = unsafe { core::mem::transmute(entry) };
+        self.write_cluster(handle.cluster, handle.offset, &raw)?;
 
-        Ok(unsafe { core::mem::transmute::<&u8, &FatEntry>(raw_entry) })
+        Ok(())
+    }
+
+    /// Records a new `File` handle for the entry at `location`.
+    pub(crate) fn open_entry(&mut self, location: EntryLocation) {
+        *self.open_files.entry(location.inode()).or_insert(0) += 1;
+    }
+
+    /// Forgets a `File` handle for the entry at `location`.
+    pub(crate) fn close_entry(&mut self, location: EntryLocation) {
+        let inode = location.inode();
+        if let Some(count) = self.open_files.get_mut(&inode) {
+            *count -= 1;
+            if *count == 0 {
+                self.open_files.remove(&inode);
+            }
+        }
+    }
+
+    /// Returns the number of `File` handles for the entry at `location`.
+    pub(crate) fn open_count(&self, location: EntryLocation) -> usize {
+        self.open_files.get(&location.inode()).copied().unwrap_or(0)
+    }
+
+    /// Writes every modified sector back to the underlying device.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.device.sync()
+    }
+
+    /// Returns the counters of the sector cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.device.stats()
+    }
+
+    /// Changes the number of sectors the sector cache may hold; see
+    /// `CachedPartition::set_capacity()`.
+    pub fn set_cache_capacity(&mut self, capacity: usize) -> io::Result<()> {
+        self.device.set_capacity(capacity)
     }
 
     pub fn root_cluster(&self) -> Cluster {
@@ -171,7 +560,7 @@ impl<HANDLE: VFatHandle> VFat<HANDLE> {
             // we can be smart and seek from current cluster
 
             current_cluster = cloff.cluster;
-            current_offset = offset - cloff.total_offset;
+            current_offset = cloff.offset + (offset - cloff.total_offset);
         } else {
             // we need to seek from file start cluster
 
@@ -179,14 +568,14 @@ impl<HANDLE: VFatHandle> VFat<HANDLE> {
             current_offset = offset;
         }
 
-        'cluster_loop: while current_offset >= self.cluster_size_bytes() {
-            match self.fat_entry(current_cluster)?.status() {
-                Status::Data(next) => current_cluster = next,
-                Status::Eoc(_) => break 'cluster_loop,
-                _ => return ioerr!(Other, "unexpected fat entry"),
+        'cluster_loop: while current_offset >= self.cluster_bytes(current_cluster) {
+            let size = self.cluster_bytes(current_cluster);
+            match self.next_cluster(current_cluster)? {
+                Some(next) => current_cluster = next,
+                None => break 'cluster_loop,
             }
 
-            current_offset -= self.cluster_size_bytes();
+            current_offset -= size;
         }
 
         Ok(SeekHandle {
@@ -0,0 +1,52 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use fat32::traits::BlockDevice;
+use shim::io;
+
+/// A disk image on the host file system, accessed in 512 byte sectors.
+pub struct ImageFile {
+    file: File,
+    writable: bool,
+}
+
+impl ImageFile {
+    /// Opens the image at `path`. Writes fail unless `writable` is set.
+    pub fn open<P: AsRef<Path>>(path: P, writable: bool) -> std::io::Result<ImageFile> {
+        let file = OpenOptions::new().read(true).write(writable).open(path)?;
+        Ok(ImageFile { file, writable })
+    }
+
+    fn seek_to(&mut self, n: u64) -> io::Result<()> {
+        let offset = n * self.sector_size();
+        match self.file.seek(SeekFrom::Start(offset)) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "seek past end of image")),
+        }
+    }
+}
+
+impl BlockDevice for ImageFile {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let to_read = std::cmp::min(self.sector_size() as usize, buf.len());
+        self.seek_to(n)?;
+        match self.file.read_exact(&mut buf[..to_read]) {
+            Ok(()) => Ok(to_read),
+            Err(_) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "sector past end of image")),
+        }
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        if !self.writable {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "image opened read-only"));
+        }
+
+        let to_write = std::cmp::min(self.sector_size() as usize, buf.len());
+        self.seek_to(n)?;
+        match self.file.write_all(&buf[..to_write]) {
+            Ok(()) => Ok(to_write),
+            Err(_) => Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write sector")),
+        }
+    }
+}
@@ -0,0 +1,348 @@
+mod image;
+#[cfg(test)]
+mod tests;
+
+use std::collections::HashSet;
+use std::env;
+use std::fs::File as HostFile;
+use std::io::{self as std_io, Read as StdRead, Write as StdWrite};
+use std::path::Path;
+
+use fat32::partition;
+use fat32::traits::{Dir as DirTrait, Entry as EntryTrait, FileSystem, Metadata as MetadataTrait};
+use fat32::vfat::{BiosParameterBlock, Dir, DynVFatHandle, Entry, VFat, VFatHandle};
+use shim::io::{Read, Write};
+
+use image::ImageFile;
+
+const USAGE: &str = "usage: fatctl <image> <command> [args...]
+
+commands:
+  info                  show the partition table and volume layout
+  ls [path]             list a directory
+  tree [path]           list a directory recursively
+  cat <path>            write a file to stdout
+  stat <path>           show the metadata and first cluster of an entry
+  chain <path>          dump the cluster chain of an entry
+  fat [first] [count]   dump raw FAT entries
+  fsck [-r]             check the volume, -r repairs problems
+  put <host> <path>     copy a host file into the image
+  mkdir <path>          create a directory
+  rm <path>             remove a file or an empty directory
+  mv <from> <to>        move or rename an entry";
+
+type CmdResult = Result<(), String>;
+
+fn err<E: std::fmt::Debug>(context: &str) -> impl Fn(E) -> String + '_ {
+    move |e| format!("{}: {:?}", context, e)
+}
+
+fn entry_cluster(entry: &Entry<DynVFatHandle>) -> u32 {
+    match entry {
+        Entry::File(f) => f.cluster.raw(),
+        Entry::Dir(d) => d.cluster.raw(),
+    }
+}
+
+fn entry_size(entry: &Entry<DynVFatHandle>) -> u64 {
+    match entry {
+        Entry::File(f) => f.size as u64,
+        Entry::Dir(_) => 0,
+    }
+}
+
+fn describe(entry: &Entry<DynVFatHandle>) -> String {
+    let metadata = entry.metadata();
+    let mut flags = String::new();
+    flags.push(if entry.is_dir() { 'd' } else { '-' });
+    flags.push(if metadata.hidden() { 'h' } else { '-' });
+    flags.push(if metadata.read_only() { 'r' } else { '-' });
+
+    format!("{} {:>9} {} {}", flags, entry_size(entry), metadata.last_modified, entry.name())
+}
+
+/// Splits `path` into its parent directory and final component.
+fn split(path: &str) -> Result<(&str, &str), String> {
+    let path = Path::new(path);
+    let name = path.file_name().and_then(|n| n.to_str()).ok_or_else(|| format!("{}: no file name", path.display()))?;
+    let parent = path.parent().and_then(|p| p.to_str()).filter(|p| !p.is_empty()).unwrap_or("/");
+    Ok((parent, name))
+}
+
+fn open_dir(vfat: &DynVFatHandle, path: &str) -> Result<Dir<DynVFatHandle>, String> {
+    vfat.open_dir(path).map_err(err(path))
+}
+
+/// Prints the partition table and the layout of the first FAT partition of
+/// `device`, before the volume is mounted from it.
+fn partition_info(device: &mut ImageFile) -> CmdResult {
+    let partitions = partition::partitions(&mut *device).map_err(err("partition table"))?;
+
+    println!("partitions:");
+    for p in partitions.iter() {
+        println!("  {}: start {:>8} sectors {:>8} {:?}{}", p.index, p.start, p.num_sectors, p.kind,
+                 if p.kind.is_fat() { " (fat)" } else { "" });
+    }
+
+    if let Some(p) = partitions.iter().find(|p| p.kind.is_fat()) {
+        let bpb = BiosParameterBlock::from(&mut *device, p.start).map_err(err("bpb"))?;
+        println!("volume:");
+        println!("  label:               {}", String::from_utf8_lossy(&bpb.volume_label).trim_end());
+        println!("  bytes per sector:    {}", { bpb.bytes_per_sector });
+        println!("  sectors per cluster: {}", bpb.sectors_per_cluster);
+        println!("  reserved sectors:    {}", { bpb.reserved_sectors });
+        println!("  fats:                {} x {} sectors", bpb.fat_count, bpb.sectors_per_fat());
+        println!("  root entries:        {}", { bpb.max_directory_entries });
+    }
+
+    Ok(())
+}
+
+fn info(vfat: &DynVFatHandle) -> CmdResult {
+    vfat.lock(|fs| {
+        println!("  type:                {}", fs.fat_type().name());
+        println!("  clusters:            {} x {} bytes", fs.cluster_count(), fs.cluster_size_bytes());
+        println!("  root cluster:        {}", fs.root_cluster().raw());
+    });
+
+    Ok(())
+}
+
+fn ls(vfat: &DynVFatHandle, path: &str) -> CmdResult {
+    match vfat.open(path).map_err(err(path))? {
+        entry @ Entry::File(_) => println!("{}", describe(&entry)),
+        Entry::Dir(dir) => {
+            for entry in dir.entries().map_err(err(path))? {
+                println!("{}", describe(&entry));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn tree(dir: &Dir<DynVFatHandle>, indent: usize) -> CmdResult {
+    for entry in dir.entries().map_err(err(&dir.name))? {
+        if entry.name() == "." || entry.name() == ".." {
+            continue;
+        }
+
+        match &entry {
+            Entry::File(f) => println!("{:indent$}{} ({} bytes)", "", f.name, f.size, indent = indent),
+            Entry::Dir(d) => {
+                println!("{:indent$}{}/", "", d.name, indent = indent);
+                tree(d, indent + 2)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cat(vfat: &DynVFatHandle, path: &str) -> CmdResult {
+    let mut file = vfat.open_file(path).map_err(err(path))?;
+    let stdout = std_io::stdout();
+    let mut stdout = stdout.lock();
+
+    let mut buf = [0u8; 4096];
+    loop {
+        let read = file.read(&mut buf).map_err(err(path))?;
+        if read == 0 {
+            return Ok(());
+        }
+        stdout.write_all(&buf[..read]).map_err(err("stdout"))?;
+    }
+}
+
+/// The clusters of the chain starting at `start`. The chain ends at the first
+/// entry that does not continue it; that entry's value is returned.
+fn walk_chain(vfat: &DynVFatHandle, start: u32) -> Result<(Vec<u32>, u32), String> {
+    vfat.lock(|fs| {
+        let mut clusters = Vec::new();
+        let mut seen = HashSet::new();
+        let mut current = start;
+        loop {
+            if !seen.insert(current) {
+                return Err(format!("chain loops back to cluster {}", current));
+            }
+            clusters.push(current);
+
+            match fs.fat_link(current).map_err(err("fat"))? {
+                (_, Some(next)) => current = next,
+                (value, None) => return Ok((clusters, value)),
+            }
+        }
+    })
+}
+
+fn stat(vfat: &DynVFatHandle, path: &str) -> CmdResult {
+    let entry = vfat.open(path).map_err(err(path))?;
+    let metadata = entry.metadata();
+
+    println!("name:      {}", entry.name());
+    println!("type:      {}", if entry.is_dir() { "directory" } else { "file" });
+    println!("size:      {}", entry_size(&entry));
+    println!("cluster:   {}", entry_cluster(&entry));
+    println!("created:   {}", metadata.creation);
+    println!("modified:  {}", metadata.last_modified);
+    println!("accessed:  {}", metadata.last_accessed);
+    println!("flags:     {}{}{}{}",
+             if metadata.read_only() { "read-only " } else { "" },
+             if metadata.hidden() { "hidden " } else { "" },
+             if metadata.attributes.system() { "system " } else { "" },
+             if metadata.attributes.archive() { "archive" } else { "" });
+
+    let cluster = entry_cluster(&entry);
+    if cluster >= 2 {
+        let (clusters, end) = walk_chain(vfat, cluster)?;
+        println!("clusters:  {} (ends with {:#010x})", clusters.len(), end);
+    }
+    Ok(())
+}
+
+fn chain(vfat: &DynVFatHandle, path: &str) -> CmdResult {
+    let cluster = entry_cluster(&vfat.open(path).map_err(err(path))?);
+    if cluster < 2 {
+        println!("{}: no clusters allocated", path);
+        return Ok(());
+    }
+
+    let (clusters, end) = walk_chain(vfat, cluster)?;
+
+    // print contiguous runs as ranges.
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for &cluster in clusters.iter() {
+        match runs.last_mut() {
+            Some((_, last)) if *last + 1 == cluster => *last = cluster,
+            _ => runs.push((cluster, cluster)),
+        }
+    }
+    for (first, last) in runs.iter() {
+        if first == last {
+            println!("{}", first);
+        } else {
+            println!("{}-{} ({} clusters)", first, last, last - first + 1);
+        }
+    }
+    println!("{} clusters, ends with {:#010x}", clusters.len(), end);
+    Ok(())
+}
+
+fn fat(vfat: &DynVFatHandle, args: &[String]) -> CmdResult {
+    let first: u32 = args.get(0).map(|a| a.parse()).unwrap_or(Ok(0)).map_err(err("first"))?;
+    let count: u32 = args.get(1).map(|a| a.parse()).unwrap_or(Ok(32)).map_err(err("count"))?;
+
+    vfat.lock(|fs| {
+        let end = std::cmp::min(first.saturating_add(count), fs.cluster_count() + 2);
+        for cluster in first..end {
+            match fs.fat_link(cluster).map_err(err("fat"))? {
+                (value, Some(next)) => println!("{:>8}: {:#010x} -> {}", cluster, value, next),
+                (value, None) => println!("{:>8}: {:#010x}", cluster, value),
+            }
+        }
+        Ok(())
+    })
+}
+
+fn fsck(vfat: &DynVFatHandle, repair: bool) -> CmdResult {
+    let report = vfat.lock(|fs| fs.check(repair)).map_err(err("fsck"))?;
+    for problem in report.problems.iter() {
+        println!("{}", problem);
+    }
+    println!("{} files, {} directories, {} clusters in use",
+             report.files, report.directories, report.used_clusters);
+    if report.is_clean() {
+        println!("volume is clean");
+        Ok(())
+    } else {
+        Err(format!("{} problem(s), {} repaired", report.problems.len(), report.repaired))
+    }
+}
+
+fn put(vfat: &DynVFatHandle, host: &str, path: &str) -> CmdResult {
+    let mut data = Vec::new();
+    HostFile::open(host).and_then(|mut f| f.read_to_end(&mut data)).map_err(err(host))?;
+
+    let (parent, name) = split(path)?;
+    let dir = open_dir(vfat, parent)?;
+    let mut file = match dir.find(name) {
+        Ok(Entry::File(mut file)) => {
+            file.set_len(0).map_err(err(path))?;
+            file
+        }
+        Ok(Entry::Dir(_)) => return Err(format!("{}: is a directory", path)),
+        Err(_) => dir.create_file(name).map_err(err(path))?,
+    };
+
+    file.write_all(&data).map_err(err(path))?;
+    file.flush().map_err(err(path))
+}
+
+fn mkdir(vfat: &DynVFatHandle, path: &str) -> CmdResult {
+    let (parent, name) = split(path)?;
+    open_dir(vfat, parent)?.create_dir(name).map_err(err(path))?;
+    Ok(())
+}
+
+fn rm(vfat: &DynVFatHandle, path: &str) -> CmdResult {
+    let (parent, name) = split(path)?;
+    open_dir(vfat, parent)?.remove(name).map_err(err(path))
+}
+
+fn mv(vfat: &DynVFatHandle, from: &str, to: &str) -> CmdResult {
+    let (from_parent, from_name) = split(from)?;
+    let (to_parent, to_name) = split(to)?;
+    let source = open_dir(vfat, from_parent)?;
+    let target = open_dir(vfat, to_parent)?;
+    source.rename(from_name, &target, to_name).map_err(err(from))
+}
+
+fn run(args: &[String]) -> CmdResult {
+    if args.len() < 2 {
+        return Err(String::from(USAGE));
+    }
+
+    let image = args[0].as_str();
+    let command = args[1].as_str();
+    let args = &args[2..];
+    let arg = |i: usize| args.get(i).map(|s| s.as_str()).ok_or_else(|| String::from(USAGE));
+    let path = args.get(0).map(|s| s.as_str()).unwrap_or("/");
+
+    let repair = command == "fsck" && args.iter().any(|a| a == "-r");
+    let writable = repair || ["put", "mkdir", "rm", "mv"].contains(&command);
+    let mut device = ImageFile::open(image, writable).map_err(err(image))?;
+    if command == "info" {
+        partition_info(&mut device)?;
+    }
+    let vfat = VFat::<DynVFatHandle>::from(device).map_err(err(image))?;
+
+    let result = match command {
+        "info" => info(&vfat),
+        "ls" => ls(&vfat, path),
+        "tree" => {
+            println!("{}", path);
+            tree(&open_dir(&vfat, path)?, 2)
+        }
+        "cat" => cat(&vfat, arg(0)?),
+        "stat" => stat(&vfat, arg(0)?),
+        "chain" => chain(&vfat, arg(0)?),
+        "fat" => fat(&vfat, args),
+        "fsck" => fsck(&vfat, repair),
+        "put" => put(&vfat, arg(0)?, arg(1)?),
+        "mkdir" => mkdir(&vfat, arg(0)?),
+        "rm" => rm(&vfat, arg(0)?),
+        "mv" => mv(&vfat, arg(0)?, arg(1)?),
+        _ => Err(format!("unknown command: {}\n\n{}", command, USAGE)),
+    };
+
+    if writable {
+        vfat.lock(|fs| fs.flush()).map_err(err("flush"))?;
+    }
+    result
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(message) = run(&args) {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs;
+use std::path::PathBuf;
+
+use fat32::traits::{Entry as EntryTrait, File as FileTrait, FileSystem};
+use fat32::vfat::{DynVFatHandle, VFat};
+use shim::io::Read;
+
+use crate::image::ImageFile;
+use crate::run;
+
+/// A copy of a fixture image in `ext/fat32-imgs`, removed when dropped.
+struct Image(PathBuf);
+
+impl Image {
+    fn copy(name: &str, test: &str) -> Image {
+        let source = concat!(env!("CARGO_MANIFEST_DIR"), "/../../ext/fat32-imgs/").to_string() + name;
+        let path = std::env::temp_dir().join(format!("fatctl-{}-{}-{}", std::process::id(), test, name));
+        fs::copy(&source, &path).expect("copy fixture image");
+        Image(path)
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().expect("utf-8 path")
+    }
+
+    fn run(&self, args: &[&str]) -> Result<(), String> {
+        let mut full = vec![self.path().to_string()];
+        full.extend(args.iter().map(|a| a.to_string()));
+        run(&full)
+    }
+
+    fn mount(&self) -> DynVFatHandle {
+        let device = ImageFile::open(&self.0, false).expect("open image");
+        VFat::<DynVFatHandle>::from(device).expect("mount image")
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn names_in(vfat: &DynVFatHandle, path: &str) -> Vec<String> {
+    use fat32::traits::Dir;
+
+    let mut names: Vec<String> = vfat.open_dir(path).expect("directory")
+        .entries().expect("entries")
+        .map(|e| e.name().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_read_commands() {
+    for name in ["fat12.img", "fat16.img"].iter() {
+        let image = Image::copy(name, "read");
+        image.run(&["info"]).expect("info");
+        image.run(&["ls", "/"]).expect("ls");
+        image.run(&["tree", "/"]).expect("tree");
+        image.run(&["cat", "/HELLO.TXT"]).expect("cat");
+        image.run(&["stat", "/DATA.BIN"]).expect("stat");
+        image.run(&["chain", "/DATA.BIN"]).expect("chain");
+        image.run(&["fat", "0", "8"]).expect("fat");
+        image.run(&["fsck"]).expect("fsck");
+
+        assert!(image.run(&["cat", "/MISSING"]).is_err());
+        assert!(image.run(&["bogus"]).is_err());
+        assert!(image.run(&[]).is_err());
+    }
+}
+
+#[test]
+fn test_read_only_commands_do_not_write() {
+    let image = Image::copy("fat16.img", "readonly");
+    let before = fs::read(image.path()).expect("read image");
+    image.run(&["ls", "/SUBDIR"]).expect("ls");
+    image.run(&["fsck"]).expect("fsck");
+    assert_eq!(fs::read(image.path()).expect("read image"), before);
+}
+
+#[test]
+fn test_write_commands() {
+    let image = Image::copy("fat16.img", "write");
+    let host = std::env::temp_dir().join(format!("fatctl-{}-host.txt", std::process::id()));
+    fs::write(&host, b"from the host").expect("write host file");
+    let put = image.run(&["put", host.to_str().expect("utf-8 path"), "/SUBDIR/put.txt"]);
+    fs::remove_file(&host).expect("remove host file");
+    put.expect("put");
+
+    image.run(&["mkdir", "/NEW"]).expect("mkdir");
+    image.run(&["mv", "/SUBDIR/put.txt", "/NEW/moved.txt"]).expect("mv");
+    image.run(&["rm", "/HELLO.TXT"]).expect("rm");
+    assert!(image.run(&["rm", "/SUBDIR"]).is_err());
+    image.run(&["fsck"]).expect("fsck");
+
+    let vfat = image.mount();
+    assert_eq!(names_in(&vfat, "/"), vec!["DATA.BIN", "NEW", "SUBDIR"]);
+    assert_eq!(names_in(&vfat, "/NEW"), vec![".", "..", "moved.txt"]);
+
+    let mut file = vfat.open_file("/NEW/moved.txt").expect("file");
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).expect("read");
+    assert_eq!(data, b"from the host");
+    assert_eq!(file.size(), data.len() as u64);
+}
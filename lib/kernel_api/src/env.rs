@@ -0,0 +1,77 @@
+//! Arguments, environment and auxiliary vector of a user program.
+//!
+//! The kernel passes them to `_start` as argc, argv, envp and auxv in `x0`-`x3`,
+//! which has to call `init()` before they can be read.
+
+use core::{slice, str};
+
+use crate::AT_NULL;
+
+static mut ARGV: &[*const u8] = &[];
+static mut ENVP: &[*const u8] = &[];
+static mut AUXV: *const u64 = core::ptr::null();
+
+/// Remembers the arguments the kernel passed to `_start`.
+///
+/// # Safety
+///
+/// Must be called once, with the values of `x0`-`x3` at `_start`, before any
+/// other function of this module.
+pub unsafe fn init(argc: usize, argv: *const *const u8, envp: *const *const u8, auxv: *const u64) {
+    let mut envc = 0;
+    while !(*envp.add(envc)).is_null() {
+        envc += 1;
+    }
+
+    ARGV = slice::from_raw_parts(argv, argc);
+    ENVP = slice::from_raw_parts(envp, envc);
+    AUXV = auxv;
+}
+
+/// The NUL terminated string at `ptr`, empty if it is not UTF-8.
+unsafe fn c_str(ptr: *const u8) -> &'static str {
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    str::from_utf8(slice::from_raw_parts(ptr, len)).unwrap_or("")
+}
+
+/// The arguments of the program, starting with its name.
+pub fn args() -> impl Iterator<Item=&'static str> {
+    unsafe { ARGV.iter().map(|&ptr| c_str(ptr)) }
+}
+
+/// The environment variables of the program, each `KEY=value`.
+pub fn vars() -> impl Iterator<Item=&'static str> {
+    unsafe { ENVP.iter().map(|&ptr| c_str(ptr)) }
+}
+
+/// The value of the environment variable `key`.
+pub fn var(key: &str) -> Option<&'static str> {
+    vars().find_map(|var| {
+        let mut parts = var.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(k), Some(value)) if k == key => Some(value),
+            _ => None,
+        }
+    })
+}
+
+/// The value of the `AT_*` entry `key` of the auxiliary vector.
+pub fn auxv(key: u64) -> Option<u64> {
+    unsafe {
+        if AUXV.is_null() {
+            return None;
+        }
+
+        let mut entry = AUXV;
+        while *entry != AT_NULL {
+            if *entry == key {
+                return Some(*entry.add(1));
+            }
+            entry = entry.add(2);
+        }
+        None
+    }
+}
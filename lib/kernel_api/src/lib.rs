@@ -9,6 +9,9 @@ use shim::io;
 #[cfg(feature = "user-space")]
 pub mod syscall;
 
+#[cfg(feature = "user-space")]
+pub mod env;
+
 pub mod hypercall;
 
 #[macro_use]
@@ -30,6 +33,8 @@ pub enum OsError {
     BadAddress = 50,
     FileExists = 60,
     InvalidArgument = 70,
+    BadFileDescriptor = 80,
+    WouldBlock = 90,
 
     IoError = 101,
     IoErrorEof = 102,
@@ -57,11 +62,14 @@ impl core::convert::From<u64> for OsError {
             50 => OsError::BadAddress,
             60 => OsError::FileExists,
             70 => OsError::InvalidArgument,
+            80 => OsError::BadFileDescriptor,
+            90 => OsError::WouldBlock,
 
             101 => OsError::IoError,
             102 => OsError::IoErrorEof,
             103 => OsError::IoErrorInvalidData,
             104 => OsError::IoErrorInvalidInput,
+            105 => OsError::IoErrorTimedOut,
 
             200 => OsError::InvalidSocket,
             201 => OsError::SocketAlreadyOpen,
@@ -82,6 +90,8 @@ impl core::convert::From<io::Error> for OsError {
             io::ErrorKind::InvalidInput => OsError::IoErrorInvalidInput,
             io::ErrorKind::TimedOut => OsError::IoErrorTimedOut,
             io::ErrorKind::NotFound => OsError::NoEntry,
+            io::ErrorKind::AlreadyExists => OsError::FileExists,
+            io::ErrorKind::PermissionDenied => OsError::NoAccess,
             _ => OsError::IoError,
         }
     }
@@ -110,6 +120,197 @@ pub const NR_WRITE: usize = 4;
 pub const NR_GETPID: usize = 5;
 pub const NR_WAITPID: usize = 6;
 pub const NR_SBRK: usize = 7;
+pub const NR_OPEN: usize = 8;
+pub const NR_READ: usize = 9;
+pub const NR_WRITE_FD: usize = 10;
+pub const NR_CLOSE: usize = 11;
+pub const NR_LSEEK: usize = 12;
+pub const NR_STAT: usize = 13;
+pub const NR_READDIR: usize = 14;
+pub const NR_MKDIR: usize = 15;
+pub const NR_UNLINK: usize = 16;
+pub const NR_SPAWN: usize = 17;
+pub const NR_EXEC: usize = 18;
+pub const NR_MMAP: usize = 19;
+pub const NR_MUNMAP: usize = 20;
+pub const NR_MPROTECT: usize = 21;
+pub const NR_FORK: usize = 22;
+pub const NR_THREAD_CREATE: usize = 23;
+pub const NR_THREAD_EXIT: usize = 24;
+pub const NR_THREAD_JOIN: usize = 25;
+pub const NR_FUTEX_WAIT: usize = 26;
+pub const NR_FUTEX_WAKE: usize = 27;
+
+/*****************/
+/* file syscalls */
+/*****************/
+
+// `open` flags
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_ACCMODE: u64 = 3;
+pub const O_CREAT: u64 = 0o100;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
+
+// `lseek` origins
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// Longest path accepted by the file syscalls, in bytes.
+pub const PATH_MAX: usize = 1024;
+/// Longest file name `readdir` returns, in bytes.
+pub const NAME_MAX: usize = 255;
+
+/// Metadata of a file or directory, filled in by `stat`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Stat {
+    pub size: u64,
+    pub is_dir: bool,
+    pub read_only: bool,
+}
+
+/// A directory entry, filled in by `readdir`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct DirEnt {
+    pub stat: Stat,
+    pub name_len: u16,
+    pub name: [u8; NAME_MAX],
+}
+
+impl DirEnt {
+    /// Returns an entry named `name`, truncated to `NAME_MAX` bytes.
+    pub fn new(name: &str, stat: Stat) -> Self {
+        let mut entry = DirEnt { stat, ..DirEnt::default() };
+        let len = core::cmp::min(name.len(), NAME_MAX);
+        entry.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        entry.name_len = len as u16;
+        entry
+    }
+
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("")
+    }
+}
+
+impl Default for DirEnt {
+    fn default() -> Self {
+        DirEnt { stat: Stat::default(), name_len: 0, name: [0; NAME_MAX] }
+    }
+}
+
+impl fmt::Debug for DirEnt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirEnt")
+            .field("stat", &self.stat)
+            .field("name", &self.name())
+            .finish()
+    }
+}
+
+/*******************/
+/* memory syscalls */
+/*******************/
+
+// `mmap` and `mprotect` protection flags. Mapped pages are always readable.
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+// `mmap` flags
+/// Map exactly at the given address instead of treating it as a hint.
+pub const MAP_FIXED: u64 = 0x10;
+/// Zero-filled memory not backed by a file. The descriptor is ignored.
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/********************/
+/* process syscalls */
+/********************/
+
+/// Most arguments plus environment variables `spawn` and `exec` accept.
+pub const MAX_ARGS: usize = 64;
+/// Most bytes of arguments plus environment variables `spawn` and `exec`
+/// accept.
+pub const ARG_MAX: usize = 16 * 1024;
+
+// `spawn` stdio entries besides descriptors of the calling process
+/// The child gets the descriptor of the same number of the calling process.
+pub const FD_INHERIT: u64 = u64::max_value();
+/// The descriptor is closed in the child.
+pub const FD_NONE: u64 = u64::max_value() - 1;
+
+/// Stack size of a thread when `thread_create` is given zero, in bytes.
+pub const THREAD_STACK_SIZE: usize = 256 * 1024;
+
+// auxiliary vector entry types, see `env::auxv`
+pub const AT_NULL: u64 = 0;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+/// Pid of the process. Not defined by Linux.
+pub const AT_PID: u64 = 0x100;
+
+/// How a process ended, reported by `waitpid`, or how a thread ended,
+/// reported by `thread_join`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ExitStatus {
+    /// The process called `exit` with this code.
+    Exited(i32),
+    /// The process was killed, e.g. from the shell.
+    Killed,
+    /// The process was killed after a fault it could not handle.
+    Faulted,
+}
+
+impl ExitStatus {
+    pub fn success(&self) -> bool {
+        *self == ExitStatus::Exited(0)
+    }
+
+    /// The status as the two registers `waitpid` returns it in.
+    pub fn to_raw(&self) -> (u64, u64) {
+        match *self {
+            ExitStatus::Exited(code) => (0, code as u32 as u64),
+            ExitStatus::Killed => (1, 0),
+            ExitStatus::Faulted => (2, 0),
+        }
+    }
+
+    pub fn from_raw(kind: u64, code: u64) -> Self {
+        match kind {
+            0 => ExitStatus::Exited(code as u32 as i32),
+            2 => ExitStatus::Faulted,
+            _ => ExitStatus::Killed,
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitStatus::Exited(code) => write!(f, "exited with {}", code),
+            ExitStatus::Killed => write!(f, "killed"),
+            ExitStatus::Faulted => write!(f, "faulted"),
+        }
+    }
+}
+
+/// A string passed to the kernel, e.g. an element of `argv`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RawStr {
+    pub ptr: u64,
+    pub len: u64,
+}
+
+impl RawStr {
+    pub fn new(s: &str) -> Self {
+        RawStr { ptr: s.as_ptr() as u64, len: s.len() as u64 }
+    }
+}
 
 /**************/
 /* hypercalls */
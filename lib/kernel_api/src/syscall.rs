@@ -1,5 +1,6 @@
 use core::fmt;
 use core::fmt::Write;
+use core::sync::atomic::AtomicU32;
 use core::time::Duration;
 
 use crate::*;
@@ -28,7 +29,12 @@ pub fn time() -> Duration {
 }
 
 pub fn exit() -> ! {
-    unsafe { do_syscall0!(NR_EXIT); }
+    exit_with(0)
+}
+
+/// Ends the calling process with `code` as its exit status.
+pub fn exit_with(code: i32) -> ! {
+    unsafe { do_syscall0!(NR_EXIT, code as u64); }
     loop{}
 }
 
@@ -40,14 +46,191 @@ pub fn getpid() -> u64 {
     unsafe { do_syscall1!(NR_GETPID) }
 }
 
-pub fn waitpid(pid: u64) -> OsResult<Duration> {
-    unsafe { do_syscall1r!(NR_WAITPID, pid) }.map(|ms| Duration::from_millis(ms))
+/// Waits for process `pid` to end. Returns how long it waited and how the
+/// process ended. A child of the calling process that already ended is
+/// reported once, after waiting for no time.
+pub fn waitpid(pid: u64) -> OsResult<(Duration, ExitStatus)> {
+    unsafe { do_syscall3r!(NR_WAITPID, pid) }
+        .map(|(ms, kind, code)| (Duration::from_millis(ms), ExitStatus::from_raw(kind, code)))
 }
 
 pub fn sbrk(increment: i64) -> OsResult<*const u8> {
     unsafe { do_syscall1r!(NR_SBRK, increment as u64) }.map(|addr| addr as *const u8)
 }
 
+/// Maps `len` bytes of memory with the `PROT_*` protection `prot` and returns
+/// their address.
+///
+/// With `MAP_ANONYMOUS` in `flags` the memory is zero-filled, otherwise it is a
+/// read-only view of the file open as `fd` starting at the page aligned
+/// `offset`. `addr` is a hint unless `MAP_FIXED` is given; pass zero to let
+/// the kernel choose.
+pub fn mmap(addr: *mut u8, len: usize, prot: u64, flags: u64, fd: u64, offset: u64) -> OsResult<*mut u8> {
+    unsafe { do_syscall1r!(NR_MMAP, addr as u64, len as u64, prot, flags, fd, offset) }.map(|addr| addr as *mut u8)
+}
+
+/// Unmaps the mappings in the `len` bytes at `addr`.
+pub fn munmap(addr: *mut u8, len: usize) -> OsResult<()> {
+    unsafe { do_syscall1r!(NR_MUNMAP, addr as u64, len as u64) }.map(|_| ())
+}
+
+/// Changes the protection of the `len` mapped bytes at `addr` to `prot`.
+pub fn mprotect(addr: *mut u8, len: usize, prot: u64) -> OsResult<()> {
+    unsafe { do_syscall1r!(NR_MPROTECT, addr as u64, len as u64, prot) }.map(|_| ())
+}
+
+/// Opens the file or directory at `path` with the `O_*` flags and returns its
+/// descriptor.
+pub fn open(path: &str, flags: u64) -> OsResult<u64> {
+    unsafe { do_syscall1r!(NR_OPEN, path.as_ptr() as u64, path.len() as u64, flags) }
+}
+
+/// Reads from descriptor `fd` into `buf`. Returns the number of bytes read,
+/// zero at the end of a file.
+pub fn read(fd: u64, buf: &mut [u8]) -> OsResult<usize> {
+    unsafe { do_syscall1r!(NR_READ, fd, buf.as_mut_ptr() as u64, buf.len() as u64) }.map(|n| n as usize)
+}
+
+/// Writes `buf` to descriptor `fd` and returns the number of bytes written.
+pub fn write_fd(fd: u64, buf: &[u8]) -> OsResult<usize> {
+    unsafe { do_syscall1r!(NR_WRITE_FD, fd, buf.as_ptr() as u64, buf.len() as u64) }.map(|n| n as usize)
+}
+
+pub fn close(fd: u64) -> OsResult<()> {
+    unsafe { do_syscall1r!(NR_CLOSE, fd) }.map(|_| ())
+}
+
+/// Moves the position of `fd` to `offset` relative to `whence`, one of the
+/// `SEEK_*` origins. Returns the new position.
+pub fn lseek(fd: u64, offset: i64, whence: u64) -> OsResult<u64> {
+    unsafe { do_syscall1r!(NR_LSEEK, fd, offset as u64, whence) }
+}
+
+pub fn stat(path: &str) -> OsResult<Stat> {
+    let mut stat = Stat::default();
+    let ptr = &mut stat as *mut Stat as u64;
+    unsafe { do_syscall1r!(NR_STAT, path.as_ptr() as u64, path.len() as u64, ptr) }.map(|_| stat)
+}
+
+/// Returns the next entry of the directory open as `fd`, or `None` once all
+/// entries were returned.
+pub fn readdir(fd: u64) -> OsResult<Option<DirEnt>> {
+    let mut entry = DirEnt::default();
+    let ptr = &mut entry as *mut DirEnt as u64;
+    unsafe { do_syscall1r!(NR_READDIR, fd, ptr) }.map(|more| if more != 0 { Some(entry) } else { None })
+}
+
+pub fn mkdir(path: &str) -> OsResult<()> {
+    unsafe { do_syscall1r!(NR_MKDIR, path.as_ptr() as u64, path.len() as u64) }.map(|_| ())
+}
+
+/// Removes the file or empty directory at `path`.
+pub fn unlink(path: &str) -> OsResult<()> {
+    unsafe { do_syscall1r!(NR_UNLINK, path.as_ptr() as u64, path.len() as u64) }.map(|_| ())
+}
+
+/// Fills `raw` with the strings of `strs`.
+fn raw_strs<'a>(strs: &[&str], raw: &'a mut [RawStr; MAX_ARGS]) -> OsResult<&'a [RawStr]> {
+    if strs.len() > MAX_ARGS {
+        return Err(OsError::InvalidArgument);
+    }
+    for (raw, s) in raw.iter_mut().zip(strs.iter()) {
+        *raw = RawStr::new(s);
+    }
+    Ok(&raw[..strs.len()])
+}
+
+/// Starts the program at `path` as a new process and returns its pid, which
+/// can be passed to `waitpid`.
+///
+/// The new process gets a copy of all descriptors of the calling process.
+/// With `stdio`, its descriptors 0, 1 and 2 are instead the given descriptors
+/// of the calling process, `FD_INHERIT` or `FD_NONE`; every other descriptor
+/// is still inherited.
+pub fn spawn(path: &str, argv: &[&str], envp: &[&str], stdio: Option<[u64; 3]>) -> OsResult<u64> {
+    let (mut raw_argv, mut raw_envp) = ([RawStr::default(); MAX_ARGS], [RawStr::default(); MAX_ARGS]);
+    let argv = raw_strs(argv, &mut raw_argv)?;
+    let envp = raw_strs(envp, &mut raw_envp)?;
+    let stdio_ptr = stdio.as_ref().map(|fds| fds.as_ptr() as u64).unwrap_or(0);
+
+    unsafe {
+        do_syscall1r!(NR_SPAWN, path.as_ptr() as u64, path.len() as u64,
+            argv.as_ptr() as u64, argv.len() as u64, envp.as_ptr() as u64, envp.len() as u64, stdio_ptr)
+    }
+}
+
+/// Creates a copy of the calling process with only the calling thread.
+/// Returns the pid of the copy in the calling process, and zero in the copy.
+pub fn fork() -> OsResult<u64> {
+    unsafe { do_syscall1r!(NR_FORK) }
+}
+
+/// Starts a thread of the calling process that runs `entry(arg)` on a stack of
+/// `stack_size` bytes, or `THREAD_STACK_SIZE` if zero, and returns its id.
+///
+/// The thread shares the memory and descriptors of the process. `entry` must
+/// end the thread with `thread_exit`; returning from it faults.
+pub fn thread_create(entry: extern "C" fn(u64) -> !, arg: u64, stack_size: usize) -> OsResult<u64> {
+    unsafe { do_syscall1r!(NR_THREAD_CREATE, entry as usize as u64, arg, stack_size as u64) }
+}
+
+/// Ends the calling thread with `code` as its exit status. Ending the main
+/// thread ends the process, like `exit_with`.
+pub fn thread_exit(code: i32) -> ! {
+    unsafe { do_syscall0!(NR_THREAD_EXIT, code as u64); }
+    loop{}
+}
+
+/// Waits for thread `tid` of the calling process to end and returns how it
+/// ended. Each thread can be joined once.
+pub fn thread_join(tid: u64) -> OsResult<ExitStatus> {
+    unsafe { do_syscall2r!(NR_THREAD_JOIN, tid) }.map(|(kind, code)| ExitStatus::from_raw(kind, code))
+}
+
+/// Sleeps until the 32-bit word at `addr` is woken by `futex_wake`, or
+/// `timeout` passed, as long as the word holds `expected`. Threads of a
+/// process can wake each other; a forked copy of the process cannot.
+///
+/// Returns `OsError::WouldBlock` right away if the word does not hold
+/// `expected`, and `OsError::IoErrorTimedOut` if `timeout` passed before the
+/// word was woken.
+pub fn futex_wait(addr: &AtomicU32, expected: u32, timeout: Option<Duration>) -> OsResult<()> {
+    let ms = match timeout {
+        None => 0,
+        Some(timeout) => core::cmp::max(1, core::cmp::min(timeout.as_millis(), core::u64::MAX as u128) as u64),
+    };
+    unsafe { do_syscall0r!(NR_FUTEX_WAIT, addr as *const AtomicU32 as u64, expected as u64, ms) }
+}
+
+/// Wakes up to `count` threads sleeping in `futex_wait` on the word at
+/// `addr`, and returns how many were woken.
+pub fn futex_wake(addr: &AtomicU32, count: usize) -> OsResult<usize> {
+    unsafe { do_syscall1r!(NR_FUTEX_WAKE, addr as *const AtomicU32 as u64, count as u64) }.map(|n| n as usize)
+}
+
+/// Replaces the program of the calling process with the one at `path`,
+/// keeping its pid and descriptors. Must be called from the main thread; the
+/// other threads are ended. Only returns if that fails.
+pub fn exec(path: &str, argv: &[&str], envp: &[&str]) -> OsError {
+    let (mut raw_argv, mut raw_envp) = ([RawStr::default(); MAX_ARGS], [RawStr::default(); MAX_ARGS]);
+    let argv = match raw_strs(argv, &mut raw_argv) {
+        Ok(argv) => argv,
+        Err(e) => return e,
+    };
+    let envp = match raw_strs(envp, &mut raw_envp) {
+        Ok(envp) => envp,
+        Err(e) => return e,
+    };
+
+    let res = unsafe {
+        do_syscall0r!(NR_EXEC, path.as_ptr() as u64, path.len() as u64,
+            argv.as_ptr() as u64, argv.len() as u64, envp.as_ptr() as u64, envp.len() as u64)
+    };
+    match res {
+        Ok(()) => OsError::Unknown,
+        Err(e) => e,
+    }
+}
 
 struct Console;
 
@@ -276,8 +276,20 @@ impl<T: SchedInfo> CoreScheduler<T> {
         id
     }
 
+    /// Kills `proc`, which is not the current process.
+    fn reap(&mut self, mut proc: ProcessInfo<T>) {
+        proc.process.set_state(self.inner.info.dead_state());
+        self.inner.info.on_process_killed(*proc.process);
+    }
+
     fn switch_to(&mut self, tf: &mut T::Frame) -> Option<usize> {
         while let Some(mut proc) = self.run_queue.pop_front() {
+            // killed while it was not running, e.g. along with another process.
+            if proc.process.should_kill() {
+                self.reap(proc);
+                continue;
+            }
+
             if matches!(proc.process.get_send_to_core(), Some(_)) {
                 self.send_to_core(proc);
                 continue;
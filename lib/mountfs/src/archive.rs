@@ -0,0 +1,573 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::str;
+
+use shim::{ioerr, newioerr};
+use shim::ffi::OsStr;
+use shim::io;
+use shim::path::{Component, Path};
+
+use crate::fs::FileSystem;
+use crate::mount::{Metadata, mfs};
+use crate::mount::mfs::{Dir, FileId, FsId, INode};
+
+const ROOT_INODE: INode = 0;
+
+const TAR_BLOCK: usize = 512;
+const CPIO_HEADER: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+enum NodeKind {
+    File(&'static [u8]),
+    Dir(BTreeMap<String, INode>),
+}
+
+struct Node {
+    name: String,
+    parent: INode,
+    kind: NodeKind,
+}
+
+impl Node {
+    fn size(&self) -> u64 {
+        match &self.kind {
+            NodeKind::File(data) => data.len() as u64,
+            NodeKind::Dir(_) => 0,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.kind, NodeKind::Dir(_))
+    }
+}
+
+/// The directory tree of an archive. The inode of a node is its index.
+struct Tree {
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    fn new() -> Self {
+        Tree {
+            nodes: vec![Node { name: String::from("/"), parent: ROOT_INODE, kind: NodeKind::Dir(BTreeMap::new()) }],
+        }
+    }
+
+    fn lookup(&self, dir: INode, name: &str) -> Option<INode> {
+        match name {
+            "." => Some(dir),
+            ".." => Some(self.nodes[dir].parent),
+            _ => match &self.nodes[dir].kind {
+                NodeKind::Dir(children) => children.get(name).copied(),
+                NodeKind::File(_) => None,
+            },
+        }
+    }
+
+    /// Adds the entry at `path`, creating missing parent directories. A file
+    /// appearing twice keeps the later contents, as when extracting.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidData` if `path` contains `..`, lies inside a file or
+    /// turns a file into a directory or the other way around.
+    fn add(&mut self, path: &str, kind: NodeKind) -> io::Result<()> {
+        let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty() && *name != ".").collect();
+        if names.contains(&"..") {
+            return ioerr!(InvalidData, "archive entry path contains '..'");
+        }
+
+        let (last, parents) = match names.split_last() {
+            Some(split) => split,
+            // the archive's entry for its root directory.
+            None => return Ok(()),
+        };
+
+        let mut dir = ROOT_INODE;
+        for name in parents {
+            dir = match self.lookup(dir, name) {
+                Some(inode) if self.nodes[inode].is_dir() => inode,
+                Some(_) => return ioerr!(InvalidData, "archive entry inside a file"),
+                None => self.insert(dir, name, NodeKind::Dir(BTreeMap::new())),
+            };
+        }
+
+        match self.lookup(dir, last) {
+            Some(inode) => match (&mut self.nodes[inode].kind, kind) {
+                (NodeKind::Dir(_), NodeKind::Dir(_)) => {}
+                (NodeKind::File(existing), NodeKind::File(data)) => *existing = data,
+                _ => return ioerr!(InvalidData, "archive entry changes between file and directory"),
+            },
+            None => {
+                self.insert(dir, last, kind);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn insert(&mut self, dir: INode, name: &str, kind: NodeKind) -> INode {
+        let inode = self.nodes.len();
+        self.nodes.push(Node { name: String::from(name), parent: dir, kind });
+        if let NodeKind::Dir(children) = &mut self.nodes[dir].kind {
+            children.insert(String::from(name), inode);
+        }
+        inode
+    }
+}
+
+fn slice(data: &'static [u8], start: usize, len: usize) -> io::Result<&'static [u8]> {
+    data.get(start..start.checked_add(len).ok_or(newioerr!(InvalidData, "truncated archive"))?)
+        .ok_or(newioerr!(InvalidData, "truncated archive"))
+}
+
+fn align(value: usize, to: usize) -> usize {
+    (value + to - 1) / to * to
+}
+
+/// Parses a NUL or space terminated octal number of a tar header.
+fn parse_octal(field: &[u8]) -> io::Result<usize> {
+    let mut value: usize = 0;
+    for &c in field.iter().skip_while(|&&c| c == b' ') {
+        match c {
+            b'0'..=b'7' => value = value.checked_mul(8).ok_or(newioerr!(InvalidData, "bad tar number"))? + (c - b'0') as usize,
+            b'\0' | b' ' => break,
+            _ => return ioerr!(InvalidData, "bad tar number"),
+        }
+    }
+    Ok(value)
+}
+
+fn parse_hex(field: &[u8]) -> io::Result<u32> {
+    let field = str::from_utf8(field).map_err(|_| newioerr!(InvalidData, "bad cpio number"))?;
+    u32::from_str_radix(field, 16).map_err(|_| newioerr!(InvalidData, "bad cpio number"))
+}
+
+fn c_str(field: &[u8]) -> io::Result<&str> {
+    let end = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..end]).map_err(|_| newioerr!(InvalidData, "archive name is not valid UTF-8"))
+}
+
+fn parse_tar(data: &'static [u8], tree: &mut Tree) -> io::Result<()> {
+    let mut offset = 0;
+
+    while offset + TAR_BLOCK <= data.len() {
+        let header = &data[offset..offset + TAR_BLOCK];
+        // the archive ends with two zero blocks.
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if &header[257..262] != b"ustar" {
+            return ioerr!(InvalidData, "bad tar header");
+        }
+
+        let size = parse_octal(&header[124..136])?;
+        let name = c_str(&header[0..100])?;
+        let prefix = c_str(&header[345..500])?;
+        let contents = slice(data, offset + TAR_BLOCK, size)?;
+
+        let mut path = String::from(prefix);
+        if !path.is_empty() {
+            path.push('/');
+        }
+        path.push_str(name);
+
+        match header[156] {
+            b'0' | b'\0' => tree.add(&path, NodeKind::File(contents))?,
+            b'5' => tree.add(&path, NodeKind::Dir(BTreeMap::new()))?,
+            // links, devices and extension headers are not supported.
+            _ => {}
+        }
+
+        offset += TAR_BLOCK + align(size, TAR_BLOCK);
+    }
+
+    Ok(())
+}
+
+fn parse_cpio(data: &'static [u8], tree: &mut Tree) -> io::Result<()> {
+    let mut offset = 0;
+
+    loop {
+        let header = slice(data, offset, CPIO_HEADER)?;
+        if &header[0..6] != b"070701" && &header[0..6] != b"070702" {
+            return ioerr!(InvalidData, "bad cpio header");
+        }
+
+        let field = |i: usize| parse_hex(&header[6 + i * 8..6 + (i + 1) * 8]);
+        let mode = field(1)?;
+        let size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name = c_str(slice(data, offset + CPIO_HEADER, name_size)?)?;
+        if name == CPIO_TRAILER {
+            return Ok(());
+        }
+
+        let start = align(offset + CPIO_HEADER + name_size, 4);
+        let contents = slice(data, start, size)?;
+
+        match mode & S_IFMT {
+            S_IFREG => tree.add(name, NodeKind::File(contents))?,
+            S_IFDIR => tree.add(name, NodeKind::Dir(BTreeMap::new()))?,
+            // links and devices are not supported.
+            _ => {}
+        }
+
+        offset = align(start + size, 4);
+    }
+}
+
+fn metadata() -> Metadata {
+    Metadata {
+        read_only: Some(true),
+        hidden: Some(false),
+        ..Metadata::default()
+    }
+}
+
+/// A read-only file system serving the contents of a ustar or newc cpio
+/// archive held in memory.
+///
+/// Regular files and directories are supported, other entries are skipped.
+/// File contents are not copied out of the archive.
+pub struct ArchiveFileSystem {
+    id: FsId,
+    tree: Arc<Tree>,
+}
+
+impl ArchiveFileSystem {
+    /// Parses the archive in `data`, detecting its format from the first
+    /// header. An empty slice is an empty archive.
+    ///
+    /// # Errors
+    ///
+    /// If `data` is neither a ustar nor a newc cpio archive, or is truncated,
+    /// an error kind of `InvalidData` is returned.
+    pub fn new(data: &'static [u8]) -> io::Result<Self> {
+        let mut tree = Tree::new();
+
+        if data.starts_with(b"0707") {
+            parse_cpio(data, &mut tree)?;
+        } else {
+            parse_tar(data, &mut tree)?;
+        }
+
+        Ok(Self {
+            id: 0,
+            tree: Arc::new(tree),
+        })
+    }
+
+    fn entry(&self, inode: INode) -> mfs::Entry {
+        let node = &self.tree.nodes[inode];
+        let id = FileId(self.id, inode);
+
+        match &node.kind {
+            NodeKind::Dir(_) => mfs::Entry::Dir(Arc::new(ArchiveDir { id, tree: self.tree.clone() })),
+            NodeKind::File(data) => mfs::Entry::File(Box::new(ArchiveFile {
+                id,
+                tree: self.tree.clone(),
+                data,
+                position: 0,
+            })),
+        }
+    }
+
+    fn dir_inode(&self, dir: &Arc<dyn Dir>) -> io::Result<INode> {
+        let dir: &ArchiveDir = dir.downcast_ref().ok_or(newioerr!(InvalidInput, "[archive] bad directory handle"))?;
+        Ok(dir.id.1)
+    }
+}
+
+impl mfs::FileSystem for ArchiveFileSystem {
+    fn set_id(&mut self, id: FsId) {
+        self.id = id;
+    }
+
+    fn get_name(&self) -> Option<String> {
+        Some(String::from("archive"))
+    }
+
+    fn open(&self, _manager: &FileSystem, path: &Path) -> io::Result<mfs::Entry> {
+        let mut inode = ROOT_INODE;
+
+        for component in path.components() {
+            match component {
+                Component::RootDir => inode = ROOT_INODE,
+                Component::CurDir => {}
+                Component::ParentDir => inode = self.tree.nodes[inode].parent,
+                Component::Normal(name) => {
+                    let name = name.to_str().ok_or(newioerr!(NotFound, "no such archive entry"))?;
+                    inode = self.tree.lookup(inode, name).ok_or(newioerr!(NotFound, "no such archive entry"))?;
+                }
+                _ => return ioerr!(InvalidInput, "unexpected path item"),
+            }
+        }
+
+        Ok(self.entry(inode))
+    }
+
+    fn entries(&self, _manager: &FileSystem, dir: Arc<dyn Dir>) -> io::Result<Box<dyn Iterator<Item=mfs::DirEntry>>> {
+        let inode = self.dir_inode(&dir)?;
+
+        let mut entries = Vec::new();
+        if let NodeKind::Dir(children) = &self.tree.nodes[inode].kind {
+            for (name, &child) in children.iter() {
+                let node = &self.tree.nodes[child];
+                entries.push(mfs::DirEntry::new(name.clone(), metadata(), node.size(), node.is_dir(), FileId(self.id, child)));
+            }
+        }
+
+        Ok(Box::new(entries.into_iter()))
+    }
+
+    fn dir_entry(&self, _manager: &FileSystem, dir: Arc<dyn Dir>, path: &OsStr) -> io::Result<mfs::Entry> {
+        let inode = self.dir_inode(&dir)?;
+
+        let name = path.to_str().ok_or(newioerr!(NotFound, "no such archive entry"))?;
+        let child = self.tree.lookup(inode, name).ok_or(newioerr!(NotFound, "no such archive entry"))?;
+        Ok(self.entry(child))
+    }
+}
+
+struct ArchiveDir {
+    id: FileId,
+    tree: Arc<Tree>,
+}
+
+impl mfs::FileInfo for ArchiveDir {
+    fn name(&self) -> &str {
+        self.tree.nodes[self.id.1].name.as_str()
+    }
+
+    fn metadata(&self) -> Metadata {
+        metadata()
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn is_directory(&self) -> bool {
+        true
+    }
+
+    fn get_id(&self) -> FileId {
+        self.id
+    }
+}
+
+impl mfs::Dir for ArchiveDir {}
+
+struct ArchiveFile {
+    id: FileId,
+    tree: Arc<Tree>,
+    data: &'static [u8],
+    position: u64,
+}
+
+impl mfs::FileInfo for ArchiveFile {
+    fn name(&self) -> &str {
+        self.tree.nodes[self.id.1].name.as_str()
+    }
+
+    fn metadata(&self) -> Metadata {
+        metadata()
+    }
+
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn is_directory(&self) -> bool {
+        false
+    }
+
+    fn get_id(&self) -> FileId {
+        self.id
+    }
+}
+
+impl io::Read for ArchiveFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.data.len() as u64 {
+            return Ok(0);
+        }
+
+        let start = self.position as usize;
+        let amount = min(self.data.len() - start, buf.len());
+        buf[..amount].copy_from_slice(&self.data[start..start + amount]);
+        self.position += amount as u64;
+        Ok(amount)
+    }
+}
+
+impl io::Write for ArchiveFile {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        ioerr!(PermissionDenied, "read-only file system")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Seek for ArchiveFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            io::SeekFrom::Start(offset) => offset as i64,
+            io::SeekFrom::End(offset) => self.data.len() as i64 + offset,
+            io::SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+
+        if position < 0 {
+            return ioerr!(InvalidInput, "cannot seek before start of file");
+        }
+
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+impl mfs::File for ArchiveFile {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use shim::io::{self, Read, Write};
+
+    use crate::fs::FileSystem;
+    use crate::test_util::{mount, names};
+
+    use super::ArchiveFileSystem;
+
+    fn tar_entry(archive: &mut Vec<u8>, name: &str, kind: u8, data: &[u8]) {
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize((archive.len() + 511) / 512 * 512, 0);
+    }
+
+    fn cpio_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let fields = [0, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        archive.extend_from_slice(b"070701");
+        for field in fields.iter() {
+            archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize((archive.len() + 3) / 4 * 4, 0);
+        archive.extend_from_slice(data);
+        archive.resize((archive.len() + 3) / 4 * 4, 0);
+    }
+
+    fn mounted(archive: Vec<u8>) -> FileSystem {
+        let data: &'static [u8] = Box::leak(archive.into_boxed_slice());
+        mount(Box::new(ArchiveFileSystem::new(data).unwrap()))
+    }
+
+    fn contents(fs: &mut FileSystem, path: &str) -> String {
+        let mut file = fs.open(path).unwrap().into_file().unwrap();
+        let mut data = String::new();
+        file.read_to_string(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_archive_tar() {
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "./", b'5', b"");
+        tar_entry(&mut archive, "./bin/", b'5', b"");
+        tar_entry(&mut archive, "./bin/init", b'0', &[0x7f; 700]);
+        tar_entry(&mut archive, "./etc/motd", b'0', b"hello");
+        tar_entry(&mut archive, "./link", b'2', b"");
+        archive.resize(archive.len() + 1024, 0);
+
+        let mut fs = mounted(archive);
+        assert_eq!(names(&mut fs, "/"), ["bin", "etc"]);
+        assert_eq!(names(&mut fs, "/etc"), ["motd"]);
+        assert_eq!(contents(&mut fs, "/etc/motd"), "hello");
+
+        let mut init = fs.open("/bin/init").unwrap().into_file().unwrap();
+        let mut data = Vec::new();
+        init.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [0x7f; 700].as_ref());
+        assert_eq!(init.write(b"x").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(fs.create_file("/new").err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_archive_cpio() {
+        let mut archive = Vec::new();
+        cpio_entry(&mut archive, ".", 0o040755, b"");
+        cpio_entry(&mut archive, "bin", 0o040755, b"");
+        cpio_entry(&mut archive, "bin/fib", 0o100755, b"ELF!");
+        cpio_entry(&mut archive, "etc/rc", 0o100644, b"run fib 30\n");
+        cpio_entry(&mut archive, "TRAILER!!!", 0, b"");
+
+        let mut fs = mounted(archive);
+        assert_eq!(names(&mut fs, "/"), ["bin", "etc"]);
+        assert_eq!(contents(&mut fs, "/bin/fib"), "ELF!");
+        assert_eq!(contents(&mut fs, "/etc/rc"), "run fib 30\n");
+        assert_eq!(fs.open("/etc/missing").err().unwrap().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_archive_invalid() {
+        assert!(ArchiveFileSystem::new(&[]).is_ok());
+        assert!(ArchiveFileSystem::new(&[1; 512]).is_err());
+        assert!(ArchiveFileSystem::new(b"070701").is_err());
+    }
+
+    #[test]
+    fn test_archive_bad_paths() {
+        fn load(entries: &[(&str, u8, &[u8])]) -> io::Result<ArchiveFileSystem> {
+            let mut archive = Vec::new();
+            for (name, kind, data) in entries.iter() {
+                tar_entry(&mut archive, name, *kind, data);
+            }
+            archive.resize(archive.len() + 1024, 0);
+            ArchiveFileSystem::new(Box::leak(archive.into_boxed_slice()))
+        }
+
+        for path in ["a/..", "x/../y", "../escape"].iter() {
+            let err = load(&[(path, b'0', b"data")]).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", path);
+        }
+
+        let err = load(&[("dir/", b'5', b""), ("dir/file", b'0', b"x"), ("dir", b'0', b"y")]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = load(&[("file", b'0', b"x"), ("file/", b'5', b"")]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let archive = load(&[("file", b'0', b"old"), ("file", b'0', b"new")]).unwrap();
+        let mut fs = mount(Box::new(archive));
+        assert_eq!(contents(&mut fs, "/file"), "new");
+    }
+}
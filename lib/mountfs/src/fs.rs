@@ -2,25 +2,56 @@ use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use hashbrown::HashMap;
 
-use shim::{io, ioerr};
+use shim::{io, ioerr, newioerr};
 use shim::{path::Path, path::PathBuf};
 use shim::ffi::OsStr;
 use shim::path::Component;
 
-use crate::mount::mfs;
+use crate::mount::{Metadata, mfs};
 use crate::mount::mfs::{FileId, FsId};
 
+/// Options a file system is mounted with.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MountOptions {
+    /// Refuse to create, remove or write to files.
+    pub read_only: bool,
+}
+
+/// State shared between a mount and the files opened through it. The number
+/// of references tells whether the mount is busy.
+struct MountState {
+    read_only: AtomicBool,
+}
+
 pub(crate) struct Mount {
     pub path: PathBuf,
     pub delegate: Box<dyn mfs::FileSystem>,
+    state: Arc<MountState>,
+    /// Set once the mount was lazily unmounted. The file system is dropped
+    /// when its last open file is closed.
+    detached: bool,
+}
+
+impl Mount {
+    fn is_busy(&self) -> bool {
+        Arc::strong_count(&self.state) > 1
+    }
+
+    /// Wraps a file opened through this mount so that it counts towards the
+    /// mount being busy and follows its read-only flag.
+    fn track(&self, file: Box<dyn mfs::File>) -> Box<dyn mfs::File> {
+        Box::new(MountedFile { inner: file, state: self.state.clone() })
+    }
 }
 
 pub struct MountInfo {
     pub path: PathBuf,
     pub fs_name: Option<String>,
+    pub read_only: bool,
 }
 
 pub struct FileSystem {
@@ -38,63 +69,256 @@ impl FileSystem {
         }
     }
 
+    /// Lists the attached mounts. Lazily unmounted file systems are left out.
     pub fn get_mounts(&self) -> Vec<MountInfo> {
         let mut mounts: Vec<MountInfo> = Vec::new();
-        for mount in self.filesystems.values() {
+        for mount in self.filesystems.values().filter(|m| !m.detached) {
             mounts.push(MountInfo {
                 path: mount.path.clone(),
                 fs_name: mount.delegate.get_name(),
+                read_only: mount.state.read_only.load(Ordering::Relaxed),
             })
         }
         mounts
     }
 
-    pub fn mount(&mut self, path: Option<&dyn AsRef<Path>>, mut delegate: Box<dyn mfs::FileSystem>) -> io::Result<()> {
+    pub fn mount(&mut self, path: Option<&dyn AsRef<Path>>, delegate: Box<dyn mfs::FileSystem>) -> io::Result<()> {
+        self.mount_with(path, delegate, MountOptions::default())
+    }
+
+    /// Mounts `delegate` at `path`, or as the root file system if `path` is
+    /// `None`. Nothing is changed if mounting fails.
+    ///
+    /// # Errors
+    ///
+    /// If `path` is not a directory, an error kind of `InvalidInput` is
+    /// returned. If a file system is already mounted at `path`, an error kind
+    /// of `AlreadyExists` is returned.
+    pub fn mount_with(&mut self, path: Option<&dyn AsRef<Path>>, mut delegate: Box<dyn mfs::FileSystem>, options: MountOptions) -> io::Result<()> {
+        self.reap_detached();
+
         let fs_id = (self.fs_id) as FsId;
         self.fs_id += 1;
 
         delegate.set_id(fs_id);
 
+        // the mount is added before looking up its mount point, as file
+        // systems like the meta file system create mount points on demand.
         self.filesystems.insert(fs_id, Mount {
             path: path.as_ref().map(|p| p.as_ref().to_path_buf()).unwrap_or(PathBuf::from("/")),
             delegate,
+            state: Arc::new(MountState { read_only: AtomicBool::new(options.read_only) }),
+            detached: false,
         });
 
+        match self.mount_point(path) {
+            Ok(mount_id) => {
+                self.mounts.insert(mount_id, fs_id);
+                Ok(())
+            }
+            Err(e) => {
+                self.filesystems.remove(&fs_id);
+                Err(e)
+            }
+        }
+    }
+
+    /// Looks up the id of the directory a file system is to be mounted on.
+    fn mount_point(&mut self, path: Option<&dyn AsRef<Path>>) -> io::Result<Option<FileId>> {
         let mount_id = match path {
-            Some(path) => Some(self.open(path.as_ref())?.get_id()),
+            Some(path) => match self.open(path.as_ref())? {
+                mfs::Entry::Dir(dir) => Some(dir.get_id()),
+                mfs::Entry::File(_) => return ioerr!(InvalidInput, "mount point is not a directory"),
+            },
             None => None,
         };
 
-        self.mounts.insert(mount_id, fs_id);
+        if self.mounts.contains_key(&mount_id) {
+            return ioerr!(AlreadyExists, "a file system is already mounted there");
+        }
+
+        Ok(mount_id)
+    }
+
+    /// Unmounts the file system mounted at `path` after syncing it.
+    ///
+    /// If files opened through the mount are still open, the mount is busy.
+    /// A busy mount is detached anyway when `lazy` is set: it disappears from
+    /// the tree right away and is dropped once its last file is closed.
+    ///
+    /// # Errors
+    ///
+    /// If no file system is mounted at `path`, an error kind of
+    /// `InvalidInput` is returned. The root file system, and file systems
+    /// with others mounted inside them, cannot be unmounted and an error kind
+    /// of `PermissionDenied` is returned. A busy mount that is not unmounted
+    /// lazily results in an error kind of `Other`.
+    pub fn unmount<P: AsRef<Path>>(&mut self, path: P, lazy: bool) -> io::Result<()> {
+        self.reap_detached();
+
+        let mount_id = self.open(path.as_ref())?.get_id();
+        let fs_id = match self.mounts.get(&Some(mount_id)) {
+            Some(fs_id) => *fs_id,
+            None if self.root_dir()?.get_id() == mount_id => return ioerr!(PermissionDenied, "cannot unmount the root file system"),
+            None => return ioerr!(InvalidInput, "not a mount point"),
+        };
+
+        if self.mounts.keys().any(|key| matches!(key, Some(FileId(id, _)) if *id == fs_id)) {
+            return ioerr!(PermissionDenied, "other file systems are mounted inside");
+        }
 
-        // TODO if there is a mounting error, remove filesystem from self.filesystems
+        let mount = self.filesystems.get_mut(&fs_id).unwrap();
+        if mount.is_busy() {
+            if !lazy {
+                return ioerr!(Other, "file system is busy");
+            }
+            mount.detached = true;
+        } else {
+            mount.delegate.sync()?;
+            self.filesystems.remove(&fs_id);
+        }
 
+        self.mounts.remove(&Some(mount_id));
         Ok(())
     }
 
-    pub fn entries(&self, dir: Arc<dyn mfs::Dir>) -> io::Result<Box<dyn Iterator<Item=mfs::DirEntry>>> {
-        let file_id = dir.get_id();
+    /// Changes the options of the file system mounted at `path`. Files that
+    /// are already open follow the new options too.
+    pub fn remount<P: AsRef<Path>>(&mut self, path: P, options: MountOptions) -> io::Result<()> {
+        let mount_id = self.open(path.as_ref())?.get_id();
+        let fs_id = match self.mounts.get(&Some(mount_id)) {
+            Some(fs_id) => *fs_id,
+            None if self.root_dir()?.get_id() == mount_id => mount_id.0,
+            None => return ioerr!(InvalidInput, "not a mount point"),
+        };
 
-        if let Some(mounted_id) = self.mounts.get(&Some(file_id)) {
-            let fs = self.filesystems.get(mounted_id).unwrap();
-            let root = fs.delegate.open(self, &Path::new("/"))?.into_dir().expect("expected root to be a dir");
-            fs.delegate.entries(self, root)
-        } else {
-            let fs = self.filesystems.get(&file_id.0).unwrap();
-            fs.delegate.entries(self, dir)
+        let mount = self.filesystems.get(&fs_id).unwrap();
+        if options.read_only {
+            mount.delegate.sync()?;
         }
+        mount.state.read_only.store(options.read_only, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Drops lazily unmounted file systems without open files.
+    fn reap_detached(&mut self) {
+        let idle: Vec<FsId> = self.filesystems.iter()
+            .filter(|(_, mount)| mount.detached && !mount.is_busy())
+            .map(|(id, _)| *id)
+            .collect();
+
+        for fs_id in idle {
+            let mount = self.filesystems.remove(&fs_id).unwrap();
+            if let Err(e) = mount.delegate.sync() {
+                error!("failed to sync detached file system at {:?}: {:?}", mount.path, e);
+            }
+        }
+    }
+
+    /// Syncs every mounted file system. All file systems are synced even if
+    /// one of them fails; the first error is returned.
+    pub fn sync(&self) -> io::Result<()> {
+        let mut result = Ok(());
+        for mount in self.filesystems.values() {
+            if let Err(e) = mount.delegate.sync() {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    pub fn entries(&self, dir: Arc<dyn mfs::Dir>) -> io::Result<Box<dyn Iterator<Item=mfs::DirEntry>>> {
+        let (mount, dir) = self.owner(dir)?;
+        mount.delegate.entries(self, dir)
     }
 
     pub fn dir_entry(&self, dir: Arc<dyn mfs::Dir>, path: &OsStr) -> io::Result<mfs::Entry> {
+        let (mount, dir) = self.owner(dir)?;
+        match mount.delegate.dir_entry(self, dir, path)? {
+            mfs::Entry::File(file) => Ok(mfs::Entry::File(mount.track(file))),
+            dir => Ok(dir),
+        }
+    }
+
+    /// Creates an empty file at `path` and returns it.
+    ///
+    /// # Errors
+    ///
+    /// If the parent of `path` is not a directory, an error kind of
+    /// `InvalidInput` is returned. All other errors come from the file system
+    /// owning the parent directory.
+    pub fn create_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<Box<dyn mfs::File>> {
+        let (dir, name) = self.parent_dir(path.as_ref())?;
+        let (mount, dir) = self.writable_owner(dir)?;
+        let file = mount.delegate.create_file(self, dir, name)?;
+        Ok(mount.track(file))
+    }
+
+    /// Creates an empty directory at `path` and returns it.
+    ///
+    /// # Errors
+    ///
+    /// As for `create_file()`.
+    pub fn create_dir<P: AsRef<Path>>(&mut self, path: P) -> io::Result<Arc<dyn mfs::Dir>> {
+        let (dir, name) = self.parent_dir(path.as_ref())?;
+        let (mount, dir) = self.writable_owner(dir)?;
+        mount.delegate.create_dir(self, dir, name)
+    }
+
+    /// Removes the file or empty directory at `path`.
+    ///
+    /// # Errors
+    ///
+    /// If a file system is mounted at `path`, an error kind of
+    /// `PermissionDenied` is returned. Otherwise as for `create_file()`.
+    pub fn remove<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let id = self.open(path.as_ref())?.get_id();
+        if self.mounts.contains_key(&Some(id)) {
+            return ioerr!(PermissionDenied, "cannot remove a mount point");
+        }
+
+        let (dir, name) = self.parent_dir(path.as_ref())?;
+        let (mount, dir) = self.writable_owner(dir)?;
+        mount.delegate.remove(self, dir, name)
+    }
+
+    /// Returns the mount serving the contents of `dir` together with the
+    /// directory handle its file system expects. A directory another file
+    /// system is mounted on is served by the root of the mounted file system.
+    fn owner(&self, dir: Arc<dyn mfs::Dir>) -> io::Result<(&Mount, Arc<dyn mfs::Dir>)> {
         let file_id = dir.get_id();
 
         if let Some(mounted_id) = self.mounts.get(&Some(file_id)) {
-            let fs = self.filesystems.get(mounted_id).unwrap();
-            let root = fs.delegate.open(self, &Path::new("/"))?.into_dir().expect("expected root to be a dir");
-            fs.delegate.dir_entry(self, root, path)
+            let mount = self.filesystems.get(mounted_id).unwrap();
+            let root = mount.delegate.open(self, &Path::new("/"))?.into_dir().expect("expected root to be a dir");
+            Ok((mount, root))
         } else {
-            let fs = self.filesystems.get(&file_id.0).unwrap();
-            fs.delegate.dir_entry(self, dir, path)
+            let mount = self.filesystems.get(&file_id.0).ok_or(newioerr!(NotFound, "file system was unmounted"))?;
+            Ok((mount, dir))
+        }
+    }
+
+    /// As `owner()`, but fails with `PermissionDenied` if the mount is
+    /// read-only.
+    fn writable_owner(&self, dir: Arc<dyn mfs::Dir>) -> io::Result<(&Mount, Arc<dyn mfs::Dir>)> {
+        let (mount, dir) = self.owner(dir)?;
+        if mount.state.read_only.load(Ordering::Relaxed) {
+            return ioerr!(PermissionDenied, "read-only file system");
+        }
+        Ok((mount, dir))
+    }
+
+    /// Opens the parent directory of `path` and returns it with the last
+    /// component of `path`.
+    fn parent_dir<'a>(&mut self, path: &'a Path) -> io::Result<(Arc<dyn mfs::Dir>, &'a OsStr)> {
+        let name = path.file_name().ok_or(newioerr!(InvalidInput, "path has no file name"))?;
+        let parent = path.parent().ok_or(newioerr!(InvalidInput, "path has no parent"))?;
+
+        match self.open(parent)? {
+            mfs::Entry::Dir(dir) => Ok((dir, name)),
+            mfs::Entry::File(_) => ioerr!(InvalidInput, "parent is not a directory"),
         }
     }
 
@@ -123,9 +347,196 @@ impl FileSystem {
     }
 }
 
+struct MountedFile {
+    inner: Box<dyn mfs::File>,
+    state: Arc<MountState>,
+}
 
+impl MountedFile {
+    fn check_writable(&self) -> io::Result<()> {
+        if self.state.read_only.load(Ordering::Relaxed) {
+            return ioerr!(PermissionDenied, "read-only file system");
+        }
+        Ok(())
+    }
+}
 
+impl mfs::FileInfo for MountedFile {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
 
+    fn metadata(&self) -> Metadata {
+        self.inner.metadata()
+    }
 
+    fn size(&self) -> u64 {
+        mfs::File::size(self.inner.as_ref())
+    }
 
+    fn is_directory(&self) -> bool {
+        false
+    }
+
+    fn get_id(&self) -> FileId {
+        self.inner.get_id()
+    }
+}
 
+impl io::Read for MountedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl io::Write for MountedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_writable()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl io::Seek for MountedFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl mfs::File for MountedFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn size(&self) -> u64 {
+        mfs::File::size(self.inner.as_ref())
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.check_writable()?;
+        self.inner.set_len(size)
+    }
+
+    fn inner_file(&self) -> Option<&(dyn mfs::File + 'static)> {
+        Some(self.inner.as_ref())
+    }
+
+    fn inner_file_mut(&mut self) -> Option<&mut (dyn mfs::File + 'static)> {
+        Some(self.inner.as_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use shim::io::{self, Write};
+
+    use crate::TmpFileSystem;
+    use crate::test_util::{mount, names};
+    use crate::tmp::TmpFile;
+
+    use super::{FileSystem, MountOptions};
+
+    fn tmpfs() -> Box<TmpFileSystem> {
+        Box::new(TmpFileSystem::new(1024, 16))
+    }
+
+    fn mounted() -> FileSystem {
+        let mut fs = mount(tmpfs());
+        fs.create_dir("/mnt").unwrap();
+        fs.create_file("/file").unwrap();
+        fs
+    }
+
+    #[test]
+    fn test_mount_rollback() {
+        let mut fs = mounted();
+
+        assert_eq!(fs.mount(Some(&"/missing"), tmpfs()).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(fs.mount(Some(&"/file"), tmpfs()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        fs.mount(Some(&"/mnt"), tmpfs()).unwrap();
+        assert_eq!(fs.mount(Some(&"/mnt"), tmpfs()).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        assert_eq!(fs.filesystems.len(), 2);
+        assert_eq!(fs.get_mounts().len(), 2);
+    }
+
+    #[test]
+    fn test_unmount() {
+        let mut fs = mounted();
+        fs.mount(Some(&"/mnt"), tmpfs()).unwrap();
+        fs.create_file("/mnt/inner").unwrap();
+
+        assert_eq!(names(&mut fs, "/mnt"), ["inner"]);
+        fs.unmount("/mnt", false).unwrap();
+        assert!(names(&mut fs, "/mnt").is_empty());
+
+        assert_eq!(fs.unmount("/mnt", false).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(fs.unmount("/", false).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        fs.mount(Some(&"/mnt"), tmpfs()).unwrap();
+        fs.create_dir("/mnt/nested").unwrap();
+        fs.mount(Some(&"/mnt/nested"), tmpfs()).unwrap();
+        assert_eq!(fs.unmount("/mnt", false).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        fs.unmount("/mnt/nested", false).unwrap();
+        fs.unmount("/mnt", false).unwrap();
+        assert_eq!(fs.filesystems.len(), 1);
+    }
+
+    #[test]
+    fn test_unmount_busy_and_lazy() {
+        let mut fs = mounted();
+        fs.mount(Some(&"/mnt"), tmpfs()).unwrap();
+        let mut file = fs.create_file("/mnt/open").unwrap();
+
+        assert_eq!(fs.unmount("/mnt", false).unwrap_err().kind(), io::ErrorKind::Other);
+        fs.unmount("/mnt", true).unwrap();
+        assert!(names(&mut fs, "/mnt").is_empty());
+        assert_eq!(fs.get_mounts().len(), 1);
+
+        // the detached file system stays usable through open files.
+        file.write_all(b"still here").unwrap();
+        assert_eq!(fs.filesystems.len(), 2);
+
+        drop(file);
+        fs.mount(Some(&"/mnt"), tmpfs()).unwrap();
+        assert_eq!(fs.filesystems.len(), 2);
+    }
+
+    #[test]
+    fn test_remount_read_only() {
+        let mut fs = mounted();
+        fs.mount(Some(&"/mnt"), tmpfs()).unwrap();
+        let mut file = fs.create_file("/mnt/file").unwrap();
+
+        fs.remount("/mnt", MountOptions { read_only: true }).unwrap();
+        assert!(fs.get_mounts().iter().any(|m| m.read_only));
+        assert_eq!(file.write(b"x").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(fs.create_file("/mnt/other").err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(fs.remove("/mnt/file").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        // the root file system is not affected.
+        fs.create_file("/other").unwrap();
+
+        fs.remount("/mnt", MountOptions::default()).unwrap();
+        file.write_all(b"x").unwrap();
+        fs.remove("/mnt/file").unwrap();
+    }
+
+    #[test]
+    fn test_downcast_mounted_file() {
+        let mut fs = mounted();
+        let mut file = fs.open("/file").unwrap().into_file().unwrap();
+
+        assert!(!file.is::<TmpFile>());
+        assert!(file.downcast_file_ref::<TmpFile>().is_some());
+        assert!(file.downcast_file_mut::<TmpFile>().is_some());
+        assert!(file.downcast_file_ref::<super::MountedFile>().is_some());
+    }
+}
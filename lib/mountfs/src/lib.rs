@@ -14,13 +14,24 @@ extern crate downcast_rs;
 #[macro_use]
 extern crate log;
 
+pub(crate) mod archive;
 pub mod fs;
 pub(crate) mod meta;
 pub mod mount;
+pub(crate) mod ninep;
 pub(crate) mod null;
+pub(crate) mod overlay;
+pub(crate) mod tmp;
 
+#[cfg(test)]
+pub(crate) mod test_util;
+
+pub use archive::ArchiveFileSystem;
 pub use null::NullFileSystem;
 pub use meta::MetaFileSystem;
+pub use ninep::NinePFileSystem;
+pub use overlay::OverlayFileSystem;
+pub use tmp::TmpFileSystem;
 
 #[cfg(test)]
 mod tests {
@@ -1,7 +1,7 @@
 use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::sync::Arc;
-use shim::{io, path::Path};
+use shim::{io, ioerr, path::Path};
 use crate::mount::Metadata;
 use crate::fs;
 use downcast_rs::{Downcast, DowncastSync};
@@ -34,10 +34,49 @@ pub trait File: FileInfo + io::Read + io::Write + io::Seek + Downcast {
 
     /// Returns the size of the file in bytes.
     fn size(&self) -> u64;
+
+    /// Truncates or extends the file to `size` bytes.
+    ///
+    /// # Errors
+    ///
+    /// The default implementation returns an error of `PermissionDenied`.
+    fn set_len(&mut self, _size: u64) -> io::Result<()> {
+        ioerr!(PermissionDenied, "file cannot be resized")
+    }
+
+    /// Returns the file this one wraps, if it only adds behaviour on top of
+    /// another file. `downcast_file_ref()` looks through such wrappers.
+    fn inner_file(&self) -> Option<&(dyn File + 'static)> {
+        None
+    }
+
+    /// Mutable version of `inner_file()`.
+    fn inner_file_mut(&mut self) -> Option<&mut (dyn File + 'static)> {
+        None
+    }
 }
 
 impl_downcast!(File);
 
+impl dyn File {
+    /// Like `downcast_ref()`, but also finds a `T` wrapped by this file, such
+    /// as a file opened through `fs::FileSystem`.
+    pub fn downcast_file_ref<T: File>(&self) -> Option<&T> {
+        match self.downcast_ref::<T>() {
+            Some(file) => Some(file),
+            None => self.inner_file()?.downcast_file_ref(),
+        }
+    }
+
+    /// Like `downcast_mut()`, but also finds a `T` wrapped by this file.
+    pub fn downcast_file_mut<T: File>(&mut self) -> Option<&mut T> {
+        if self.is::<T>() {
+            return self.downcast_mut();
+        }
+        self.inner_file_mut()?.downcast_file_mut()
+    }
+}
+
 /// Trait implemented by directories in a file system.
 pub trait Dir: FileInfo + DowncastSync {
 
@@ -216,6 +255,44 @@ pub trait FileSystem: Send {
     /// get entry named `path` in `dir`.
     fn dir_entry(&self, manager: &fs::FileSystem, dir: Arc<dyn Dir>, path: &OsStr) -> io::Result<Entry>;
 
+    /// Writes any data the file system buffers in memory to its backing store.
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Creates an empty file named `name` in `dir`.
+    ///
+    /// # Errors
+    ///
+    /// If an entry named `name` already exists, an error kind of
+    /// `AlreadyExists` is returned. The default implementation returns an
+    /// error kind of `PermissionDenied`.
+    fn create_file(&self, _manager: &fs::FileSystem, _dir: Arc<dyn Dir>, _name: &OsStr) -> io::Result<Box<dyn File>> {
+        ioerr!(PermissionDenied, "read-only file system")
+    }
+
+    /// Creates an empty directory named `name` in `dir`.
+    ///
+    /// # Errors
+    ///
+    /// If an entry named `name` already exists, an error kind of
+    /// `AlreadyExists` is returned. The default implementation returns an
+    /// error kind of `PermissionDenied`.
+    fn create_dir(&self, _manager: &fs::FileSystem, _dir: Arc<dyn Dir>, _name: &OsStr) -> io::Result<Arc<dyn Dir>> {
+        ioerr!(PermissionDenied, "read-only file system")
+    }
+
+    /// Removes the file or empty directory named `name` from `dir`.
+    ///
+    /// # Errors
+    ///
+    /// If there is no entry named `name`, an error kind of `NotFound` is
+    /// returned. The default implementation returns an error kind of
+    /// `PermissionDenied`.
+    fn remove(&self, _manager: &fs::FileSystem, _dir: Arc<dyn Dir>, _name: &OsStr) -> io::Result<()> {
+        ioerr!(PermissionDenied, "read-only file system")
+    }
+
 }
 
 
@@ -0,0 +1,1072 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use hashbrown::HashMap;
+use spin::Mutex;
+
+use shim::{ioerr, newioerr};
+use shim::ffi::OsStr;
+use shim::io;
+use shim::path::{Component, Path};
+
+use crate::fs::FileSystem;
+use crate::mount::{Metadata, mfs};
+use crate::mount::mfs::{Dir, FileId, FsId};
+
+const VERSION: &str = "9P2000.L";
+
+const MSIZE: u32 = 8192;
+const NOTAG: u16 = !0;
+const NOFID: u32 = !0;
+/// Bytes of a Tread/Rwrite message besides the data.
+const IOHDRSZ: u32 = 24;
+
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TMKDIR: u8 = 72;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+const QTDIR: u8 = 0x80;
+
+const O_RDONLY: u32 = 0;
+const O_RDWR: u32 = 2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const AT_REMOVEDIR: u32 = 0x200;
+
+const GETATTR_BASIC: u64 = 0x7ff;
+const SETATTR_SIZE: u32 = 0x8;
+
+/// Walks are split into requests of at most this many names.
+const MAXWELEM: usize = 16;
+
+fn malformed<T>() -> io::Result<T> {
+    ioerr!(InvalidData, "malformed 9p message")
+}
+
+/// Maps the Linux errno of an Rlerror to an error.
+fn errno_to_error(errno: u32) -> io::Error {
+    match errno {
+        2 => newioerr!(NotFound, "no such file or directory"),
+        1 | 13 | 30 => newioerr!(PermissionDenied, "permission denied"),
+        17 => newioerr!(AlreadyExists, "file already exists"),
+        20 => newioerr!(InvalidInput, "not a directory"),
+        21 => newioerr!(InvalidInput, "is a directory"),
+        22 => newioerr!(InvalidInput, "invalid argument"),
+        28 => newioerr!(Other, "no space left on device"),
+        39 => newioerr!(Other, "directory not empty"),
+        _ => newioerr!(Other, "9p server error"),
+    }
+}
+
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn new(ty: u8, tag: u16) -> Self {
+        let mut e = Encoder(Vec::new());
+        e.u32(0).u8(ty).u16(tag);
+        e
+    }
+
+    fn u8(&mut self, v: u8) -> &mut Self {
+        self.0.push(v);
+        self
+    }
+
+    fn u16(&mut self, v: u16) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn str(&mut self, v: &str) -> &mut Self {
+        self.u16(v.len() as u16);
+        self.0.extend_from_slice(v.as_bytes());
+        self
+    }
+
+    fn bytes(&mut self, v: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(v);
+        self
+    }
+
+    /// Returns the message with its size filled in.
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.0.len() as u32;
+        self.0[..4].copy_from_slice(&size.to_le_bytes());
+        self.0
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return malformed();
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let mut b = [0u8; 2];
+        b.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(b))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    fn str(&mut self) -> io::Result<&'a str> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.take(len)?).or_else(|_| malformed())
+    }
+
+    fn qid(&mut self) -> io::Result<Qid> {
+        let ty = self.u8()?;
+        let _version = self.u32()?;
+        Ok(Qid { ty, path: self.u64()? })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Qid {
+    ty: u8,
+    path: u64,
+}
+
+impl Qid {
+    fn is_dir(&self) -> bool {
+        self.ty & QTDIR != 0
+    }
+}
+
+struct Receiver {
+    reader: Box<dyn io::Read + Send>,
+    /// Responses read on behalf of other requests, by tag.
+    pending: HashMap<u16, Vec<u8>>,
+}
+
+impl Receiver {
+    /// Reads the next message and returns its tag and the whole message.
+    fn next(&mut self) -> io::Result<(u16, Vec<u8>)> {
+        let mut size = [0u8; 4];
+        self.reader.read_exact(&mut size)?;
+        let size = u32::from_le_bytes(size) as usize;
+        if size < 7 || size > MSIZE as usize {
+            return malformed();
+        }
+
+        let mut message = vec![0u8; size];
+        message[..4].copy_from_slice(&(size as u32).to_le_bytes());
+        self.reader.read_exact(&mut message[4..])?;
+        let tag = u16::from_le_bytes([message[5], message[6]]);
+        Ok((tag, message))
+    }
+}
+
+struct Fids {
+    next: u32,
+    free: Vec<u32>,
+}
+
+/// A 9P connection. Requests from several handles can be outstanding at once:
+/// whoever waits for a response reads the connection and sets aside the
+/// responses belonging to other tags.
+struct Client {
+    writer: Mutex<Box<dyn io::Write + Send>>,
+    receiver: Mutex<Receiver>,
+    next_tag: AtomicU16,
+    fids: Mutex<Fids>,
+    msize: u32,
+}
+
+impl Client {
+    fn tag(&self) -> u16 {
+        loop {
+            let tag = self.next_tag.fetch_add(1, Ordering::Relaxed);
+            if tag != NOTAG {
+                return tag;
+            }
+        }
+    }
+
+    fn alloc_fid(&self) -> u32 {
+        let mut fids = self.fids.lock();
+        fids.free.pop().unwrap_or_else(|| {
+            let fid = fids.next;
+            fids.next += 1;
+            fid
+        })
+    }
+
+    /// Sends a request of type `ty` with the body written by `body` and waits
+    /// for its response. Returns the response without its header.
+    fn rpc(&self, ty: u8, body: impl FnOnce(&mut Encoder)) -> io::Result<Vec<u8>> {
+        let tag = if ty == TVERSION { NOTAG } else { self.tag() };
+        let mut request = Encoder::new(ty, tag);
+        body(&mut request);
+        let request = request.finish();
+        if request.len() > self.msize as usize {
+            return ioerr!(InvalidInput, "9p request too large");
+        }
+
+        self.writer.lock().write_all(&request)?;
+
+        let mut response = loop {
+            let mut receiver = self.receiver.lock();
+            if let Some(response) = receiver.pending.remove(&tag) {
+                break response;
+            }
+
+            let (response_tag, response) = receiver.next()?;
+            if response_tag == tag {
+                break response;
+            }
+            receiver.pending.insert(response_tag, response);
+        };
+
+        let response_ty = response[4];
+        response.drain(..7);
+        if response_ty == RLERROR {
+            let errno = Decoder(&response).u32()?;
+            return Err(errno_to_error(errno));
+        }
+        if response_ty != ty + 1 {
+            return malformed();
+        }
+        Ok(response)
+    }
+
+    /// Walks from `fid` along `names` to a new fid. With no names the new fid
+    /// is a clone of `fid`.
+    fn walk(&self, fid: u32, names: &[&str]) -> io::Result<u32> {
+        let newfid = self.alloc_fid();
+        let mut from = fid;
+
+        let mut chunks: Vec<&[&str]> = names.chunks(MAXWELEM).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let result = self.rpc(TWALK, |e| {
+                e.u32(from).u32(newfid).u16(chunk.len() as u16);
+                for name in chunk {
+                    e.str(name);
+                }
+            });
+
+            let walked = match result {
+                Ok(response) => Decoder(&response).u16()? as usize,
+                Err(e) => return Err(self.walk_failed(newfid, i, e)),
+            };
+            // a partial walk leaves newfid as it was before this chunk.
+            if walked != chunk.len() {
+                return Err(self.walk_failed(newfid, i, newioerr!(NotFound, "no such file or directory")));
+            }
+            from = newfid;
+        }
+
+        Ok(newfid)
+    }
+
+    /// Releases `newfid` after a walk failed with `done` chunks walked. It
+    /// was only bound if one of them was.
+    fn walk_failed(&self, newfid: u32, done: usize, error: io::Error) -> io::Error {
+        if done > 0 {
+            self.clunk(newfid);
+        } else {
+            self.fids.lock().free.push(newfid);
+        }
+        error
+    }
+
+    fn clunk(&self, fid: u32) {
+        if let Err(e) = self.rpc(TCLUNK, |e| { e.u32(fid); }) {
+            warn!("9p: failed to clunk fid {}: {:?}", fid, e);
+        }
+        self.fids.lock().free.push(fid);
+    }
+
+    fn getattr(&self, fid: u32) -> io::Result<(Qid, u64)> {
+        let response = self.rpc(TGETATTR, |e| { e.u32(fid).u64(GETATTR_BASIC); })?;
+        let mut d = Decoder(&response);
+        let _valid = d.u64()?;
+        let qid = d.qid()?;
+        let _mode_uid_gid = d.take(12)?;
+        let _nlink_rdev = d.take(16)?;
+        let size = d.u64()?;
+        Ok((qid, size))
+    }
+
+    /// Opens `fid` and returns the largest read or write it allows.
+    fn lopen(&self, fid: u32, flags: u32) -> io::Result<u32> {
+        let response = self.rpc(TLOPEN, |e| { e.u32(fid).u32(flags); })?;
+        let mut d = Decoder(&response);
+        d.qid()?;
+        Ok(self.iounit(d.u32()?))
+    }
+
+    fn iounit(&self, iounit: u32) -> u32 {
+        let max = self.msize - IOHDRSZ;
+        if iounit == 0 { max } else { min(iounit, max) }
+    }
+
+    /// Lists the directory `fid`, which must not be open yet.
+    fn readdir(&self, fid: u32) -> io::Result<Vec<(Qid, String)>> {
+        let count = self.lopen(fid, O_RDONLY)?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+
+        loop {
+            let response = self.rpc(TREADDIR, |e| { e.u32(fid).u64(offset).u32(count); })?;
+            let mut d = Decoder(&response);
+            let len = d.u32()? as usize;
+            let mut d = Decoder(d.take(len)?);
+            if len == 0 {
+                return Ok(entries);
+            }
+
+            while !d.0.is_empty() {
+                let qid = d.qid()?;
+                offset = d.u64()?;
+                let _ty = d.u8()?;
+                let name = d.str()?;
+                if name != "." && name != ".." {
+                    entries.push((qid, String::from(name)));
+                }
+            }
+        }
+    }
+}
+
+fn metadata() -> Metadata {
+    Metadata {
+        read_only: Some(false),
+        hidden: Some(false),
+        ..Metadata::default()
+    }
+}
+
+/// A file system served by a 9P2000.L server.
+///
+/// The connection is given as its two directions, so that a transport like
+/// a TCP connection can be split between readers and writers. Reads from the
+/// connection should fail, rather than block forever, if the server stops
+/// responding.
+pub struct NinePFileSystem {
+    id: FsId,
+    client: Arc<Client>,
+    root: u32,
+}
+
+impl NinePFileSystem {
+    /// Negotiates the protocol version and attaches to the tree `aname` of
+    /// the server as `uname`.
+    ///
+    /// # Errors
+    ///
+    /// If the server does not speak 9P2000.L, an error kind of `InvalidData`
+    /// is returned.
+    pub fn connect(reader: Box<dyn io::Read + Send>, writer: Box<dyn io::Write + Send>, uname: &str, aname: &str) -> io::Result<Self> {
+        let mut client = Client {
+            writer: Mutex::new(writer),
+            receiver: Mutex::new(Receiver { reader, pending: HashMap::new() }),
+            next_tag: AtomicU16::new(0),
+            fids: Mutex::new(Fids { next: 1, free: Vec::new() }),
+            msize: MSIZE,
+        };
+
+        let response = client.rpc(TVERSION, |e| { e.u32(MSIZE).str(VERSION); })?;
+        let mut d = Decoder(&response);
+        client.msize = min(d.u32()?, MSIZE);
+        if d.str()? != VERSION || client.msize <= IOHDRSZ {
+            return ioerr!(InvalidData, "server does not support 9P2000.L");
+        }
+
+        let root = 0;
+        client.rpc(TATTACH, |e| { e.u32(root).u32(NOFID).str(uname).str(aname).u32(NOFID); })?;
+
+        Ok(Self {
+            id: 0,
+            client: Arc::new(client),
+            root,
+        })
+    }
+
+    /// Turns `fid` into an entry named `name`, clunking it on failure.
+    fn entry(&self, fid: u32, name: &str) -> io::Result<mfs::Entry> {
+        let result = self.client.getattr(fid).and_then(|(qid, size)| {
+            if qid.is_dir() {
+                return Ok(mfs::Entry::Dir(Arc::new(NinePDir {
+                    id: FileId(self.id, qid.path as usize),
+                    name: String::from(name),
+                    handle: Handle::new(&self.client, fid),
+                })));
+            }
+
+            // fall back to reading only when the server refuses writing.
+            let iounit = match self.client.lopen(fid, O_RDWR) {
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => self.client.lopen(fid, O_RDONLY)?,
+                result => result?,
+            };
+            Ok(self.file(fid, qid, name, size, iounit))
+        });
+
+        if result.is_err() {
+            self.client.clunk(fid);
+        }
+        result
+    }
+
+    fn file(&self, fid: u32, qid: Qid, name: &str, size: u64, iounit: u32) -> mfs::Entry {
+        mfs::Entry::File(Box::new(NinePFile {
+            id: FileId(self.id, qid.path as usize),
+            name: String::from(name),
+            handle: Handle::new(&self.client, fid),
+            iounit,
+            size,
+            position: 0,
+        }))
+    }
+
+    fn dir_fid(dir: &Arc<dyn Dir>) -> io::Result<u32> {
+        let dir: &NinePDir = dir.downcast_ref().ok_or(newioerr!(InvalidInput, "[9p] bad directory handle"))?;
+        Ok(dir.handle.fid)
+    }
+}
+
+impl Drop for NinePFileSystem {
+    fn drop(&mut self) {
+        self.client.clunk(self.root);
+    }
+}
+
+impl mfs::FileSystem for NinePFileSystem {
+    fn set_id(&mut self, id: FsId) {
+        self.id = id;
+    }
+
+    fn get_name(&self) -> Option<String> {
+        Some(String::from("9p"))
+    }
+
+    fn open(&self, _manager: &FileSystem, path: &Path) -> io::Result<mfs::Entry> {
+        let mut names: Vec<&str> = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir => names.push(".."),
+                Component::Normal(name) => names.push(name.to_str().ok_or(newioerr!(NotFound, "no such file or directory"))?),
+                _ => return ioerr!(InvalidInput, "unexpected path item"),
+            }
+        }
+
+        let fid = self.client.walk(self.root, &names)?;
+        self.entry(fid, names.last().copied().unwrap_or("/"))
+    }
+
+    fn entries(&self, _manager: &FileSystem, dir: Arc<dyn Dir>) -> io::Result<Box<dyn Iterator<Item=mfs::DirEntry>>> {
+        let fid = self.client.walk(Self::dir_fid(&dir)?, &[])?;
+        let listing = self.client.readdir(fid);
+        self.client.clunk(fid);
+
+        // directory data carries no sizes. Asking for them would take a walk
+        // per entry, so they are only known once a file is opened.
+        let entries: Vec<mfs::DirEntry> = listing?.into_iter()
+            .map(|(qid, name)| mfs::DirEntry::new(name, metadata(), 0, qid.is_dir(), FileId(self.id, qid.path as usize)))
+            .collect();
+
+        Ok(Box::new(entries.into_iter()))
+    }
+
+    fn dir_entry(&self, _manager: &FileSystem, dir: Arc<dyn Dir>, path: &OsStr) -> io::Result<mfs::Entry> {
+        let name = path.to_str().ok_or(newioerr!(NotFound, "no such file or directory"))?;
+        let fid = self.client.walk(Self::dir_fid(&dir)?, &[name])?;
+        self.entry(fid, name)
+    }
+
+    fn create_file(&self, _manager: &FileSystem, dir: Arc<dyn Dir>, name: &OsStr) -> io::Result<Box<dyn mfs::File>> {
+        let name = name.to_str().ok_or(newioerr!(InvalidInput, "file name is not valid UTF-8"))?;
+        let fid = self.client.walk(Self::dir_fid(&dir)?, &[])?;
+
+        // on success the fid refers to the new, opened file.
+        let result = self.client.rpc(TLCREATE, |e| { e.u32(fid).str(name).u32(O_RDWR | O_CREAT | O_EXCL).u32(0o644).u32(0); });
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                self.client.clunk(fid);
+                return Err(e);
+            }
+        };
+
+        let mut d = Decoder(&response);
+        let qid = d.qid()?;
+        let iounit = self.client.iounit(d.u32()?);
+        match self.file(fid, qid, name, 0, iounit) {
+            mfs::Entry::File(file) => Ok(file),
+            mfs::Entry::Dir(_) => unreachable!("created a file"),
+        }
+    }
+
+    fn create_dir(&self, _manager: &FileSystem, dir: Arc<dyn Dir>, name: &OsStr) -> io::Result<Arc<dyn Dir>> {
+        let name = name.to_str().ok_or(newioerr!(InvalidInput, "file name is not valid UTF-8"))?;
+        let dir_fid = Self::dir_fid(&dir)?;
+        self.client.rpc(TMKDIR, |e| { e.u32(dir_fid).str(name).u32(0o755).u32(0); })?;
+
+        let fid = self.client.walk(dir_fid, &[name])?;
+        match self.entry(fid, name)? {
+            mfs::Entry::Dir(dir) => Ok(dir),
+            mfs::Entry::File(_) => ioerr!(Other, "created directory is a file"),
+        }
+    }
+
+    fn remove(&self, _manager: &FileSystem, dir: Arc<dyn Dir>, name: &OsStr) -> io::Result<()> {
+        let name = name.to_str().ok_or(newioerr!(NotFound, "no such file or directory"))?;
+        let dir_fid = Self::dir_fid(&dir)?;
+
+        let fid = self.client.walk(dir_fid, &[name])?;
+        let attr = self.client.getattr(fid);
+        self.client.clunk(fid);
+
+        let flags = if attr?.0.is_dir() { AT_REMOVEDIR } else { 0 };
+        self.client.rpc(TUNLINKAT, |e| { e.u32(dir_fid).str(name).u32(flags); })?;
+        Ok(())
+    }
+}
+
+/// A fid that is clunked when dropped.
+struct Handle {
+    client: Arc<Client>,
+    fid: u32,
+}
+
+impl Handle {
+    fn new(client: &Arc<Client>, fid: u32) -> Self {
+        Handle { client: client.clone(), fid }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.client.clunk(self.fid);
+    }
+}
+
+struct NinePDir {
+    id: FileId,
+    name: String,
+    handle: Handle,
+}
+
+impl mfs::FileInfo for NinePDir {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn metadata(&self) -> Metadata {
+        metadata()
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn is_directory(&self) -> bool {
+        true
+    }
+
+    fn get_id(&self) -> FileId {
+        self.id
+    }
+}
+
+impl mfs::Dir for NinePDir {}
+
+struct NinePFile {
+    id: FileId,
+    name: String,
+    handle: Handle,
+    iounit: u32,
+    /// The size when the file was opened, updated by writes through this
+    /// handle.
+    size: u64,
+    position: u64,
+}
+
+impl mfs::FileInfo for NinePFile {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn metadata(&self) -> Metadata {
+        metadata()
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn is_directory(&self) -> bool {
+        false
+    }
+
+    fn get_id(&self) -> FileId {
+        self.id
+    }
+}
+
+impl io::Read for NinePFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = min(buf.len(), self.iounit as usize) as u32;
+        let (fid, offset) = (self.handle.fid, self.position);
+        let response = self.handle.client.rpc(TREAD, |e| { e.u32(fid).u64(offset).u32(count); })?;
+
+        let mut d = Decoder(&response);
+        let len = d.u32()? as usize;
+        if len > buf.len() {
+            return malformed();
+        }
+        buf[..len].copy_from_slice(d.take(len)?);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl io::Write for NinePFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let data = &buf[..min(buf.len(), self.iounit as usize)];
+        let (fid, offset) = (self.handle.fid, self.position);
+        let response = self.handle.client.rpc(TWRITE, |e| { e.u32(fid).u64(offset).u32(data.len() as u32).bytes(data); })?;
+
+        let written = min(Decoder(&response).u32()? as usize, data.len());
+        self.position += written as u64;
+        self.size = self.size.max(self.position);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Seek for NinePFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            io::SeekFrom::Start(offset) => offset as i64,
+            io::SeekFrom::End(offset) => self.size as i64 + offset,
+            io::SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+
+        if position < 0 {
+            return ioerr!(InvalidInput, "cannot seek before start of file");
+        }
+
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+impl mfs::File for NinePFile {
+    fn sync(&mut self) -> io::Result<()> {
+        let fid = self.handle.fid;
+        self.handle.client.rpc(TFSYNC, |e| { e.u32(fid).u32(0); })?;
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        let fid = self.handle.fid;
+        self.handle.client.rpc(TSETATTR, |e| { e.u32(fid).u32(SETATTR_SIZE).u32(0).u32(0).u32(0).u64(size).u64(0).u64(0).u64(0).u64(0); })?;
+        self.size = size;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::collections::{BTreeMap, VecDeque};
+    use alloc::string::String;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    use hashbrown::HashMap;
+    use spin::Mutex;
+
+    use shim::io::{self, Read, Seek, SeekFrom, Write};
+
+    use crate::fs::FileSystem;
+    use crate::mount::mfs::File;
+    use crate::test_util::{mount, names};
+
+    use super::*;
+
+    /// An in-memory 9P2000.L server handling requests as they are written.
+    #[derive(Default)]
+    struct Server {
+        files: BTreeMap<String, Vec<u8>>,
+        dirs: Vec<String>,
+        fids: HashMap<u32, String>,
+        outgoing: VecDeque<u8>,
+        incoming: Vec<u8>,
+        requests: usize,
+    }
+
+    fn qid_path(path: &str) -> u64 {
+        path.bytes().fold(7u64, |h, b| h.wrapping_mul(31).wrapping_add(b as u64))
+    }
+
+    impl Server {
+        fn qid(&self, e: &mut Encoder, path: &str) {
+            let ty = if self.dirs.iter().any(|d| d == path) { QTDIR } else { 0 };
+            e.u8(ty).u32(0).u64(qid_path(path));
+        }
+
+        fn exists(&self, path: &str) -> bool {
+            self.files.contains_key(path) || self.dirs.iter().any(|d| d == path)
+        }
+
+        fn handle(&mut self, message: &[u8]) {
+            self.requests += 1;
+            let mut d = Decoder(&message[4..]);
+            let ty = d.u8().unwrap();
+            let tag = d.u16().unwrap();
+
+            let mut e = Encoder::new(ty + 1, tag);
+            let error = match self.respond(ty, &mut d, &mut e) {
+                Ok(()) => None,
+                Err(errno) => Some(errno),
+            };
+            let response = match error {
+                None => e.finish(),
+                Some(errno) => {
+                    let mut e = Encoder::new(RLERROR, tag);
+                    e.u32(errno);
+                    e.finish()
+                }
+            };
+            self.outgoing.extend(response);
+        }
+
+        fn respond(&mut self, ty: u8, d: &mut Decoder, e: &mut Encoder) -> Result<(), u32> {
+            let child = |dir: &str, name: &str| if dir == "/" { format!("/{}", name) } else { format!("{}/{}", dir, name) };
+
+            match ty {
+                TVERSION => {
+                    e.u32(d.u32().unwrap()).str(VERSION);
+                }
+                TATTACH => {
+                    self.fids.insert(d.u32().unwrap(), String::from("/"));
+                    self.qid(e, "/");
+                }
+                TWALK => {
+                    let (fid, newfid, n) = (d.u32().unwrap(), d.u32().unwrap(), d.u16().unwrap());
+                    let mut path = self.fids[&fid].clone();
+                    let mut qids = Vec::new();
+                    for _ in 0..n {
+                        let next = child(&path, d.str().unwrap());
+                        if !self.exists(&next) {
+                            break;
+                        }
+                        path = next;
+                        qids.push(path.clone());
+                    }
+                    if n > 0 && qids.is_empty() {
+                        return Err(2);
+                    }
+                    if qids.len() == n as usize {
+                        self.fids.insert(newfid, path);
+                    }
+                    e.u16(qids.len() as u16);
+                    for qid in qids {
+                        self.qid(e, &qid);
+                    }
+                }
+                TLOPEN => {
+                    let path = self.fids[&d.u32().unwrap()].clone();
+                    self.qid(e, &path);
+                    e.u32(0);
+                }
+                TLCREATE => {
+                    let fid = d.u32().unwrap();
+                    let path = child(&self.fids[&fid], d.str().unwrap());
+                    if self.exists(&path) {
+                        return Err(17);
+                    }
+                    self.files.insert(path.clone(), Vec::new());
+                    self.qid(e, &path);
+                    e.u32(0);
+                    self.fids.insert(fid, path);
+                }
+                TMKDIR => {
+                    let path = child(&self.fids[&d.u32().unwrap()], d.str().unwrap());
+                    self.dirs.push(path.clone());
+                    self.qid(e, &path);
+                }
+                TUNLINKAT => {
+                    let path = child(&self.fids[&d.u32().unwrap()], d.str().unwrap());
+                    if self.files.remove(&path).is_none() {
+                        self.dirs.retain(|d| *d != path);
+                    }
+                }
+                TGETATTR => {
+                    let path = self.fids[&d.u32().unwrap()].clone();
+                    e.u64(GETATTR_BASIC);
+                    self.qid(e, &path);
+                    e.bytes(&[0; 28]).u64(self.files.get(&path).map(|f| f.len()).unwrap_or(0) as u64).bytes(&[0; 96]);
+                }
+                TREAD => {
+                    let path = &self.fids[&d.u32().unwrap()];
+                    let (offset, count) = (d.u64().unwrap() as usize, d.u32().unwrap() as usize);
+                    let data = &self.files[path];
+                    let data = &data[offset.min(data.len())..(offset + count).min(data.len())];
+                    e.u32(data.len() as u32).bytes(data);
+                }
+                TWRITE => {
+                    let path = self.fids[&d.u32().unwrap()].clone();
+                    let (offset, count) = (d.u64().unwrap() as usize, d.u32().unwrap() as usize);
+                    let data = d.take(count).unwrap();
+                    let file = self.files.get_mut(&path).unwrap();
+                    if file.len() < offset + count {
+                        file.resize(offset + count, 0);
+                    }
+                    file[offset..offset + count].copy_from_slice(data);
+                    e.u32(count as u32);
+                }
+                TREADDIR => {
+                    let path = self.fids[&d.u32().unwrap()].clone();
+                    let offset = d.u64().unwrap() as usize;
+                    let prefix = child(&path, "");
+                    let mut names: Vec<String> = self.files.keys().cloned().chain(self.dirs.iter().cloned())
+                        .filter(|p| p.len() > prefix.len() && p.starts_with(&prefix) && !p[prefix.len()..].contains('/'))
+                        .collect();
+                    names.sort();
+
+                    let mut entries = Encoder(Vec::new());
+                    for (i, name) in names.iter().enumerate().skip(offset) {
+                        self.qid(&mut entries, name);
+                        entries.u64(i as u64 + 1).u8(0).str(&name[prefix.len()..]);
+                    }
+                    e.u32(entries.0.len() as u32).bytes(&entries.0);
+                }
+                TCLUNK => {
+                    self.fids.remove(&d.u32().unwrap());
+                }
+                _ => return Err(95),
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Clone)]
+    struct Connection(Arc<Mutex<Server>>);
+
+    impl io::Write for Connection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut server = self.0.lock();
+            server.incoming.extend_from_slice(buf);
+            while server.incoming.len() >= 4 {
+                let size = u32::from_le_bytes([server.incoming[0], server.incoming[1], server.incoming[2], server.incoming[3]]) as usize;
+                if server.incoming.len() < size {
+                    break;
+                }
+                let message: Vec<u8> = server.incoming.drain(..size).collect();
+                server.handle(&message);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl io::Read for Connection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut server = self.0.lock();
+            let n = buf.len().min(server.outgoing.len());
+            for (i, b) in server.outgoing.drain(..n).enumerate() {
+                buf[i] = b;
+            }
+            Ok(n)
+        }
+    }
+
+    fn mounted() -> (FileSystem, Arc<Mutex<Server>>) {
+        let mut server = Server::default();
+        server.dirs.push(String::from("/"));
+        server.dirs.push(String::from("/bin"));
+        server.files.insert(String::from("/bin/fib"), (0..200u8).collect());
+        server.files.insert(String::from("/motd"), b"hello from the host".to_vec());
+
+        let server = Arc::new(Mutex::new(server));
+        let connection = Connection(server.clone());
+        let ninep = NinePFileSystem::connect(Box::new(connection.clone()), Box::new(connection), "pi", "/").unwrap();
+
+        (mount(Box::new(ninep)), server)
+    }
+
+    #[test]
+    fn test_ninep_read() {
+        let (mut fs, server) = mounted();
+
+        assert_eq!(names(&mut fs, "/"), ["bin", "motd"]);
+        assert_eq!(names(&mut fs, "/bin"), ["fib"]);
+
+        let mut motd = String::new();
+        fs.open("/motd").unwrap().into_file().unwrap().read_to_string(&mut motd).unwrap();
+        assert_eq!(motd, "hello from the host");
+
+        let mut fib = fs.open("/bin/fib").unwrap().into_file().unwrap();
+        assert_eq!(File::size(fib.as_ref()), 200);
+        fib.seek(SeekFrom::Start(150)).unwrap();
+        let mut data = Vec::new();
+        fib.read_to_end(&mut data).unwrap();
+        assert_eq!(data, (150..200u8).collect::<Vec<u8>>());
+        drop(fib);
+
+        assert_eq!(fs.open("/missing").err().unwrap().kind(), io::ErrorKind::NotFound);
+        assert_eq!(fs.open("/bin/missing").err().unwrap().kind(), io::ErrorKind::NotFound);
+
+        // every fid but the root was clunked.
+        assert_eq!(server.lock().fids.len(), 1);
+    }
+
+    #[test]
+    fn test_ninep_write() {
+        let (mut fs, server) = mounted();
+
+        let mut file = fs.create_file("/new").unwrap();
+        file.write_all(b"written on the pi").unwrap();
+        drop(file);
+        assert_eq!(server.lock().files["/new"], b"written on the pi");
+        assert_eq!(fs.create_file("/new").err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+
+        fs.create_dir("/dir").unwrap();
+        assert_eq!(names(&mut fs, "/"), ["bin", "dir", "motd", "new"]);
+        fs.remove("/new").unwrap();
+        fs.remove("/dir").unwrap();
+        assert_eq!(names(&mut fs, "/"), ["bin", "motd"]);
+        assert_eq!(server.lock().fids.len(), 1);
+    }
+
+    #[test]
+    fn test_ninep_multiplexing() {
+        let (_, server) = mounted();
+        let connection = Connection(server.clone());
+        let client = Client {
+            writer: Mutex::new(Box::new(connection.clone())),
+            receiver: Mutex::new(Receiver { reader: Box::new(connection), pending: HashMap::new() }),
+            next_tag: AtomicU16::new(0),
+            fids: Mutex::new(Fids { next: 100, free: Vec::new() }),
+            msize: MSIZE,
+        };
+        client.rpc(TATTACH, |e| { e.u32(99).u32(NOFID).str("pi").str("/").u32(NOFID); }).unwrap();
+
+        // a response for another request arrives first and is set aside.
+        let mut other = Encoder::new(TCLUNK + 1, 500);
+        other.u32(0);
+        server.lock().outgoing.extend(other.finish());
+
+        let (qid, size) = client.getattr(99).unwrap();
+        assert!(qid.is_dir());
+        assert_eq!(size, 0);
+        assert_eq!(client.receiver.lock().pending.len(), 1);
+        assert!(client.receiver.lock().pending.contains_key(&500));
+    }
+
+    #[test]
+    fn test_ninep_failed_walks() {
+        let (_, server) = mounted();
+        let connection = Connection(server.clone());
+        let client = Client {
+            writer: Mutex::new(Box::new(connection.clone())),
+            receiver: Mutex::new(Receiver { reader: Box::new(connection), pending: HashMap::new() }),
+            next_tag: AtomicU16::new(0),
+            fids: Mutex::new(Fids { next: 100, free: Vec::new() }),
+            msize: MSIZE,
+        };
+        client.rpc(TATTACH, |e| { e.u32(99).u32(NOFID).str("pi").str("/").u32(NOFID); }).unwrap();
+        let fids = server.lock().fids.len();
+
+        // a walk failing in its first request never binds the new fid.
+        let requests = server.lock().requests;
+        assert_eq!(client.walk(99, &["bin", "missing"]).err().unwrap().kind(), io::ErrorKind::NotFound);
+        assert_eq!(server.lock().requests, requests + 1);
+        assert_eq!(client.fids.lock().free, [100]);
+
+        // one failing after a complete request has to clunk it.
+        let mut path = String::new();
+        for _ in 0..MAXWELEM {
+            path.push_str("/d");
+            server.lock().dirs.push(path.clone());
+        }
+        let mut names = vec!["d"; MAXWELEM];
+        names.push("missing");
+        let requests = server.lock().requests;
+        assert_eq!(client.walk(99, &names).err().unwrap().kind(), io::ErrorKind::NotFound);
+        assert_eq!(server.lock().requests, requests + 3);
+        assert_eq!(server.lock().fids.len(), fids);
+    }
+
+    #[test]
+    fn test_ninep_entries_requests() {
+        let (mut fs, server) = mounted();
+        let mut listing = || {
+            let requests = server.lock().requests;
+            names(&mut fs, "/");
+            server.lock().requests - requests
+        };
+
+        // listing a directory takes no request per entry.
+        let before = listing();
+        for i in 0..20 {
+            server.lock().files.insert(format!("/file{}", i), Vec::new());
+        }
+        assert_eq!(listing(), before);
+    }
+}
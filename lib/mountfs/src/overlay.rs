@@ -0,0 +1,578 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use hashbrown::{HashMap, HashSet};
+use spin::Mutex;
+
+use shim::{ioerr, newioerr};
+use shim::ffi::OsStr;
+use shim::io;
+use shim::path::{Component, Path};
+
+use crate::fs::FileSystem;
+use crate::mount::{Metadata, mfs};
+use crate::mount::mfs::{Dir, FileId, FsId, INode};
+use crate::tmp::TmpFileSystem;
+
+const ROOT_INODE: INode = 0;
+
+// Paths inside the overlay are absolute, `/` separated and normalized.
+
+fn join(dir: &str, name: &str) -> String {
+    if dir == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[..i],
+    }
+}
+
+fn file_name(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(i) if path != "/" => &path[i + 1..],
+        _ => "/",
+    }
+}
+
+fn is_not_found<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn upper_entry(upper: &TmpFileSystem, path: &str) -> io::Result<Option<mfs::Entry>> {
+    is_not_found(upper.open_path(Path::new(path)))
+}
+
+/// Returns the upper layer directory at `path`, creating it and any missing
+/// parents first.
+fn upper_dir(upper: &TmpFileSystem, path: &str) -> io::Result<Arc<dyn Dir>> {
+    let mut dir = upper.open_path(Path::new("/"))?.into_dir().expect("tmpfs root is a directory");
+    let mut current = String::from("/");
+
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        current = join(&current, name);
+        dir = match upper_entry(upper, &current)? {
+            Some(mfs::Entry::Dir(child)) => child,
+            Some(mfs::Entry::File(_)) => return ioerr!(InvalidInput, "not a directory"),
+            None => upper.create(&dir, OsStr::new(name), true)?.into_dir().expect("created a directory"),
+        };
+    }
+
+    Ok(dir)
+}
+
+struct Layers {
+    /// Inodes are assigned per path so that ids, and mounts on top of overlay
+    /// directories, survive copy-up.
+    inodes: HashMap<String, INode>,
+    next_inode: INode,
+    /// Paths whose lower layer entry was removed. A directory recreated at a
+    /// whited out path hides the lower directory's contents.
+    whiteouts: HashSet<String>,
+}
+
+impl Layers {
+    fn inode(&mut self, path: &str) -> INode {
+        if path == "/" {
+            return ROOT_INODE;
+        }
+        if let Some(&inode) = self.inodes.get(path) {
+            return inode;
+        }
+
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.inodes.insert(String::from(path), inode);
+        inode
+    }
+}
+
+/// A file system stacking a writable in-memory upper layer over a read-only
+/// lower file system.
+///
+/// Entries in the upper layer shadow lower entries of the same name, and
+/// directories present in both are merged. Lower files are copied to the
+/// upper layer when they are first modified, and removing a lower entry
+/// records a whiteout instead of touching the lower file system.
+///
+/// A handle opened on a lower file keeps reading the lower file after the
+/// file is copied up through another handle, until it writes to the file
+/// itself. Reopen the file to see the changes made through other handles.
+pub struct OverlayFileSystem {
+    id: FsId,
+    lower: Box<dyn mfs::FileSystem>,
+    upper: Arc<TmpFileSystem>,
+    layers: Mutex<Layers>,
+}
+
+impl OverlayFileSystem {
+    pub fn new(lower: Box<dyn mfs::FileSystem>, upper: TmpFileSystem) -> Self {
+        Self {
+            id: 0,
+            lower,
+            upper: Arc::new(upper),
+            layers: Mutex::new(Layers {
+                inodes: HashMap::new(),
+                next_inode: ROOT_INODE + 1,
+                whiteouts: HashSet::new(),
+            }),
+        }
+    }
+
+    fn root(&self, manager: &FileSystem) -> io::Result<OverlayDir> {
+        let lower = self.lower.open(manager, Path::new("/"))?.into_dir();
+        Ok(OverlayDir { id: FileId(self.id, ROOT_INODE), path: String::from("/"), lower })
+    }
+
+    fn resolve(&self, manager: &FileSystem, path: &Path) -> io::Result<mfs::Entry> {
+        let mut names: Vec<&str> = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir => {
+                    names.pop();
+                }
+                Component::Normal(name) => names.push(name.to_str().ok_or(newioerr!(NotFound, "no such overlay entry"))?),
+                _ => return ioerr!(InvalidInput, "unexpected path item"),
+            }
+        }
+
+        let mut entry = mfs::Entry::Dir(Arc::new(self.root(manager)?));
+        for name in names {
+            entry = match entry {
+                mfs::Entry::Dir(dir) => self.lookup(manager, overlay_dir(&dir)?, name)?,
+                mfs::Entry::File(_) => return ioerr!(InvalidInput, "found file in directory traversal"),
+            };
+        }
+
+        Ok(entry)
+    }
+
+    fn lower_entry(&self, manager: &FileSystem, dir: &OverlayDir, name: &str) -> io::Result<Option<mfs::Entry>> {
+        let lower = match &dir.lower {
+            Some(lower) => lower.clone(),
+            None => return Ok(None),
+        };
+        if self.layers.lock().whiteouts.contains(&join(&dir.path, name)) {
+            return Ok(None);
+        }
+
+        is_not_found(self.lower.dir_entry(manager, lower, OsStr::new(name)))
+    }
+
+    fn lookup(&self, manager: &FileSystem, dir: &OverlayDir, name: &str) -> io::Result<mfs::Entry> {
+        let path = join(&dir.path, name);
+        let upper = upper_entry(&self.upper, &path)?;
+        let lower = self.lower_entry(manager, dir, name)?;
+        let id = FileId(self.id, self.layers.lock().inode(&path));
+
+        match (upper, lower) {
+            (Some(mfs::Entry::Dir(_)), lower) => {
+                let lower = lower.and_then(|entry| entry.into_dir());
+                Ok(mfs::Entry::Dir(Arc::new(OverlayDir { id, path, lower })))
+            }
+            (None, Some(mfs::Entry::Dir(lower))) => {
+                Ok(mfs::Entry::Dir(Arc::new(OverlayDir { id, path, lower: Some(lower) })))
+            }
+            (Some(mfs::Entry::File(inner)), _) => Ok(self.file(id, path, inner, true)),
+            (None, Some(mfs::Entry::File(inner))) => Ok(self.file(id, path, inner, false)),
+            (None, None) => ioerr!(NotFound, "no such overlay entry"),
+        }
+    }
+
+    fn file(&self, id: FileId, path: String, inner: Box<dyn mfs::File>, copied: bool) -> mfs::Entry {
+        mfs::Entry::File(Box::new(OverlayFile { id, path, inner, copied, upper: self.upper.clone() }))
+    }
+
+    /// Creates `name` in the upper layer copy of `dir`.
+    fn create(&self, manager: &FileSystem, dir: &Arc<dyn Dir>, name: &OsStr, is_dir: bool) -> io::Result<mfs::Entry> {
+        let dir = overlay_dir(dir)?;
+        let name_str = name.to_str().ok_or(newioerr!(InvalidInput, "file name is not valid UTF-8"))?;
+        if is_not_found(self.lookup(manager, dir, name_str))?.is_some() {
+            return ioerr!(AlreadyExists, "file already exists");
+        }
+
+        let parent = upper_dir(&self.upper, &dir.path)?;
+        let path = join(&dir.path, name_str);
+        let id = FileId(self.id, self.layers.lock().inode(&path));
+
+        match self.upper.create(&parent, name, is_dir)? {
+            mfs::Entry::Dir(_) => Ok(mfs::Entry::Dir(Arc::new(OverlayDir { id, path, lower: None }))),
+            mfs::Entry::File(inner) => Ok(self.file(id, path, inner, true)),
+        }
+    }
+}
+
+fn overlay_dir(dir: &Arc<dyn Dir>) -> io::Result<&OverlayDir> {
+    dir.downcast_ref().ok_or(newioerr!(InvalidInput, "[overlay] bad directory handle"))
+}
+
+impl mfs::FileSystem for OverlayFileSystem {
+    fn set_id(&mut self, id: FsId) {
+        self.id = id;
+    }
+
+    fn get_name(&self) -> Option<String> {
+        Some(String::from("overlay"))
+    }
+
+    fn open(&self, manager: &FileSystem, path: &Path) -> io::Result<mfs::Entry> {
+        self.resolve(manager, path)
+    }
+
+    fn entries(&self, manager: &FileSystem, dir: Arc<dyn Dir>) -> io::Result<Box<dyn Iterator<Item=mfs::DirEntry>>> {
+        let dir = overlay_dir(&dir)?;
+        let mut merged: BTreeMap<String, mfs::DirEntry> = BTreeMap::new();
+
+        if let Some(lower) = &dir.lower {
+            let entries = self.lower.entries(manager, lower.clone())?;
+            let layers = self.layers.lock();
+            for entry in entries {
+                if entry.name == "." || entry.name == ".." || layers.whiteouts.contains(&join(&dir.path, &entry.name)) {
+                    continue;
+                }
+                merged.insert(entry.name.clone(), entry);
+            }
+        }
+
+        if let Some(mfs::Entry::Dir(upper)) = upper_entry(&self.upper, &dir.path)? {
+            for entry in mfs::FileSystem::entries(self.upper.as_ref(), manager, upper)? {
+                merged.insert(entry.name.clone(), entry);
+            }
+        }
+
+        let mut layers = self.layers.lock();
+        let entries: Vec<mfs::DirEntry> = merged.into_iter().map(|(name, mut entry)| {
+            entry.id = FileId(self.id, layers.inode(&join(&dir.path, &name)));
+            entry
+        }).collect();
+
+        Ok(Box::new(entries.into_iter()))
+    }
+
+    fn dir_entry(&self, manager: &FileSystem, dir: Arc<dyn Dir>, path: &OsStr) -> io::Result<mfs::Entry> {
+        let dir = overlay_dir(&dir)?;
+        match path.to_str().ok_or(newioerr!(NotFound, "no such overlay entry"))? {
+            "." => self.resolve(manager, Path::new(&dir.path)),
+            ".." => self.resolve(manager, Path::new(parent(&dir.path))),
+            name => self.lookup(manager, dir, name),
+        }
+    }
+
+    fn create_file(&self, manager: &FileSystem, dir: Arc<dyn Dir>, name: &OsStr) -> io::Result<Box<dyn mfs::File>> {
+        match self.create(manager, &dir, name, false)? {
+            mfs::Entry::File(file) => Ok(file),
+            mfs::Entry::Dir(_) => unreachable!("created a file"),
+        }
+    }
+
+    fn create_dir(&self, manager: &FileSystem, dir: Arc<dyn Dir>, name: &OsStr) -> io::Result<Arc<dyn Dir>> {
+        match self.create(manager, &dir, name, true)? {
+            mfs::Entry::Dir(dir) => Ok(dir),
+            mfs::Entry::File(_) => unreachable!("created a directory"),
+        }
+    }
+
+    fn remove(&self, manager: &FileSystem, dir: Arc<dyn Dir>, name: &OsStr) -> io::Result<()> {
+        let overlay = overlay_dir(&dir)?;
+        let name_str = name.to_str().ok_or(newioerr!(NotFound, "no such overlay entry"))?;
+
+        if let mfs::Entry::Dir(child) = self.lookup(manager, overlay, name_str)? {
+            if self.entries(manager, child)?.next().is_some() {
+                return ioerr!(Other, "directory not empty");
+            }
+        }
+
+        let path = join(&overlay.path, name_str);
+        if upper_entry(&self.upper, &path)?.is_some() {
+            let parent = upper_dir(&self.upper, &overlay.path)?;
+            self.upper.remove_entry(&parent, name)?;
+        }
+        if self.lower_entry(manager, overlay, name_str)?.is_some() {
+            self.layers.lock().whiteouts.insert(path);
+        }
+
+        Ok(())
+    }
+}
+
+struct OverlayDir {
+    id: FileId,
+    path: String,
+    /// The lower layer directory merged into this one, if any.
+    lower: Option<Arc<dyn Dir>>,
+}
+
+impl mfs::FileInfo for OverlayDir {
+    fn name(&self) -> &str {
+        file_name(&self.path)
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata::default()
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn is_directory(&self) -> bool {
+        true
+    }
+
+    fn get_id(&self) -> FileId {
+        self.id
+    }
+}
+
+impl mfs::Dir for OverlayDir {}
+
+struct OverlayFile {
+    id: FileId,
+    path: String,
+    inner: Box<dyn mfs::File>,
+    /// Whether `inner` belongs to the upper layer.
+    copied: bool,
+    upper: Arc<TmpFileSystem>,
+}
+
+impl OverlayFile {
+    /// Switches `inner` to an upper layer copy of the file, keeping the
+    /// current position. A copy made through another handle is reused.
+    fn copy_up(&mut self) -> io::Result<()> {
+        if self.copied {
+            return Ok(());
+        }
+
+        let position = self.inner.seek(io::SeekFrom::Current(0))?;
+        let mut file = match upper_entry(&self.upper, &self.path)? {
+            Some(mfs::Entry::File(file)) => file,
+            Some(mfs::Entry::Dir(_)) => return ioerr!(InvalidInput, "not a file"),
+            None => {
+                let dir = upper_dir(&self.upper, parent(&self.path))?;
+                let name = OsStr::new(file_name(&self.path));
+                let mut file = self.upper.create(&dir, name, false)?.into_file().expect("created a file");
+
+                if let Err(e) = copy_into(self.inner.as_mut(), file.as_mut()) {
+                    let _ = self.upper.remove_entry(&dir, name);
+                    return Err(e);
+                }
+                file
+            }
+        };
+
+        file.seek(io::SeekFrom::Start(position))?;
+        self.inner = file;
+        self.copied = true;
+        Ok(())
+    }
+}
+
+fn copy_into(from: &mut dyn mfs::File, to: &mut dyn mfs::File) -> io::Result<()> {
+    let mut buf = [0u8; 512];
+    from.seek(io::SeekFrom::Start(0))?;
+    loop {
+        let read = from.read(&mut buf)?;
+        if read == 0 {
+            return Ok(());
+        }
+        io::Write::write_all(to, &buf[..read])?;
+    }
+}
+
+impl mfs::FileInfo for OverlayFile {
+    fn name(&self) -> &str {
+        file_name(&self.path)
+    }
+
+    fn metadata(&self) -> Metadata {
+        self.inner.metadata()
+    }
+
+    fn size(&self) -> u64 {
+        mfs::File::size(self.inner.as_ref())
+    }
+
+    fn is_directory(&self) -> bool {
+        false
+    }
+
+    fn get_id(&self) -> FileId {
+        self.id
+    }
+}
+
+impl io::Read for OverlayFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl io::Write for OverlayFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.copy_up()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl io::Seek for OverlayFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl mfs::File for OverlayFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn size(&self) -> u64 {
+        mfs::File::size(self.inner.as_ref())
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.copy_up()?;
+        self.inner.set_len(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use shim::ffi::OsStr;
+    use shim::io::{self, Read, Seek, SeekFrom, Write};
+    use shim::path::Path;
+
+    use crate::fs::FileSystem;
+    use crate::mount::mfs::{File, FileInfo};
+    use crate::test_util::{mount, names};
+    use crate::tmp::TmpFileSystem;
+
+    use super::OverlayFileSystem;
+
+    /// Mounts an overlay over a lower layer holding `/etc/conf` and
+    /// `/etc/old`. Also returns a handle to the lower `/etc/conf`.
+    fn mounted() -> (FileSystem, Box<dyn File>) {
+        let lower = TmpFileSystem::new(1024, 16);
+        let root = lower.open_path(Path::new("/")).unwrap().into_dir().unwrap();
+        let etc = lower.create(&root, OsStr::new("etc"), true).unwrap().into_dir().unwrap();
+        let mut conf = lower.create(&etc, OsStr::new("conf"), false).unwrap().into_file().unwrap();
+        conf.write_all(b"lower").unwrap();
+        lower.create(&etc, OsStr::new("old"), false).unwrap();
+
+        let overlay = OverlayFileSystem::new(Box::new(lower), TmpFileSystem::new(1024, 16));
+        (mount(Box::new(overlay)), conf)
+    }
+
+    fn contents(file: &mut dyn File) -> String {
+        let mut data = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_overlay_copy_up() {
+        let (mut fs, mut lower_conf) = mounted();
+
+        let mut conf = fs.open("/etc/conf").unwrap().into_file().unwrap();
+        assert_eq!(contents(conf.as_mut()), "lower");
+        let id = conf.get_id();
+
+        conf.seek(SeekFrom::Start(2)).unwrap();
+        conf.write_all(b"ng").unwrap();
+        assert_eq!(contents(conf.as_mut()), "longr");
+
+        let mut reopened = fs.open("/etc/conf").unwrap().into_file().unwrap();
+        assert_eq!(contents(reopened.as_mut()), "longr");
+        assert_eq!(reopened.get_id(), id);
+        assert_eq!(contents(lower_conf.as_mut()), "lower");
+
+        let mut old = fs.open("/etc/old").unwrap().into_file().unwrap();
+        old.set_len(3).unwrap();
+        assert_eq!(File::size(fs.open("/etc/old").unwrap().into_file().unwrap().as_ref()), 3);
+        assert_eq!(names(&mut fs, "/etc"), ["conf", "old"]);
+    }
+
+    #[test]
+    fn test_overlay_stale_lower_handle() {
+        let (mut fs, _) = mounted();
+
+        let mut stale = fs.open("/etc/conf").unwrap().into_file().unwrap();
+        let mut writer = fs.open("/etc/conf").unwrap().into_file().unwrap();
+        writer.write_all(b"upper").unwrap();
+
+        // the handle opened before the copy-up still reads the lower file...
+        assert_eq!(contents(stale.as_mut()), "lower");
+        assert_eq!(contents(fs.open("/etc/conf").unwrap().into_file().unwrap().as_mut()), "upper");
+
+        // ...until it writes, which switches it to the existing copy.
+        stale.seek(SeekFrom::Start(0)).unwrap();
+        stale.write_all(b"U").unwrap();
+        assert_eq!(contents(stale.as_mut()), "Upper");
+        assert_eq!(contents(writer.as_mut()), "Upper");
+    }
+
+    #[test]
+    fn test_overlay_whiteouts() {
+        let (mut fs, mut lower_conf) = mounted();
+
+        fs.remove("/etc/old").unwrap();
+        assert_eq!(fs.open("/etc/old").err().unwrap().kind(), io::ErrorKind::NotFound);
+        assert_eq!(names(&mut fs, "/etc"), ["conf"]);
+
+        let mut old = fs.create_file("/etc/old").unwrap();
+        assert_eq!(contents(old.as_mut()), "");
+        assert_eq!(fs.create_file("/etc/old").err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+
+        assert!(fs.remove("/etc").is_err());
+        fs.remove("/etc/old").unwrap();
+        fs.remove("/etc/conf").unwrap();
+        fs.remove("/etc").unwrap();
+        assert!(names(&mut fs, "/").is_empty());
+
+        // the recreated directory does not show the lower contents.
+        fs.create_dir("/etc").unwrap();
+        assert!(names(&mut fs, "/etc").is_empty());
+        assert_eq!(contents(lower_conf.as_mut()), "lower");
+    }
+
+    #[test]
+    fn test_overlay_merged_entries() {
+        let (mut fs, _) = mounted();
+
+        fs.create_dir("/bin").unwrap();
+        fs.create_file("/bin/test").unwrap();
+        fs.create_file("/etc/new").unwrap();
+
+        assert_eq!(names(&mut fs, "/"), ["bin", "etc"]);
+        assert_eq!(names(&mut fs, "/etc"), ["conf", "new", "old"]);
+        assert_eq!(names(&mut fs, "/bin"), ["test"]);
+
+        let dir = fs.open("/etc").unwrap().into_dir().unwrap();
+        let conf = fs.entries(dir).unwrap().find(|e| e.name == "conf").unwrap();
+        assert_eq!(conf.id, fs.open("/etc/conf").unwrap().get_id());
+    }
+}
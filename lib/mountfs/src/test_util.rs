@@ -0,0 +1,19 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::fs::FileSystem;
+use crate::mount::mfs;
+
+/// Returns a file system manager with `fs` mounted at the root.
+pub(crate) fn mount(fs: Box<dyn mfs::FileSystem>) -> FileSystem {
+    let mut manager = FileSystem::new();
+    manager.mount(None, fs).unwrap();
+    manager
+}
+
+/// Returns the names of the entries of the directory at `path`.
+pub(crate) fn names(fs: &mut FileSystem, path: &str) -> Vec<String> {
+    let dir = fs.open(path).unwrap().into_dir().unwrap();
+    fs.entries(dir).unwrap().map(|e| e.name).collect()
+}
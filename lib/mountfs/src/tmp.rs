@@ -0,0 +1,548 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+
+use hashbrown::HashMap;
+use spin::Mutex;
+
+use shim::{ioerr, newioerr};
+use shim::ffi::OsStr;
+use shim::io;
+use shim::path::{Component, Path};
+
+use crate::fs::FileSystem;
+use crate::mount::{Metadata, mfs};
+use crate::mount::mfs::{Dir, FileId, FileInfo, FsId, INode};
+
+const ROOT_INODE: INode = 0;
+
+enum NodeKind {
+    File(Vec<u8>),
+    Dir(BTreeMap<String, INode>),
+}
+
+struct Node {
+    name: String,
+    parent: INode,
+    kind: NodeKind,
+}
+
+impl Node {
+    fn size(&self) -> u64 {
+        match &self.kind {
+            NodeKind::File(data) => data.len() as u64,
+            NodeKind::Dir(_) => 0,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.kind, NodeKind::Dir(_))
+    }
+}
+
+/// The state shared by a `TmpFileSystem` and the handles it gave out.
+struct Nodes {
+    nodes: HashMap<INode, Node>,
+    /// Inodes are never reused, so a handle to a removed entry cannot alias
+    /// an entry created later.
+    next_inode: INode,
+    /// Bytes of file data currently stored.
+    used: usize,
+    capacity: usize,
+    max_nodes: usize,
+}
+
+impl Nodes {
+    fn get(&self, inode: INode) -> io::Result<&Node> {
+        self.nodes.get(&inode).ok_or(newioerr!(NotFound, "tmpfs entry was removed"))
+    }
+
+    fn children(&self, inode: INode) -> io::Result<&BTreeMap<String, INode>> {
+        match &self.get(inode)?.kind {
+            NodeKind::Dir(children) => Ok(children),
+            NodeKind::File(_) => ioerr!(InvalidInput, "not a directory"),
+        }
+    }
+
+    fn data(&mut self, inode: INode) -> io::Result<&mut Vec<u8>> {
+        match self.nodes.get_mut(&inode).map(|n| &mut n.kind) {
+            Some(NodeKind::File(data)) => Ok(data),
+            Some(NodeKind::Dir(_)) => ioerr!(InvalidInput, "not a file"),
+            None => ioerr!(NotFound, "tmpfs entry was removed"),
+        }
+    }
+
+    fn lookup(&self, dir: INode, name: &str) -> io::Result<INode> {
+        match name {
+            "." => Ok(dir),
+            ".." => Ok(self.get(dir)?.parent),
+            _ => self.children(dir)?.get(name).copied().ok_or(newioerr!(NotFound, "no such tmpfs entry")),
+        }
+    }
+
+    fn insert(&mut self, dir: INode, name: &OsStr, kind: NodeKind) -> io::Result<INode> {
+        let name = validate_name(name)?;
+        if self.children(dir)?.contains_key(name) {
+            return ioerr!(AlreadyExists, "file already exists");
+        }
+        if self.nodes.len() >= self.max_nodes {
+            return ioerr!(Other, "tmpfs is out of inodes");
+        }
+
+        let inode = self.next_inode;
+        self.next_inode += 1;
+
+        self.nodes.insert(inode, Node { name: String::from(name), parent: dir, kind });
+        if let Some(NodeKind::Dir(children)) = self.nodes.get_mut(&dir).map(|n| &mut n.kind) {
+            children.insert(String::from(name), inode);
+        }
+
+        Ok(inode)
+    }
+
+    /// Resizes the data of file `inode` to `size` bytes, zero filling any
+    /// extension, while keeping the total within the capacity.
+    fn resize(&mut self, inode: INode, size: usize) -> io::Result<()> {
+        let current = self.data(inode)?.len();
+        if size > current && size - current > self.capacity - self.used {
+            return ioerr!(Other, "no space left on tmpfs");
+        }
+
+        self.used = self.used - current + size;
+        self.data(inode)?.resize(size, 0);
+        Ok(())
+    }
+}
+
+fn validate_name(name: &OsStr) -> io::Result<&str> {
+    let name = name.to_str().ok_or(newioerr!(InvalidInput, "file name is not valid UTF-8"))?;
+    if name.is_empty() || name == "." || name == ".." || name.contains(|c| c == '/' || c == '\0') {
+        return ioerr!(InvalidInput, "invalid file name");
+    }
+    Ok(name)
+}
+
+fn metadata() -> Metadata {
+    Metadata {
+        read_only: Some(false),
+        hidden: Some(false),
+        ..Metadata::default()
+    }
+}
+
+/// A file system keeping all of its files in memory.
+///
+/// File data is limited to `capacity` bytes in total and the number of files
+/// and directories, including the root, to `max_nodes`. Handles to removed
+/// entries stay valid objects, but every operation on them fails with
+/// `NotFound`.
+pub struct TmpFileSystem {
+    id: FsId,
+    nodes: Arc<Mutex<Nodes>>,
+}
+
+impl TmpFileSystem {
+    pub fn new(capacity: usize, max_nodes: usize) -> Self {
+        let mut nodes = HashMap::new();
+        nodes.insert(ROOT_INODE, Node {
+            name: String::from("/"),
+            parent: ROOT_INODE,
+            kind: NodeKind::Dir(BTreeMap::new()),
+        });
+
+        Self {
+            id: 0,
+            nodes: Arc::new(Mutex::new(Nodes {
+                nodes,
+                next_inode: ROOT_INODE + 1,
+                used: 0,
+                capacity,
+                max_nodes,
+            })),
+        }
+    }
+
+    /// Bytes of file data currently stored.
+    pub fn used(&self) -> usize {
+        self.nodes.lock().used
+    }
+
+    /// Maximum number of bytes of file data.
+    pub fn capacity(&self) -> usize {
+        self.nodes.lock().capacity
+    }
+
+    /// Opens the entry at `path` without going through a mount manager.
+    pub(crate) fn open_path(&self, path: &Path) -> io::Result<mfs::Entry> {
+        let nodes = self.nodes.lock();
+        let mut inode = ROOT_INODE;
+
+        for component in path.components() {
+            match component {
+                Component::RootDir => inode = ROOT_INODE,
+                Component::CurDir => {}
+                Component::ParentDir => inode = nodes.get(inode)?.parent,
+                Component::Normal(name) => {
+                    let name = name.to_str().ok_or(newioerr!(NotFound, "no such tmpfs entry"))?;
+                    inode = nodes.lookup(inode, name)?;
+                }
+                _ => return ioerr!(InvalidInput, "unexpected path item"),
+            }
+        }
+
+        self.entry(&nodes, inode)
+    }
+
+    /// Creates an empty file or directory named `name` in `dir`.
+    pub(crate) fn create(&self, dir: &Arc<dyn Dir>, name: &OsStr, is_dir: bool) -> io::Result<mfs::Entry> {
+        let inode = self.dir_inode(dir)?;
+        let mut nodes = self.nodes.lock();
+
+        let kind = if is_dir { NodeKind::Dir(BTreeMap::new()) } else { NodeKind::File(Vec::new()) };
+        let child = nodes.insert(inode, name, kind)?;
+        self.entry(&nodes, child)
+    }
+
+    /// Removes the file or empty directory named `name` from `dir`.
+    pub(crate) fn remove_entry(&self, dir: &Arc<dyn Dir>, name: &OsStr) -> io::Result<()> {
+        let inode = self.dir_inode(dir)?;
+        let mut nodes = self.nodes.lock();
+
+        let name = validate_name(name)?;
+        let child = nodes.lookup(inode, name)?;
+        match &nodes.get(child)?.kind {
+            NodeKind::Dir(children) if !children.is_empty() => return ioerr!(Other, "directory not empty"),
+            NodeKind::File(data) => nodes.used -= data.len(),
+            NodeKind::Dir(_) => {}
+        }
+
+        nodes.nodes.remove(&child);
+        if let Some(NodeKind::Dir(children)) = nodes.nodes.get_mut(&inode).map(|n| &mut n.kind) {
+            children.remove(name);
+        }
+
+        Ok(())
+    }
+
+    fn entry(&self, nodes: &Nodes, inode: INode) -> io::Result<mfs::Entry> {
+        let node = nodes.get(inode)?;
+        let id = FileId(self.id, inode);
+
+        if node.is_dir() {
+            Ok(mfs::Entry::Dir(Arc::new(TmpDir { id, name: node.name.clone() })))
+        } else {
+            Ok(mfs::Entry::File(Box::new(TmpFile {
+                id,
+                name: node.name.clone(),
+                nodes: self.nodes.clone(),
+                position: 0,
+            })))
+        }
+    }
+
+    fn dir_inode(&self, dir: &Arc<dyn Dir>) -> io::Result<INode> {
+        let dir: &TmpDir = dir.downcast_ref().ok_or(newioerr!(InvalidInput, "[tmpfs] bad directory handle"))?;
+        Ok(dir.id.1)
+    }
+}
+
+impl mfs::FileSystem for TmpFileSystem {
+    fn set_id(&mut self, id: FsId) {
+        self.id = id;
+    }
+
+    fn get_name(&self) -> Option<String> {
+        Some(String::from("tmpfs"))
+    }
+
+    fn open(&self, _manager: &FileSystem, path: &Path) -> io::Result<mfs::Entry> {
+        self.open_path(path)
+    }
+
+    fn entries(&self, _manager: &FileSystem, dir: Arc<dyn Dir>) -> io::Result<Box<dyn Iterator<Item=mfs::DirEntry>>> {
+        let inode = self.dir_inode(&dir)?;
+        let nodes = self.nodes.lock();
+
+        let mut entries = Vec::new();
+        for (name, &child) in nodes.children(inode)?.iter() {
+            let node = nodes.get(child)?;
+            entries.push(mfs::DirEntry::new(name.clone(), metadata(), node.size(), node.is_dir(), FileId(self.id, child)));
+        }
+
+        Ok(Box::new(entries.into_iter()))
+    }
+
+    fn dir_entry(&self, _manager: &FileSystem, dir: Arc<dyn Dir>, path: &OsStr) -> io::Result<mfs::Entry> {
+        let inode = self.dir_inode(&dir)?;
+        let nodes = self.nodes.lock();
+
+        let name = path.to_str().ok_or(newioerr!(NotFound, "no such tmpfs entry"))?;
+        let child = nodes.lookup(inode, name)?;
+        self.entry(&nodes, child)
+    }
+
+    fn create_file(&self, _manager: &FileSystem, dir: Arc<dyn Dir>, name: &OsStr) -> io::Result<Box<dyn mfs::File>> {
+        match self.create(&dir, name, false)? {
+            mfs::Entry::File(file) => Ok(file),
+            mfs::Entry::Dir(_) => unreachable!("created a file"),
+        }
+    }
+
+    fn create_dir(&self, _manager: &FileSystem, dir: Arc<dyn Dir>, name: &OsStr) -> io::Result<Arc<dyn Dir>> {
+        match self.create(&dir, name, true)? {
+            mfs::Entry::Dir(dir) => Ok(dir),
+            mfs::Entry::File(_) => unreachable!("created a directory"),
+        }
+    }
+
+    fn remove(&self, _manager: &FileSystem, dir: Arc<dyn Dir>, name: &OsStr) -> io::Result<()> {
+        self.remove_entry(&dir, name)
+    }
+}
+
+struct TmpDir {
+    id: FileId,
+    name: String,
+}
+
+impl mfs::FileInfo for TmpDir {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn metadata(&self) -> Metadata {
+        metadata()
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn is_directory(&self) -> bool {
+        true
+    }
+
+    fn get_id(&self) -> FileId {
+        self.id
+    }
+}
+
+impl mfs::Dir for TmpDir {}
+
+pub(crate) struct TmpFile {
+    id: FileId,
+    name: String,
+    nodes: Arc<Mutex<Nodes>>,
+    position: u64,
+}
+
+impl TmpFile {
+    fn len(&self) -> u64 {
+        self.nodes.lock().get(self.id.1).map(|n| n.size()).unwrap_or(0)
+    }
+}
+
+impl mfs::FileInfo for TmpFile {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn metadata(&self) -> Metadata {
+        metadata()
+    }
+
+    fn size(&self) -> u64 {
+        self.len()
+    }
+
+    fn is_directory(&self) -> bool {
+        false
+    }
+
+    fn get_id(&self) -> FileId {
+        self.id
+    }
+}
+
+impl io::Read for TmpFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut nodes = self.nodes.lock();
+        let data = nodes.data(self.id.1)?;
+
+        if self.position >= data.len() as u64 {
+            return Ok(0);
+        }
+
+        let start = self.position as usize;
+        let amount = min(data.len() - start, buf.len());
+        buf[..amount].copy_from_slice(&data[start..start + amount]);
+        self.position += amount as u64;
+        Ok(amount)
+    }
+}
+
+impl io::Write for TmpFile {
+    /// Writes as much of `buf` as fits into the remaining capacity.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut nodes = self.nodes.lock();
+        let inode = self.id.1;
+        let start = self.position as usize;
+        let current = nodes.data(inode)?.len();
+
+        // the file can grow up to `limit` bytes before the capacity runs out.
+        let limit = current + (nodes.capacity - nodes.used);
+        if start >= limit {
+            return ioerr!(Other, "no space left on tmpfs");
+        }
+        let end = min(start + buf.len(), limit);
+
+        if end > current {
+            nodes.resize(inode, end)?;
+        }
+
+        let amount = end - start;
+        nodes.data(inode)?[start..end].copy_from_slice(&buf[..amount]);
+        self.position = end as u64;
+        Ok(amount)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Seek for TmpFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            io::SeekFrom::Start(offset) => offset as i64,
+            io::SeekFrom::End(offset) => self.len() as i64 + offset,
+            io::SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+
+        if position < 0 {
+            return ioerr!(InvalidInput, "cannot seek before start of file");
+        }
+
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+impl mfs::File for TmpFile {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.len()
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        if size > usize::max_value() as u64 {
+            return ioerr!(InvalidInput, "file size too large");
+        }
+        self.nodes.lock().resize(self.id.1, size as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use shim::io::{self, Read, Seek, SeekFrom, Write};
+
+    use crate::fs::FileSystem;
+    use crate::mount::mfs::{File, FileInfo};
+
+    use crate::test_util::{mount, names};
+
+    use super::TmpFileSystem;
+
+    fn mounted(capacity: usize, max_nodes: usize) -> FileSystem {
+        mount(Box::new(TmpFileSystem::new(capacity, max_nodes)))
+    }
+
+    #[test]
+    fn test_tmpfs_create_write_read() {
+        let mut fs = mounted(1024, 16);
+        fs.create_dir("/dir").unwrap();
+
+        let mut file = fs.create_file("/dir/file").unwrap();
+        file.write_all(b"hello world").unwrap();
+
+        let mut file = fs.open("/dir/file").unwrap().into_file().unwrap();
+        let mut data = String::new();
+        file.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "hello world");
+
+        file.seek(SeekFrom::Start(6)).unwrap();
+        file.write_all(b"tmpfs").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        data.clear();
+        file.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "hello tmpfs");
+
+        assert_eq!(names(&mut fs, "/"), ["dir"]);
+        assert_eq!(names(&mut fs, "/dir"), ["file"]);
+        assert_eq!(fs.create_file("/dir/file").err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs.create_file("/missing/file").err().unwrap().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_tmpfs_truncate_and_remove() {
+        let mut fs = mounted(1024, 16);
+        let mut file = fs.create_file("/file").unwrap();
+        file.write_all(&[1; 100]).unwrap();
+
+        file.set_len(10).unwrap();
+        assert_eq!(File::size(file.as_ref()), 10);
+        file.set_len(20).unwrap();
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(&data[..10], &[1; 10]);
+        assert_eq!(&data[10..], &[0; 10]);
+
+        fs.create_dir("/dir").unwrap();
+        fs.create_file("/dir/inner").unwrap();
+        assert!(fs.remove("/dir").is_err());
+        fs.remove("/dir/inner").unwrap();
+        fs.remove("/dir").unwrap();
+
+        fs.remove("/file").unwrap();
+        assert_eq!(file.read(&mut [0; 4]).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(names(&mut fs, "/").is_empty());
+
+        // inodes are not reused after removal.
+        let id = file.get_id();
+        assert_ne!(fs.create_file("/file").unwrap().get_id(), id);
+    }
+
+    #[test]
+    fn test_tmpfs_limits() {
+        let mut fs = mounted(64, 3);
+        let mut file = fs.create_file("/a").unwrap();
+
+        assert_eq!(file.write(&[7; 100]).unwrap(), 64);
+        assert!(file.write(&[7]).is_err());
+        assert!(file.set_len(65).is_err());
+
+        file.set_len(32).unwrap();
+        let mut other = fs.create_file("/b").unwrap();
+        other.write_all(&[1; 32]).unwrap();
+        assert!(other.write(&[1]).is_err());
+
+        assert!(fs.create_file("/c").is_err());
+        fs.remove("/b").unwrap();
+        fs.create_file("/c").unwrap();
+    }
+}
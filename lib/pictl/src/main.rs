@@ -9,13 +9,13 @@ use std::fs::File;
 use std::{io, mem};
 use pigrate_core::FakeTrapFrame;
 use pigrate_core::bundle::{MemoryBundle, ProcessBundle};
+use pigrate_core::elf::ElfImage;
 use std::net::TcpStream;
 use std::time::Duration;
 use pigrate_core::message::{Message, Echo};
 use pigrate_core::Error;
 
 pub const PAGE_SIZE: usize = 64 * 1024;
-pub const USER_IMG_BASE: usize = 0xffff_ffff_c000_0000;
 pub const STACK_BASE: usize = 0xffffffffffff0000;
 
 fn run_program(args: &[String]) {
@@ -31,8 +31,16 @@ fn run_program(args: &[String]) {
     io::copy(&mut file, &mut binary).expect("failed to read all of file");
     mem::drop(file);
 
+    let elf = match ElfImage::parse(&binary) {
+        Ok(elf) => elf,
+        Err(e) => {
+            println!("not a valid executable: {:?}", e);
+            return;
+        }
+    };
+
     let mut frame = FakeTrapFrame::default();
-    frame.elr = USER_IMG_BASE as u64;
+    frame.elr = elf.entry;
     frame.sp = STACK_BASE.wrapping_add(PAGE_SIZE) as u64;
     frame.spsr = 0x40000000;
 
@@ -44,13 +52,19 @@ fn run_program(args: &[String]) {
         memory.generic_pages.insert(STACK_BASE as u64, page);
     }
 
-    for (i, chunk) in binary.chunks(PAGE_SIZE).enumerate() {
-        let mut page: Vec<u8> = Vec::new();
-        page.extend(chunk.iter());
-        page.resize(PAGE_SIZE, 0);
-        memory.generic_pages.insert((USER_IMG_BASE + i * PAGE_SIZE) as u64, page);
-    }
+    // pages not covered by the file contents stay zero, which zeroes the bss.
+    for segment in elf.segments.iter() {
+        let first = segment.vaddr & !(PAGE_SIZE as u64 - 1);
+        for base in (first..segment.end()).step_by(PAGE_SIZE) {
+            memory.generic_pages.entry(base).or_insert_with(|| vec![0; PAGE_SIZE]);
+        }
 
+        for (i, byte) in elf.segment_data(segment).iter().enumerate() {
+            let va = segment.vaddr + i as u64;
+            let page = memory.generic_pages.get_mut(&(va & !(PAGE_SIZE as u64 - 1))).unwrap();
+            page[(va as usize) & (PAGE_SIZE - 1)] = *byte;
+        }
+    }
 
     let mut frame_enc = Vec::<u8>::new();
     frame_enc.extend_from_slice(frame.as_bytes());
@@ -0,0 +1,242 @@
+//! A minimal parser for the ELF64 executables run as user processes.
+//!
+//! Only what is needed to load a statically linked AArch64 executable is
+//! parsed: the entry point and the `PT_LOAD` program headers.
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LE: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_AARCH64: u16 = 183;
+
+const HEADER_SIZE: usize = 64;
+const PH_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+
+/// Segment is executable.
+pub const PF_X: u32 = 1;
+/// Segment is writable.
+pub const PF_W: u32 = 2;
+/// Segment is readable.
+pub const PF_R: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ElfError {
+    /// The file ends before a header or segment it describes.
+    Truncated,
+    /// The file is not an ELF file.
+    BadMagic,
+    /// Not a little endian ELF64 AArch64 executable.
+    Unsupported,
+    /// A segment with inconsistent sizes or addresses.
+    BadSegment,
+    /// The entry point is not in an executable segment.
+    BadEntry,
+}
+
+/// A loadable segment, i.e. a `PT_LOAD` program header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub vaddr: u64,
+    /// Size in memory. The bytes past `file_size` are zero (bss).
+    pub mem_size: u64,
+    pub offset: u64,
+    pub file_size: u64,
+    /// `PF_*` permission flags.
+    pub flags: u32,
+}
+
+impl Segment {
+    /// End of the segment in memory, exclusive.
+    pub fn end(&self) -> u64 {
+        self.vaddr + self.mem_size
+    }
+}
+
+/// A parsed ELF executable borrowing the file contents.
+pub struct ElfImage<'a> {
+    data: &'a [u8],
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+impl<'a> ElfImage<'a> {
+    /// Parses the headers of `data` and checks that every loadable segment
+    /// lies within the file and the entry point within an executable segment.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(if data.starts_with(MAGIC) { ElfError::Truncated } else { ElfError::BadMagic });
+        }
+        if &data[..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != CLASS_64 || data[5] != DATA_LE
+            || u16_at(data, 16) != ET_EXEC || u16_at(data, 18) != EM_AARCH64 {
+            return Err(ElfError::Unsupported);
+        }
+
+        let entry = u64_at(data, 24);
+        let ph_offset = u64_at(data, 32) as usize;
+        let ph_size = u16_at(data, 54) as usize;
+        let ph_count = u16_at(data, 56) as usize;
+
+        if ph_count > 0 && ph_size < PH_SIZE {
+            return Err(ElfError::Unsupported);
+        }
+        let ph_end = ph_size.checked_mul(ph_count)
+            .and_then(|len| len.checked_add(ph_offset))
+            .ok_or(ElfError::Truncated)?;
+        if ph_end > data.len() {
+            return Err(ElfError::Truncated);
+        }
+
+        let mut segments = Vec::new();
+        for i in 0..ph_count {
+            let ph = &data[ph_offset + i * ph_size..];
+            if u32_at(ph, 0) != PT_LOAD {
+                continue;
+            }
+
+            let segment = Segment {
+                flags: u32_at(ph, 4),
+                offset: u64_at(ph, 8),
+                vaddr: u64_at(ph, 16),
+                file_size: u64_at(ph, 32),
+                mem_size: u64_at(ph, 40),
+            };
+
+            if segment.file_size > segment.mem_size || segment.vaddr.checked_add(segment.mem_size).is_none() {
+                return Err(ElfError::BadSegment);
+            }
+            match segment.offset.checked_add(segment.file_size) {
+                Some(end) if end <= data.len() as u64 => {}
+                _ => return Err(ElfError::Truncated),
+            }
+
+            segments.push(segment);
+        }
+
+        segments.sort_by_key(|s| s.vaddr);
+        if segments.windows(2).any(|w| w[0].end() > w[1].vaddr) {
+            return Err(ElfError::BadSegment);
+        }
+
+        if !segments.iter().any(|s| s.flags & PF_X != 0 && s.vaddr <= entry && entry < s.end()) {
+            return Err(ElfError::BadEntry);
+        }
+
+        Ok(ElfImage { data, entry, segments })
+    }
+
+    /// The bytes of `segment` stored in the file, `file_size` long.
+    pub fn segment_data(&self, segment: &Segment) -> &'a [u8] {
+        &self.data[segment.offset as usize..(segment.offset + segment.file_size) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put_u16(buf: &mut [u8], offset: usize, v: u16) {
+        buf[offset..offset + 2].copy_from_slice(&v.to_le_bytes());
+    }
+
+    fn put_u32(buf: &mut [u8], offset: usize, v: u32) {
+        buf[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+    }
+
+    fn put_u64(buf: &mut [u8], offset: usize, v: u64) {
+        buf[offset..offset + 8].copy_from_slice(&v.to_le_bytes());
+    }
+
+    /// An executable with a text segment at 0x1000 holding `text` and a data
+    /// segment at 0x3000 holding 4 bytes followed by 12 bytes of bss.
+    fn image(entry: u64, text: &[u8]) -> Vec<u8> {
+        let text_offset = HEADER_SIZE + 2 * PH_SIZE;
+        let data_offset = text_offset + text.len();
+        let mut buf = vec![0u8; data_offset + 4];
+
+        buf[..4].copy_from_slice(MAGIC);
+        buf[4] = CLASS_64;
+        buf[5] = DATA_LE;
+        buf[6] = 1;
+        put_u16(&mut buf, 16, ET_EXEC);
+        put_u16(&mut buf, 18, EM_AARCH64);
+        put_u64(&mut buf, 24, entry);
+        put_u64(&mut buf, 32, HEADER_SIZE as u64);
+        put_u16(&mut buf, 54, PH_SIZE as u16);
+        put_u16(&mut buf, 56, 2);
+
+        let segments = [
+            (PF_R | PF_X, text_offset, 0x1000, text.len(), text.len()),
+            (PF_R | PF_W, data_offset, 0x3000, 4, 16),
+        ];
+        for (i, &(flags, offset, vaddr, file_size, mem_size)) in segments.iter().enumerate() {
+            let ph = HEADER_SIZE + i * PH_SIZE;
+            put_u32(&mut buf, ph, PT_LOAD);
+            put_u32(&mut buf, ph + 4, flags);
+            put_u64(&mut buf, ph + 8, offset as u64);
+            put_u64(&mut buf, ph + 16, vaddr);
+            put_u64(&mut buf, ph + 32, file_size as u64);
+            put_u64(&mut buf, ph + 40, mem_size as u64);
+        }
+
+        buf[text_offset..data_offset].copy_from_slice(text);
+        buf[data_offset..].copy_from_slice(&[1, 2, 3, 4]);
+        buf
+    }
+
+    #[test]
+    fn test_elf_segments() {
+        let buf = image(0x1004, &[0xaa; 8]);
+        let elf = ElfImage::parse(&buf).expect("valid image");
+
+        assert_eq!(elf.entry, 0x1004);
+        assert_eq!(elf.segments.len(), 2);
+        assert_eq!(elf.segments[0].flags, PF_R | PF_X);
+        assert_eq!(elf.segment_data(&elf.segments[0]), &[0xaa; 8]);
+        assert_eq!(elf.segments[1].vaddr, 0x3000);
+        assert_eq!(elf.segments[1].mem_size, 16);
+        assert_eq!(elf.segment_data(&elf.segments[1]), &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_elf_malformed() {
+        assert_eq!(ElfImage::parse(b"not an elf file").err(), Some(ElfError::BadMagic));
+
+        let buf = image(0x1000, &[0; 8]);
+        assert_eq!(ElfImage::parse(&buf[..HEADER_SIZE + 10]).err(), Some(ElfError::Truncated));
+        assert_eq!(ElfImage::parse(&buf[..buf.len() - 1]).err(), Some(ElfError::Truncated));
+
+        let mut bad_machine = buf.clone();
+        put_u16(&mut bad_machine, 18, 62);
+        assert_eq!(ElfImage::parse(&bad_machine).err(), Some(ElfError::Unsupported));
+
+        // the entry point is in the data segment
+        assert_eq!(ElfImage::parse(&image(0x3000, &[0; 8])).err(), Some(ElfError::BadEntry));
+
+        let mut overlapping = buf.clone();
+        put_u64(&mut overlapping, HEADER_SIZE + PH_SIZE + 16, 0x1004);
+        assert_eq!(ElfImage::parse(&overlapping).err(), Some(ElfError::BadSegment));
+
+        let mut bss_smaller = buf;
+        put_u64(&mut bss_smaller, HEADER_SIZE + PH_SIZE + 40, 2);
+        assert_eq!(ElfImage::parse(&bss_smaller).err(), Some(ElfError::BadSegment));
+    }
+}
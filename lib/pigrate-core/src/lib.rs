@@ -8,6 +8,7 @@ extern crate serde;
 extern crate serde_cbor;
 
 pub mod bundle;
+pub mod elf;
 mod error;
 mod frame;
 pub mod message;
@@ -3,7 +3,7 @@
 IMG=fs.img
 MNT=mnt
 
-PROGS=(sleep fib)
+PROGS=(sleep fib futex)
 
 for d in ${PROGS[@]}; do
     (cd $d; make build)
@@ -28,5 +28,5 @@ sudo mount $LOP1 $MNT
 trap "sudo umount $MNT; rmdir $MNT; sudo losetup -d $LO" EXIT
 
 for d in ${PROGS[@]}; do
-    sudo cp $d/build/$d.bin $MNT/$d
+    sudo cp $d/build/$d.elf $MNT/$d
 done
@@ -4,7 +4,7 @@
 
 mod cr0;
 
-use kernel_api::println;
+use kernel_api::{env, println};
 use kernel_api::syscall::{getpid, time};
 
 fn fib(n: u64) -> u64 {
@@ -16,9 +16,11 @@ fn fib(n: u64) -> u64 {
 }
 
 fn main() {
+    let n = env::args().nth(1).and_then(|arg| arg.parse().ok()).unwrap_or(40);
+
     println!("Started...");
 
-    let rtn = fib(40);
+    let rtn = fib(n);
 
     println!("Ended: Result = {}", rtn);
 }
@@ -0,0 +1,53 @@
+#![feature(asm)]
+#![no_std]
+#![no_main]
+
+mod cr0;
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use kernel_api::{println, OsError};
+use kernel_api::syscall::{exit_with, fork, futex_wait, futex_wake, sleep, thread_create, thread_exit, thread_join, waitpid};
+
+/// The word the second thread waits on. Its page becomes copy-on-write when
+/// the process forks, and is copied again by the first write after that.
+static WORD: AtomicU32 = AtomicU32::new(0);
+
+extern "C" fn waiter(_: u64) -> ! {
+    while WORD.load(Ordering::SeqCst) == 0 {
+        match futex_wait(&WORD, 0, Some(Duration::from_secs(5))) {
+            Ok(()) | Err(OsError::WouldBlock) => {}
+            Err(_) => thread_exit(1),
+        }
+    }
+    thread_exit(0);
+}
+
+fn check(ok: bool, what: &str) {
+    if !ok {
+        println!("futex: {} failed", what);
+        exit_with(1);
+    }
+}
+
+fn main() {
+    let tid = thread_create(waiter, 0, 0);
+    check(tid.is_ok(), "thread_create");
+
+    // give the thread time to start waiting before the page is shared.
+    let _ = sleep(Duration::from_millis(100));
+
+    let child = match fork() {
+        Ok(0) => exit_with(0),
+        Ok(child) => child,
+        Err(_) => return check(false, "fork"),
+    };
+
+    WORD.store(1, Ordering::SeqCst);
+    check(futex_wake(&WORD, 1) == Ok(1), "wake after fork");
+    check(thread_join(tid.unwrap()).map(|status| status.success()) == Ok(true), "wait");
+    check(waitpid(child).is_ok(), "waitpid");
+
+    println!("futex: ok");
+}